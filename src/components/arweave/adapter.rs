@@ -1,8 +1,146 @@
-use crate::prelude::Error;
+use super::verify_tx_data;
+use crate::prelude::{CacheWeight, Error};
+use crate::util::lfu_cache::LfuCache;
 use async_trait::async_trait;
 use bytes::Bytes;
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArweaveTag {
+    pub name: String,
+    pub value: String,
+}
+
+impl CacheWeight for ArweaveTag {
+    fn indirect_weight(&self) -> usize {
+        self.name.indirect_weight() + self.value.indirect_weight()
+    }
+}
+
+/// Everything about a transaction except its data. Transactions are
+/// immutable, and so is their metadata.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArweaveTxMetadata {
+    pub owner: String,
+    pub tags: Vec<ArweaveTag>,
+    pub data_root: String,
+    pub data_size: u64,
+}
+
+impl CacheWeight for ArweaveTxMetadata {
+    fn indirect_weight(&self) -> usize {
+        self.owner.indirect_weight()
+            + self.tags.indirect_weight()
+            + self.data_root.indirect_weight()
+    }
+}
 
 #[async_trait]
 pub trait ArweaveAdapter: Send + Sync {
     async fn tx_data(&self, tx_id: &str) -> Result<Bytes, Error>;
+
+    /// Fetch the metadata of a transaction without fetching its data
+    async fn tx_metadata(&self, tx_id: &str) -> Result<ArweaveTxMetadata, Error>;
+
+    /// Like `tx_data`, but also check that the data matches the
+    /// `data_root` and `data_size` of the transaction
+    async fn verified_tx_data(
+        &self,
+        tx_id: &str,
+        data_root: &str,
+        data_size: u64,
+    ) -> Result<Bytes, Error> {
+        let data = self.tx_data(tx_id).await?;
+        verify_tx_data(&data, data_root, data_size)?;
+        Ok(data)
+    }
+}
+
+/// An `ArweaveAdapter` that caches the metadata of transactions, since
+/// looking up the tags of the same transaction over and over again is
+/// common during indexing. Data is not cached here, since it is much larger
+/// and needs a separate budget. The cache holds at most about
+/// `max_metadata_weight` bytes of metadata.
+pub struct CachingArweaveAdapter<A> {
+    adapter: A,
+    metadata: Mutex<LfuCache<String, ArweaveTxMetadata>>,
+    max_metadata_weight: usize,
+}
+
+impl<A: ArweaveAdapter> CachingArweaveAdapter<A> {
+    pub fn new(adapter: A, max_metadata_weight: usize) -> Self {
+        Self {
+            adapter,
+            metadata: Mutex::new(LfuCache::new()),
+            max_metadata_weight,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ArweaveAdapter> ArweaveAdapter for CachingArweaveAdapter<A> {
+    async fn tx_data(&self, tx_id: &str) -> Result<Bytes, Error> {
+        self.adapter.tx_data(tx_id).await
+    }
+
+    async fn tx_metadata(&self, tx_id: &str) -> Result<ArweaveTxMetadata, Error> {
+        let tx_id = tx_id.to_owned();
+        if let Some(metadata) = self.metadata.lock().unwrap().get(&tx_id) {
+            return Ok(metadata.clone());
+        }
+
+        let metadata = self.adapter.tx_metadata(&tx_id).await?;
+        let mut cache = self.metadata.lock().unwrap();
+        cache.insert(tx_id, metadata.clone());
+        cache.evict(self.max_metadata_weight);
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingAdapter {
+        data_calls: AtomicUsize,
+        metadata_calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ArweaveAdapter for CountingAdapter {
+        async fn tx_data(&self, tx_id: &str) -> Result<Bytes, Error> {
+            self.data_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::from(tx_id.to_owned()))
+        }
+
+        async fn tx_metadata(&self, tx_id: &str) -> Result<ArweaveTxMetadata, Error> {
+            self.metadata_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(ArweaveTxMetadata {
+                owner: "owner".to_owned(),
+                tags: vec![ArweaveTag {
+                    name: "Content-Type".to_owned(),
+                    value: "application/json".to_owned(),
+                }],
+                data_root: String::new(),
+                data_size: tx_id.len() as u64,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_is_cached() {
+        let adapter = CachingArweaveAdapter::new(CountingAdapter::default(), 1_000_000);
+
+        for _ in 0..5 {
+            let metadata = adapter.tx_metadata("tx1").await.unwrap();
+            assert_eq!("Content-Type", metadata.tags[0].name);
+            adapter.tx_data("tx1").await.unwrap();
+        }
+        adapter.tx_metadata("tx2").await.unwrap();
+
+        assert_eq!(2, adapter.adapter.metadata_calls.load(Ordering::SeqCst));
+        assert_eq!(5, adapter.adapter.data_calls.load(Ordering::SeqCst));
+    }
 }
@@ -0,0 +1,189 @@
+//! Verification of the data of an Arweave transaction against the
+//! `data_root` and `data_size` of the transaction. The `data_root` is the
+//! root of a Merkle tree over the chunks of the data, computed the same way
+//! as the Arweave node and `arweave-js` do it. Checking it means that a
+//! gateway we do not trust can not hand us different data for a
+//! transaction.
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+const MIN_CHUNK_SIZE: usize = 32 * 1024;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ArweaveError {
+    #[error("transaction data has {actual} bytes, but the transaction says it has {expected}")]
+    DataSizeMismatch { expected: u64, actual: u64 },
+    #[error(
+        "transaction data has data root `{actual}`, but the transaction says it is `{expected}`"
+    )]
+    DataRootMismatch { expected: String, actual: String },
+}
+
+type Hash = [u8; 32];
+
+fn hash(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut out = Hash::default();
+    out.copy_from_slice(&hasher.finalize());
+    out
+}
+
+/// A number as the 32 byte big endian buffer the Merkle tree uses
+fn note(n: usize) -> Hash {
+    let mut buf = Hash::default();
+    buf[24..].copy_from_slice(&(n as u64).to_be_bytes());
+    buf
+}
+
+struct Node {
+    id: Hash,
+    max_byte_range: usize,
+}
+
+/// Split `data` into chunks of at most `MAX_CHUNK_SIZE` bytes. If that
+/// would leave a last chunk smaller than `MIN_CHUNK_SIZE`, the last two
+/// chunks are balanced instead.
+fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while rest.len() >= MAX_CHUNK_SIZE {
+        let mut size = MAX_CHUNK_SIZE;
+        let next = rest.len() - MAX_CHUNK_SIZE;
+        if next > 0 && next < MIN_CHUNK_SIZE {
+            size = (rest.len() + 1) / 2;
+        }
+        let (chunk, tail) = rest.split_at(size);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+    chunks
+}
+
+/// Compute the `data_root` for `data`
+pub fn data_root(data: &[u8]) -> [u8; 32] {
+    let mut offset = 0;
+    let mut layer: Vec<Node> = chunks(data)
+        .into_iter()
+        .map(|chunk| {
+            offset += chunk.len();
+            let data_hash = hash(&[chunk]);
+            Node {
+                id: hash(&[
+                    &hash(&[&data_hash[..]])[..],
+                    &hash(&[&note(offset)[..]])[..],
+                ]),
+                max_byte_range: offset,
+            }
+        })
+        .collect();
+
+    while layer.len() > 1 {
+        let mut nodes = layer.into_iter();
+        let mut next = Vec::new();
+        while let Some(left) = nodes.next() {
+            match nodes.next() {
+                Some(right) => next.push(Node {
+                    id: hash(&[
+                        &hash(&[&left.id[..]])[..],
+                        &hash(&[&right.id[..]])[..],
+                        &hash(&[&note(left.max_byte_range)[..]])[..],
+                    ]),
+                    max_byte_range: right.max_byte_range,
+                }),
+                None => next.push(left),
+            }
+        }
+        layer = next;
+    }
+    layer[0].id
+}
+
+/// Check that `data` is the data of a transaction with the given
+/// `data_root`, encoded in base64url like in the transaction, and
+/// `data_size`.
+pub fn verify_tx_data(data: &[u8], data_root: &str, data_size: u64) -> Result<(), ArweaveError> {
+    if data.len() as u64 != data_size {
+        return Err(ArweaveError::DataSizeMismatch {
+            expected: data_size,
+            actual: data.len() as u64,
+        });
+    }
+    // Transactions without data have an empty data root
+    if data.is_empty() && data_root.is_empty() {
+        return Ok(());
+    }
+    let actual = base64::encode_config(self::data_root(data), base64::URL_SAFE_NO_PAD);
+    if actual != data_root.trim_end_matches('=') {
+        return Err(ArweaveError::DataRootMismatch {
+            expected: data_root.to_owned(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded_root(data: &[u8]) -> String {
+        base64::encode_config(data_root(data), base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn chunking() {
+        let sizes = |len: usize| {
+            chunks(&vec![0; len])
+                .into_iter()
+                .map(|chunk| chunk.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![0], sizes(0));
+        assert_eq!(vec![MAX_CHUNK_SIZE, 0], sizes(MAX_CHUNK_SIZE));
+        let len = MAX_CHUNK_SIZE + MIN_CHUNK_SIZE;
+        assert_eq!(vec![MAX_CHUNK_SIZE, MIN_CHUNK_SIZE], sizes(len));
+        // A small last chunk gets balanced with the one before it
+        let len = MAX_CHUNK_SIZE + 10;
+        assert_eq!(vec![len - len / 2, len / 2], sizes(len));
+    }
+
+    #[test]
+    fn verify_matching_data() {
+        for len in &[1, 1000, MAX_CHUNK_SIZE + 10, 3 * MAX_CHUNK_SIZE + 5] {
+            let data: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            let root = encoded_root(&data);
+            assert_eq!(Ok(()), verify_tx_data(&data, &root, *len as u64));
+        }
+        assert_eq!(Ok(()), verify_tx_data(&[], "", 0));
+    }
+
+    #[test]
+    fn reject_tampered_data() {
+        let data: Vec<u8> = (0..3 * MAX_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let root = encoded_root(&data);
+
+        let mut tampered = data.clone();
+        tampered[2 * MAX_CHUNK_SIZE + 7] ^= 1;
+        match verify_tx_data(&tampered, &root, tampered.len() as u64) {
+            Err(ArweaveError::DataRootMismatch { expected, actual }) => {
+                assert_eq!(root, expected);
+                assert_eq!(encoded_root(&tampered), actual);
+            }
+            other => panic!("expected a data root mismatch, got {:?}", other),
+        }
+
+        assert_eq!(
+            Err(ArweaveError::DataSizeMismatch {
+                expected: 10,
+                actual: 9
+            }),
+            verify_tx_data(&data[..9], &root, 10)
+        );
+    }
+}
@@ -1,3 +1,5 @@
 mod adapter;
+mod data_root;
 
 pub use adapter::*;
+pub use data_root::{data_root, verify_tx_data, ArweaveError};
@@ -4,10 +4,12 @@ use crate::data::query::{CacheStatus, Query, QueryTarget};
 use crate::data::subscription::{Subscription, SubscriptionError, SubscriptionResult};
 use crate::data::{graphql::effort::LoadManager, query::QueryResults};
 use crate::prelude::SubgraphDeploymentId;
+use crate::util::unfair_semaphore::UnfairPermit;
 
 use async_trait::async_trait;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
 
 /// Future for subscription results.
 pub type SubscriptionResultFuture =
@@ -50,9 +52,55 @@ pub trait GraphQlRunner: Send + Sync + 'static {
     fn load_manager(&self) -> Arc<LoadManager>;
 }
 
+/// Permission to run a query, which is given back when it is dropped. It
+/// holds a permit from the semaphore of the load manager that handed it
+/// out, and from the semaphore that is shared by all load managers, if
+/// there is one
+pub struct QueryPermit {
+    _permit: LocalPermit,
+    _shared: Option<OwnedSemaphorePermit>,
+}
+
+/// A permit from the semaphore of a load manager, which depends on the
+/// `SemaphoreMode` it uses
+pub(crate) enum LocalPermit {
+    Fair(OwnedSemaphorePermit),
+    Unfair(UnfairPermit),
+}
+
+impl QueryPermit {
+    pub fn new(permit: OwnedSemaphorePermit, shared: Option<OwnedSemaphorePermit>) -> Self {
+        QueryPermit {
+            _permit: LocalPermit::Fair(permit),
+            _shared: shared,
+        }
+    }
+
+    /// A permit where the load manager's own permit is from an
+    /// `UnfairSemaphore`
+    pub fn unfair(permit: UnfairPermit, shared: Option<OwnedSemaphorePermit>) -> Self {
+        QueryPermit {
+            _permit: LocalPermit::Unfair(permit),
+            _shared: shared,
+        }
+    }
+}
+
 #[async_trait]
 pub trait QueryLoadManager: Send + Sync {
-    async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit;
+    async fn query_permit(&self) -> QueryPermit;
 
     fn record_work(&self, shape_hash: u64, duration: Duration, cache_status: CacheStatus);
+
+    /// Like `record_work`, for a query against `deployment`. Load managers
+    /// that do not track the effort per deployment only record the work
+    fn record_deployment_work(
+        &self,
+        _deployment: &SubgraphDeploymentId,
+        shape_hash: u64,
+        duration: Duration,
+        cache_status: CacheStatus,
+    ) {
+        self.record_work(shape_hash, duration, cache_status)
+    }
 }
@@ -0,0 +1,376 @@
+use async_trait::async_trait;
+use futures03::compat::Future01CompatExt;
+use futures03::future::join_all;
+use serde::Serialize;
+use slog::Logger;
+use std::sync::Arc;
+
+use crate::components::ethereum::EthereumAdapter;
+use crate::components::link_resolver::LinkResolver;
+use crate::components::store::{StoreError, SubgraphStore};
+use crate::components::sub::{InstanceState, SubgraphInstanceManager};
+use crate::data::sub::{Link, SubgraphDeploymentId};
+use crate::prelude::NodeId;
+
+/// The health of one component of the node
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ComponentHealth {
+    pub name: String,
+    pub healthy: bool,
+    /// What is wrong with the component, or any other information that
+    /// helps an operator understand its state
+    pub detail: Option<String>,
+}
+
+impl ComponentHealth {
+    pub fn healthy(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            healthy: true,
+            detail: None,
+        }
+    }
+
+    pub fn unhealthy(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            healthy: false,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// A component that can report whether it is able to do its work, e.g.,
+/// the store, an Ethereum adapter, the link resolver or the subgraph
+/// instance manager. Checks should be cheap since they run every time the
+/// node's readiness is queried.
+#[async_trait]
+pub trait HealthCheck: Send + Sync + 'static {
+    async fn health(&self) -> ComponentHealth;
+}
+
+/// An RPC endpoint is healthy if it answers a `net_version` request
+#[async_trait]
+impl HealthCheck for web3::transports::Http {
+    async fn health(&self) -> ComponentHealth {
+        let name = "ethereum_rpc";
+        match self.health_check().compat().await {
+            Ok(true) => ComponentHealth::healthy(name),
+            Ok(false) => ComponentHealth::unhealthy(name, "the endpoint can not be reached"),
+            Err(e) => ComponentHealth::unhealthy(name, e.to_string()),
+        }
+    }
+}
+
+/// The health of an Ethereum adapter, which is healthy if its node tells
+/// us which network it is connected to
+pub struct EthereumAdapterHealth {
+    logger: Logger,
+    network: String,
+    adapter: Arc<dyn EthereumAdapter>,
+}
+
+impl EthereumAdapterHealth {
+    pub fn new(logger: &Logger, network: String, adapter: Arc<dyn EthereumAdapter>) -> Self {
+        Self {
+            logger: logger.clone(),
+            network,
+            adapter,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for EthereumAdapterHealth {
+    async fn health(&self) -> ComponentHealth {
+        let name = format!("ethereum:{}", self.network);
+        match self.adapter.net_identifiers(&self.logger).compat().await {
+            Ok(_) => ComponentHealth::healthy(name),
+            Err(e) => ComponentHealth::unhealthy(name, e.to_string()),
+        }
+    }
+}
+
+/// The health of the store, which is healthy if it can look up the
+/// deployments assigned to `node`
+pub struct StoreHealth {
+    store: Arc<dyn SubgraphStore>,
+    node: NodeId,
+}
+
+impl StoreHealth {
+    pub fn new(store: Arc<dyn SubgraphStore>, node: NodeId) -> Self {
+        Self { store, node }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for StoreHealth {
+    async fn health(&self) -> ComponentHealth {
+        match self.store.assignments(&self.node) {
+            Ok(_) => ComponentHealth::healthy("store"),
+            Err(e) => ComponentHealth::unhealthy("store", e.to_string()),
+        }
+    }
+}
+
+/// The health of a link resolver, which is healthy if it can fetch
+/// `probe`. The probe should be a small file that is always available,
+/// e.g., one that the node's operator pinned
+pub struct LinkResolverHealth {
+    logger: Logger,
+    resolver: Arc<dyn LinkResolver>,
+    probe: Link,
+}
+
+impl LinkResolverHealth {
+    pub fn new(logger: &Logger, resolver: Arc<dyn LinkResolver>, probe: Link) -> Self {
+        Self {
+            logger: logger.clone(),
+            resolver,
+            probe,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for LinkResolverHealth {
+    async fn health(&self) -> ComponentHealth {
+        match self.resolver.cat(&self.logger, &self.probe).await {
+            Ok(_) => ComponentHealth::healthy("link_resolver"),
+            Err(e) => ComponentHealth::unhealthy("link_resolver", e.to_string()),
+        }
+    }
+}
+
+/// The health of a subgraph instance manager, which is unhealthy if the
+/// instance for any of the deployments returned by `assignments` has
+/// stopped. Deployments whose status the manager does not know are assumed
+/// to be fine
+pub struct InstanceManagerHealth {
+    manager: Arc<dyn SubgraphInstanceManager>,
+    assignments: Box<Assignments>,
+}
+
+type Assignments = dyn Fn() -> Result<Vec<SubgraphDeploymentId>, StoreError> + Send + Sync;
+
+impl InstanceManagerHealth {
+    pub fn new<F>(manager: Arc<dyn SubgraphInstanceManager>, assignments: F) -> Self
+    where
+        F: Fn() -> Result<Vec<SubgraphDeploymentId>, StoreError> + Send + Sync + 'static,
+    {
+        Self {
+            manager,
+            assignments: Box::new(assignments),
+        }
+    }
+
+    /// Check the deployments that `store` assigns to `node`
+    pub fn for_node(
+        manager: Arc<dyn SubgraphInstanceManager>,
+        store: Arc<dyn SubgraphStore>,
+        node: NodeId,
+    ) -> Self {
+        Self::new(manager, move || store.assignments(&node))
+    }
+}
+
+#[async_trait]
+impl HealthCheck for InstanceManagerHealth {
+    async fn health(&self) -> ComponentHealth {
+        let name = "instance_manager";
+        let deployments = match (self.assignments)() {
+            Ok(deployments) => deployments,
+            Err(e) => return ComponentHealth::unhealthy(name, e.to_string()),
+        };
+        let mut stopped = Vec::new();
+        for id in deployments {
+            if let Some(status) = self.manager.subgraph_status(&id).await {
+                if status.state == InstanceState::Stopped {
+                    stopped.push(id.to_string());
+                }
+            }
+        }
+        if stopped.is_empty() {
+            ComponentHealth::healthy(name)
+        } else {
+            let detail = format!("stopped subgraphs: {}", stopped.join(", "));
+            ComponentHealth::unhealthy(name, detail)
+        }
+    }
+}
+
+/// The combined health of all components of a node, as reported on the
+/// `/ready` endpoint
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Readiness {
+    /// `true` if all components are healthy
+    pub ready: bool,
+    pub components: Vec<ComponentHealth>,
+}
+
+/// Combines the health of a number of components into the readiness of the
+/// node. The node is only ready if every component is healthy.
+#[derive(Clone, Default)]
+pub struct HealthAggregator {
+    checks: Vec<Arc<dyn HealthCheck>>,
+}
+
+impl HealthAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, check: Arc<dyn HealthCheck>) {
+        self.checks.push(check);
+    }
+
+    /// Run all health checks concurrently and combine their results
+    pub async fn readiness(&self) -> Readiness {
+        let components = join_all(self.checks.iter().map(|check| check.health())).await;
+        Readiness {
+            ready: components.iter().all(|component| component.healthy),
+            components,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Mock(ComponentHealth);
+
+    #[async_trait]
+    impl HealthCheck for Mock {
+        async fn health(&self) -> ComponentHealth {
+            self.0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let mut aggregator = HealthAggregator::new();
+        assert!(aggregator.readiness().await.ready);
+
+        aggregator.add(Arc::new(Mock(ComponentHealth::healthy("store"))));
+        aggregator.add(Arc::new(Mock(ComponentHealth::healthy("ethereum"))));
+        assert!(aggregator.readiness().await.ready);
+
+        let down = ComponentHealth::unhealthy("ipfs", "gateway timed out");
+        aggregator.add(Arc::new(Mock(down.clone())));
+        let readiness = aggregator.readiness().await;
+        assert!(!readiness.ready);
+        assert_eq!(3, readiness.components.len());
+        assert_eq!(down, readiness.components[2]);
+    }
+
+    #[tokio::test]
+    async fn component_health() {
+        use crate::components::ethereum::{EthereumNetworkIdentifier, MockEthereumAdapter};
+        use crate::components::sub::InstanceStatus;
+        use crate::prelude::{anyhow, Error, JsonValueStream};
+        use slog::{o, Discard};
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        let logger = Logger::root(Discard, o!());
+
+        // Nothing listens on a port that we just released
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (_eloop, http) = web3::transports::Http::new(&format!("http://{}", addr)).unwrap();
+        assert!(!http.health().await.healthy);
+
+        let mut adapter = MockEthereumAdapter::new();
+        adapter.expect_net_identifiers().returning(|_| {
+            Box::new(futures::future::ok(EthereumNetworkIdentifier {
+                net_version: "1".to_owned(),
+                genesis_block_hash: Default::default(),
+            }))
+        });
+        let health = EthereumAdapterHealth::new(&logger, "mainnet".to_owned(), Arc::new(adapter));
+        assert_eq!(
+            ComponentHealth::healthy("ethereum:mainnet"),
+            health.health().await
+        );
+
+        struct Resolver;
+
+        #[async_trait]
+        impl LinkResolver for Resolver {
+            fn with_timeout(self, _timeout: Duration) -> Self {
+                self
+            }
+
+            fn with_retries(self) -> Self {
+                self
+            }
+
+            async fn cat(&self, _logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+                match link.link.as_str() {
+                    "/ipfs/probe" => Ok(vec![]),
+                    _ => Err(anyhow!("gateway timed out")),
+                }
+            }
+
+            async fn json_stream(
+                &self,
+                _logger: &Logger,
+                _link: &Link,
+            ) -> Result<JsonValueStream, Error> {
+                unimplemented!()
+            }
+        }
+
+        let probe = |link: &str| Link {
+            link: link.to_owned(),
+        };
+        let health = LinkResolverHealth::new(&logger, Arc::new(Resolver), probe("/ipfs/probe"));
+        assert!(health.health().await.healthy);
+        let health = LinkResolverHealth::new(&logger, Arc::new(Resolver), probe("/ipfs/gone"));
+        assert_eq!(
+            ComponentHealth::unhealthy("link_resolver", "gateway timed out"),
+            health.health().await
+        );
+
+        struct Manager;
+
+        #[async_trait]
+        impl SubgraphInstanceManager for Manager {
+            async fn start_subgraph(
+                self: Arc<Self>,
+                _id: SubgraphDeploymentId,
+                _manifest: serde_yaml::Mapping,
+            ) {
+            }
+
+            fn stop_subgraph(&self, _id: SubgraphDeploymentId) {}
+
+            async fn subgraph_status(&self, id: &SubgraphDeploymentId) -> Option<InstanceStatus> {
+                let state = match id.as_str() {
+                    "QmStopped" => InstanceState::Stopped,
+                    "QmPaused" => InstanceState::Paused,
+                    _ => return None,
+                };
+                Some(InstanceStatus { state, block: None })
+            }
+        }
+
+        let health = |ids: &'static [&'static str]| {
+            InstanceManagerHealth::new(Arc::new(Manager), move || {
+                Ok(ids
+                    .iter()
+                    .map(|id| SubgraphDeploymentId::new(*id).unwrap())
+                    .collect())
+            })
+        };
+        assert!(health(&["QmPaused", "QmUnknown"]).health().await.healthy);
+        assert_eq!(
+            ComponentHealth::unhealthy("instance_manager", "stopped subgraphs: QmStopped"),
+            health(&["QmPaused", "QmStopped"]).health().await
+        );
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::pin::Pin;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures03::prelude::Stream;
 use serde_json::Value;
 use slog::Logger;
 
+use crate::components::metrics::{
+    metric_or_noop, CounterVec, HistogramVec, MetricsRegistry, NoopCounterVec, NoopHistogramVec,
+};
 use crate::data::sub::Link;
 use crate::prelude::Error;
 
@@ -33,6 +40,17 @@ pub trait LinkResolver: Send + Sync + 'static {
     where
         Self: Sized;
 
+    /// Makes the resolver try `gateway` first for every fetch, before
+    /// falling back to its other gateways. This is used for deployments
+    /// whose files are only pinned on a particular gateway. Resolvers that
+    /// only ever fetch from one place ignore the preference.
+    fn with_preferred_gateway(self, _gateway: String) -> Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+
     /// Fetches the link contents as bytes.
     async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error>;
 
@@ -42,3 +60,633 @@ pub trait LinkResolver: Send + Sync + 'static {
     /// separately.
     async fn json_stream(&self, logger: &Logger, link: &Link) -> Result<JsonValueStream, Error>;
 }
+
+/// The order in which a resolver with several `gateways` should try them
+/// for a fetch. Without a preferred gateway, this rotates through the
+/// gateways starting at `next`, so that the load is spread across them.
+/// A `preferred` gateway always comes first, whether it is one of
+/// `gateways` or not, and is not tried twice.
+pub fn gateway_order<'a>(
+    gateways: &'a [String],
+    next: usize,
+    preferred: Option<&'a str>,
+) -> Vec<&'a str> {
+    let start = if gateways.is_empty() {
+        0
+    } else {
+        next % gateways.len()
+    };
+    preferred
+        .into_iter()
+        .chain(
+            gateways[start..]
+                .iter()
+                .chain(gateways[..start].iter())
+                .map(|gateway| gateway.as_str())
+                .filter(|gateway| Some(*gateway) != preferred),
+        )
+        .collect()
+}
+
+/// Try a fetch against `gateways` in order, moving on to the next gateway
+/// whenever one fails and starting over after the last one, until a fetch
+/// succeeds or `max_attempts` attempts have been made in total. The budget
+/// is shared by all gateways so that the time a resolve can take does not
+/// grow with the number of gateways. Returns the error from the last
+/// attempt if all of them fail.
+pub async fn try_gateways<T, F, Fut>(
+    gateways: &[&str],
+    max_attempts: usize,
+    mut fetch: F,
+) -> Result<T, Error>
+where
+    F: FnMut(&str) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut last_error = anyhow::anyhow!("no gateways to fetch from");
+    for gateway in gateways.iter().cycle().take(max_attempts) {
+        match fetch(gateway).await {
+            Ok(value) => return Ok(value),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// A resolver that fetches links from a list of HTTP IPFS gateways. Every
+/// fetch starts at the next gateway in a round-robin shared by all clones of
+/// the resolver and fails over to the other gateways, within a total budget
+/// of one attempt per gateway. `with_retries` raises the budget to
+/// `DEFAULT_RETRY_ROUNDS` passes over the gateways rather than retrying
+/// forever, and `with_retry_budget` sets it explicitly. A deployment whose
+/// files are pinned on a particular gateway should use a clone of the
+/// node's resolver `with_preferred_gateway`. Links to IPNS names are
+/// fetched conditionally through a `ConditionalCache` shared by the clones.
+#[derive(Clone)]
+pub struct GatewayResolver {
+    client: reqwest::Client,
+    gateways: Arc<Vec<String>>,
+    next: Arc<AtomicUsize>,
+    preferred: Option<String>,
+    timeout: Duration,
+    retry: bool,
+    retry_budget: Option<usize>,
+    names: Arc<ConditionalCache>,
+    metrics: Option<LinkResolverMetrics>,
+}
+
+impl GatewayResolver {
+    /// How many times `with_retries` goes through all the gateways before
+    /// giving up, unless a budget is set `with_retry_budget`
+    pub const DEFAULT_RETRY_ROUNDS: usize = 10;
+
+    pub fn new(gateways: Vec<String>) -> Self {
+        GatewayResolver {
+            client: reqwest::Client::new(),
+            gateways: Arc::new(gateways),
+            next: Arc::new(AtomicUsize::new(0)),
+            preferred: None,
+            timeout: Duration::from_secs(60),
+            retry: false,
+            retry_budget: None,
+            names: Arc::new(ConditionalCache::new()),
+            metrics: None,
+        }
+    }
+
+    /// Limit every fetch to `attempts` attempts in total, across all
+    /// gateways, whether retries are enabled or not
+    pub fn with_retry_budget(mut self, attempts: usize) -> Self {
+        self.retry_budget = Some(attempts);
+        self
+    }
+
+    fn max_attempts(&self, gateways: usize) -> usize {
+        match self.retry_budget {
+            Some(attempts) => attempts,
+            None if self.retry => gateways * Self::DEFAULT_RETRY_ROUNDS,
+            None => gateways,
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: LinkResolverMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Fetch `path`, which starts with either `/ipfs/` or `/ipns/`, from
+    /// `gateway`
+    async fn fetch(&self, gateway: &str, path: &str) -> Result<Vec<u8>, Error> {
+        let url = format!("{}{}", gateway.trim_end_matches('/'), path);
+        let fetch = async {
+            if path.starts_with("/ipns/") {
+                let body = self
+                    .names
+                    .fetch(&url, |validators| self.fetch_conditional(&url, validators))
+                    .await?;
+                return Ok((*body).clone());
+            }
+            let response = self
+                .client
+                .get(&url)
+                .timeout(self.timeout)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, Error>(response.bytes().await?.to_vec())
+        };
+        match &self.metrics {
+            Some(metrics) => metrics.measure(gateway, fetch).await,
+            None => fetch.await,
+        }
+    }
+
+    async fn fetch_conditional(
+        &self,
+        url: &str,
+        validators: Option<Validators>,
+    ) -> Result<ConditionalResponse, Error> {
+        use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+        let mut request = self.client.get(url).timeout(self.timeout);
+        if let Some(validators) = validators {
+            if let Some(etag) = validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(ConditionalResponse::NotModified);
+        }
+        let response = response.error_for_status()?;
+        let header = |name: reqwest::header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        Ok(ConditionalResponse::Content {
+            validators,
+            body: response.bytes().await?.to_vec(),
+        })
+    }
+}
+
+#[async_trait]
+impl LinkResolver for GatewayResolver {
+    fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn with_retries(mut self) -> Self {
+        self.retry = true;
+        self
+    }
+
+    fn with_preferred_gateway(mut self, gateway: String) -> Self {
+        self.preferred = Some(gateway);
+        self
+    }
+
+    async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        let path = if link.link.starts_with("/ipns/") {
+            link.link.clone()
+        } else {
+            format!("/ipfs/{}", link.link.trim_start_matches("/ipfs/"))
+        };
+        let next = self.next.fetch_add(1, Ordering::SeqCst);
+        let order = gateway_order(&self.gateways, next, self.preferred.as_deref());
+        try_gateways(&order, self.max_attempts(order.len()), |gateway| {
+            let path = &path;
+            let gateway = gateway.to_owned();
+            async move {
+                self.fetch(&gateway, path).await.map_err(|e| {
+                    slog::debug!(logger, "Failed to fetch link from gateway";
+                                 "link" => path, "gateway" => &gateway, "error" => e.to_string());
+                    e
+                })
+            }
+        })
+        .await
+    }
+
+    async fn json_stream(&self, logger: &Logger, link: &Link) -> Result<JsonValueStream, Error> {
+        let bytes = self.cat(logger, link).await?;
+        let values: Vec<_> = bytes
+            .split(|b| *b == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                serde_json::from_slice(line)
+                    .map(|value| JsonStreamValue { value, line: i + 1 })
+                    .map_err(Error::from)
+            })
+            .collect();
+        Ok(Box::pin(futures03::stream::iter(values)))
+    }
+}
+
+/// The validators a server sent along with mutable content, used to make
+/// the next request for it conditional. They should be sent as the
+/// `If-None-Match` and `If-Modified-Since` headers respectively.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// The response to a (possibly conditional) request for mutable content
+pub enum ConditionalResponse {
+    /// The server responded with `304 Not Modified`
+    NotModified,
+    /// The server sent the content
+    Content {
+        validators: Validators,
+        body: Vec<u8>,
+    },
+}
+
+/// A cache for content behind mutable names like IPNS names, which avoids
+/// downloading the content again as long as the server says it has not
+/// changed. Content addressed by a CID never changes and should not go
+/// through this cache.
+#[derive(Default)]
+pub struct ConditionalCache {
+    entries: Mutex<HashMap<String, (Validators, Arc<Vec<u8>>)>>,
+}
+
+impl ConditionalCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fetch the content for `name`. `fetch` is passed the validators from
+    /// the last time the content was fetched, if there are any, and must
+    /// make its request conditional on them. A `NotModified` response is
+    /// answered from the cache.
+    pub async fn fetch<F, Fut>(&self, name: &str, fetch: F) -> Result<Arc<Vec<u8>>, Error>
+    where
+        F: FnOnce(Option<Validators>) -> Fut,
+        Fut: Future<Output = Result<ConditionalResponse, Error>>,
+    {
+        let cached = self.entries.lock().unwrap().get(name).cloned();
+        let validators = cached.as_ref().map(|(validators, _)| validators.clone());
+        match fetch(validators).await? {
+            ConditionalResponse::NotModified => cached.map(|(_, body)| body).ok_or_else(|| {
+                anyhow::anyhow!("got `304 Not Modified` for `{}` which is not cached", name)
+            }),
+            ConditionalResponse::Content { validators, body } => {
+                let body = Arc::new(body);
+                let mut entries = self.entries.lock().unwrap();
+                if validators.is_empty() {
+                    // Without validators, we can never get a 304 for this
+                    entries.remove(name);
+                } else {
+                    entries.insert(name.to_owned(), (validators, body.clone()));
+                }
+                Ok(body)
+            }
+        }
+    }
+}
+
+/// Metrics about fetches from IPFS gateways, labeled by the host of the
+/// gateway, so that slow or failing gateways can be spotted.
+#[derive(Clone)]
+pub struct LinkResolverMetrics {
+    fetch_duration: Box<HistogramVec>,
+    errors: Box<CounterVec>,
+}
+
+impl LinkResolverMetrics {
+    pub fn new(logger: &Logger, registry: Arc<impl MetricsRegistry>) -> Self {
+        let fetch_duration = metric_or_noop(
+            logger,
+            "link_resolver_fetch_duration",
+            registry.new_histogram_vec(
+                "link_resolver_fetch_duration",
+                "Measures the duration of fetches from a gateway",
+                vec![String::from("gateway")],
+                vec![0.05, 0.2, 0.5, 1.0, 3.0, 10.0, 30.0],
+            ),
+            || Box::new(NoopHistogramVec(&["gateway"]).into()),
+        );
+        let errors = metric_or_noop(
+            logger,
+            "link_resolver_errors_total",
+            registry.new_counter_vec(
+                "link_resolver_errors_total",
+                "Counts failed fetches from a gateway",
+                vec![String::from("gateway")],
+            ),
+            || Box::new(NoopCounterVec(&["gateway"]).into()),
+        );
+        Self {
+            fetch_duration,
+            errors,
+        }
+    }
+
+    pub fn observe_fetch(&self, duration: f64, gateway: &str) {
+        self.fetch_duration
+            .with_label_values(vec![gateway].as_slice())
+            .observe(duration);
+    }
+
+    pub fn add_error(&self, gateway: &str) {
+        self.errors
+            .with_label_values(vec![gateway].as_slice())
+            .inc();
+    }
+
+    /// Run `fetch` against `gateway`, recording how long it took and
+    /// whether it failed
+    pub async fn measure<T, E>(
+        &self,
+        gateway: &str,
+        fetch: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let result = fetch.await;
+        self.observe_fetch(start.elapsed().as_secs_f64(), gateway);
+        if result.is_err() {
+            self.add_error(gateway);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::metrics::MockMetricsRegistry;
+    use futures03::stream::StreamExt;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    async fn gateway(delay_ms: u64, fail: bool) -> Result<Vec<u8>, Error> {
+        tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+        if fail {
+            Err(anyhow::anyhow!("gateway timeout"))
+        } else {
+            Ok(b"{}".to_vec())
+        }
+    }
+
+    #[test]
+    fn preferred_gateway_comes_first() {
+        let gateways: Vec<_> = vec!["a", "b", "c"].into_iter().map(String::from).collect();
+
+        assert_eq!(vec!["a", "b", "c"], gateway_order(&gateways, 0, None));
+        assert_eq!(vec!["c", "a", "b"], gateway_order(&gateways, 5, None));
+
+        for next in 0..3 {
+            let order = gateway_order(&gateways, next, Some("b"));
+            assert_eq!("b", order[0]);
+            assert_eq!(3, order.len());
+        }
+        assert_eq!(vec!["b", "c", "a"], gateway_order(&gateways, 2, Some("b")));
+        assert_eq!(
+            vec!["pinned", "b", "c", "a"],
+            gateway_order(&gateways, 1, Some("pinned"))
+        );
+        assert_eq!(vec!["pinned"], gateway_order(&[], 1, Some("pinned")));
+    }
+
+    /// Serve every request on a local port from a background thread,
+    /// answering with `status` and counting the requests in `hits`
+    fn serve(status: &'static str, hits: Arc<AtomicUsize>) -> String {
+        serve_with(move |_| {
+            hits.fetch_add(1, Ordering::SeqCst);
+            let body = "{\"pinned\": true}";
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+        })
+    }
+
+    /// Serve every request on a local port from a background thread with
+    /// the response `respond` builds from the request head
+    fn serve_with(respond: impl Fn(&str) -> String + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = respond(&String::from_utf8_lossy(&request));
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn pinned_gateway_is_tried_first() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let link = Link::from("/ipfs/QmManifest".to_owned());
+        let hits: Vec<_> = (0..3).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+        let down = serve("500 Internal Server Error", hits[0].clone());
+        let other = serve("200 OK", hits[1].clone());
+        let pinned = serve("200 OK", hits[2].clone());
+
+        let resolver = GatewayResolver::new(vec![down.clone(), other.clone()]);
+        let deployment = resolver.clone().with_preferred_gateway(pinned);
+
+        // The node's resolver rotates through its gateways and fails over
+        resolver.cat(&logger, &link).await.unwrap();
+        resolver.cat(&logger, &link).await.unwrap();
+        assert_eq!(1, hits[0].load(Ordering::SeqCst));
+        assert_eq!(2, hits[1].load(Ordering::SeqCst));
+
+        // The deployment only ever goes to its pinned gateway, wherever
+        // the round-robin is
+        for _ in 0..3 {
+            let values: Vec<_> = deployment
+                .json_stream(&logger, &link)
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(1, values.len());
+            let value = values.into_iter().next().unwrap().unwrap();
+            assert_eq!(serde_json::json!({ "pinned": true }), value.value);
+        }
+        assert_eq!(3, hits[2].load(Ordering::SeqCst));
+        assert_eq!(1, hits[0].load(Ordering::SeqCst));
+        assert_eq!(2, hits[1].load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn retry_budget_is_shared() {
+        let mut attempts = Vec::new();
+        let result: Result<(), Error> = try_gateways(&["a", "b", "c"], 4, |gateway| {
+            attempts.push(gateway.to_owned());
+            let err = anyhow::anyhow!("{} is down", gateway);
+            async move { Err(err) }
+        })
+        .await;
+        assert_eq!(vec!["a", "b", "c", "a"], attempts);
+        assert_eq!("a is down", result.unwrap_err().to_string());
+
+        let mut attempts = 0;
+        let result = try_gateways(&["a", "b", "c"], 4, |gateway| {
+            attempts += 1;
+            let gateway = gateway.to_owned();
+            async move {
+                if gateway == "b" {
+                    Ok(gateway)
+                } else {
+                    Err(anyhow::anyhow!("down"))
+                }
+            }
+        })
+        .await;
+        assert_eq!("b", result.unwrap());
+        assert_eq!(2, attempts);
+    }
+
+    #[tokio::test]
+    async fn resolver_stops_at_retry_budget() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let link = Link::from("/ipfs/QmManifest".to_owned());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let gateways = (0..3)
+            .map(|_| serve("500 Internal Server Error", hits.clone()))
+            .collect();
+
+        let resolver = GatewayResolver::new(gateways)
+            .with_retries()
+            .with_retry_budget(4);
+        assert!(resolver.cat(&logger, &link).await.is_err());
+        assert_eq!(4, hits.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn not_modified_is_served_from_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache = ConditionalCache::new();
+        let requests = AtomicUsize::new(0);
+        // A server that only ever has one version of the content
+        let server = |validators: Option<Validators>| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match validators.and_then(|v| v.etag) {
+                    Some(etag) if etag == "\"v1\"" => {
+                        Ok::<_, Error>(ConditionalResponse::NotModified)
+                    }
+                    _ => Ok(ConditionalResponse::Content {
+                        validators: Validators {
+                            etag: Some("\"v1\"".to_owned()),
+                            last_modified: None,
+                        },
+                        body: b"content".to_vec(),
+                    }),
+                }
+            }
+        };
+
+        let first = cache.fetch("/ipns/name", server).await.unwrap();
+        let second = cache.fetch("/ipns/name", server).await.unwrap();
+        assert_eq!(b"content".to_vec(), *first);
+        assert_eq!(first, second);
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+
+        let err = cache
+            .fetch("/ipns/other", |_| async {
+                Ok(ConditionalResponse::NotModified)
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not cached"));
+    }
+
+    #[tokio::test]
+    async fn ipns_names_are_fetched_conditionally() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let link = Link::from("/ipns/name".to_owned());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let gateway = {
+            let requests = requests.clone();
+            serve_with(move |request| {
+                requests.lock().unwrap().push(request.to_lowercase());
+                if request.to_lowercase().contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_owned()
+                } else {
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\
+                     Content-Length: 7\r\nConnection: close\r\n\r\ncontent"
+                        .to_owned()
+                }
+            })
+        };
+
+        let resolver = GatewayResolver::new(vec![gateway]);
+        let first = resolver.cat(&logger, &link).await.unwrap();
+        let second = resolver.cat(&logger, &link).await.unwrap();
+        assert_eq!(b"content".to_vec(), first);
+        assert_eq!(first, second);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+        assert!(requests[0].starts_with("get /ipns/name "));
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn metrics_by_gateway() {
+        let metrics = LinkResolverMetrics::new(
+            &Logger::root(slog::Discard, slog::o!()),
+            Arc::new(MockMetricsRegistry::new()),
+        );
+
+        for _ in 0..3 {
+            metrics.measure("fast.io", gateway(1, false)).await.unwrap();
+            metrics
+                .measure("slow.io", gateway(100, false))
+                .await
+                .unwrap();
+        }
+        metrics
+            .measure("slow.io", gateway(1, true))
+            .await
+            .unwrap_err();
+
+        let durations = |host| metrics.fetch_duration.with_label_values(&[host]);
+        assert_eq!(3, durations("fast.io").get_sample_count());
+        assert_eq!(4, durations("slow.io").get_sample_count());
+        assert!(durations("slow.io").get_sample_sum() >= 0.3);
+        assert!(durations("fast.io").get_sample_sum() < durations("slow.io").get_sample_sum());
+
+        let errors = |host| metrics.errors.with_label_values(&[host]).get() as u64;
+        assert_eq!(0, errors("fast.io"));
+        assert_eq!(1, errors("slow.io"));
+    }
+}
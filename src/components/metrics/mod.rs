@@ -3,7 +3,9 @@ pub use prometheus::{
     labels, Counter, CounterVec, Error as PrometheusError, Gauge, GaugeVec, Histogram,
     HistogramOpts, HistogramVec, Opts, Registry,
 };
+use slog::{error, Logger};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 /// Metrics for measuring where time is spent during indexing.
 pub mod stopwatch;
@@ -11,6 +13,10 @@ pub mod stopwatch;
 /// Aggregates over individual values.
 pub mod aggregate;
 
+/// Export of load manager signals to OpenTelemetry.
+#[cfg(feature = "otel")]
+pub mod otel;
+
 fn deployment_labels(subgraph: &str) -> HashMap<String, String> {
     labels! { String::from("deployment") => String::from(subgraph), }
 }
@@ -35,6 +41,71 @@ pub fn gauge_with_labels(
     Gauge::with_opts(opts)
 }
 
+/// Stand-in for a counter that could not be created. It behaves like a
+/// normal counter, but is not registered anywhere.
+pub struct NoopCounter;
+
+impl From<NoopCounter> for Counter {
+    fn from(_: NoopCounter) -> Self {
+        Counter::new("noop_counter", "A counter that is not registered").unwrap()
+    }
+}
+
+/// Stand-in for a gauge that could not be created. It behaves like a
+/// normal gauge, but is not registered anywhere.
+pub struct NoopGauge;
+
+impl From<NoopGauge> for Gauge {
+    fn from(_: NoopGauge) -> Self {
+        Gauge::new("noop_gauge", "A gauge that is not registered").unwrap()
+    }
+}
+
+/// Stand-in for a counter vec with the given labels that could not be
+/// created. It behaves like a normal counter vec, but is not registered
+/// anywhere.
+pub struct NoopCounterVec<'a>(pub &'a [&'a str]);
+
+impl From<NoopCounterVec<'_>> for CounterVec {
+    fn from(noop: NoopCounterVec) -> Self {
+        let opts = Opts::new("noop_counter_vec", "A counter vec that is not registered");
+        CounterVec::new(opts, noop.0).unwrap()
+    }
+}
+
+/// Stand-in for a histogram vec with the given labels that could not be
+/// created. It behaves like a normal histogram vec, but is not registered
+/// anywhere.
+pub struct NoopHistogramVec<'a>(pub &'a [&'a str]);
+
+impl From<NoopHistogramVec<'_>> for HistogramVec {
+    fn from(noop: NoopHistogramVec) -> Self {
+        let opts = HistogramOpts::new(
+            "noop_histogram_vec",
+            "A histogram vec that is not registered",
+        );
+        HistogramVec::new(opts, noop.0).unwrap()
+    }
+}
+
+/// Return the metric from `result`, or, if creating the metric `name`
+/// failed, log the error and return `noop()` instead. Failing to create a
+/// metric, e.g., because one with the same name already exists, should not
+/// stop the node from working.
+pub fn metric_or_noop<M>(
+    logger: &Logger,
+    name: &str,
+    result: Result<M, PrometheusError>,
+    noop: impl FnOnce() -> M,
+) -> M {
+    result.unwrap_or_else(|e| {
+        error!(logger, "Failed to create metric, it will not be reported";
+            "metric" => name,
+            "error" => e.to_string());
+        noop()
+    })
+}
+
 pub trait MetricsRegistry: Send + Sync + 'static {
     fn register(&self, name: &str, c: Box<dyn Collector>);
 
@@ -126,6 +197,41 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         Ok(gauges)
     }
 
+    /// Return a name for a new metric based on `name` that no other metric
+    /// created through this registry uses, by appending a suffix like `_2`
+    /// if necessary. Registries that do not keep track of the names they
+    /// hand out return `name` unchanged.
+    fn unique_name(&self, name: &str) -> String {
+        name.to_owned()
+    }
+
+    /// Like `new_gauge`, but register the gauge under `unique_name(name)`
+    /// so that several components can each have their own gauge. Returns
+    /// the gauge together with the name it was registered under.
+    fn new_unique_gauge(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<(Box<Gauge>, String), PrometheusError> {
+        let name = self.unique_name(name);
+        let gauge = self.new_gauge(&name, help, const_labels)?;
+        Ok((gauge, name))
+    }
+
+    /// Like `new_counter`, but register the counter under
+    /// `unique_name(name)`. Returns the counter together with the name it
+    /// was registered under.
+    fn new_unique_counter(
+        &self,
+        name: &str,
+        help: &str,
+    ) -> Result<(Box<Counter>, String), PrometheusError> {
+        let name = self.unique_name(name);
+        let counter = self.new_counter(&name, help)?;
+        Ok((counter, name))
+    }
+
     fn new_counter(&self, name: &str, help: &str) -> Result<Box<Counter>, PrometheusError> {
         let opts = Opts::new(name.clone(), help);
         let counter = Box::new(Counter::with_opts(opts)?);
@@ -260,3 +366,91 @@ pub trait MetricsRegistry: Send + Sync + 'static {
         Ok(histograms)
     }
 }
+
+/// Hands out metric names, appending a suffix to names that have been
+/// handed out before. Meant to implement `MetricsRegistry::unique_name`.
+#[derive(Default)]
+pub struct MetricNames {
+    used: Mutex<HashMap<String, usize>>,
+}
+
+impl MetricNames {
+    pub fn unique(&self, name: &str) -> String {
+        let mut used = self.used.lock().unwrap();
+        let count = used.entry(name.to_owned()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            name.to_owned()
+        } else {
+            format!("{}_{}", name, count)
+        }
+    }
+}
+
+/// A `MetricsRegistry` that creates metrics but does not register them
+/// anywhere. Meant for tests.
+#[derive(Clone, Default)]
+pub struct MockMetricsRegistry {
+    names: Arc<MetricNames>,
+}
+
+impl MockMetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MetricsRegistry for MockMetricsRegistry {
+    fn register(&self, _name: &str, _c: Box<dyn Collector>) {}
+
+    fn unique_name(&self, name: &str) -> String {
+        self.names.unique(name)
+    }
+
+    fn unregister(&self, _metric: Box<dyn Collector>) {}
+
+    fn global_counter(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Counter, PrometheusError> {
+        counter_with_labels(name, help, const_labels)
+    }
+
+    fn global_gauge(
+        &self,
+        name: &str,
+        help: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Gauge, PrometheusError> {
+        gauge_with_labels(name, help, const_labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_metric_names() {
+        let registry = MockMetricsRegistry::new();
+        let (first, first_name) = registry
+            .new_unique_gauge("query_effort_ms", "effort", HashMap::new())
+            .unwrap();
+        let (second, second_name) = registry
+            .new_unique_gauge("query_effort_ms", "effort", HashMap::new())
+            .unwrap();
+        assert_eq!("query_effort_ms", first_name);
+        assert_eq!("query_effort_ms_2", second_name);
+
+        first.set(5.0);
+        assert_eq!(5.0, first.get());
+        assert_eq!(0.0, second.get());
+
+        let (_, name) = registry.new_unique_counter("query_effort_ms", "c").unwrap();
+        assert_eq!("query_effort_ms_3", name);
+        let (_, name) = registry.new_unique_counter("other", "c").unwrap();
+        assert_eq!("other", name);
+    }
+}
@@ -0,0 +1,61 @@
+use opentelemetry::metrics::{Counter, Meter, ValueRecorder};
+use opentelemetry::KeyValue;
+
+use crate::data::graphql::effort::LoadMetricsExporter;
+use crate::data::query::CacheStatus;
+
+/// Sends the signals of the `LoadManager` to OpenTelemetry. The
+/// instruments have the same names as the corresponding Prometheus
+/// metrics. The values of the Prometheus gauges are recorded every time
+/// they change, so that a last-value aggregation yields the same value
+/// as the gauge.
+pub struct OtelLoadExporter {
+    effort: ValueRecorder<f64>,
+    semaphore_wait: ValueRecorder<f64>,
+    cache_status: Counter<u64>,
+    jailed: Counter<u64>,
+}
+
+impl OtelLoadExporter {
+    pub fn new(meter: &Meter) -> Self {
+        Self {
+            effort: meter
+                .f64_value_recorder("query_effort_ms")
+                .with_description("Moving average of time spent running queries")
+                .init(),
+            semaphore_wait: meter
+                .f64_value_recorder("query_semaphore_wait_ms")
+                .with_description("Moving average of time spent waiting for the query semaphore")
+                .init(),
+            cache_status: meter
+                .u64_counter("query_cache_status_count")
+                .with_description("Count toplevel GraphQL fields executed and their cache status")
+                .init(),
+            jailed: meter
+                .u64_counter("query_jailed_count")
+                .with_description("Count queries that were jailed for being too expensive")
+                .init(),
+        }
+    }
+}
+
+impl LoadMetricsExporter for OtelLoadExporter {
+    fn effort_ms(&self, effort_ms: f64) {
+        self.effort.record(effort_ms, &[]);
+    }
+
+    fn semaphore_wait_ms(&self, wait_ms: f64) {
+        self.semaphore_wait.record(wait_ms, &[]);
+    }
+
+    fn cache_status(&self, cache_status: CacheStatus) {
+        self.cache_status.add(
+            1,
+            &[KeyValue::new("cache_status", cache_status.to_string())],
+        );
+    }
+
+    fn jailed(&self, _shape_hash: u64) {
+        self.jailed.add(1, &[]);
+    }
+}
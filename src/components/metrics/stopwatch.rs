@@ -1,11 +1,18 @@
+use crate::components::metrics::{metric_or_noop, NoopCounterVec};
 use crate::prelude::*;
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Mutex};
 use std::time::Instant;
 
+/// Where a stopwatch gets the current time from
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
 /// This is a "section guard", that closes the section on drop.
 pub struct Section {
     id: String,
     stopwatch: StopwatchMetrics,
+    // Whether the section was started while the stopwatch was enabled. Only
+    // such sections need to be ended.
+    started: bool,
 }
 
 impl Section {
@@ -15,8 +22,10 @@ impl Section {
 
 impl Drop for Section {
     fn drop(&mut self) {
-        self.stopwatch
-            .end_section(std::mem::replace(&mut self.id, String::new()))
+        if self.started {
+            self.stopwatch
+                .end_section(std::mem::replace(&mut self.id, String::new()))
+        }
     }
 }
 
@@ -33,6 +42,13 @@ impl Drop for Section {
 /// // do stuff...
 /// // At the end of the scope `_main_section` is dropped, which is equivalent to calling
 /// // `_main_section.end()`.
+/// ```
+///
+/// By default, time is recorded under the name of the innermost section
+/// only. A stopwatch created with `with_hierarchy(.., true)` records it
+/// under the path of all open sections instead, e.g. `main_section/child_section`,
+/// so that sections with the same name under different parents are kept
+/// apart.
 #[derive(Clone)]
 pub struct StopwatchMetrics {
     disabled: Arc<AtomicBool>,
@@ -45,21 +61,36 @@ impl StopwatchMetrics {
         subgraph_id: SubgraphDeploymentId,
         registry: Arc<dyn MetricsRegistry>,
     ) -> Self {
+        Self::with_hierarchy(logger, subgraph_id, registry, false)
+    }
+
+    /// Create a stopwatch that labels the time spent in a section with the
+    /// path of all enclosing sections if `hierarchical` is `true`, and with
+    /// just the name of the section otherwise
+    pub fn with_hierarchy(
+        logger: Logger,
+        subgraph_id: SubgraphDeploymentId,
+        registry: Arc<dyn MetricsRegistry>,
+        hierarchical: bool,
+    ) -> Self {
+        let counter = metric_or_noop(
+            &logger,
+            "deployment_sync_secs",
+            registry.new_deployment_counter_vec(
+                "deployment_sync_secs",
+                "total time spent syncing",
+                subgraph_id.as_str(),
+                vec!["section".to_owned()],
+            ),
+            || Box::new(NoopCounterVec(&["section"]).into()),
+        );
         let mut inner = StopwatchInner {
-            counter: *registry
-                .new_deployment_counter_vec(
-                    "deployment_sync_secs",
-                    "total time spent syncing",
-                    subgraph_id.as_str(),
-                    vec!["section".to_owned()],
-                )
-                .expect(&format!(
-                    "failed to register subgraph_sync_total_secs prometheus counter for {}",
-                    subgraph_id
-                )),
+            counter: *counter,
             logger,
             section_stack: Vec::new(),
+            clock: Arc::new(Instant::now),
             timer: Instant::now(),
+            hierarchical,
         };
 
         // Start a base section so that all time is accounted for.
@@ -71,9 +102,21 @@ impl StopwatchMetrics {
         }
     }
 
+    /// Measure time with `clock` instead of the system clock
+    #[cfg(test)]
+    fn with_clock(self, clock: Clock) -> Self {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.timer = clock();
+            inner.clock = clock;
+        }
+        self
+    }
+
     pub fn start_section(&self, id: &str) -> Section {
         let id = id.to_owned();
-        if !self.disabled.load(Ordering::SeqCst) {
+        let started = !self.disabled.load(Ordering::SeqCst);
+        if started {
             self.inner.lock().unwrap().start_section(id.clone())
         }
 
@@ -81,19 +124,43 @@ impl StopwatchMetrics {
         Section {
             id,
             stopwatch: self.clone(),
+            started,
         }
     }
 
     /// Turns `start_section` and `end_section` into no-ops, no more metrics will be updated.
+    /// Sections that are open when the stopwatch is disabled are still
+    /// closed when they end.
     pub fn disable(&self) {
         self.disabled.store(true, Ordering::SeqCst)
     }
 
-    fn end_section(&self, id: String) {
+    /// Undo `disable`. Recording resumes from the current instant, and the
+    /// time spent while the stopwatch was disabled is not attributed to any
+    /// section. Enabling a stopwatch that is not disabled does nothing.
+    pub fn enable(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if self.disabled.swap(false, Ordering::SeqCst) {
+            inner.timer = (inner.clock)();
+        }
+    }
+
+    /// Record the time spent in the currently executing section so far,
+    /// without ending it. Meant to be called at block boundaries so that
+    /// the metrics for a block include all of the time spent on it.
+    pub fn flush(&self) {
         if !self.disabled.load(Ordering::SeqCst) {
-            self.inner.lock().unwrap().end_section(id)
+            self.inner.lock().unwrap().record_and_reset()
         }
     }
+
+    /// End the section `id`. Sections that were started while the
+    /// stopwatch was enabled are always taken off the section stack, but
+    /// their time is only recorded if the stopwatch is still enabled
+    fn end_section(&self, id: String) {
+        let record = !self.disabled.load(Ordering::SeqCst);
+        self.inner.lock().unwrap().end_section(id, record)
+    }
 }
 
 struct StopwatchInner {
@@ -105,17 +172,35 @@ struct StopwatchInner {
     // The top section (last item) is the one that's currently executing.
     section_stack: Vec<String>,
 
-    // The timer is reset whenever a section starts or ends.
+    clock: Clock,
+
+    // The timer is reset whenever a section starts or ends, and when the
+    // stopwatch is flushed.
     timer: Instant,
+
+    // Whether to label sections with the path of all open sections.
+    hierarchical: bool,
 }
 
 impl StopwatchInner {
+    /// The label under which time for the currently executing section is
+    /// recorded. In hierarchical mode, this is the path of all open
+    /// sections, leaving out the base section unless it is the only one.
+    fn section_label(&self) -> Option<String> {
+        if self.hierarchical && self.section_stack.len() > 1 {
+            Some(self.section_stack[1..].join("/"))
+        } else {
+            self.section_stack.last().cloned()
+        }
+    }
+
     fn record_and_reset(&mut self) {
-        if let Some(section) = self.section_stack.last() {
+        let now = (self.clock)();
+        if let Some(section) = self.section_label() {
             // Register the current timer.
-            let elapsed = self.timer.elapsed().as_secs_f64();
+            let elapsed = now.saturating_duration_since(self.timer).as_secs_f64();
             self.counter
-                .get_metric_with_label_values(&[section])
+                .get_metric_with_label_values(&[&section])
                 .map(|counter| counter.inc_by(elapsed))
                 .unwrap_or_else(|e| {
                     error!(self.logger, "failed to find counter for section";
@@ -125,7 +210,7 @@ impl StopwatchInner {
         }
 
         // Reset the timer.
-        self.timer = Instant::now();
+        self.timer = now;
     }
 
     fn start_section(&mut self, id: String) {
@@ -133,11 +218,13 @@ impl StopwatchInner {
         self.section_stack.push(id);
     }
 
-    fn end_section(&mut self, id: String) {
+    fn end_section(&mut self, id: String, record: bool) {
         // Validate that the expected section is running.
         match self.section_stack.last() {
             Some(current_section) if current_section == &id => {
-                self.record_and_reset();
+                if record {
+                    self.record_and_reset();
+                }
                 self.section_stack.pop();
             }
             Some(current_section) => error!(self.logger, "`end_section` with mismatched section";
@@ -148,3 +235,151 @@ impl StopwatchInner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::metrics::MockMetricsRegistry;
+
+    /// A clock that only moves when the test advances it
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl ManualClock {
+        fn advance(&self, millis: u64) {
+            *self.0.lock().unwrap() += Duration::from_millis(millis);
+        }
+    }
+
+    fn stopwatch(hierarchical: bool) -> (StopwatchMetrics, ManualClock) {
+        let clock = ManualClock(Arc::new(Mutex::new(Instant::now())));
+        let now = clock.clone();
+        let stopwatch = StopwatchMetrics::with_hierarchy(
+            Logger::root(slog::Discard, o!()),
+            SubgraphDeploymentId::new("stopwatch").unwrap(),
+            Arc::new(MockMetricsRegistry::new()),
+            hierarchical,
+        )
+        .with_clock(Arc::new(move || *now.0.lock().unwrap()));
+        (stopwatch, clock)
+    }
+
+    fn assert_secs(expected: f64, stopwatch: &StopwatchMetrics, label: &str) {
+        let secs = stopwatch
+            .inner
+            .lock()
+            .unwrap()
+            .counter
+            .get_metric_with_label_values(&[label])
+            .unwrap()
+            .get();
+        assert!(
+            (expected - secs).abs() < 1e-9,
+            "section `{}` took {}s instead of {}s",
+            label,
+            secs,
+            expected
+        );
+    }
+
+    fn run_children(stopwatch: &StopwatchMetrics, clock: &ManualClock) {
+        for (parent, millis) in &[("a", 10), ("b", 50)] {
+            let _parent = stopwatch.start_section(parent);
+            clock.advance(1);
+            let child = stopwatch.start_section("child");
+            clock.advance(*millis);
+            child.end();
+        }
+    }
+
+    #[test]
+    fn hierarchical_sections() {
+        let (stopwatch, clock) = stopwatch(true);
+        run_children(&stopwatch, &clock);
+
+        assert_secs(0.01, &stopwatch, "a/child");
+        assert_secs(0.05, &stopwatch, "b/child");
+        assert_secs(0.001, &stopwatch, "a");
+        assert_secs(0.0, &stopwatch, "child");
+    }
+
+    #[test]
+    fn flat_sections() {
+        let (stopwatch, clock) = stopwatch(false);
+        run_children(&stopwatch, &clock);
+
+        assert_secs(0.06, &stopwatch, "child");
+        assert_secs(0.001, &stopwatch, "b");
+        assert_secs(0.0, &stopwatch, "a/child");
+        assert_secs(0.0, &stopwatch, "b/child");
+    }
+
+    #[test]
+    fn enable_after_disable() {
+        let (stopwatch, clock) = stopwatch(false);
+
+        stopwatch.disable();
+        let section = stopwatch.start_section("work");
+        clock.advance(100);
+        section.end();
+
+        stopwatch.enable();
+        let section = stopwatch.start_section("work");
+        clock.advance(10);
+        section.end();
+
+        assert_secs(0.01, &stopwatch, "work");
+        assert_secs(0.0, &stopwatch, "unknown");
+    }
+
+    #[test]
+    fn enable_while_enabled() {
+        let (stopwatch, clock) = stopwatch(false);
+
+        let section = stopwatch.start_section("work");
+        clock.advance(10);
+        stopwatch.enable();
+        clock.advance(20);
+        section.end();
+
+        assert_secs(0.03, &stopwatch, "work");
+    }
+
+    #[test]
+    fn disable_while_section_is_open() {
+        let (stopwatch, clock) = stopwatch(false);
+        let stack =
+            |stopwatch: &StopwatchMetrics| stopwatch.inner.lock().unwrap().section_stack.clone();
+
+        let section = stopwatch.start_section("work");
+        clock.advance(10);
+        stopwatch.disable();
+        section.end();
+        assert_eq!(vec!["unknown".to_owned()], stack(&stopwatch));
+        assert_secs(0.0, &stopwatch, "work");
+
+        stopwatch.enable();
+        let section = stopwatch.start_section("other");
+        clock.advance(20);
+        section.end();
+        assert_eq!(vec!["unknown".to_owned()], stack(&stopwatch));
+        assert_secs(0.02, &stopwatch, "other");
+        assert_secs(0.0, &stopwatch, "work");
+    }
+
+    #[test]
+    fn flush_keeps_section_open() {
+        let (stopwatch, clock) = stopwatch(false);
+
+        let section = stopwatch.start_section("work");
+        clock.advance(50);
+        assert_secs(0.0, &stopwatch, "work");
+
+        stopwatch.flush();
+        assert_secs(0.05, &stopwatch, "work");
+
+        clock.advance(10);
+        section.end();
+        assert_secs(0.06, &stopwatch, "work");
+    }
+}
@@ -21,6 +21,9 @@ pub mod store;
 
 pub mod link_resolver;
 
+/// Health checks for components, combined into the readiness of the node.
+pub mod health;
+
 /// Components dealing with collecting metrics
 pub mod metrics;
 
@@ -40,4 +43,82 @@ pub trait EventProducer<E> {
     ///
     /// Avoid calling directly, prefer helpers such as `forward`.
     fn take_event_stream(&mut self) -> Option<Box<dyn Stream<Item = E, Error = ()> + Send>>;
+
+    /// Like `take_event_stream`, but for producers that can hold back,
+    /// buffer at most `capacity` events that the consumer has not taken
+    /// yet. Producers that override this must not drop events when the
+    /// buffer is full; they wait until the consumer has taken an event
+    /// before they produce the next one, so that a slow consumer slows the
+    /// producer down instead of making it use more and more memory.
+    ///
+    /// The default implementation ignores `capacity` and returns the
+    /// unbounded stream from `take_event_stream`, i.e., events are never
+    /// dropped, but they pile up if the consumer is slow.
+    fn take_event_stream_bounded(
+        &mut self,
+        _capacity: usize,
+    ) -> Option<Box<dyn Stream<Item = E, Error = ()> + Send>> {
+        self.take_event_stream()
+    }
+}
+
+/// Forward all events from `producer` to `consumer`. Returns `None` if the
+/// producer's event stream has already been taken. The returned future
+/// completes when the producer's stream ends.
+pub fn forward<E>(
+    producer: &mut impl EventProducer<E>,
+    consumer: &impl EventConsumer<E>,
+) -> Option<impl Future<Item = (), Error = ()>> {
+    producer
+        .take_event_stream()
+        .map(|stream| stream.forward(consumer.event_sink()).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc;
+
+    struct Producer(Option<Vec<u32>>);
+
+    impl EventProducer<u32> for Producer {
+        fn take_event_stream(&mut self) -> Option<Box<dyn Stream<Item = u32, Error = ()> + Send>> {
+            self.0
+                .take()
+                .map(|events| Box::new(futures::stream::iter_ok(events)) as Box<_>)
+        }
+    }
+
+    struct Consumer(mpsc::Sender<u32>);
+
+    impl EventConsumer<u32> for Consumer {
+        fn event_sink(&self) -> Box<dyn Sink<SinkItem = u32, SinkError = ()> + Send> {
+            Box::new(self.0.clone().sink_map_err(|_| ()))
+        }
+    }
+
+    #[test]
+    fn forward_events() {
+        let (sender, receiver) = mpsc::channel(100);
+        let mut producer = Producer(Some((0..100).collect()));
+        let consumer = Consumer(sender);
+
+        forward(&mut producer, &consumer).unwrap().wait().unwrap();
+        assert!(forward(&mut producer, &consumer).is_none());
+
+        drop(consumer);
+        let received = receiver.collect().wait().unwrap();
+        assert_eq!((0..100).collect::<Vec<_>>(), received);
+    }
+
+    #[test]
+    fn bounded_falls_back_to_unbounded() {
+        let mut producer = Producer(Some((0..100).collect()));
+        let events = producer.take_event_stream_bounded(10).unwrap();
+        assert_eq!(
+            (0..100).collect::<Vec<_>>(),
+            events.collect().wait().unwrap()
+        );
+        assert!(producer.take_event_stream_bounded(10).is_none());
+    }
 }
@@ -1,19 +1,82 @@
+use serde_json::{json, Value};
 use std::io;
 use std::sync::Arc;
 
+use super::subscription::SubscriptionRegistry;
 use crate::prelude::Logger;
 use crate::prelude::NodeId;
 
+/// The JSON-RPC method that lists the active subscriptions of the node
+pub const SUBSCRIPTION_LIST: &str = "subscription_list";
+
 /// Common trait for JSON-RPC admin server implementations.
 pub trait JsonRpcServer<P> {
     type Server;
 
+    /// Start the admin server. It answers `SUBSCRIPTION_LIST` with the
+    /// `subscription_list` of `subscriptions`.
     fn serve(
         port: u16,
         http_port: u16,
         ws_port: u16,
         provider: Arc<P>,
+        subscriptions: Arc<SubscriptionRegistry>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error>;
 }
+
+/// The result of the `SUBSCRIPTION_LIST` method: the active subscriptions,
+/// oldest first, with the connection they were started on, the shape hash
+/// of their query as a hex string, and how long they have been open in
+/// seconds
+pub fn subscription_list(subscriptions: &SubscriptionRegistry) -> Value {
+    subscriptions
+        .active_subscriptions()
+        .into_iter()
+        .map(|info| {
+            json!({
+                "connectionId": info.connection_id,
+                "shapeHash": format!("{:016x}", info.shape_hash),
+                "ageSeconds": info.age.as_secs_f64(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::graphql::shape_hash::shape_hash;
+    use graphql_parser::parse_query;
+
+    #[test]
+    fn list_subscriptions() {
+        let query = parse_query("subscription { things { id } }")
+            .unwrap()
+            .into_static();
+        let subscriptions = SubscriptionRegistry::new();
+        assert_eq!(json!([]), subscription_list(&subscriptions));
+
+        let _first = subscriptions.register("conn1", &query).unwrap();
+        let _second = subscriptions.register("conn2", &query).unwrap();
+        let list = subscription_list(&subscriptions);
+        let listed: Vec<_> = list
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|info| {
+                assert!(info["ageSeconds"].as_f64().unwrap() >= 0.0);
+                (
+                    info["connectionId"].as_str().unwrap(),
+                    info["shapeHash"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        let hash = format!("{:016x}", shape_hash(&query));
+        assert_eq!(
+            vec![("conn1", hash.as_str()), ("conn2", hash.as_str())],
+            listed
+        );
+    }
+}
@@ -1,6 +1,974 @@
 use async_trait::async_trait;
+use futures03::channel::mpsc;
+use futures03::future::{self, BoxFuture, FutureExt};
+use futures03::stream::{self, StreamExt};
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::components::metrics::{Counter, Gauge, MetricsRegistry, PrometheusError};
+use crate::data::graphql::shape_hash::shape_hash;
+use crate::prelude::{q, BlockNumber, QueryExecutionError, QueryResult, QueryResultStream};
+use thiserror::Error;
 
 #[async_trait]
 pub trait SubscriptionServer {
+    /// Serve subscriptions on `port` forever. This is the same as
+    /// `serve_with_shutdown` with a `shutdown` that never resolves.
     async fn serve(self, port: u16);
+
+    /// The registry in which the server registers every subscription it
+    /// starts, if it has one. The default `serve_with_shutdown` uses it to
+    /// refuse new subscriptions and to wait for the existing ones.
+    fn subscriptions(&self) -> Option<Arc<SubscriptionRegistry>> {
+        None
+    }
+
+    /// Serve subscriptions on `port` until `shutdown` resolves. Once it
+    /// does, the server stops accepting new subscriptions, lets the
+    /// existing ones finish, closes the socket and returns.
+    ///
+    /// By default, this closes the server's `subscriptions` registry when
+    /// `shutdown` resolves, so that registering a new subscription fails,
+    /// and keeps serving until the registry is drained. Servers without a
+    /// registry stop serving right away.
+    async fn serve_with_shutdown<F>(self, port: u16, shutdown: F)
+    where
+        Self: Sized + Send,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let subscriptions = self.subscriptions();
+        let serve = match future::select(self.serve(port), shutdown.boxed()).await {
+            future::Either::Left(_) => return,
+            future::Either::Right((_, serve)) => serve,
+        };
+        if let Some(subscriptions) = subscriptions {
+            subscriptions.close();
+            future::select(serve, subscriptions.drained().boxed()).await;
+        }
+    }
+
+    /// Like `serve_with_shutdown`, and also sends the address the server
+    /// is listening on to `ready` as soon as it accepts connections.
+    /// Waiting for that signal is the only reliable way to know that
+    /// connecting to the server will succeed. Implementations must not
+    /// fail if nobody is waiting for the signal. Servers that do not
+    /// override this can not tell when they are ready; they drop `ready`
+    /// right away so that nobody waits for the signal forever.
+    async fn serve_ready(
+        self,
+        port: u16,
+        ready: oneshot::Sender<SocketAddr>,
+        shutdown: BoxFuture<'static, ()>,
+    ) where
+        Self: Sized + Send,
+    {
+        drop(ready);
+        self.serve_with_shutdown(port, shutdown).await
+    }
+}
+
+/// What we know about a subscription that is currently active
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscriptionInfo {
+    /// The connection on which the subscription was started
+    pub connection_id: String,
+    /// The `shape_hash` of the subscription query
+    pub shape_hash: u64,
+    /// How long ago the subscription was started
+    pub age: Duration,
+}
+
+/// The error for registering a subscription with a `SubscriptionRegistry`
+/// that was closed because the server is shutting down
+#[derive(Debug, Error, Clone, PartialEq)]
+#[error("the server is shutting down and does not accept new subscriptions")]
+pub struct ShuttingDown;
+
+struct ActiveEntry {
+    connection_id: String,
+    shape_hash: u64,
+    started: Instant,
+}
+
+/// Keeps track of all subscriptions that are currently active so that they
+/// can be listed with `active_subscriptions`. A `SubscriptionServer` should
+/// register every subscription it starts, and hold on to the returned
+/// `ActiveSubscription` for as long as the subscription is running. The
+/// node shares the registry with its admin server, which lists the
+/// subscriptions with `admin::subscription_list`.
+#[derive(Default)]
+pub struct SubscriptionRegistry {
+    next_id: AtomicUsize,
+    closed: AtomicBool,
+    active: Mutex<HashMap<usize, ActiveEntry>>,
+    /// Waiting for `active` to become empty; only ever locked while
+    /// `active` is locked
+    drain_waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Register a new subscription for `query` on the connection
+    /// `connection_id`. The subscription is considered active until the
+    /// returned guard is dropped. Fails once the registry is closed; the
+    /// server must then refuse the subscription.
+    pub fn register(
+        self: &Arc<Self>,
+        connection_id: &str,
+        query: &q::Document,
+    ) -> Result<ActiveSubscription, ShuttingDown> {
+        let entry = ActiveEntry {
+            connection_id: connection_id.to_owned(),
+            shape_hash: shape_hash(query),
+            started: Instant::now(),
+        };
+        let mut active = self.active.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ShuttingDown);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        active.insert(id, entry);
+        Ok(ActiveSubscription {
+            id,
+            registry: self.clone(),
+        })
+    }
+
+    /// Refuse all further registrations
+    pub fn close(&self) {
+        let _active = self.active.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Resolves as soon as there are no active subscriptions. Unless the
+    /// registry is closed, new subscriptions can become active right after.
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        let active = self.active.lock().unwrap();
+        if active.is_empty() {
+            sender.send(()).ok();
+        } else {
+            self.drain_waiters.lock().unwrap().push(sender);
+        }
+        async move {
+            receiver.await.ok();
+        }
+    }
+
+    /// List all active subscriptions, oldest first
+    pub fn active_subscriptions(&self) -> Vec<SubscriptionInfo> {
+        let now = Instant::now();
+        let active = self.active.lock().unwrap();
+        let mut entries: Vec<_> = active.iter().collect();
+        entries.sort_by_key(|(id, entry)| (entry.started, **id));
+        entries
+            .into_iter()
+            .map(|(_, entry)| SubscriptionInfo {
+                connection_id: entry.connection_id.clone(),
+                shape_hash: entry.shape_hash,
+                age: now.saturating_duration_since(entry.started),
+            })
+            .collect()
+    }
+}
+
+/// A guard for a subscription in a `SubscriptionRegistry`; dropping it
+/// removes the subscription from the registry
+pub struct ActiveSubscription {
+    id: usize,
+    registry: Arc<SubscriptionRegistry>,
+}
+
+impl Drop for ActiveSubscription {
+    fn drop(&mut self) {
+        let mut active = self.registry.active.lock().unwrap();
+        active.remove(&self.id);
+        if active.is_empty() {
+            for waiter in self.registry.drain_waiters.lock().unwrap().drain(..) {
+                waiter.send(()).ok();
+            }
+        }
+    }
+}
+
+/// The reason for closing the subscription of a client that does not keep
+/// up with the events we send it
+#[derive(Debug, Error, Clone, PartialEq)]
+#[error(
+    "subscription closed because the client is too slow: \
+     {buffered} events were buffered for {duration:?}"
+)]
+pub struct SlowConsumer {
+    pub buffered: usize,
+    pub duration: Duration,
+}
+
+/// Metrics for one subscription, together with the detection of clients
+/// that consume events too slowly. A server calls `buffered` whenever it
+/// queues an event for the client and `sent` whenever it has written an
+/// event to the client. Once more than `max_buffered` events have been
+/// waiting for longer than `max_slow`, `buffered` returns an error, and
+/// the server should close the subscription with that error as the reason
+/// to protect its memory. `forward` does all of that for the results of a
+/// subscription.
+pub struct SubscriptionTracker {
+    registry: Arc<dyn MetricsRegistry>,
+    events_sent: Box<Counter>,
+    events_buffered: Box<Gauge>,
+    buffered: usize,
+    max_buffered: usize,
+    max_slow: Duration,
+    slow_since: Option<Instant>,
+}
+
+impl SubscriptionTracker {
+    pub fn new(
+        registry: Arc<dyn MetricsRegistry>,
+        subscription_id: &str,
+        max_buffered: usize,
+        max_slow: Duration,
+    ) -> Result<Self, PrometheusError> {
+        let labels = || {
+            let mut labels = HashMap::new();
+            labels.insert("subscription".to_owned(), subscription_id.to_owned());
+            labels
+        };
+        let events_sent = registry.new_counter_with_labels(
+            "subscription_events_sent",
+            "Number of events sent to a subscriber",
+            labels(),
+        )?;
+        let events_buffered = registry.new_gauge(
+            "subscription_events_buffered",
+            "Number of events waiting to be sent to a subscriber",
+            labels(),
+        )?;
+        Ok(Self {
+            registry,
+            events_sent,
+            events_buffered,
+            buffered: 0,
+            max_buffered,
+            max_slow,
+            slow_since: None,
+        })
+    }
+
+    /// Record that an event was queued for the client at `now`
+    pub fn buffered(&mut self, now: Instant) -> Result<(), SlowConsumer> {
+        self.buffered += 1;
+        self.events_buffered.set(self.buffered as f64);
+        if self.buffered <= self.max_buffered {
+            return Ok(());
+        }
+        let slow_since = *self.slow_since.get_or_insert(now);
+        let duration = now.saturating_duration_since(slow_since);
+        if duration > self.max_slow {
+            Err(SlowConsumer {
+                buffered: self.buffered,
+                duration,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record that a buffered event was sent to the client
+    pub fn sent(&mut self) {
+        self.buffered = self.buffered.saturating_sub(1);
+        self.events_buffered.set(self.buffered as f64);
+        self.events_sent.inc();
+        if self.buffered <= self.max_buffered {
+            self.slow_since = None;
+        }
+    }
+
+    pub fn events_sent(&self) -> u64 {
+        self.events_sent.get() as u64
+    }
+
+    pub fn events_buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Forward `results` to a client that reads them from the returned
+    /// stream at its own pace. The returned future, which must be spawned,
+    /// reads `results` as they are produced and buffers them for the
+    /// client. When the client is too slow, the buffered results are
+    /// dropped, the client receives one last result with a
+    /// `QueryExecutionError::SubscriptionClosed` that says why, and the
+    /// stream ends.
+    pub fn forward(
+        self,
+        results: QueryResultStream,
+    ) -> (BoxFuture<'static, ()>, QueryResultStream) {
+        let tracker = Arc::new(Mutex::new(self));
+        let closed: Arc<Mutex<Option<Arc<QueryResult>>>> = Arc::new(Mutex::new(None));
+        let (sender, receiver) = mpsc::unbounded();
+
+        let producer = {
+            let tracker = tracker.clone();
+            let closed = closed.clone();
+            async move {
+                let mut results = results;
+                while let Some(result) = results.next().await {
+                    let buffered = tracker.lock().unwrap().buffered(Instant::now());
+                    if let Err(reason) = buffered {
+                        let error = QueryExecutionError::SubscriptionClosed(reason.to_string());
+                        *closed.lock().unwrap() = Some(Arc::new(error.into()));
+                        // Dropping the sender wakes up the client
+                        return;
+                    }
+                    if sender.unbounded_send(result).is_err() {
+                        // The client is gone
+                        return;
+                    }
+                }
+            }
+        };
+
+        let mut receiver = Some(receiver);
+        let client = stream::poll_fn(move |cx| {
+            if let Some(reason) = closed.lock().unwrap().take() {
+                receiver = None;
+                return Poll::Ready(Some(reason));
+            }
+            let poll = match &mut receiver {
+                Some(receiver) => receiver.poll_next_unpin(cx),
+                None => return Poll::Ready(None),
+            };
+            if let Poll::Ready(Some(_)) = &poll {
+                tracker.lock().unwrap().sent();
+            }
+            poll
+        });
+        (producer.boxed(), Box::new(client))
+    }
+}
+
+impl Drop for SubscriptionTracker {
+    fn drop(&mut self) {
+        self.registry.unregister(self.events_sent.clone());
+        self.registry.unregister(self.events_buffered.clone());
+    }
+}
+
+/// The token a client presents when it reconnects to continue a
+/// subscription where it left off
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResumeToken(String);
+
+impl ResumeToken {
+    pub fn new(token: String) -> Self {
+        ResumeToken(token)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ResumeError {
+    #[error("unknown subscription resume token `{0}`")]
+    UnknownToken(ResumeToken),
+    #[error(
+        "subscription `{token}` can not be resumed since it was disconnected {disconnected:?} ago"
+    )]
+    Expired {
+        token: ResumeToken,
+        disconnected: Duration,
+    },
+    #[error("subscription `{0}` can not be resumed since the client missed too many events")]
+    TooManyEvents(ResumeToken),
+}
+
+struct Cursor<E> {
+    /// The block of the last event the client received
+    delivered: Option<BlockNumber>,
+    /// Events the client has not received yet, in block order
+    pending: VecDeque<(BlockNumber, E)>,
+    /// When the client disconnected, if it is not connected
+    disconnected: Option<Instant>,
+    /// Set once more events were queued than we keep; the events were
+    /// dropped, and the subscription can not be resumed anymore
+    overflowed: bool,
+}
+
+impl<E> Cursor<E> {
+    fn expired(&self, now: Instant, retention: Duration) -> bool {
+        self.disconnected.map_or(false, |disconnected| {
+            now.saturating_duration_since(disconnected) > retention
+        })
+    }
+}
+
+/// Keeps the events of subscriptions around for `retention` after their
+/// client disconnects, so that the client can reconnect with the
+/// subscription's `ResumeToken` and continue from the last block it
+/// received. A `SubscriptionServer` hands out a token with `start` when it
+/// starts a subscription, keeps `push`ing the subscription's events while
+/// the client is disconnected, and acknowledges every event it sent to the
+/// client with `delivered`. Events for blocks up to the last delivered one
+/// are never handed out again, so that resuming neither loses nor
+/// duplicates events.
+///
+/// At most `max_pending` undelivered events are kept for each subscription;
+/// when a client misses more than that, its events are dropped and the
+/// subscription can not be resumed anymore. Subscriptions whose client has
+/// been disconnected for longer than `retention` are dropped the next time
+/// an event is queued for them, or when `expire` is called.
+pub struct ResumableSubscriptions<E> {
+    retention: Duration,
+    max_pending: usize,
+    cursors: Mutex<HashMap<ResumeToken, Cursor<E>>>,
+}
+
+impl<E: Clone> ResumableSubscriptions<E> {
+    pub fn new(retention: Duration, max_pending: usize) -> Self {
+        Self {
+            retention,
+            max_pending,
+            cursors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start tracking a new subscription
+    pub fn start(&self) -> ResumeToken {
+        let token = ResumeToken(format!("{:032x}", thread_rng().gen::<u128>()));
+        let cursor = Cursor {
+            delivered: None,
+            pending: VecDeque::new(),
+            disconnected: None,
+            overflowed: false,
+        };
+        self.cursors.lock().unwrap().insert(token.clone(), cursor);
+        token
+    }
+
+    /// Queue the event for `block` at `now`. Events for blocks that were
+    /// already delivered are ignored; returns `false` if the event was
+    /// ignored, if the subscription is not known or expired, or if it has
+    /// too many undelivered events
+    pub fn push(&self, token: &ResumeToken, block: BlockNumber, event: E, now: Instant) -> bool {
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = match cursors.get_mut(token) {
+            Some(cursor) => cursor,
+            None => return false,
+        };
+        if cursor.expired(now, self.retention) {
+            cursors.remove(token);
+            return false;
+        }
+        if cursor.overflowed {
+            return false;
+        }
+        let latest = cursor
+            .pending
+            .back()
+            .map(|(block, _)| *block)
+            .or(cursor.delivered);
+        if latest.map_or(false, |latest| block <= latest) {
+            return false;
+        }
+        if cursor.pending.len() >= self.max_pending {
+            cursor.pending.clear();
+            cursor.overflowed = true;
+            return false;
+        }
+        cursor.pending.push_back((block, event));
+        true
+    }
+
+    /// Record that the client received all events up to and including
+    /// `block`
+    pub fn delivered(&self, token: &ResumeToken, block: BlockNumber) {
+        if let Some(cursor) = self.cursors.lock().unwrap().get_mut(token) {
+            while cursor
+                .pending
+                .front()
+                .map_or(false, |(pending, _)| *pending <= block)
+            {
+                cursor.pending.pop_front();
+            }
+            cursor.delivered = cursor.delivered.max(Some(block));
+        }
+    }
+
+    /// Record that the client disconnected at `now`
+    pub fn disconnected(&self, token: &ResumeToken, now: Instant) {
+        if let Some(cursor) = self.cursors.lock().unwrap().get_mut(token) {
+            cursor.disconnected = Some(now);
+        }
+    }
+
+    /// Resume the subscription for `token` at `now`, and return the events
+    /// the client has not received yet. The events stay queued until they
+    /// are `delivered`
+    pub fn resume(
+        &self,
+        token: &ResumeToken,
+        now: Instant,
+    ) -> Result<Vec<(BlockNumber, E)>, ResumeError> {
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = cursors
+            .get_mut(token)
+            .ok_or_else(|| ResumeError::UnknownToken(token.clone()))?;
+        if let Some(disconnected) = cursor.disconnected {
+            let disconnected = now.saturating_duration_since(disconnected);
+            if disconnected > self.retention {
+                cursors.remove(token);
+                return Err(ResumeError::Expired {
+                    token: token.clone(),
+                    disconnected,
+                });
+            }
+        }
+        if cursor.overflowed {
+            cursors.remove(token);
+            return Err(ResumeError::TooManyEvents(token.clone()));
+        }
+        cursor.disconnected = None;
+        Ok(cursor.pending.iter().cloned().collect())
+    }
+
+    /// Stop tracking the subscription, e.g., because the client ended it
+    pub fn remove(&self, token: &ResumeToken) {
+        self.cursors.lock().unwrap().remove(token);
+    }
+
+    /// Stop tracking all subscriptions whose client has been disconnected
+    /// for longer than the retention window as of `now`
+    pub fn expire(&self, now: Instant) {
+        let retention = self.retention;
+        self.cursors
+            .lock()
+            .unwrap()
+            .retain(|_, cursor| !cursor.expired(now, retention));
+    }
+
+    pub fn len(&self) -> usize {
+        self.cursors.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphql_parser::parse_query;
+    use std::net::{TcpListener, TcpStream};
+
+    struct TestServer;
+
+    #[async_trait]
+    impl SubscriptionServer for TestServer {
+        async fn serve(self, port: u16) {
+            let (ready, _) = oneshot::channel();
+            self.serve_ready(port, ready, future::pending().boxed())
+                .await
+        }
+
+        async fn serve_ready(
+            self,
+            port: u16,
+            ready: oneshot::Sender<SocketAddr>,
+            mut shutdown: BoxFuture<'static, ()>,
+        ) {
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let _ = ready.send(listener.local_addr().unwrap());
+
+            let mut connections = Vec::new();
+            loop {
+                match listener.accept() {
+                    Ok((conn, _)) => connections.push(conn),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("accept failed: {}", e),
+                }
+                let tick = Box::pin(tokio::time::delay_for(Duration::from_millis(10)));
+                if let future::Either::Left(_) = future::select(&mut shutdown, tick).await {
+                    return;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_after_ready() {
+        let (ready, bound) = oneshot::channel();
+        tokio::spawn(TestServer.serve_ready(0, ready, future::pending().boxed()));
+
+        let addr = bound.await.expect("server signals readiness");
+        TcpStream::connect(addr).expect("server accepts connections once it is ready");
+    }
+
+    /// A server that only implements `serve`, like servers that were
+    /// written before shutdown and readiness were added
+    struct ServeOnly;
+
+    #[async_trait]
+    impl SubscriptionServer for ServeOnly {
+        async fn serve(self, _port: u16) {
+            future::pending::<()>().await
+        }
+    }
+
+    #[tokio::test]
+    async fn default_readiness() {
+        // The server never signals readiness, and waiting for it fails
+        // instead of hanging
+        let (ready, bound) = oneshot::channel();
+        let server = tokio::spawn(ServeOnly.serve_ready(0, ready, future::ready(()).boxed()));
+        assert!(bound.await.is_err());
+        server.await.expect("server stops after shutdown");
+    }
+
+    /// A request to start a subscription on a `RegistryServer`. The
+    /// subscription runs until `finish` resolves, and then sends `true`
+    /// to `done`; a refused subscription sends `false` right away.
+    struct Start {
+        finish: oneshot::Receiver<()>,
+        done: oneshot::Sender<bool>,
+    }
+
+    /// A server that relies on the default `serve_with_shutdown`, and that
+    /// runs its subscriptions as part of `serve`, so that they are killed
+    /// when it stops serving
+    struct RegistryServer {
+        registry: Arc<SubscriptionRegistry>,
+        starts: mpsc::UnboundedReceiver<Start>,
+    }
+
+    #[async_trait]
+    impl SubscriptionServer for RegistryServer {
+        async fn serve(self, _port: u16) {
+            let query = parse_query("subscription { things { id } }")
+                .unwrap()
+                .into_static();
+            let mut starts = self.starts;
+            let mut running = stream::FuturesUnordered::new();
+            running.push(future::pending::<()>().boxed());
+            loop {
+                let start = match future::select(starts.next(), running.next()).await {
+                    future::Either::Left((Some(start), _)) => start,
+                    future::Either::Left((None, _)) => return,
+                    future::Either::Right(_) => continue,
+                };
+                match self.registry.register("conn", &query) {
+                    Ok(active) => running.push(
+                        async move {
+                            start.finish.await.ok();
+                            start.done.send(true).ok();
+                            drop(active);
+                        }
+                        .boxed(),
+                    ),
+                    Err(ShuttingDown) => {
+                        start.done.send(false).ok();
+                    }
+                }
+            }
+        }
+
+        fn subscriptions(&self) -> Option<Arc<SubscriptionRegistry>> {
+            Some(self.registry.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_subscriptions() {
+        let registry = SubscriptionRegistry::new();
+        let (starts, requests) = mpsc::unbounded();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = async {
+            stopped.await.ok();
+        };
+        let server = RegistryServer {
+            registry: registry.clone(),
+            starts: requests,
+        };
+        let mut server = tokio::spawn(server.serve_with_shutdown(0, shutdown));
+        let start = || {
+            let (finish, finished) = oneshot::channel();
+            let (done, is_done) = oneshot::channel();
+            starts
+                .unbounded_send(Start {
+                    finish: finished,
+                    done,
+                })
+                .unwrap();
+            (finish, is_done)
+        };
+
+        let (finish, open) = start();
+        while registry.active_subscriptions().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        stop.send(()).unwrap();
+
+        // Once shutdown resolves, new subscriptions are refused, but the
+        // server keeps running the open one. Subscriptions that get in
+        // before that end right away since nothing holds on to `finish`
+        loop {
+            let (_, started) = start();
+            if started.await == Ok(false) {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let waited = tokio::time::timeout(Duration::from_millis(50), &mut server).await;
+        assert!(waited.is_err(), "server waits for the open subscription");
+
+        // The open subscription completes, and then the server stops
+        finish.send(()).unwrap();
+        assert_eq!(Ok(true), open.await);
+        server.await.expect("server stops after draining");
+        assert!(registry.active_subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_socket() {
+        let (ready, bound) = oneshot::channel();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = async {
+            stopped.await.ok();
+        };
+        let server = tokio::spawn(TestServer.serve_ready(0, ready, shutdown.boxed()));
+
+        let addr = bound.await.expect("server signals readiness");
+        TcpStream::connect(addr).expect("server accepts connections before shutdown");
+
+        stop.send(()).unwrap();
+        server.await.expect("server stops after shutdown");
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn list_active_subscriptions() {
+        let q1 = parse_query("subscription { things { id } }")
+            .unwrap()
+            .into_static();
+        let q2 = parse_query("subscription { others { id name } }")
+            .unwrap()
+            .into_static();
+
+        let registry = SubscriptionRegistry::new();
+        assert!(registry.active_subscriptions().is_empty());
+
+        let first = registry.register("conn1", &q1).unwrap();
+        let second = registry.register("conn1", &q2).unwrap();
+        let third = registry.register("conn2", &q1).unwrap();
+
+        let active = registry.active_subscriptions();
+        let listed: Vec<_> = active
+            .iter()
+            .map(|info| (info.connection_id.as_str(), info.shape_hash))
+            .collect();
+        assert_eq!(
+            vec![
+                ("conn1", shape_hash(&q1)),
+                ("conn1", shape_hash(&q2)),
+                ("conn2", shape_hash(&q1))
+            ],
+            listed
+        );
+        assert!(active[0].age >= active[2].age);
+
+        drop(second);
+        let listed: Vec<_> = registry
+            .active_subscriptions()
+            .into_iter()
+            .map(|info| info.connection_id)
+            .collect();
+        assert_eq!(vec!["conn1", "conn2"], listed);
+
+        drop(first);
+        drop(third);
+        assert!(registry.active_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn slow_consumer_gets_closed() {
+        use crate::components::metrics::MockMetricsRegistry;
+
+        let registry = Arc::new(MockMetricsRegistry::new());
+        let max_slow = Duration::from_secs(5);
+        let mut tracker = SubscriptionTracker::new(registry, "sub1", 10, max_slow).unwrap();
+        let start = Instant::now();
+
+        // A client that keeps up is never closed, no matter how long it runs
+        for i in 0..100 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(Ok(()), tracker.buffered(now));
+            tracker.sent();
+        }
+        assert_eq!(100, tracker.events_sent());
+        assert_eq!(0, tracker.events_buffered());
+
+        // Going over the threshold briefly is fine
+        let start = start + Duration::from_secs(100);
+        for _ in 0..20 {
+            assert_eq!(Ok(()), tracker.buffered(start));
+        }
+        assert_eq!(20, tracker.events_buffered());
+        for _ in 0..15 {
+            tracker.sent();
+        }
+        assert_eq!(Ok(()), tracker.buffered(start + Duration::from_secs(10)));
+
+        // Staying over the threshold for too long closes the subscription
+        let start = start + Duration::from_secs(20);
+        for i in 0..10 {
+            assert_eq!(Ok(()), tracker.buffered(start + Duration::from_millis(i)));
+        }
+        let err = tracker
+            .buffered(start + max_slow + Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(17, err.buffered);
+        assert!(err.duration > max_slow);
+        assert!(err.to_string().contains("too slow"));
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_receives_close_reason() {
+        use crate::components::metrics::MockMetricsRegistry;
+        use std::collections::BTreeMap;
+
+        fn results(count: i32) -> QueryResultStream {
+            Box::new(Box::pin(stream::iter(0..count).then(|i| async move {
+                // Make sure that time passes between results
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+                let mut map = BTreeMap::new();
+                map.insert("block".to_owned(), q::Value::Int(i.into()));
+                Arc::new(QueryResult::from(map))
+            })))
+        }
+        let tracker = |max_buffered| {
+            let registry = Arc::new(MockMetricsRegistry::new());
+            SubscriptionTracker::new(registry, "sub1", max_buffered, Duration::from_millis(0))
+                .unwrap()
+        };
+
+        // The client does not read anything until all results were
+        // produced, which is fine as long as it does not fall behind by
+        // too much
+        let (producer, client) = tracker(10).forward(results(10));
+        producer.await;
+        let received = client.collect::<Vec<_>>().await;
+        assert_eq!(10, received.len());
+        assert!(received.iter().all(|result| !result.has_errors()));
+
+        // A client that falls behind for too long only gets told why its
+        // subscription was closed
+        let (producer, client) = tracker(2).forward(results(10));
+        producer.await;
+        let received = client.collect::<Vec<_>>().await;
+        assert_eq!(1, received.len());
+        assert!(received[0].has_errors() && !received[0].has_data());
+        let reason = serde_json::to_string(&*received[0]).unwrap();
+        assert!(reason.contains("too slow"));
+    }
+
+    #[test]
+    fn resume_after_reconnect() {
+        let retention = Duration::from_secs(60);
+        let subscriptions = ResumableSubscriptions::new(retention, 10);
+        let token = subscriptions.start();
+        let start = Instant::now();
+        let blocks = |events: Vec<(BlockNumber, &str)>| -> Vec<BlockNumber> {
+            events.into_iter().map(|(block, _)| block).collect()
+        };
+
+        assert!(subscriptions.push(&token, 1, "one", start));
+        assert!(subscriptions.push(&token, 2, "two", start));
+        subscriptions.delivered(&token, 1);
+
+        // Events keep coming in while the client is away
+        subscriptions.disconnected(&token, start);
+        assert!(subscriptions.push(&token, 3, "three", start));
+
+        let missed = subscriptions
+            .resume(&token, start + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(vec![2, 3], blocks(missed));
+        subscriptions.delivered(&token, 3);
+
+        // Events that were delivered are never sent again
+        assert!(!subscriptions.push(&token, 3, "three", start));
+        assert!(subscriptions.push(&token, 4, "four", start));
+        let pending = subscriptions.resume(&token, start).unwrap();
+        assert_eq!(vec![(4, "four")], pending);
+
+        // Once the retention window has passed, the subscription is gone
+        subscriptions.disconnected(&token, start);
+        let later = start + retention + Duration::from_secs(1);
+        match subscriptions.resume(&token, later) {
+            Err(ResumeError::Expired { disconnected, .. }) => {
+                assert!(disconnected > retention)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            Err(ResumeError::UnknownToken(token.clone())),
+            subscriptions.resume(&token, later)
+        );
+
+        let other = subscriptions.start();
+        subscriptions.disconnected(&other, start);
+        subscriptions.expire(start + retention);
+        assert_eq!(1, subscriptions.len());
+        subscriptions.expire(later);
+        assert!(subscriptions.is_empty());
+    }
+
+    #[test]
+    fn pending_events_are_bounded() {
+        let retention = Duration::from_secs(60);
+        let subscriptions = ResumableSubscriptions::new(retention, 3);
+        let start = Instant::now();
+
+        // A client that misses too many events can not resume
+        let token = subscriptions.start();
+        subscriptions.disconnected(&token, start);
+        for block in 1..=3 {
+            assert!(subscriptions.push(&token, block, block, start));
+        }
+        assert!(!subscriptions.push(&token, 4, 4, start));
+        assert!(!subscriptions.push(&token, 5, 5, start));
+        assert_eq!(
+            Err(ResumeError::TooManyEvents(token.clone())),
+            subscriptions.resume(&token, start)
+        );
+        assert!(subscriptions.is_empty());
+
+        // Queueing an event for a client that has been gone for too long
+        // drops its subscription
+        let token = subscriptions.start();
+        subscriptions.disconnected(&token, start);
+        assert!(subscriptions.push(&token, 1, 1, start));
+        let later = start + retention + Duration::from_secs(1);
+        assert!(!subscriptions.push(&token, 2, 2, later));
+        assert!(subscriptions.is_empty());
+    }
 }
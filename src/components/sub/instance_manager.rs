@@ -1,7 +1,34 @@
 use std::sync::Arc;
 
-use crate::prelude::SubgraphDeploymentId;
+use crate::prelude::{anyhow, Error, EthereumBlockPointer, SubgraphDeploymentId};
 
+/// Whether a subgraph instance is currently processing blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstanceState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+/// The status of a subgraph instance as seen by the instance manager
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstanceStatus {
+    pub state: InstanceState,
+    /// The block the instance has processed last, if any
+    pub block: Option<EthereumBlockPointer>,
+}
+
+/// Runs the instances for subgraphs assigned to this node.
+///
+/// Besides starting and stopping instances, an instance can be paused: a
+/// paused instance stops processing blocks, but keeps its in-memory state
+/// so that resuming it does not require starting it from scratch. Pausing
+/// and resuming are independent of stopping: `stop_subgraph` stops an
+/// instance whether it is paused or not, and a stopped instance can not be
+/// resumed, only started again. Pausing an instance that is already paused,
+/// and resuming one that is not paused, does nothing. Instance managers
+/// that can not pause instances return an error from `pause_subgraph` and
+/// `resume_subgraph`.
 #[async_trait::async_trait]
 pub trait SubgraphInstanceManager: Send + Sync + 'static {
     async fn start_subgraph(
@@ -10,4 +37,28 @@ pub trait SubgraphInstanceManager: Send + Sync + 'static {
         manifest: serde_yaml::Mapping,
     );
     fn stop_subgraph(&self, id: SubgraphDeploymentId);
+
+    /// Stop processing blocks for the subgraph `id` until it is resumed
+    async fn pause_subgraph(&self, id: SubgraphDeploymentId) -> Result<(), Error> {
+        Err(anyhow!(
+            "can not pause subgraph `{}`: pausing subgraphs is not supported",
+            id
+        ))
+    }
+
+    /// Continue processing blocks for the paused subgraph `id` from where
+    /// it was paused
+    async fn resume_subgraph(self: Arc<Self>, id: SubgraphDeploymentId) -> Result<(), Error> {
+        Err(anyhow!(
+            "can not resume subgraph `{}`: pausing subgraphs is not supported",
+            id
+        ))
+    }
+
+    /// The status of the instance for the subgraph `id`. Returns `None` if
+    /// there is no such instance, or if the manager does not keep track of
+    /// the status of its instances.
+    async fn subgraph_status(&self, _id: &SubgraphDeploymentId) -> Option<InstanceStatus> {
+        None
+    }
 }
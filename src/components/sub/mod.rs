@@ -9,10 +9,10 @@ pub use crate::prelude::Entity;
 
 pub use self::host::{HostMetrics, MappingError, RuntimeHost, RuntimeHostBuilder};
 pub use self::instance::{BlockState, DataSourceTemplateInfo, SubgraphInstance};
-pub use self::instance_manager::SubgraphInstanceManager;
+pub use self::instance_manager::{InstanceState, InstanceStatus, SubgraphInstanceManager};
 pub use self::proof_of_indexing::{
-    BlockEventStream, ProofOfIndexing, ProofOfIndexingEvent, ProofOfIndexingFinisher,
-    SharedProofOfIndexing,
+    BlockEventStream, PoISnapshot, PoISnapshotError, ProofOfIndexing, ProofOfIndexingEvent,
+    ProofOfIndexingFinisher, SharedProofOfIndexing,
 };
 pub use self::provider::SubgraphAssignmentProvider;
 pub use self::registrar::{SubgraphRegistrar, SubgraphVersionSwitchingMode};
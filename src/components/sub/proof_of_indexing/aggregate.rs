@@ -0,0 +1,34 @@
+//! A single hash summarizing the PoIs of several deployments
+
+use stable_hash::crypto::SetHasher;
+use stable_hash::prelude::*;
+use stable_hash::utils::{stable_hash, AsBytes};
+use std::collections::BTreeMap;
+
+use crate::prelude::SubgraphDeploymentId;
+
+struct DeploymentPoIs<'a>(&'a BTreeMap<SubgraphDeploymentId, [u8; 32]>);
+
+impl StableHash for DeploymentPoIs<'_> {
+    fn stable_hash<H: StableHasher>(&self, mut sequence_number: H::Seq, state: &mut H) {
+        // The map iterates in the order of deployment ids, which makes
+        // the hash independent of the order in which PoIs were added
+        for (deployment, poi) in self.0 {
+            let mut entry = sequence_number.next_child();
+            deployment.stable_hash(entry.next_child(), state);
+            AsBytes(poi).stable_hash(entry.next_child(), state);
+        }
+        self.0
+            .len()
+            .stable_hash(sequence_number.next_child(), state);
+    }
+}
+
+/// Combine the PoIs of several deployments, usually taken at the same
+/// block, into one hash. This is a convenience to attest to the state of
+/// a set of deployments at once; unlike the individual PoIs, the result
+/// can not be verified by anybody who does not have all the individual
+/// PoIs, and it does not say which deployment differs if it does not match.
+pub fn aggregate(deployment_pois: &BTreeMap<SubgraphDeploymentId, [u8; 32]>) -> [u8; 32] {
+    stable_hash::<SetHasher, _>(&DeploymentPoIs(deployment_pois))
+}
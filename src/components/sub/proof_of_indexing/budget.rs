@@ -0,0 +1,89 @@
+//! A limit on the memory that all subgraphs on a node may use to keep their
+//! PoI state in memory across blocks
+
+use crate::components::metrics::{metric_or_noop, Gauge, MetricsRegistry, NoopGauge};
+use crate::prelude::{Logger, SubgraphDeploymentId};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct BudgetState {
+    usage: HashMap<SubgraphDeploymentId, usize>,
+    /// Subgraphs that should flush their state the next time they report
+    /// their usage
+    flush_requested: HashSet<SubgraphDeploymentId>,
+}
+
+/// The memory budget for the `PersistentProofOfIndexing` of all subgraphs
+/// on a node. Every subgraph reports how much memory its state uses after
+/// each block; when the total goes over the budget, the subgraphs that use
+/// the most memory are asked to flush their state to the store, until the
+/// state of the remaining subgraphs fits into the budget.
+pub struct ProofOfIndexingBudget {
+    limit: usize,
+    state: Mutex<BudgetState>,
+    usage_gauge: Box<Gauge>,
+}
+
+impl ProofOfIndexingBudget {
+    /// A budget of `limit` bytes for all subgraphs together. The memory
+    /// that is currently used is reported as `subgraph_poi_memory_bytes`
+    pub fn new(logger: &Logger, limit: usize, registry: Arc<dyn MetricsRegistry>) -> Self {
+        let usage_gauge = metric_or_noop(
+            logger,
+            "subgraph_poi_memory_bytes",
+            registry.new_gauge(
+                "subgraph_poi_memory_bytes",
+                "Estimated memory used by the in-memory PoI state of all subgraphs",
+                HashMap::new(),
+            ),
+            || Box::new(NoopGauge.into()),
+        );
+        ProofOfIndexingBudget {
+            limit,
+            state: Mutex::new(BudgetState::default()),
+            usage_gauge,
+        }
+    }
+
+    /// The memory in bytes that the PoI state of all subgraphs uses
+    pub fn usage(&self) -> usize {
+        self.state.lock().unwrap().usage.values().sum()
+    }
+
+    /// Record that the state of `subgraph` now uses `bytes` of memory, and
+    /// return `true` if `subgraph` should flush its state
+    pub(super) fn report(&self, subgraph: &SubgraphDeploymentId, bytes: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.usage.insert(subgraph.clone(), bytes);
+        let total: usize = state.usage.values().sum();
+        self.usage_gauge.set(total as f64);
+
+        if total > self.limit {
+            let mut users: Vec<_> = state
+                .usage
+                .iter()
+                .map(|(id, bytes)| (id.clone(), *bytes))
+                .collect();
+            users.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let mut remaining = total;
+            for (id, bytes) in users {
+                if remaining <= self.limit {
+                    break;
+                }
+                remaining -= bytes;
+                state.flush_requested.insert(id);
+            }
+        }
+        state.flush_requested.remove(subgraph)
+    }
+
+    /// Forget about `subgraph`, e.g., because it was stopped
+    pub(super) fn release(&self, subgraph: &SubgraphDeploymentId) {
+        let mut state = self.state.lock().unwrap();
+        state.usage.remove(subgraph);
+        state.flush_requested.remove(subgraph);
+        self.usage_gauge
+            .set(state.usage.values().sum::<usize>() as f64);
+    }
+}
@@ -1,9 +1,21 @@
-use crate::prelude::{impl_slog_value, Value};
+use crate::data::graphql::DocumentExt;
+use crate::prelude::{impl_slog_value, Schema, Value};
 use stable_hash::prelude::*;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use strum::AsStaticRef as _;
 use strum_macros::AsStaticStr;
+use thiserror::Error;
+
+/// A `SetEntity` event whose data does not match the schema of the
+/// subgraph. This indicates a bug in the mapping or in graph-node
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PoIEventError {
+    #[error("entity type `{0}` is not defined in the schema")]
+    UnknownEntityType(String),
+    #[error("entity type `{entity_type}` has no field `{field}`")]
+    UnknownField { entity_type: String, field: String },
+}
 
 #[derive(AsStaticStr)]
 pub enum ProofOfIndexingEvent<'a> {
@@ -41,6 +53,37 @@ impl StableHash for ProofOfIndexingEvent<'_> {
     }
 }
 
+impl ProofOfIndexingEvent<'_> {
+    /// Check that the entity type of a `SetEntity` event is defined in
+    /// `schema`, and that the schema has all the fields of its data.
+    /// `RemoveEntity` events are always valid
+    pub fn validate(&self, schema: &Schema) -> Result<(), PoIEventError> {
+        let (entity_type, data) = match self {
+            ProofOfIndexingEvent::RemoveEntity { .. } => return Ok(()),
+            ProofOfIndexingEvent::SetEntity {
+                entity_type, data, ..
+            } => (*entity_type, *data),
+        };
+        let object_type = schema
+            .document
+            .get_object_type_definition(entity_type)
+            .ok_or_else(|| PoIEventError::UnknownEntityType(entity_type.to_owned()))?;
+        // Check the fields in order so that the error is deterministic
+        let mut fields: Vec<_> = data.keys().collect();
+        fields.sort();
+        match fields
+            .into_iter()
+            .find(|field| !object_type.fields.iter().any(|f| &&f.name == field))
+        {
+            Some(field) => Err(PoIEventError::UnknownField {
+                entity_type: entity_type.to_owned(),
+                field: field.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
 /// Different than #[derive(Debug)] in order to be deterministic so logs can be
 /// diffed easily. In particular, we swap out the HashMap for a BTreeMap when
 /// printing the data field of the SetEntity variant so that the keys are
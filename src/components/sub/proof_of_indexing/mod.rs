@@ -1,9 +1,19 @@
+mod aggregate;
+mod budget;
 mod event;
 mod online;
 mod reference;
+mod snapshot;
+mod verify;
 
-pub use event::ProofOfIndexingEvent;
-pub use online::{BlockEventStream, ProofOfIndexing, ProofOfIndexingFinisher};
+pub use aggregate::aggregate;
+pub use budget::ProofOfIndexingBudget;
+pub use event::{PoIEventError, ProofOfIndexingEvent};
+pub use online::{
+    BlockEventStream, PersistentProofOfIndexing, ProofOfIndexing, ProofOfIndexingFinisher,
+};
+pub use snapshot::{PoISnapshot, PoISnapshotError};
+pub use verify::{verify_batch, VerifyJob, VerifyOutcome, VerifyResult};
 
 use atomic_refcell::AtomicRefCell;
 use std::sync::Arc;
@@ -13,14 +23,14 @@ pub type SharedProofOfIndexing = Option<Arc<AtomicRefCell<ProofOfIndexing>>>;
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::prelude::{EthereumBlockPointer, SubgraphDeploymentId, Value};
+    use crate::prelude::{BlockNumber, EthereumBlockPointer, SubgraphDeploymentId, Value};
     use maplit::hashmap;
     use online::ProofOfIndexingFinisher;
     use reference::*;
     use slog::{o, Discard, Logger};
     use stable_hash::crypto::SetHasher;
     use stable_hash::utils::stable_hash;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use std::convert::TryInto;
     use web3::types::{Address, H256};
 
@@ -69,6 +79,73 @@ mod tests {
         offline
     }
 
+    /// Like `check_equal`, but keeps the paused regions in a `PoISnapshot`
+    /// between blocks, as a node that gets restarted in the middle of
+    /// indexing would
+    fn check_snapshots(reference: &PoI) -> String {
+        let logger = Logger::root(Discard, o!());
+
+        let mut db: Option<Vec<u8>> = None;
+
+        let mut block_count = 1;
+        for causality_region in reference.causality_regions.values() {
+            block_count = causality_region.blocks.len();
+            break;
+        }
+
+        for block_i in 0..block_count {
+            // Resume from the snapshot of the previous block, if there is one
+            let mut regions = match &db {
+                Some(bytes) => PoISnapshot::decode(bytes)
+                    .unwrap()
+                    .resume(block_i as u64 - 1)
+                    .unwrap(),
+                None => Default::default(),
+            };
+
+            let mut stream = ProofOfIndexing::new(block_i.try_into().unwrap());
+
+            for (name, region) in reference.causality_regions.iter() {
+                let block = &region.blocks[block_i];
+
+                for evt in block.events.iter() {
+                    stream.write(&logger, name, evt);
+                }
+            }
+
+            for (name, region) in stream.take() {
+                let prev = regions.get(&name);
+                let update = region.pause(prev.map(|v| &v[..]));
+                regions.insert(name, update);
+            }
+
+            let snapshot = PoISnapshot {
+                block_number: block_i as u64,
+                regions,
+            };
+            db = Some(snapshot.encode());
+        }
+
+        let block_number = (block_count - 1) as u64;
+        let db = PoISnapshot::decode(&db.unwrap())
+            .unwrap()
+            .resume(block_number)
+            .unwrap();
+        let block_ptr = EthereumBlockPointer::from((reference.block_hash, block_number));
+
+        // This region emulates the request
+        let mut finisher =
+            ProofOfIndexingFinisher::new(&block_ptr, &reference.subgraph_id, &reference.indexer);
+        for (name, region) in db.iter() {
+            finisher.add_causality_region(name, region);
+        }
+
+        let online = hex::encode(finisher.finish());
+        let offline = hex::encode(stable_hash::<SetHasher, _>(reference));
+        assert_eq!(&online, &offline);
+        offline
+    }
+
     /// This test checks that each case resolves to a unique hash, and that
     /// in each case the reference and online versions match
     #[test]
@@ -235,9 +312,517 @@ mod tests {
         let mut results = HashMap::new();
         for (name, data) in cases.drain() {
             let result = check_equal(&data);
+            assert_eq!(result, check_snapshots(&data));
             if let Some(prev) = results.insert(result, name) {
                 assert!(false, "Found conflict for case: {} == {}", name, prev);
             }
         }
     }
+
+    #[test]
+    fn builder_matches_fixture() {
+        let data = hashmap! {
+            "val".to_owned() => Value::Int(1)
+        };
+        let data_empty = hashmap! {};
+
+        let fixture = PoI {
+            subgraph_id: SubgraphDeploymentId::new("b").unwrap(),
+            block_hash: H256::repeat_byte(3),
+            causality_regions: hashmap! {
+                "eth".to_owned() => CausalityRegion {
+                    blocks: vec! [
+                        Block::default(),
+                        Block {
+                            events: vec![
+                                ProofOfIndexingEvent::SetEntity {
+                                    entity_type: "type",
+                                    id: "id",
+                                    data: &data,
+                                }
+                            ]
+                        },
+                        Block::default(),
+                        Block {
+                            events: vec![
+                                ProofOfIndexingEvent::SetEntity {
+                                    entity_type: "type",
+                                    id: "id",
+                                    data: &data_empty,
+                                }
+                            ]
+                        },
+                        Block::default(),
+                    ],
+                },
+            },
+            indexer: Some(Address::repeat_byte(1)),
+        };
+
+        let built = PoIBuilder::new(
+            SubgraphDeploymentId::new("b").unwrap(),
+            H256::repeat_byte(3),
+            Some(Address::repeat_byte(1)),
+        )
+        .region("eth")
+        .block()
+        .set_entity("type", "id", &data)
+        .block()
+        .block()
+        .set_entity("type", "id", &data_empty)
+        .block()
+        .build();
+
+        assert_eq!(
+            stable_hash::<SetHasher, _>(&fixture),
+            stable_hash::<SetHasher, _>(&built)
+        );
+        check_equal(&built);
+
+        // Regions with fewer blocks get padded with empty blocks
+        let padded = PoIBuilder::new(
+            SubgraphDeploymentId::new("b").unwrap(),
+            H256::repeat_byte(3),
+            None,
+        )
+        .region("eth")
+        .block()
+        .remove_entity("type", "id")
+        .block()
+        .region("ipfs")
+        .block()
+        .set_entity("type", "id", &data)
+        .build();
+        assert_eq!(3, padded.causality_regions["ipfs"].blocks.len());
+        check_equal(&padded);
+    }
+
+    #[test]
+    fn validate_events() {
+        use crate::log::Capture;
+        use crate::prelude::Schema;
+
+        let schema = Schema::parse(
+            "type Thing @entity { id: ID!, name: String }",
+            SubgraphDeploymentId::new("validate").unwrap(),
+        )
+        .unwrap();
+        let valid = hashmap! {
+            "id".to_owned() => Value::String("one".to_owned()),
+            "name".to_owned() => Value::String("One".to_owned()),
+        };
+        let invalid = hashmap! {
+            "id".to_owned() => Value::String("one".to_owned()),
+            "nmae".to_owned() => Value::String("One".to_owned()),
+        };
+        fn set<'a>(
+            entity_type: &'a str,
+            data: &'a HashMap<String, Value>,
+        ) -> ProofOfIndexingEvent<'a> {
+            ProofOfIndexingEvent::SetEntity {
+                entity_type,
+                id: "one",
+                data,
+            }
+        }
+
+        assert_eq!(Ok(()), set("Thing", &valid).validate(&schema));
+        assert_eq!(
+            Err(PoIEventError::UnknownField {
+                entity_type: "Thing".to_owned(),
+                field: "nmae".to_owned(),
+            }),
+            set("Thing", &invalid).validate(&schema)
+        );
+        assert_eq!(
+            Err(PoIEventError::UnknownEntityType("Other".to_owned())),
+            set("Other", &valid).validate(&schema)
+        );
+        let remove = ProofOfIndexingEvent::RemoveEntity {
+            entity_type: "Other",
+            id: "one",
+        };
+        assert_eq!(Ok(()), remove.validate(&schema));
+
+        // Writing an invalid event logs an error, but still hashes it
+        let (logger, logged) = Capture::logger();
+        let mut poi = ProofOfIndexing::new(0).with_schema(Arc::new(schema));
+        poi.write(&logger, "eth", &set("Thing", &valid));
+        assert!(logged.lock().unwrap().is_empty());
+        poi.write(&logger, "eth", &set("Thing", &invalid));
+        assert_eq!(
+            vec!["Proof of indexing event does not match the schema".to_owned()],
+            *logged.lock().unwrap()
+        );
+        assert!(poi.take().contains_key("eth"));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut snapshot = PoISnapshot::new(17);
+        snapshot.regions.insert("ipfs".to_owned(), vec![4, 5]);
+        snapshot.regions.insert("eth".to_owned(), vec![1, 2, 3]);
+        snapshot.regions.insert("empty".to_owned(), vec![]);
+
+        let bytes = snapshot.encode();
+        let decoded = PoISnapshot::decode(&bytes).unwrap();
+        assert_eq!(snapshot, decoded);
+        assert_eq!(bytes, decoded.encode());
+
+        assert_eq!(
+            Err(PoISnapshotError::Truncated),
+            PoISnapshot::decode(&bytes[..bytes.len() - 1])
+        );
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Err(PoISnapshotError::TrailingBytes),
+            PoISnapshot::decode(&trailing)
+        );
+
+        let regions = decoded.resume(17).unwrap();
+        assert_eq!(Some(&vec![1, 2, 3]), regions.get("eth"));
+    }
+
+    #[test]
+    fn snapshot_rejects_other_block() {
+        let mut snapshot = PoISnapshot::new(17);
+        snapshot.regions.insert("eth".to_owned(), vec![1, 2, 3]);
+
+        let decoded = PoISnapshot::decode(&snapshot.encode()).unwrap();
+        assert_eq!(
+            Err(PoISnapshotError::BlockMismatch {
+                recorded: 17,
+                requested: 18
+            }),
+            decoded.resume(18)
+        );
+    }
+
+    /// Pause the regions that result from writing one entity for each of
+    /// the `(region, id)` pairs
+    fn paused_regions(entities: &[(&str, &str)]) -> BTreeMap<String, Vec<u8>> {
+        let logger = Logger::root(Discard, o!());
+        let data = HashMap::new();
+        let mut poi = ProofOfIndexing::new(0);
+        for (region, id) in entities {
+            let event = ProofOfIndexingEvent::SetEntity {
+                entity_type: "Thing",
+                id: *id,
+                data: &data,
+            };
+            poi.write(&logger, region, &event);
+        }
+        poi.take()
+            .into_iter()
+            .map(|(name, region)| (name, region.pause(None)))
+            .collect()
+    }
+
+    fn block_ptr(number: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer::from((H256::repeat_byte(1), number))
+    }
+
+    /// A finisher for the PoI of `deployment` at block `number` that has
+    /// all of `regions` added, in the order in which they are listed
+    fn finisher<N: AsRef<str>, R: AsRef<[u8]>>(
+        deployment: &str,
+        number: u64,
+        regions: impl IntoIterator<Item = (N, R)>,
+    ) -> ProofOfIndexingFinisher {
+        let deployment = SubgraphDeploymentId::new(deployment).unwrap();
+        let mut finisher = ProofOfIndexingFinisher::new(&block_ptr(number), &deployment, &None);
+        for (name, region) in regions {
+            finisher.add_causality_region(name.as_ref(), region.as_ref());
+        }
+        finisher
+    }
+
+    fn verify_job(
+        deployment: &str,
+        regions: BTreeMap<String, Vec<u8>>,
+        reference: BTreeMap<String, Vec<u8>>,
+    ) -> VerifyJob {
+        let finisher = finisher(deployment, 0, &reference);
+        let expected_regions = reference
+            .iter()
+            .map(|(name, region)| (name.clone(), finisher.region_digest(name, region)))
+            .collect();
+        VerifyJob {
+            deployment: SubgraphDeploymentId::new(deployment).unwrap(),
+            block: block_ptr(0),
+            indexer: None,
+            regions,
+            expected: finisher.finish(),
+            expected_regions,
+        }
+    }
+
+    #[test]
+    fn disable_region() {
+        let logger = Logger::root(Discard, o!());
+        let data = HashMap::new();
+        let event = |id| ProofOfIndexingEvent::SetEntity {
+            entity_type: "Thing",
+            id,
+            data: &data,
+        };
+        let finish = |regions: BTreeMap<String, Vec<u8>>| finisher("disabled", 0, regions).finish();
+
+        let mut poi = ProofOfIndexing::new(0);
+        poi.write(&logger, "eth", &event("1"));
+        poi.write(&logger, "ipfs", &event("2"));
+        poi.disable_region("ipfs");
+        poi.write(&logger, "ipfs", &event("3"));
+        poi.write(&logger, "eth", &event("4"));
+        let regions: BTreeMap<_, _> = poi
+            .take()
+            .into_iter()
+            .map(|(name, region)| (name, region.pause(None)))
+            .collect();
+        assert_eq!(
+            vec!["eth"],
+            regions.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+
+        // The PoI is the same as if nothing had been written to the
+        // disabled region, and differs from the PoI with all regions
+        let without = paused_regions(&[("eth", "1"), ("eth", "4")]);
+        let with = paused_regions(&[("eth", "1"), ("ipfs", "2"), ("ipfs", "3"), ("eth", "4")]);
+        assert_eq!(finish(without), finish(regions.clone()));
+        assert_ne!(finish(with), finish(regions));
+    }
+
+    #[test]
+    fn finisher_is_independent_of_region_order() {
+        let regions = paused_regions(&[("eth", "1"), ("ipfs", "2"), ("file", "3"), ("eth", "4")]);
+        let finish = |names: &[&str]| {
+            let regions = names.iter().map(|name| (*name, &regions[*name]));
+            finisher("order", 0, regions).finish()
+        };
+
+        let sorted = finish(&["eth", "file", "ipfs"]);
+        assert_eq!(sorted, finish(&["ipfs", "eth", "file"]));
+        assert_eq!(sorted, finish(&["file", "ipfs", "eth"]));
+        assert_ne!(sorted, finish(&["eth", "file"]));
+    }
+
+    #[test]
+    fn persistent_matches_pause_resume() {
+        let logger = Logger::root(Discard, o!());
+        let data = HashMap::new();
+        let block_ptr = block_ptr(4);
+        let deployment = SubgraphDeploymentId::new("persistent").unwrap();
+        let ids = ["1", "2", "3", "4", "5"];
+
+        // The events of block `i`; the `ipfs` region is not written to in
+        // every block
+        let block = |i: usize| {
+            let mut poi = ProofOfIndexing::new(i as BlockNumber);
+            let event = ProofOfIndexingEvent::SetEntity {
+                entity_type: "Thing",
+                id: ids[i],
+                data: &data,
+            };
+            poi.write(&logger, "eth", &event);
+            if i % 2 == 0 {
+                let event = ProofOfIndexingEvent::RemoveEntity {
+                    entity_type: "Thing",
+                    id: ids[i],
+                };
+                poi.write(&logger, "ipfs", &event);
+            }
+            poi
+        };
+
+        let mut db: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut persistent = PersistentProofOfIndexing::new();
+        for i in 0..5 {
+            for (name, region) in block(i).take() {
+                let update = region.pause(db.get(&name).map(|v| &v[..]));
+                db.insert(name, update);
+            }
+            persistent.add_block(block(i));
+        }
+
+        let expected = finisher("persistent", 4, &db).finish();
+
+        assert_eq!(
+            db.into_iter().collect::<BTreeMap<_, _>>(),
+            persistent.paused_regions()
+        );
+        assert_eq!(expected, persistent.finish(&block_ptr, &deployment, &None));
+    }
+
+    #[test]
+    fn finish_hex() {
+        let regions = paused_regions(&[("eth", "1"), ("ipfs", "2")]);
+
+        let hex = finisher("hex", 0, &regions).finish_hex();
+        assert_eq!(
+            format!("0x{}", hex::encode(finisher("hex", 0, &regions).finish())),
+            hex
+        );
+        assert_eq!(66, hex.len());
+        assert_eq!(hex.to_lowercase(), hex);
+    }
+
+    #[test]
+    fn event_count() {
+        let logger = Logger::root(Discard, o!());
+        let data = HashMap::new();
+        let set = ProofOfIndexingEvent::SetEntity {
+            entity_type: "Thing",
+            id: "1",
+            data: &data,
+        };
+        let remove = ProofOfIndexingEvent::RemoveEntity {
+            entity_type: "Thing",
+            id: "1",
+        };
+
+        let mut poi = ProofOfIndexing::new(3);
+        assert_eq!(0, poi.event_count());
+        poi.write(&logger, "eth", &set);
+        poi.write(&logger, "eth", &remove);
+        poi.write(&logger, "ipfs", &set);
+        assert_eq!(3, poi.event_count());
+
+        poi.disable_region("ipfs");
+        poi.write(&logger, "ipfs", &remove);
+        assert_eq!(3, poi.event_count());
+
+        assert_eq!(0, ProofOfIndexing::new(4).event_count());
+    }
+
+    #[test]
+    fn budget_forces_early_flush() {
+        use crate::components::metrics::MockMetricsRegistry;
+
+        let logger = Logger::root(Discard, o!());
+        let data = HashMap::new();
+        let ids = ["1", "2", "3"];
+        let block = |i: usize, regions: &[&str]| {
+            let mut poi = ProofOfIndexing::new(i as BlockNumber);
+            for region in regions {
+                let event = ProofOfIndexingEvent::SetEntity {
+                    entity_type: "Thing",
+                    id: ids[i],
+                    data: &data,
+                };
+                poi.write(&logger, region, &event);
+            }
+            poi
+        };
+        let regions_a = ["eth", "ipfs", "arweave"];
+        let regions_b = ["eth", "ipfs"];
+        let usage = |regions: &[&str]| {
+            let mut poi = PersistentProofOfIndexing::new();
+            poi.add_block(block(0, regions));
+            poi.memory_usage()
+        };
+        let (usage_a, usage_b) = (usage(&regions_a), usage(&regions_b));
+        assert!(usage_a > usage_b);
+
+        // The budget fits either subgraph, but not both
+        let registry = Arc::new(MockMetricsRegistry::new());
+        let budget = Arc::new(ProofOfIndexingBudget::new(
+            &logger,
+            usage_a + usage_b - 1,
+            registry,
+        ));
+        let deployment_a = SubgraphDeploymentId::new("a").unwrap();
+        let deployment_b = SubgraphDeploymentId::new("b").unwrap();
+        let mut a = PersistentProofOfIndexing::new().with_budget(budget.clone(), deployment_a);
+        let mut b = PersistentProofOfIndexing::new().with_budget(budget.clone(), deployment_b);
+
+        a.add_block(block(0, &regions_a));
+        assert!(!a.needs_flush());
+        b.add_block(block(0, &regions_b));
+        assert_eq!(usage_a + usage_b, budget.usage());
+
+        // Going over the budget makes the subgraph that uses the most
+        // memory flush, even though `b` went over the budget
+        assert!(!b.needs_flush());
+        a.add_block(block(1, &regions_a));
+        assert!(a.needs_flush());
+        let mut stored = BTreeMap::new();
+        a.flush_into(&mut stored);
+        assert!(!a.needs_flush());
+        assert_eq!(0, a.memory_usage());
+        assert_eq!(usage_b, budget.usage());
+
+        // Flushing early does not change the regions
+        a.add_block(block(2, &regions_a));
+        a.flush_into(&mut stored);
+        let mut db: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        for i in 0..3 {
+            for (name, region) in block(i, &regions_a).take() {
+                let update = region.pause(db.get(&name).map(|v| &v[..]));
+                db.insert(name, update);
+            }
+        }
+        assert_eq!(db, stored);
+
+        drop(b);
+        assert_eq!(0, budget.usage());
+    }
+
+    #[tokio::test]
+    async fn verify_batch_finds_mismatch() {
+        let good = paused_regions(&[("eth", "1"), ("ipfs", "2")]);
+        let bad = paused_regions(&[("eth", "1"), ("ipfs", "3")]);
+
+        let jobs = vec![
+            verify_job("a", good.clone(), good.clone()),
+            verify_job("b", bad.clone(), good.clone()),
+            verify_job("c", bad.clone(), bad.clone()),
+            verify_job("d", paused_regions(&[("eth", "1")]), good.clone()),
+        ];
+        let results = verify_batch(jobs).await;
+
+        let outcomes: Vec<_> = results
+            .iter()
+            .map(|result| (result.deployment.as_str(), &result.outcome))
+            .collect();
+        assert_eq!(4, outcomes.len());
+        assert_eq!(("a", &VerifyOutcome::Match), outcomes[0]);
+        assert_eq!(("c", &VerifyOutcome::Match), outcomes[2]);
+        match outcomes[1] {
+            ("b", VerifyOutcome::Mismatch { regions, .. }) => assert_eq!(&vec!["ipfs"], regions),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        match outcomes[3] {
+            ("d", VerifyOutcome::Mismatch { regions, .. }) => assert_eq!(&vec!["ipfs"], regions),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+
+    #[test]
+    fn aggregate_pois() {
+        let id = |id: &str| SubgraphDeploymentId::new(id).unwrap();
+        let pois = vec![
+            (id("a"), [1u8; 32]),
+            (id("b"), [2u8; 32]),
+            (id("c"), [3u8; 32]),
+        ];
+
+        let forward: BTreeMap<_, _> = pois.iter().cloned().collect();
+        let backward: BTreeMap<_, _> = pois.iter().rev().cloned().collect();
+        let hash = aggregate(&forward);
+        assert_eq!(hash, aggregate(&backward));
+
+        let mut changed = forward.clone();
+        changed.insert(id("b"), [4u8; 32]);
+        assert_ne!(hash, aggregate(&changed));
+
+        let mut swapped = forward.clone();
+        swapped.insert(id("a"), [2u8; 32]);
+        swapped.insert(id("b"), [1u8; 32]);
+        assert_ne!(hash, aggregate(&swapped));
+
+        let mut fewer = forward.clone();
+        fewer.remove(&id("c"));
+        assert_ne!(hash, aggregate(&fewer));
+    }
 }
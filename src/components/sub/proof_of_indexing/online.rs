@@ -2,15 +2,18 @@
 //! Any hash constructed from here should be the same as if the same data was given
 //! to the reference implementation, but this is updated incrementally
 
-use super::ProofOfIndexingEvent;
-use crate::prelude::{debug, BlockNumber, EthereumBlockPointer, Logger, SubgraphDeploymentId};
+use super::{ProofOfIndexingBudget, ProofOfIndexingEvent};
+use crate::prelude::{
+    debug, error, BlockNumber, EthereumBlockPointer, Logger, Schema, SubgraphDeploymentId,
+};
 use lazy_static::lazy_static;
 use stable_hash::crypto::{Blake3SeqNo, SetHasher};
 use stable_hash::prelude::*;
 use stable_hash::utils::AsBytes;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fmt;
+use std::sync::Arc;
 use web3::types::Address;
 
 lazy_static! {
@@ -83,14 +86,19 @@ impl BlockEventStream {
     /// be resumed later. Cases in which the hash function is resumed include
     /// when asking for the final PoI, or when combining with the next modified
     /// block via the argument `prev`
-    pub fn pause(mut self, prev: Option<&[u8]>) -> Vec<u8> {
+    pub fn pause(self, prev: Option<&[u8]>) -> Vec<u8> {
+        self.pause_state(prev.map(SetHasher::from_bytes)).to_bytes()
+    }
+
+    /// Like `pause`, but combine with and return the hash function itself
+    /// rather than its serialized form
+    fn pause_state(mut self, prev: Option<SetHasher>) -> SetHasher {
         self.vec_length.stable_hash(self.seq_no, &mut self.digest);
         let mut state = self.digest;
         if let Some(prev) = prev {
-            let prev = SetHasher::from_bytes(prev);
             state.finish_unordered(prev, SequenceNumber::root());
         }
-        state.to_bytes()
+        state
     }
 
     fn write(&mut self, event: &ProofOfIndexingEvent<'_>) {
@@ -107,6 +115,13 @@ pub struct ProofOfIndexing {
     /// state with other data sources. This may also give us some freedom to change
     /// the order of triggers in the future.
     per_causality_region: HashMap<String, BlockEventStream>,
+    /// If set, events are checked against the schema before they are
+    /// hashed
+    schema: Option<Arc<Schema>>,
+    /// Causality regions whose events are dropped
+    disabled_regions: HashSet<String>,
+    /// The number of events written to all regions for this block
+    event_count: usize,
 }
 
 impl fmt::Debug for ProofOfIndexing {
@@ -120,8 +135,31 @@ impl ProofOfIndexing {
         Self {
             block_number,
             per_causality_region: HashMap::new(),
+            schema: None,
+            disabled_regions: HashSet::new(),
+            event_count: 0,
         }
     }
+
+    /// Check every event against `schema` before hashing it, and log an
+    /// error for events that do not match it. Such events are still added
+    /// to the PoI
+    pub fn with_schema(mut self, schema: Arc<Schema>) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Drop all events that are written to `causality_region` from now on,
+    /// and leave it out of `take`, while other regions are still updated.
+    /// This is meant for temporarily excluding an unreliable data source,
+    /// e.g., during a reorg. Since the region is missing from the PoI, this
+    /// changes the resulting PoI, which will then not match the PoI of
+    /// indexers that did not disable the region; only use this
+    /// deliberately
+    pub fn disable_region(&mut self, causality_region: &str) {
+        self.disabled_regions.insert(causality_region.to_owned());
+    }
+
     /// Adds an event to the digest of the ProofOfIndexingStream local to the causality region
     pub fn write(
         &mut self,
@@ -129,6 +167,10 @@ impl ProofOfIndexing {
         causality_region: &str,
         event: &ProofOfIndexingEvent<'_>,
     ) {
+        if self.disabled_regions.contains(causality_region) {
+            return;
+        }
+
         if *LOG_EVENTS {
             debug!(
                 logger,
@@ -138,6 +180,20 @@ impl ProofOfIndexing {
             );
         }
 
+        if let Some(schema) = &self.schema {
+            if let Err(e) = event.validate(schema) {
+                error!(
+                    logger,
+                    "Proof of indexing event does not match the schema";
+                    "error" => e.to_string(),
+                    "event" => &event,
+                    "causality_region" => causality_region
+                );
+            }
+        }
+
+        self.event_count += 1;
+
         // This may be better with the raw_entry API, once that is stabilized
         if let Some(causality_region) = self.per_causality_region.get_mut(causality_region) {
             causality_region.write(event);
@@ -148,8 +204,146 @@ impl ProofOfIndexing {
                 .insert(causality_region.to_owned(), entry);
         }
     }
+    /// The number of events that were written for this block, across all
+    /// causality regions. Events for disabled regions are not counted
+    pub fn event_count(&self) -> usize {
+        self.event_count
+    }
+
     pub fn take(self) -> HashMap<String, BlockEventStream> {
+        let disabled = self.disabled_regions;
         self.per_causality_region
+            .into_iter()
+            .filter(|(name, _)| !disabled.contains(name))
+            .collect()
+    }
+}
+
+/// The paused state of every causality region, kept in memory across
+/// blocks. Adding the `ProofOfIndexing` for each block with `add_block`
+/// gives the same regions as pausing each region with the bytes from its
+/// previous block, without serializing the state for every block. This is
+/// useful when the regions do not need to be persisted between blocks.
+///
+/// With a `ProofOfIndexingBudget`, the state can also be flushed to the
+/// store with `flush_into` whenever the budget asks for it, so that the
+/// memory for the state of all subgraphs stays within the budget.
+#[derive(Default)]
+pub struct PersistentProofOfIndexing {
+    regions: HashMap<String, SetHasher>,
+    budget: Option<(Arc<ProofOfIndexingBudget>, SubgraphDeploymentId)>,
+    needs_flush: bool,
+}
+
+/// An estimate of the memory a paused region needs, not counting its name.
+/// The state of a region is a number of up to 2048 bits
+const REGION_SIZE: usize = 256 + std::mem::size_of::<(String, SetHasher)>();
+
+impl fmt::Debug for PersistentProofOfIndexing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PersistentProofOfIndexing")
+            .field("regions", &self.regions.keys())
+            .finish()
+    }
+}
+
+impl PersistentProofOfIndexing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the memory that the state of `subgraph_id` uses against
+    /// `budget`
+    pub fn with_budget(
+        mut self,
+        budget: Arc<ProofOfIndexingBudget>,
+        subgraph_id: SubgraphDeploymentId,
+    ) -> Self {
+        budget.report(&subgraph_id, self.memory_usage());
+        self.budget = Some((budget, subgraph_id));
+        self
+    }
+
+    /// Combine the events of one block with the state of each region.
+    /// Blocks must be added in order
+    pub fn add_block(&mut self, poi: ProofOfIndexing) {
+        for (name, stream) in poi.take() {
+            let prev = self.regions.remove(&name);
+            self.regions.insert(name, stream.pause_state(prev));
+        }
+        if let Some((budget, subgraph_id)) = &self.budget {
+            if budget.report(subgraph_id, self.memory_usage()) {
+                self.needs_flush = true;
+            }
+        }
+    }
+
+    /// An estimate of the memory in bytes that the state of all regions
+    /// uses
+    pub fn memory_usage(&self) -> usize {
+        self.regions
+            .keys()
+            .map(|name| REGION_SIZE + name.len())
+            .sum()
+    }
+
+    /// Whether the budget asked us to flush our state with `flush_into`
+    /// to stay within the budget
+    pub fn needs_flush(&self) -> bool {
+        self.needs_flush
+    }
+
+    /// Combine the state of each region with the paused region of the same
+    /// name in `stored`, the regions as they are kept in the store, and
+    /// clear the state that is kept in memory. Since regions are combined
+    /// independently of the order of blocks, adding more blocks after a
+    /// flush and flushing again produces the same regions as pausing each
+    /// block with the bytes from the store. After a flush, `finish` and
+    /// `paused_regions` only cover the blocks added since then
+    pub fn flush_into(&mut self, stored: &mut BTreeMap<String, Vec<u8>>) {
+        for (name, mut state) in self.regions.drain() {
+            if let Some(prev) = stored.get(&name) {
+                state.finish_unordered(SetHasher::from_bytes(prev), SequenceNumber::root());
+            }
+            stored.insert(name, state.to_bytes());
+        }
+        self.needs_flush = false;
+        if let Some((budget, subgraph_id)) = &self.budget {
+            budget.report(subgraph_id, 0);
+        }
+    }
+
+    /// The paused regions in the form that `BlockEventStream::pause`
+    /// produces, e.g., to persist them or to pass them to a
+    /// `ProofOfIndexingFinisher`
+    pub fn paused_regions(&self) -> BTreeMap<String, Vec<u8>> {
+        self.regions
+            .iter()
+            .map(|(name, state)| (name.clone(), state.to_bytes()))
+            .collect()
+    }
+
+    /// The PoI for `block`, the block of the last `ProofOfIndexing` that
+    /// was added
+    pub fn finish(
+        &self,
+        block: &EthereumBlockPointer,
+        subgraph_id: &SubgraphDeploymentId,
+        indexer: &Option<Address>,
+    ) -> <SetHasher as StableHasher>::Out {
+        let mut finisher = ProofOfIndexingFinisher::new(block, subgraph_id, indexer);
+        for (name, region) in self.paused_regions() {
+            finisher.add_causality_region(&name, &region);
+        }
+        finisher.finish()
+    }
+}
+
+impl Drop for PersistentProofOfIndexing {
+    fn drop(&mut self) {
+        if let Some((budget, subgraph_id)) = &self.budget {
+            budget.release(subgraph_id);
+        }
     }
 }
 
@@ -195,7 +389,29 @@ impl ProofOfIndexingFinisher {
         }
     }
 
+    /// Mix the paused causality region `region` into the PoI. Regions are
+    /// members of an unordered collection, and `SetHasher` combines
+    /// members independently of the order in which they are added, so
+    /// regions can be added in any order, e.g., in `HashMap` iteration
+    /// order, without changing the PoI
     pub fn add_causality_region(&mut self, name: &str, region: &[u8]) {
+        let state = self.region_digest(name, region);
+
+        // Mixin the region with the final value
+        let causality_regions_member_seq_no = traverse_seq_no(&[
+            0, // Poi.causality_regions
+            1, // unordered collection member
+        ]);
+
+        self.state.write(causality_regions_member_seq_no, &state);
+        self.causality_count += 1;
+    }
+
+    /// The digest of the paused causality region `region` that
+    /// `add_causality_region` mixes into the PoI. When two PoIs differ,
+    /// comparing the digests of their regions shows which regions caused
+    /// the difference.
+    pub fn region_digest(&self, name: &str, region: &[u8]) -> <SetHasher as StableHasher>::Out {
         let mut state = SetHasher::from_bytes(region);
 
         // Finish the blocks vec
@@ -212,16 +428,7 @@ impl ProofOfIndexingFinisher {
         ]);
         name.stable_hash(name_seq_no, &mut state);
 
-        let state = state.finish();
-
-        // Mixin the region with the final value
-        let causality_regions_member_seq_no = traverse_seq_no(&[
-            0, // Poi.causality_regions
-            1, // unordered collection member
-        ]);
-
-        self.state.write(causality_regions_member_seq_no, &state);
-        self.causality_count += 1;
+        state.finish()
     }
 
     pub fn finish(mut self) -> <SetHasher as StableHasher>::Out {
@@ -240,4 +447,10 @@ impl ProofOfIndexingFinisher {
 
         self.state.finish()
     }
+
+    /// Like `finish`, but return the PoI as `0x`-prefixed lowercase hex,
+    /// the form in which it is logged and returned by the API
+    pub fn finish_hex(self) -> String {
+        format!("0x{}", hex::encode(self.finish()))
+    }
 }
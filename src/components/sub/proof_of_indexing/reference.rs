@@ -1,5 +1,7 @@
 use super::ProofOfIndexingEvent;
 use crate::prelude::SubgraphDeploymentId;
+#[cfg(test)]
+use crate::prelude::Value;
 use stable_hash::prelude::*;
 use stable_hash::utils::AsBytes;
 use std::collections::HashMap;
@@ -51,3 +53,106 @@ impl StableHash for Block<'_> {
         self.events.stable_hash(sequence_number.next_child(), state);
     }
 }
+
+/// Builds a `PoI` one block at a time, e.g.
+/// `PoIBuilder::new(id, hash, None).region("eth").block().set_entity(..).block()`.
+/// Every region starts with an empty block for the genesis block, and
+/// `build` pads all regions with empty blocks so that they have the same
+/// number of blocks, as `ProofOfIndexing` would record them.
+#[cfg(test)]
+pub struct PoIBuilder<'a> {
+    poi: PoI<'a>,
+    region: Option<String>,
+}
+
+#[cfg(test)]
+impl<'a> PoIBuilder<'a> {
+    pub fn new(
+        subgraph_id: SubgraphDeploymentId,
+        block_hash: H256,
+        indexer: Option<Address>,
+    ) -> Self {
+        Self {
+            poi: PoI {
+                causality_regions: HashMap::new(),
+                subgraph_id,
+                block_hash,
+                indexer,
+            },
+            region: None,
+        }
+    }
+
+    /// Add the following blocks and events to the region `name`
+    pub fn region(mut self, name: &str) -> Self {
+        self.poi
+            .causality_regions
+            .entry(name.to_owned())
+            .or_insert_with(|| CausalityRegion {
+                blocks: vec![Block::default()],
+            });
+        self.region = Some(name.to_owned());
+        self
+    }
+
+    /// Start a new block in the current region. Events are added to the
+    /// block that was started last; a block that is followed by another
+    /// call to `block` stays empty
+    pub fn block(mut self) -> Self {
+        self.blocks().push(Block::default());
+        self
+    }
+
+    pub fn set_entity(
+        self,
+        entity_type: &'a str,
+        id: &'a str,
+        data: &'a HashMap<String, Value>,
+    ) -> Self {
+        self.event(ProofOfIndexingEvent::SetEntity {
+            entity_type,
+            id,
+            data,
+        })
+    }
+
+    pub fn remove_entity(self, entity_type: &'a str, id: &'a str) -> Self {
+        self.event(ProofOfIndexingEvent::RemoveEntity { entity_type, id })
+    }
+
+    pub fn build(mut self) -> PoI<'a> {
+        let block_count = self
+            .poi
+            .causality_regions
+            .values()
+            .map(|region| region.blocks.len())
+            .max()
+            .unwrap_or(0);
+        for region in self.poi.causality_regions.values_mut() {
+            region.blocks.resize_with(block_count, Block::default);
+        }
+        self.poi
+    }
+
+    fn event(mut self, event: ProofOfIndexingEvent<'a>) -> Self {
+        self.blocks()
+            .last_mut()
+            .expect("regions start with a block")
+            .events
+            .push(event);
+        self
+    }
+
+    fn blocks(&mut self) -> &mut Vec<Block<'a>> {
+        let name = self
+            .region
+            .as_ref()
+            .expect("call `region` before adding blocks or events");
+        &mut self
+            .poi
+            .causality_regions
+            .get_mut(name)
+            .expect("the current region exists")
+            .blocks
+    }
+}
@@ -0,0 +1,103 @@
+//! A serialized form of the paused causality regions of a PoI, so that
+//! indexing can be resumed from it later. The snapshot remembers the block
+//! it was taken at, so that regions from different blocks can not be mixed
+//! by accident.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PoISnapshotError {
+    #[error("PoI snapshot was taken at block {recorded}, but resuming at block {requested}")]
+    BlockMismatch { recorded: u64, requested: u64 },
+    #[error("PoI snapshot is truncated")]
+    Truncated,
+    #[error("PoI snapshot contains trailing bytes")]
+    TrailingBytes,
+    #[error("PoI snapshot contains a causality region name that is not valid UTF-8")]
+    InvalidRegionName,
+}
+
+/// The paused bytes of every causality region after processing the block
+/// `block_number`. The encoding is deterministic: regions are always
+/// written sorted by name, and the same snapshot produces the same bytes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoISnapshot {
+    pub block_number: u64,
+    pub regions: BTreeMap<String, Vec<u8>>,
+}
+
+impl PoISnapshot {
+    pub fn new(block_number: u64) -> Self {
+        Self {
+            block_number,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Encode as the block number followed by each region's name and
+    /// paused bytes. All numbers are big endian, and names and bytes are
+    /// prefixed with their length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.block_number.to_be_bytes());
+        buf.extend_from_slice(&(self.regions.len() as u64).to_be_bytes());
+        for (name, region) in &self.regions {
+            buf.extend_from_slice(&(name.len() as u64).to_be_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(region.len() as u64).to_be_bytes());
+            buf.extend_from_slice(region);
+        }
+        buf
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, PoISnapshotError> {
+        fn take<'a>(bytes: &mut &'a [u8], len: u64) -> Result<&'a [u8], PoISnapshotError> {
+            let len: usize = len.try_into().map_err(|_| PoISnapshotError::Truncated)?;
+            if bytes.len() < len {
+                return Err(PoISnapshotError::Truncated);
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+
+        fn take_u64(bytes: &mut &[u8]) -> Result<u64, PoISnapshotError> {
+            let head = take(bytes, 8)?;
+            Ok(u64::from_be_bytes(head.try_into().unwrap()))
+        }
+
+        let block_number = take_u64(&mut bytes)?;
+        let count = take_u64(&mut bytes)?;
+        let mut regions = BTreeMap::new();
+        for _ in 0..count {
+            let len = take_u64(&mut bytes)?;
+            let name = std::str::from_utf8(take(&mut bytes, len)?)
+                .map_err(|_| PoISnapshotError::InvalidRegionName)?
+                .to_owned();
+            let len = take_u64(&mut bytes)?;
+            let region = take(&mut bytes, len)?.to_vec();
+            regions.insert(name, region);
+        }
+        if !bytes.is_empty() {
+            return Err(PoISnapshotError::TrailingBytes);
+        }
+        Ok(Self {
+            block_number,
+            regions,
+        })
+    }
+
+    /// Return the paused regions to continue indexing from, provided
+    /// `block_number` is the block this snapshot was taken at.
+    pub fn resume(self, block_number: u64) -> Result<BTreeMap<String, Vec<u8>>, PoISnapshotError> {
+        if self.block_number != block_number {
+            return Err(PoISnapshotError::BlockMismatch {
+                recorded: self.block_number,
+                requested: block_number,
+            });
+        }
+        Ok(self.regions)
+    }
+}
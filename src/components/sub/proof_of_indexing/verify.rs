@@ -0,0 +1,107 @@
+//! Checking the PoIs of many deployments against reference values in bulk,
+//! e.g., when an operator wants to make sure that all deployments on a node
+//! agree with another indexer.
+
+use futures03::stream::{self, StreamExt};
+use std::collections::BTreeMap;
+use web3::types::Address;
+
+use super::ProofOfIndexingFinisher;
+use crate::prelude::{EthereumBlockPointer, SubgraphDeploymentId};
+use crate::task_spawn::spawn_blocking_allow_panic;
+
+/// The PoI of `deployment` at `block` that we compute from the paused
+/// causality `regions`, and the reference values it should match
+#[derive(Clone, Debug)]
+pub struct VerifyJob {
+    pub deployment: SubgraphDeploymentId,
+    pub block: EthereumBlockPointer,
+    pub indexer: Option<Address>,
+    /// The paused causality regions of the deployment at `block`, as
+    /// they are stored during indexing
+    pub regions: BTreeMap<String, Vec<u8>>,
+    /// The PoI the deployment should have
+    pub expected: [u8; 32],
+    /// The digest of each causality region in the reference, as computed
+    /// by `ProofOfIndexingFinisher::region_digest`. The digests are only
+    /// used to find the regions that differ when the PoI does not match,
+    /// and can be left empty if that is not needed.
+    pub expected_regions: BTreeMap<String, [u8; 32]>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    Match,
+    Mismatch {
+        /// The PoI we computed
+        actual: [u8; 32],
+        /// The causality regions whose digest differs from the reference,
+        /// or that are only present on one side
+        regions: Vec<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyResult {
+    pub deployment: SubgraphDeploymentId,
+    pub outcome: VerifyOutcome,
+}
+
+/// Compute the PoI of every job and compare it with the expected value.
+/// Since computing a PoI is CPU bound, jobs run on blocking threads, with
+/// at most one job per CPU at a time. Results are in the same order as
+/// `jobs`.
+pub async fn verify_batch(jobs: Vec<VerifyJob>) -> Vec<VerifyResult> {
+    stream::iter(jobs)
+        .map(|job| spawn_blocking_allow_panic(move || verify(job)))
+        .buffered(num_cpus::get())
+        .map(|result| result.expect("PoI verification does not panic"))
+        .collect::<Vec<_>>()
+        .await
+}
+
+fn verify(job: VerifyJob) -> VerifyResult {
+    let VerifyJob {
+        deployment,
+        block,
+        indexer,
+        regions,
+        expected,
+        expected_regions,
+    } = job;
+
+    let mut finisher = ProofOfIndexingFinisher::new(&block, &deployment, &indexer);
+    let digests: BTreeMap<_, _> = regions
+        .iter()
+        .map(|(name, region)| (name.as_str(), finisher.region_digest(name, region)))
+        .collect();
+    for (name, region) in &regions {
+        finisher.add_causality_region(name, region);
+    }
+    let actual = finisher.finish();
+
+    let outcome = if actual == expected {
+        VerifyOutcome::Match
+    } else {
+        let mut differing: Vec<String> = digests
+            .iter()
+            .filter(|(name, digest)| expected_regions.get(**name) != Some(*digest))
+            .map(|(name, _)| name.to_string())
+            .collect();
+        differing.extend(
+            expected_regions
+                .keys()
+                .filter(|name| !digests.contains_key(name.as_str()))
+                .cloned(),
+        );
+        differing.sort();
+        VerifyOutcome::Mismatch {
+            actual,
+            regions: differing,
+        }
+    };
+    VerifyResult {
+        deployment,
+        outcome,
+    }
+}
@@ -0,0 +1,170 @@
+//! How deeply the selection sets of a query are nested, so that queries
+//! that are too deep can be rejected before we spend any effort executing
+//! them
+
+use std::collections::{HashMap, HashSet};
+
+use super::shape_hash::{select_operation, selection_set};
+use crate::data::query::QueryExecutionError;
+use crate::prelude::q;
+
+/// The maximum nesting depth of selection sets in the operation in `query`
+/// that will be executed, where a query that only selects scalar fields
+/// has depth 1. Fragment spreads and inline fragments do not add a level
+/// of their own; the fields they contain count as if they had been written
+/// in place of the fragment. The operation is selected as for
+/// `shape_hash_for_operation`, and if no operation can be selected, this
+/// is the maximum depth of all operations in `query`.
+pub fn max_depth(query: &q::Document, operation_name: Option<&str>) -> usize {
+    let mut depths = Depths {
+        query,
+        path: HashSet::new(),
+        fragments: HashMap::new(),
+    };
+    match select_operation(query, operation_name) {
+        Some(operation) => depths.set_depth(selection_set(operation)),
+        None => query
+            .definitions
+            .iter()
+            .filter_map(|defn| match defn {
+                q::Definition::Operation(op) => Some(depths.set_depth(selection_set(op))),
+                q::Definition::Fragment(_) => None,
+            })
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+/// Check that the operation in `query` is no deeper than `max_depth`. A
+/// `max_depth` of `None` means there is no limit.
+pub fn check_max_depth(
+    query: &q::Document,
+    operation_name: Option<&str>,
+    max_depth: Option<u8>,
+) -> Result<(), QueryExecutionError> {
+    match max_depth {
+        Some(limit) if self::max_depth(query, operation_name) > limit as usize => {
+            Err(QueryExecutionError::TooDeep(limit))
+        }
+        _ => Ok(()),
+    }
+}
+
+struct Depths<'a> {
+    query: &'a q::Document,
+    /// The fragments that we are currently expanding, so that fragments
+    /// that spread themselves, which validation rejects, can not make us
+    /// recurse forever
+    path: HashSet<&'a str>,
+    /// The depth of each fragment that we expanded already. Without it,
+    /// fragments that spread the next fragment several times would take
+    /// time that is exponential in the length of such a chain
+    fragments: HashMap<&'a str, usize>,
+}
+
+impl<'a> Depths<'a> {
+    /// The depth of `set`
+    fn set_depth(&mut self, set: &'a q::SelectionSet) -> usize {
+        set.items
+            .iter()
+            .map(|item| match item {
+                q::Selection::Field(field) => 1 + self.set_depth(&field.selection_set),
+                q::Selection::InlineFragment(frag) => self.set_depth(&frag.selection_set),
+                q::Selection::FragmentSpread(spread) => {
+                    self.fragment_depth(spread.fragment_name.as_str())
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn fragment_depth(&mut self, name: &'a str) -> usize {
+        if let Some(depth) = self.fragments.get(name) {
+            return *depth;
+        }
+        let defn = self.query.definitions.iter().find_map(|defn| match defn {
+            q::Definition::Fragment(frag) if frag.name == name => Some(frag),
+            _ => None,
+        });
+        match defn {
+            Some(defn) if self.path.insert(name) => {
+                let depth = self.set_depth(&defn.selection_set);
+                self.path.remove(name);
+                self.fragments.insert(name, depth);
+                depth
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphql_parser::parse_query;
+
+    fn depth(query: &str) -> usize {
+        let query = parse_query(query).unwrap().into_static();
+        max_depth(&query, None)
+    }
+
+    #[test]
+    fn flat_query() {
+        assert_eq!(1, depth("{ a }"));
+        assert_eq!(1, depth("query { a b c }"));
+    }
+
+    #[test]
+    fn nested_query() {
+        assert_eq!(4, depth("{ a { b { c { d } } } e }"));
+        assert_eq!(3, depth("{ a { b { c } } x { ... on X { y { z } } } }"));
+
+        let query = parse_query("query a { a } query b { a { b { c } } }")
+            .unwrap()
+            .into_static();
+        assert_eq!(1, max_depth(&query, Some("a")));
+        assert_eq!(3, max_depth(&query, Some("b")));
+        assert_eq!(3, max_depth(&query, None));
+    }
+
+    #[test]
+    fn depth_through_fragments() {
+        let query = "
+            query { a { ...deep } b }
+            fragment deep on A { c { ...deeper } }
+            fragment deeper on C { d { e } }
+            fragment unused on X { f { g { h { i { j } } } } }";
+        assert_eq!(4, depth(query));
+
+        // Cycles do not make us recurse forever
+        assert_eq!(2, depth("query { a { ...f } } fragment f on A { b ...f }"));
+    }
+
+    #[test]
+    fn fragments_spread_repeatedly() {
+        // Each fragment spreads the next one twice; expanding them anew
+        // every time would take 2^40 steps
+        let mut query = "query { ...f0 }".to_owned();
+        for i in 0..40 {
+            query.push_str(&format!(
+                " fragment f{} on T {{ a {{ ...f{} }} b {{ ...f{} }} }}",
+                i,
+                i + 1,
+                i + 1
+            ));
+        }
+        query.push_str(" fragment f40 on T { c }");
+        assert_eq!(41, depth(&query));
+    }
+
+    #[test]
+    fn depth_ceiling() {
+        let query = parse_query("{ a { b { c } } }").unwrap().into_static();
+        assert!(check_max_depth(&query, None, None).is_ok());
+        assert!(check_max_depth(&query, None, Some(3)).is_ok());
+        match check_max_depth(&query, None, Some(2)) {
+            Err(QueryExecutionError::TooDeep(2)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
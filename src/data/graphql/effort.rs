@@ -1,24 +1,48 @@
 //! Utilities to keep moving statistics about queries
 
+use futures::sync::mpsc;
+use futures::Stream;
 use lazy_static::lazy_static;
 use rand::{prelude::Rng, thread_rng};
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::env;
+use std::future::Future;
 use std::iter::FromIterator;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
+use tracing::{field, info_span, Instrument, Span};
 
-use crate::components::metrics::{Counter, Gauge, MetricsRegistry};
+use crate::components::graphql::LocalPermit;
+use crate::components::metrics::{
+    metric_or_noop, Counter, Gauge, MetricsRegistry, NoopCounter, NoopGauge,
+};
 use crate::components::store::PoolWaitStats;
+use crate::data::graphql::depth::check_max_depth;
 use crate::data::graphql::shape_hash::shape_hash;
-use crate::data::query::{CacheStatus, QueryExecutionError};
+use crate::data::query::{CacheStatus, Query, QueryExecutionError};
 use crate::prelude::q;
-use crate::prelude::{async_trait, debug, info, o, warn, CheapClone, Logger, QueryLoadManager};
-use crate::util::stats::{MovingStats, BIN_SIZE, WINDOW_SIZE};
+use crate::prelude::{
+    async_trait, debug, info, o, warn, CheapClone, EventProducer, Logger, QueryLoadManager,
+    QueryPermit, SubgraphDeploymentId,
+};
+use crate::util::stats::{DecayingStats, MovingStats, BIN_SIZE, WINDOW_SIZE};
+use crate::util::unfair_semaphore::UnfairSemaphore;
 
 const ZERO_DURATION: Duration = Duration::from_millis(0);
 
+// The number of slowest queries in the window that `QueryEffort` remembers
+const MAX_SLOWEST_QUERIES: usize = 100;
+
+// The number of recent bins of effort and semaphore wait time that we look
+// at to determine the `LoadTrend`, and by how much they need to change for
+// the load to count as rising or falling
+const TREND_BINS: usize = 10;
+const TREND_THRESHOLD: f64 = 0.1;
+
 lazy_static! {
     static ref LOAD_THRESHOLD: Duration = {
         let threshold = env::var("GRAPH_LOAD_THRESHOLD")
@@ -45,13 +69,22 @@ lazy_static! {
             .unwrap_or(1e9)
     };
 
-    // Load management can be disabled by setting the threshold to 0. This
-    // makes sure in particular that we never take any of the locks
-    // associated with it
-    static ref LOAD_MANAGEMENT_DISABLED: bool = *LOAD_THRESHOLD == ZERO_DURATION;
-
     static ref SIMULATE: bool = env::var("GRAPH_LOAD_SIMULATE").is_ok();
 
+    // When set, the effort of queries decays with this half life (in
+    // seconds) so that recent work weighs more than older work
+    static ref EFFORT_HALF_LIFE: Option<Duration> = {
+        env::var("GRAPH_LOAD_EFFORT_HALF_LIFE")
+            .ok()
+            .map(|s| {
+                let secs = u64::from_str(&s).unwrap_or_else(|_| {
+                    panic!("GRAPH_LOAD_EFFORT_HALF_LIFE must be a number, but is `{}`", s)
+                });
+                Duration::from_secs(secs)
+            })
+            .filter(|half_life| *half_life > ZERO_DURATION)
+    };
+
     // There is typically no need to configure this. But this can be used to effectivey disable the
     // semaphore by setting it to a high number.
     static ref EXTRA_QUERY_PERMITS: usize = {
@@ -64,40 +97,89 @@ lazy_static! {
             })
             .unwrap_or(0)
     };
+
+    // Queries whose selection sets are nested more deeply than this are
+    // rejected before they are executed
+    static ref MAX_DEPTH: Option<u8> = {
+        env::var("GRAPH_GRAPHQL_MAX_DEPTH").ok().map(|s| {
+            u8::from_str(&s).unwrap_or_else(|_| {
+                panic!("GRAPH_GRAPHQL_MAX_DEPTH must be a number between 0 and 255, but is `{}`", s)
+            })
+        })
+    };
 }
 
 struct QueryEffort {
     inner: Arc<RwLock<QueryEffortInner>>,
 }
 
+/// One execution of a query that was among the slowest in the window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlowQuery {
+    pub shape_hash: u64,
+    pub duration: Duration,
+    /// When the execution was recorded
+    pub at: Instant,
+}
+
 /// Track the effort for queries (identified by their ShapeHash) over a
-/// time window.
+/// time window. If `half_life` is set, we also track the effort with
+/// exponential decay so that recent work counts more than older work, and
+/// report that as the current effort
 struct QueryEffortInner {
     window_size: Duration,
     bin_size: Duration,
     effort: HashMap<u64, MovingStats>,
     total: MovingStats,
+    decayed_effort: HashMap<u64, DecayingStats>,
+    decayed_total: Option<DecayingStats>,
+    // A min-heap of the slowest executions in the window, ordered by
+    // duration, with at most `MAX_SLOWEST_QUERIES` entries
+    slowest: BinaryHeap<Reverse<(Duration, Instant, u64)>>,
 }
 
-/// Create a `QueryEffort` that uses the window and bin sizes configured in
-/// the environment
+/// Create a `QueryEffort` that uses the window and bin sizes, and the half
+/// life configured in the environment
 impl Default for QueryEffort {
     fn default() -> Self {
-        Self::new(*WINDOW_SIZE, *BIN_SIZE)
+        match *EFFORT_HALF_LIFE {
+            Some(half_life) => Self::with_half_life(*WINDOW_SIZE, *BIN_SIZE, half_life),
+            None => Self::new(*WINDOW_SIZE, *BIN_SIZE),
+        }
     }
 }
 
 impl QueryEffort {
     pub fn new(window_size: Duration, bin_size: Duration) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(QueryEffortInner::new(window_size, bin_size))),
+            inner: Arc::new(RwLock::new(QueryEffortInner::new(
+                window_size,
+                bin_size,
+                None,
+            ))),
+        }
+    }
+
+    /// Like `new`, but report the current effort with exponential decay,
+    /// so that work done `half_life` ago counts half as much as work done
+    /// right now
+    pub fn with_half_life(window_size: Duration, bin_size: Duration, half_life: Duration) -> Self {
+        assert!(half_life > ZERO_DURATION);
+        Self {
+            inner: Arc::new(RwLock::new(QueryEffortInner::new(
+                window_size,
+                bin_size,
+                Some(half_life),
+            ))),
         }
     }
 
-    pub fn add(&self, shape_hash: u64, duration: Duration, gauge: &Box<Gauge>) {
+    /// Add the work for one query and return the average effort of all
+    /// queries
+    pub fn add(&self, shape_hash: u64, duration: Duration) -> Duration {
         let mut inner = self.inner.write().unwrap();
-        inner.add(shape_hash, duration);
-        gauge.set(inner.total.average().unwrap_or(ZERO_DURATION).as_millis() as f64);
+        inner.add_at(Instant::now(), shape_hash, duration);
+        inner.total.average().unwrap_or(ZERO_DURATION)
     }
 
     /// Return what we know right now about the effort for the query
@@ -106,36 +188,163 @@ impl QueryEffort {
     /// data for the particular query, return `None` as the effort
     /// for the query
     pub fn current_effort(&self, shape_hash: u64) -> (Option<Duration>, Duration) {
-        let inner = self.inner.read().unwrap();
-        let total_effort = inner.total.duration();
-        let query_effort = inner.effort.get(&shape_hash).map(|stats| stats.duration());
-        (query_effort, total_effort)
+        self.inner
+            .read()
+            .unwrap()
+            .effort_at(Instant::now(), shape_hash)
+    }
+
+    /// The total effort, as `current_effort` reports it
+    pub fn total_effort(&self) -> Duration {
+        self.inner.read().unwrap().total_at(Instant::now())
+    }
+
+    /// The relative change of the total effort over the last `bins` bins,
+    /// as computed by `MovingStats::trend`
+    pub fn trend(&self, bins: usize) -> Option<f64> {
+        self.inner.read().unwrap().total.trend(bins)
+    }
+
+    /// The `n` slowest query executions in the window, slowest first
+    pub fn slowest(&self, n: usize) -> Vec<SlowQuery> {
+        self.inner.read().unwrap().slowest_at(Instant::now(), n)
+    }
+
+    /// Fold the effort tracked by `other`, e.g., the effort from another
+    /// query node, into this one, as if all queries had been run here.
+    /// Merging an effort into itself does nothing
+    pub fn merge(&self, other: &QueryEffort) {
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            return;
+        }
+        let other = other.inner.read().unwrap();
+        self.inner.write().unwrap().merge(&other);
     }
 }
 
 impl QueryEffortInner {
-    fn new(window_size: Duration, bin_size: Duration) -> Self {
+    fn new(window_size: Duration, bin_size: Duration, half_life: Option<Duration>) -> Self {
         Self {
             window_size,
             bin_size,
             effort: HashMap::default(),
             total: MovingStats::new(window_size, bin_size),
+            decayed_effort: HashMap::default(),
+            decayed_total: half_life.map(DecayingStats::new),
+            slowest: BinaryHeap::new(),
         }
     }
 
-    fn add(&mut self, shape_hash: u64, duration: Duration) {
+    // Should the clock ever go backwards, `now` can be before instants we
+    // saw earlier. `MovingStats` then adds the measurement to its latest
+    // bin, and all comparisons with `now` saturate, so that the worst that
+    // happens is that measurements stay in the window for longer
+    fn add_at(&mut self, now: Instant, shape_hash: u64, duration: Duration) {
         let window_size = self.window_size;
         let bin_size = self.bin_size;
-        let now = Instant::now();
         self.effort
             .entry(shape_hash)
             .or_insert_with(|| MovingStats::new(window_size, bin_size))
             .add_at(now, duration);
         self.total.add_at(now, duration);
+        if let Some(decayed_total) = &mut self.decayed_total {
+            let half_life = decayed_total.half_life();
+            self.decayed_effort
+                .entry(shape_hash)
+                .or_insert_with(|| DecayingStats::new(half_life))
+                .add_at(now, duration);
+            decayed_total.add_at(now, duration);
+        }
+        self.add_slowest(now, shape_hash, duration);
+    }
+
+    /// Remember the execution if it is among the `MAX_SLOWEST_QUERIES`
+    /// slowest ones in the window. Once an entry leaves the window, the
+    /// executions it pushed out earlier are gone, too, so that right after
+    /// a few very slow queries expire, fewer entries are available
+    fn add_slowest(&mut self, now: Instant, shape_hash: u64, duration: Duration) {
+        let window_size = self.window_size;
+        let expired = |at: Instant| now.saturating_duration_since(at) > window_size;
+        if self.slowest.iter().any(|Reverse((_, at, _))| expired(*at)) {
+            self.slowest = self
+                .slowest
+                .drain()
+                .filter(|Reverse((_, at, _))| !expired(*at))
+                .collect();
+        }
+        self.slowest.push(Reverse((duration, now, shape_hash)));
+        if self.slowest.len() > MAX_SLOWEST_QUERIES {
+            self.slowest.pop();
+        }
+    }
+
+    fn merge(&mut self, other: &QueryEffortInner) {
+        let window_size = self.window_size;
+        let bin_size = self.bin_size;
+        for (shape_hash, stats) in &other.effort {
+            self.effort
+                .entry(*shape_hash)
+                .or_insert_with(|| MovingStats::new(window_size, bin_size))
+                .merge(stats);
+        }
+        self.total.merge(&other.total);
+        if let Some(decayed_total) = &mut self.decayed_total {
+            let half_life = decayed_total.half_life();
+            for (shape_hash, stats) in &other.decayed_effort {
+                self.decayed_effort
+                    .entry(*shape_hash)
+                    .or_insert_with(|| DecayingStats::new(half_life))
+                    .merge(stats);
+            }
+            if let Some(other_total) = &other.decayed_total {
+                decayed_total.merge(other_total);
+            }
+        }
+        for Reverse(entry) in &other.slowest {
+            self.slowest.push(Reverse(*entry));
+            if self.slowest.len() > MAX_SLOWEST_QUERIES {
+                self.slowest.pop();
+            }
+        }
+    }
+
+    fn slowest_at(&self, now: Instant, n: usize) -> Vec<SlowQuery> {
+        let mut slowest: Vec<_> = self
+            .slowest
+            .iter()
+            .filter(|Reverse((_, at, _))| now.saturating_duration_since(*at) <= self.window_size)
+            .map(|Reverse((duration, at, shape_hash))| SlowQuery {
+                shape_hash: *shape_hash,
+                duration: *duration,
+                at: *at,
+            })
+            .collect();
+        slowest.sort_by(|a, b| b.duration.cmp(&a.duration).then(b.at.cmp(&a.at)));
+        slowest.truncate(n);
+        slowest
+    }
+
+    fn total_at(&self, now: Instant) -> Duration {
+        match &self.decayed_total {
+            None => self.total.duration(),
+            Some(decayed_total) => decayed_total.duration_at(now),
+        }
+    }
+
+    fn effort_at(&self, now: Instant, shape_hash: u64) -> (Option<Duration>, Duration) {
+        let query_effort = match &self.decayed_total {
+            None => self.effort.get(&shape_hash).map(|stats| stats.duration()),
+            Some(_) => self
+                .decayed_effort
+                .get(&shape_hash)
+                .map(|stats| stats.duration_at(now)),
+        };
+        (query_effort, self.total_at(now))
     }
 }
 
 /// What to log about the state we are currently in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum KillStateLogEvent {
     /// Overload is starting right now
     Start,
@@ -185,6 +394,23 @@ impl KillState {
         }
     }
 
+    /// The `kill_rate` in `state` and when it was last updated
+    fn read(state: &RwLock<KillState>) -> (f64, Instant) {
+        let state = state.read().unwrap();
+        (state.kill_rate, state.last_update)
+    }
+
+    /// Move all our instants that are after `now` back to `now`. Our
+    /// arithmetic with instants saturates, but if the clock goes
+    /// backwards, e.g., when a VM is migrated, instants from before the
+    /// jump can be in the future, and we would neither update the
+    /// `kill_rate` nor log until the clock catches up with them again
+    fn rebase(&mut self, now: Instant) {
+        self.last_update = self.last_update.min(now);
+        self.overload_start = self.overload_start.map(|start| start.min(now));
+        self.last_overload_log = self.last_overload_log.min(now);
+    }
+
     fn log_event(&mut self, now: Instant, kill_rate: f64, overloaded: bool) -> KillStateLogEvent {
         use KillStateLogEvent::*;
 
@@ -192,7 +418,7 @@ impl KillState {
             if !overloaded {
                 if kill_rate == 0.0 {
                     self.overload_start = None;
-                    Resolved(overload_start.elapsed())
+                    Resolved(now.saturating_duration_since(overload_start))
                 } else {
                     Settling
                 }
@@ -200,7 +426,7 @@ impl KillState {
                 > Duration::from_secs(30)
             {
                 self.last_overload_log = now;
-                Ongoing(overload_start.elapsed())
+                Ongoing(now.saturating_duration_since(overload_start))
             } else {
                 Skip
             }
@@ -214,31 +440,362 @@ impl KillState {
     }
 }
 
+/// The effort and the kill state for one deployment when deployments are
+/// isolated from each other
+struct DeploymentEffort {
+    effort: QueryEffort,
+    kill_state: RwLock<KillState>,
+    // When we last recorded work for the deployment
+    last_work: Mutex<Instant>,
+}
+
+impl DeploymentEffort {
+    fn new(now: Instant) -> Self {
+        Self {
+            effort: QueryEffort::default(),
+            kill_state: RwLock::new(KillState::new()),
+            last_work: Mutex::new(now),
+        }
+    }
+
+    fn add(&self, now: Instant, shape_hash: u64, duration: Duration) {
+        self.effort.add(shape_hash, duration);
+        *self.last_work.lock().unwrap() = now;
+    }
+
+    /// Whether we have not recorded any work for a whole window. The
+    /// effort we tracked is then out of date, since it only gets updated
+    /// when we record work
+    fn is_idle(&self, now: Instant) -> bool {
+        now.saturating_duration_since(*self.last_work.lock().unwrap()) > *WINDOW_SIZE
+    }
+}
+
+/// Why a query is too expensive to execute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpensiveReason {
+    /// The query is on the list of blocked queries
+    Blocked,
+    /// The query caused too much of the effort during an overload
+    Jailed,
+}
+
+/// Why a query is throttled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleReason {
+    /// The node is overloaded, and the query was picked at random, based
+    /// on the kill rate and its effort
+    Overloaded,
+}
+
 /// Indicate what the load manager wants query execution to do with a query
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Decision {
     /// Proceed with executing the query
     Proceed,
     /// The query is too expensive and should not be executed
-    TooExpensive,
+    TooExpensive(ExpensiveReason),
     /// The service is overloaded, and we should not execute the query
     /// right now
-    Throttle,
+    Throttle(ThrottleReason),
+}
+
+/// Decisions are serialized as the string from `as_str`
+impl Serialize for Decision {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 impl Decision {
+    /// The name of the decision as it appears in traces
+    pub fn as_str(&self) -> &'static str {
+        use Decision::*;
+        match self {
+            Proceed => "proceed",
+            TooExpensive(_) => "too_expensive",
+            Throttle(_) => "throttle",
+        }
+    }
+
+    /// The record of making this decision for the query `shape_hash`
+    /// because of `reason`, when the query's effort was `effort`
+    pub fn to_audit_record(
+        self,
+        reason: DecisionReason,
+        shape_hash: u64,
+        effort: Option<Duration>,
+    ) -> DecisionRecord {
+        DecisionRecord {
+            decision: self,
+            reason,
+            shape_hash,
+            effort_ms: effort.map(|effort| effort.as_millis() as u64),
+        }
+    }
+
     pub fn to_result(self) -> Result<(), QueryExecutionError> {
         use Decision::*;
         match self {
             Proceed => Ok(()),
-            TooExpensive => Err(QueryExecutionError::TooExpensive),
-            Throttle => Err(QueryExecutionError::Throttled),
+            TooExpensive(_) => Err(QueryExecutionError::TooExpensive),
+            Throttle(_) => Err(QueryExecutionError::Throttled),
+        }
+    }
+}
+
+/// Where the load is heading, judging by how the effort and the time spent
+/// waiting for the query semaphore changed recently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadTrend {
+    Rising,
+    Stable,
+    Falling,
+}
+
+impl LoadTrend {
+    /// Combine the relative `changes` of several signals. The load is
+    /// rising if any of them rises noticeably, and falling if all of the
+    /// signals we have data for fall noticeably
+    fn from_changes(changes: &[Option<f64>]) -> Self {
+        let changes: Vec<f64> = changes.iter().filter_map(|change| *change).collect();
+        if changes.iter().any(|change| *change > TREND_THRESHOLD) {
+            LoadTrend::Rising
+        } else if !changes.is_empty() && changes.iter().all(|change| *change < -TREND_THRESHOLD) {
+            LoadTrend::Falling
+        } else {
+            LoadTrend::Stable
+        }
+    }
+
+    /// The value of the `query_load_trend` gauge
+    fn gauge_value(self) -> f64 {
+        match self {
+            LoadTrend::Rising => 1.0,
+            LoadTrend::Stable => 0.0,
+            LoadTrend::Falling => -1.0,
+        }
+    }
+}
+
+/// Why the load manager made a `Decision`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionReason {
+    /// The query is on the list of blocked queries
+    Blocked,
+    /// Load management is disabled
+    Disabled,
+    /// The query is jailed because it caused too much of the effort
+    /// during an overload
+    Jailed,
+    /// The node is not overloaded
+    NotOverloaded,
+    /// We have not recorded any effort yet
+    NoEffort,
+    /// The node is overloaded, and the query was randomly picked to be
+    /// throttled, or not, based on the kill rate and its effort
+    Overload,
+}
+
+/// The record of a `Decision` for an audit log. Serializing it produces a
+/// JSON object with the fields `decision`, `reason`, `shape_hash` and
+/// `effort_ms`, where `effort_ms` is `null` if we have not seen the query
+/// before
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DecisionRecord {
+    pub decision: Decision,
+    pub reason: DecisionReason,
+    pub shape_hash: u64,
+    pub effort_ms: Option<u64>,
+}
+
+/// A decision that the `LoadManager` picked for its sample of decisions,
+/// see `LoadManager::with_decision_events`
+pub type DecisionEvent = DecisionRecord;
+
+// The number of sampled decisions that are buffered for a consumer that
+// falls behind; decisions beyond that are dropped
+const DECISION_EVENT_BUFFER: usize = 1000;
+
+struct DecisionSampler {
+    rate: f64,
+    // Only set once the stream of events has been taken, so that nothing is
+    // buffered while nobody consumes the events. The lock is only taken
+    // for decisions that were picked for the sample
+    sender: Option<Mutex<mpsc::Sender<DecisionEvent>>>,
+}
+
+/// How many queries the load manager would have declined if it had not
+/// been running in simulation mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// Queries that would have been throttled because of overload
+    pub throttled: u64,
+    /// Queries that would have been declined as too expensive because
+    /// they were jailed
+    pub too_expensive: u64,
+    /// Queries that would have been jailed
+    pub jailed: u64,
+}
+
+/// What `LoadManager::analyze_blocklist` found out about a list of
+/// blocked queries
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlocklistReport {
+    /// The number of entries in the list
+    pub entries: usize,
+    /// The number of different queries the list blocks, i.e., the number
+    /// of distinct shape hashes
+    pub distinct: usize,
+    /// Groups of entries that block the same query, since their text only
+    /// differs in ways that `shape_hash` ignores, like aliases or argument
+    /// values. Each group holds the indices of its entries in the list, in
+    /// ascending order, and the groups are ordered by their first entry
+    pub duplicates: Vec<BlocklistDuplicate>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlocklistDuplicate {
+    pub shape_hash: u64,
+    pub entries: Vec<usize>,
+}
+
+#[derive(Default)]
+struct SimulationCounters {
+    throttled: AtomicU64,
+    too_expensive: AtomicU64,
+    jailed: AtomicU64,
+}
+
+/// Receives the signals that the `LoadManager` reports as Prometheus
+/// metrics, with the same semantics, so that they can also be sent to
+/// other monitoring systems. An exporter for OpenTelemetry is in
+/// `components::metrics::otel` when the `otel` feature is enabled.
+pub trait LoadMetricsExporter: Send + Sync {
+    /// The moving average of the time spent running queries in ms, the
+    /// value of the `query_effort_ms` gauge
+    fn effort_ms(&self, effort_ms: f64);
+
+    /// The moving average of the time spent waiting for the query
+    /// semaphore in ms, the value of the `query_semaphore_wait_ms` gauge
+    fn semaphore_wait_ms(&self, wait_ms: f64);
+
+    /// A toplevel field was executed with `cache_status`, as counted by
+    /// `query_cache_status_count`
+    fn cache_status(&self, cache_status: CacheStatus);
+
+    /// The query with `shape_hash` was jailed
+    fn jailed(&self, shape_hash: u64);
+}
+
+/// The thresholds that control load management. The `Default` is taken
+/// from the `GRAPH_LOAD_THRESHOLD`, `GRAPH_LOAD_JAIL_THRESHOLD`,
+/// `GRAPH_EXTRA_QUERY_PERMITS` and `GRAPH_GRAPHQL_MAX_DEPTH` environment
+/// variables
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadManagerConfig {
+    /// We are overloaded once the average time spent waiting for the
+    /// store or the query semaphore exceeds this. A threshold of 0
+    /// disables load management
+    pub load_threshold: Duration,
+    /// When we are overloaded, jail any query that causes more than this
+    /// fraction of the total effort. `None` means queries are never jailed
+    pub jail_threshold: Option<f64>,
+    /// The number of queries that may run at the same time in addition to
+    /// one per store connection and CPU
+    pub extra_query_permits: usize,
+    /// In which order queries that wait for the query semaphore get to run
+    pub semaphore_mode: SemaphoreMode,
+    /// Reject queries that are nested more deeply than this, see
+    /// `check_max_depth`. `None` means there is no limit
+    pub max_depth: Option<u8>,
+}
+
+/// How the query semaphore picks the next query to run among the ones
+/// that are waiting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SemaphoreMode {
+    /// Queries run in the order in which they arrived. Every query waits
+    /// about the same time, which under sustained overload means that
+    /// queries wait long enough for their clients to time out
+    Fair,
+    /// The query that arrived last runs first, see `UnfairSemaphore`.
+    /// Most queries are served quickly even under sustained overload, but
+    /// some of them may wait until the overload is over, or until their
+    /// client gives up
+    Unfair,
+}
+
+enum QuerySemaphore {
+    Fair(Arc<tokio::sync::Semaphore>),
+    Unfair(Arc<UnfairSemaphore>),
+}
+
+impl QuerySemaphore {
+    fn new(mode: SemaphoreMode, permits: usize) -> Self {
+        match mode {
+            SemaphoreMode::Fair => {
+                QuerySemaphore::Fair(Arc::new(tokio::sync::Semaphore::new(permits)))
+            }
+            SemaphoreMode::Unfair => {
+                QuerySemaphore::Unfair(Arc::new(UnfairSemaphore::new(permits)))
+            }
+        }
+    }
+
+    async fn acquire(&self) -> LocalPermit {
+        match self {
+            QuerySemaphore::Fair(semaphore) => {
+                LocalPermit::Fair(semaphore.cheap_clone().acquire_owned().await)
+            }
+            QuerySemaphore::Unfair(semaphore) => {
+                LocalPermit::Unfair(semaphore.clone().acquire_owned().await)
+            }
+        }
+    }
+
+    fn add_permits(&self, n: usize) {
+        match self {
+            QuerySemaphore::Fair(semaphore) => semaphore.add_permits(n),
+            QuerySemaphore::Unfair(semaphore) => semaphore.add_permits(n),
+        }
+    }
+
+    /// Take away one permit if it is available right now
+    fn try_forget(&self) -> bool {
+        match self {
+            QuerySemaphore::Fair(semaphore) => match semaphore.try_acquire() {
+                Ok(permit) => {
+                    permit.forget();
+                    true
+                }
+                Err(_) => false,
+            },
+            QuerySemaphore::Unfair(semaphore) => semaphore.try_forget(),
+        }
+    }
+}
+
+impl Default for LoadManagerConfig {
+    fn default() -> Self {
+        Self {
+            load_threshold: *LOAD_THRESHOLD,
+            jail_threshold: if *JAIL_QUERIES {
+                Some(*JAIL_THRESHOLD)
+            } else {
+                None
+            },
+            extra_query_permits: *EXTRA_QUERY_PERMITS,
+            semaphore_mode: SemaphoreMode::Fair,
+            max_depth: *MAX_DEPTH,
         }
     }
 }
 
 pub struct LoadManager {
     logger: Logger,
+    config: LoadManagerConfig,
     effort: QueryEffort,
     blocked_queries: HashSet<u64>,
     jailed_queries: RwLock<HashSet<u64>>,
@@ -246,9 +803,33 @@ pub struct LoadManager {
     effort_gauge: Box<Gauge>,
     query_counters: HashMap<CacheStatus, Counter>,
 
-    query_semaphore: Arc<tokio::sync::Semaphore>,
+    query_semaphore: QuerySemaphore,
+    // The number of permits the `query_semaphore` has in total, including
+    // the ones that are currently held
+    max_concurrent_queries: Mutex<usize>,
+    // A semaphore shared with other `LoadManager`s that caps the number of
+    // queries running in the whole process
+    shared_semaphore: Option<Arc<tokio::sync::Semaphore>>,
     semaphore_wait_stats: RwLock<MovingStats>,
     semaphore_wait_gauge: Box<Gauge>,
+    semaphore_wait_p99_gauge: Box<Gauge>,
+    load_trend_gauge: Box<Gauge>,
+
+    simulation: SimulationCounters,
+
+    exporter: Option<Arc<dyn LoadMetricsExporter>>,
+
+    // Where we send a sample of our decisions
+    decision_sampler: Option<DecisionSampler>,
+
+    // Overload thresholds for queries against particular networks that
+    // are used instead of `LOAD_THRESHOLD`
+    network_thresholds: HashMap<String, Duration>,
+
+    // The effort for each deployment when deployments are isolated from
+    // each other. Deployments are forgotten once they have been idle for
+    // a whole window
+    deployment_effort: Option<RwLock<HashMap<SubgraphDeploymentId, Arc<DeploymentEffort>>>>,
 }
 
 impl LoadManager {
@@ -257,14 +838,45 @@ impl LoadManager {
         blocked_queries: Vec<Arc<q::Document>>,
         registry: Arc<dyn MetricsRegistry>,
         store_conn_pool_size: usize,
+    ) -> Self {
+        Self::with_config(
+            logger,
+            blocked_queries,
+            registry,
+            store_conn_pool_size,
+            LoadManagerConfig::default(),
+        )
+    }
+
+    /// Like `new`, but with thresholds from `config` rather than from the
+    /// environment
+    pub fn with_config(
+        logger: &Logger,
+        blocked_queries: Vec<Arc<q::Document>>,
+        registry: Arc<dyn MetricsRegistry>,
+        store_conn_pool_size: usize,
+        config: LoadManagerConfig,
     ) -> Self {
         let logger = logger.new(o!("component" => "LoadManager"));
+        let report = Self::analyze_blocklist(&blocked_queries);
+        for duplicate in &report.duplicates {
+            warn!(logger, "Blocked queries {:?} all block the same query", duplicate.entries;
+                "shape_hash" => duplicate.shape_hash);
+        }
+        if !report.duplicates.is_empty() {
+            info!(
+                logger,
+                "The {} blocked queries only block {} different queries",
+                report.entries,
+                report.distinct
+            );
+        }
         let blocked_queries = blocked_queries
             .into_iter()
             .map(|doc| shape_hash(&doc))
             .collect::<HashSet<_>>();
 
-        let mode = if *LOAD_MANAGEMENT_DISABLED {
+        let mode = if config.load_threshold == ZERO_DURATION {
             "disabled"
         } else if *SIMULATE {
             "simulation"
@@ -273,42 +885,75 @@ impl LoadManager {
         };
         info!(logger, "Creating LoadManager in {} mode", mode,);
 
-        let effort_gauge = registry
-            .new_gauge(
+        let effort_gauge = metric_or_noop(
+            &logger,
+            "query_effort_ms",
+            registry.new_gauge(
                 "query_effort_ms",
                 "Moving average of time spent running queries",
                 HashMap::new(),
-            )
-            .expect("failed to create `query_effort_ms` counter");
+            ),
+            || Box::new(NoopGauge.into()),
+        );
         let query_counters = CacheStatus::iter()
             .map(|s| {
                 let labels = HashMap::from_iter(vec![("cache_status".to_owned(), s.to_string())]);
-                let counter = registry
-                    .global_counter(
+                let counter = metric_or_noop(
+                    &logger,
+                    "query_cache_status_count",
+                    registry.global_counter(
                         "query_cache_status_count",
                         "Count toplevel GraphQL fields executed and their cache status",
                         labels,
-                    )
-                    .expect("Failed to register query_counter metric");
+                    ),
+                    || NoopCounter.into(),
+                );
                 (s.clone(), counter)
             })
             .collect::<HashMap<_, _>>();
 
-        let semaphore_wait_gauge = registry
-            .new_gauge(
+        let semaphore_wait_gauge = metric_or_noop(
+            &logger,
+            "query_semaphore_wait_ms",
+            registry.new_gauge(
                 "query_semaphore_wait_ms",
                 "Moving average of time spent running queries",
                 HashMap::new(),
-            )
-            .expect("failed to create `query_effort_ms` counter");
+            ),
+            || Box::new(NoopGauge.into()),
+        );
+
+        let semaphore_wait_p99_gauge = metric_or_noop(
+            &logger,
+            "query_semaphore_wait_p99_ms",
+            registry.new_gauge(
+                "query_semaphore_wait_p99_ms",
+                "99th percentile of the time spent waiting for the query semaphore",
+                HashMap::new(),
+            ),
+            || Box::new(NoopGauge.into()),
+        );
+
+        let load_trend_gauge = metric_or_noop(
+            &logger,
+            "query_load_trend",
+            registry.new_gauge(
+                "query_load_trend",
+                "Whether the query load is rising (1), stable (0) or falling (-1)",
+                HashMap::new(),
+            ),
+            || Box::new(NoopGauge.into()),
+        );
 
         // A query is always consuming a CPU core, or a DB connection, or both.
         // So if more than `store_conn_pool_size + num_cpus::get()` queries are executing,
         // there will be contention for resources.
-        let max_concurrent_queries = store_conn_pool_size + num_cpus::get() + *EXTRA_QUERY_PERMITS;
-        let query_semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrent_queries));
+        let max_concurrent_queries =
+            store_conn_pool_size + num_cpus::get() + config.extra_query_permits;
+        let query_semaphore = QuerySemaphore::new(config.semaphore_mode, max_concurrent_queries);
         Self {
             logger,
+            config,
             effort: QueryEffort::default(),
             blocked_queries,
             jailed_queries: RwLock::new(HashSet::new()),
@@ -316,60 +961,439 @@ impl LoadManager {
             effort_gauge,
             query_counters,
             query_semaphore,
-            semaphore_wait_stats: RwLock::new(MovingStats::default()),
+            max_concurrent_queries: Mutex::new(max_concurrent_queries),
+            shared_semaphore: None,
+            semaphore_wait_stats: RwLock::new(MovingStats::default().with_percentiles()),
             semaphore_wait_gauge,
+            semaphore_wait_p99_gauge,
+            load_trend_gauge,
+            simulation: SimulationCounters::default(),
+            exporter: None,
+            decision_sampler: None,
+            network_thresholds: HashMap::new(),
+            deployment_effort: None,
+        }
+    }
+
+    /// Also send the load signals to `exporter`, in addition to the
+    /// Prometheus metrics
+    pub fn with_exporter(mut self, exporter: Arc<dyn LoadMetricsExporter>) -> Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    /// Also take a permit from `semaphore` for each query. When several
+    /// `LoadManager`s share `semaphore`, no more queries than it has
+    /// permits run at the same time across all of them, while each of them
+    /// still enforces its own limit
+    pub fn with_shared_semaphore(mut self, semaphore: Arc<tokio::sync::Semaphore>) -> Self {
+        self.shared_semaphore = Some(semaphore);
+        self
+    }
+
+    /// Produce a `DecisionEvent` for a random sample of the decisions made
+    /// by `decide_with_reason`, where each decision is picked with
+    /// probability `sample_rate`, clamped to `[0, 1]`. The events can be
+    /// consumed through `EventProducer::take_event_stream`. Decisions made
+    /// before the stream is taken are not sampled, and events are dropped
+    /// while the consumer is more than `DECISION_EVENT_BUFFER` events
+    /// behind
+    pub fn with_decision_events(mut self, sample_rate: f64) -> Self {
+        self.decision_sampler = Some(DecisionSampler {
+            rate: sample_rate.min(1.0).max(0.0),
+            sender: None,
+        });
+        self
+    }
+
+    /// Consider the node overloaded for queries against a network in
+    /// `thresholds` once the wait time exceeds the threshold for that
+    /// network; queries against other networks use the `load_threshold`
+    /// from the `LoadManagerConfig`. Setting that to 0 still disables load
+    /// management entirely.
+    pub fn with_network_thresholds(mut self, thresholds: HashMap<String, Duration>) -> Self {
+        self.network_thresholds = thresholds;
+        self
+    }
+
+    /// Also track the effort for each deployment, so that when we are
+    /// overloaded, only deployments that caused at least their fair share
+    /// of the effort are considered overloaded, and each of them gets its
+    /// own kill rate. Queries against deployments that caused little of
+    /// the effort are not throttled, even if we have never seen the query
+    /// before. Deployments that we have not tracked any effort for in the
+    /// last window are treated like queries without a deployment, i.e.,
+    /// with the worst effort they could possibly have. The work for a
+    /// deployment is recorded with `record_deployment_work`, and the
+    /// deployment must be passed to `decide_with_reason`
+    pub fn with_deployment_isolation(mut self) -> Self {
+        self.deployment_effort = Some(RwLock::new(HashMap::new()));
+        self
+    }
+
+    /// Like `record_work`, and also record the work for `deployment` if
+    /// deployments are isolated
+    pub fn record_deployment_work(
+        &self,
+        deployment: &SubgraphDeploymentId,
+        shape_hash: u64,
+        duration: Duration,
+        cache_status: CacheStatus,
+    ) {
+        self.record_work(shape_hash, duration, cache_status);
+        if !self.disabled() {
+            self.add_deployment_effort_at(Instant::now(), deployment, shape_hash, duration);
+        }
+    }
+
+    fn add_deployment_effort_at(
+        &self,
+        now: Instant,
+        deployment: &SubgraphDeploymentId,
+        shape_hash: u64,
+        duration: Duration,
+    ) {
+        let efforts = match &self.deployment_effort {
+            Some(efforts) => efforts,
+            None => return,
+        };
+        if let Some(tracked) = efforts.read().unwrap().get(deployment) {
+            tracked.add(now, shape_hash, duration);
+            return;
+        }
+        let mut efforts = efforts.write().unwrap();
+        efforts.retain(|_, tracked| !tracked.is_idle(now));
+        efforts
+            .entry(deployment.clone())
+            .or_insert_with(|| Arc::new(DeploymentEffort::new(now)))
+            .add(now, shape_hash, duration);
+    }
+
+    /// The effort we track for `deployment`, unless we do not track any or
+    /// it is out of date
+    fn tracked_deployment(
+        &self,
+        deployment: Option<&SubgraphDeploymentId>,
+    ) -> Option<Arc<DeploymentEffort>> {
+        self.tracked_deployment_at(Instant::now(), deployment)
+    }
+
+    fn tracked_deployment_at(
+        &self,
+        now: Instant,
+        deployment: Option<&SubgraphDeploymentId>,
+    ) -> Option<Arc<DeploymentEffort>> {
+        let efforts = self.deployment_effort.as_ref()?.read().unwrap();
+        efforts
+            .get(deployment?)
+            .filter(|tracked| !tracked.is_idle(now))
+            .cloned()
+    }
+
+    /// Whether `tracked` caused at least its fair share of the total
+    /// effort, i.e., as much as every deployment that we are tracking
+    /// would have caused if all of them had caused the same effort
+    fn causes_overload(&self, tracked: &DeploymentEffort) -> bool {
+        let total_effort = self.effort.total_effort();
+        if total_effort == ZERO_DURATION {
+            return false;
+        }
+        let now = Instant::now();
+        let deployments = match &self.deployment_effort {
+            Some(efforts) => efforts
+                .read()
+                .unwrap()
+                .values()
+                .filter(|tracked| !tracked.is_idle(now))
+                .count(),
+            None => 0,
+        };
+        let share = tracked.effort.total_effort().as_secs_f64() / total_effort.as_secs_f64();
+        share * deployments as f64 >= 1.0
+    }
+
+    /// Change the number of queries that may run at the same time to `n`,
+    /// e.g., after the store's connection pool was resized. Since permits
+    /// that queries hold can not be taken away from them, the limit can not
+    /// go below the number of queries that are currently running; returns
+    /// the limit that is now in effect
+    pub fn set_max_concurrent(&self, n: usize) -> usize {
+        let mut max = self.max_concurrent_queries.lock().unwrap();
+        if n > *max {
+            self.query_semaphore.add_permits(n - *max);
+            *max = n;
+        }
+        while *max > n {
+            if !self.query_semaphore.try_forget() {
+                break;
+            }
+            *max -= 1;
         }
+        *max
     }
 
     /// Record that we spent `duration` amount of work for the query
     /// `shape_hash`, where `cache_status` indicates whether the query
     /// was cached or had to actually run
     pub fn record_work(&self, shape_hash: u64, duration: Duration, cache_status: CacheStatus) {
+        self.add_work(shape_hash, duration, cache_status, !self.disabled())
+    }
+
+    fn add_work(
+        &self,
+        shape_hash: u64,
+        duration: Duration,
+        cache_status: CacheStatus,
+        track_effort: bool,
+    ) {
         self.query_counters
             .get(&cache_status)
             .map(|counter| counter.inc());
-        if !*LOAD_MANAGEMENT_DISABLED {
-            self.effort.add(shape_hash, duration, &self.effort_gauge);
+        if let Some(exporter) = &self.exporter {
+            exporter.cache_status(cache_status);
         }
+        if track_effort {
+            let average = self.effort.add(shape_hash, duration);
+            self.effort_gauge.set(average.as_millis() as f64);
+            if let Some(exporter) = &self.exporter {
+                exporter.effort_ms(self.effort_gauge.get());
+            }
+            self.load_trend();
+        }
+    }
+
+    /// Compare the effort and the time queries spent waiting for the query
+    /// semaphore in the most recent bins with the bins before them to
+    /// predict whether we are heading for an overload, and update the
+    /// `query_load_trend` gauge. Like the effort, this is only tracked when
+    /// load management is enabled
+    pub fn load_trend(&self) -> LoadTrend {
+        let effort = self.effort.trend(TREND_BINS);
+        let wait = self.semaphore_wait_stats.read().unwrap().trend(TREND_BINS);
+        let trend = LoadTrend::from_changes(&[effort, wait]);
+        self.load_trend_gauge.set(trend.gauge_value());
+        trend
+    }
+
+    /// Decide what to do with `query` and, if it may proceed, run it by
+    /// awaiting `execute`. Both happen in the span from `query_span` so
+    /// that queries that were throttled or declined show up in traces,
+    /// too. The work the query does should be recorded with
+    /// `record_traced_work` from within `execute`.
+    pub async fn execute_traced<F, T>(
+        &self,
+        wait_stats: &PoolWaitStats,
+        shape_hash: u64,
+        query: &str,
+        execute: F,
+    ) -> Result<T, QueryExecutionError>
+    where
+        F: Future<Output = T>,
+    {
+        let decision = self.decide(wait_stats, shape_hash, query);
+        let span = self.query_span(shape_hash, decision);
+        span.in_scope(|| decision.to_result())?;
+        Ok(execute.instrument(span).await)
+    }
+
+    /// A `tracing` span for a query with the given `decision`. Besides
+    /// the `shape_hash` and the `decision`, the span says whether the
+    /// query is jailed; the fields `effort_ms` and `cache_status` are
+    /// filled in by `record_traced_work`.
+    pub fn query_span(&self, shape_hash: u64, decision: Decision) -> Span {
+        let jailed = self.jailed_queries.read().unwrap().contains(&shape_hash);
+        info_span!(
+            "query",
+            shape_hash,
+            decision = decision.as_str(),
+            jailed,
+            effort_ms = field::Empty,
+            cache_status = field::Empty,
+        )
+    }
+
+    /// Like `record_work`, and also record the effort and the cache status
+    /// in the current span
+    pub fn record_traced_work(
+        &self,
+        shape_hash: u64,
+        duration: Duration,
+        cache_status: CacheStatus,
+    ) {
+        let span = Span::current();
+        span.record("effort_ms", &(duration.as_millis() as u64));
+        span.record("cache_status", &field::display(cache_status));
+        self.record_work(shape_hash, duration, cache_status);
+    }
+
+    /// The `n` slowest query executions within the effort window, slowest
+    /// first. At most `MAX_SLOWEST_QUERIES` executions are remembered, and
+    /// like the effort, they are only recorded when load management is
+    /// enabled
+    pub fn slowest_queries(&self, n: usize) -> Vec<SlowQuery> {
+        self.effort.slowest(n)
+    }
+
+    /// Add the query effort that `other` tracked to the effort tracked
+    /// here, e.g., to get a combined view of the effort of several query
+    /// nodes. Only the effort is merged, not the effort per deployment
+    pub fn merge_effort(&self, other: &LoadManager) {
+        self.effort.merge(&other.effort);
+    }
+
+    /// The number of toplevel fields executed so far for each cache
+    /// status. Counts only ever go up; to get the counts for a period of
+    /// time, take the difference between two snapshots.
+    pub fn cache_status_counts(&self) -> HashMap<CacheStatus, u64> {
+        CacheStatus::iter()
+            .map(|status| {
+                let count = self
+                    .query_counters
+                    .get(status)
+                    .map(|counter| counter.get() as u64)
+                    .unwrap_or(0);
+                (*status, count)
+            })
+            .collect()
     }
 
     pub fn decide(&self, wait_stats: &PoolWaitStats, shape_hash: u64, query: &str) -> Decision {
+        self.decide_for_network(wait_stats, None, shape_hash, query)
+    }
+
+    /// Like `decide`, but use the overload threshold for `network`, the
+    /// network the query is for, if one was set with
+    /// `with_network_thresholds`
+    pub fn decide_for_network(
+        &self,
+        wait_stats: &PoolWaitStats,
+        network: Option<&str>,
+        shape_hash: u64,
+        query: &str,
+    ) -> Decision {
+        self.decide_with_reason(wait_stats, network, None, shape_hash, query)
+            .0
+    }
+
+    /// Like `decide_for_network`, and also return why we made the
+    /// decision. If deployments are isolated, the query is weighed by the
+    /// effort for `deployment`. In simulation mode, the decision is
+    /// `Proceed` even when the reason would have led to declining the query
+    pub fn decide_with_reason(
+        &self,
+        wait_stats: &PoolWaitStats,
+        network: Option<&str>,
+        deployment: Option<&SubgraphDeploymentId>,
+        shape_hash: u64,
+        query: &str,
+    ) -> (Decision, DecisionReason) {
+        let (decision, reason) = self.decide_unless_disabled(
+            wait_stats,
+            network,
+            deployment,
+            shape_hash,
+            query,
+            self.disabled(),
+        );
+        self.sample_decision(decision, reason, shape_hash);
+        (decision, reason)
+    }
+
+    /// Decide whether to run `query`, as `decide_with_reason` does, and
+    /// turn the decision into a result. Queries that are nested more
+    /// deeply than the `max_depth` from the `LoadManagerConfig` are
+    /// rejected before anything else, since they can be very costly to
+    /// execute regardless of how loaded we are
+    pub fn decide_for_query(
+        &self,
+        wait_stats: &PoolWaitStats,
+        network: Option<&str>,
+        deployment: Option<&SubgraphDeploymentId>,
+        query: &Query,
+        operation_name: Option<&str>,
+    ) -> Result<(), QueryExecutionError> {
+        check_max_depth(&query.document, operation_name, self.config.max_depth)?;
+        self.decide_with_reason(
+            wait_stats,
+            network,
+            deployment,
+            query.shape_hash,
+            &query.query_text,
+        )
+        .0
+        .to_result()
+    }
+
+    fn sample_decision(&self, decision: Decision, reason: DecisionReason, shape_hash: u64) {
+        let (rate, sender) = match &self.decision_sampler {
+            Some(DecisionSampler {
+                rate,
+                sender: Some(sender),
+            }) => (*rate, sender),
+            _ => return,
+        };
+        if !thread_rng().gen_bool(rate) {
+            return;
+        }
+        let (effort, _) = self.effort.current_effort(shape_hash);
+        let event = decision.to_audit_record(reason, shape_hash, effort);
+        // The buffer is full if the consumer falls behind, and the receiver
+        // is gone if the consumer stopped; in both cases, we drop the event
+        sender.lock().unwrap().try_send(event).ok();
+    }
+
+    fn decide_unless_disabled(
+        &self,
+        wait_stats: &PoolWaitStats,
+        network: Option<&str>,
+        deployment: Option<&SubgraphDeploymentId>,
+        shape_hash: u64,
+        query: &str,
+        disabled: bool,
+    ) -> (Decision, DecisionReason) {
         use Decision::*;
+        use DecisionReason::*;
 
         if self.blocked_queries.contains(&shape_hash) {
-            return TooExpensive;
+            return (TooExpensive(ExpensiveReason::Blocked), Blocked);
         }
-        if *LOAD_MANAGEMENT_DISABLED {
-            return Proceed;
+        if disabled {
+            return (Proceed, Disabled);
         }
 
         if self.jailed_queries.read().unwrap().contains(&shape_hash) {
-            return if *SIMULATE { Proceed } else { TooExpensive };
+            let decision = TooExpensive(ExpensiveReason::Jailed);
+            return (self.enforce(decision, *SIMULATE), Jailed);
         }
 
-        let (overloaded, wait_ms) = self.overloaded(wait_stats);
-        let (kill_rate, last_update) = self.kill_state();
+        // With isolated deployments, a deployment is only overloaded if it
+        // caused enough of the effort, and its kill rate is its own
+        let (overloaded, wait_ms) = self.overloaded(wait_stats, network);
+        let tracked = self.tracked_deployment(deployment);
+        let (overloaded, kill_state) = match &tracked {
+            Some(tracked) => (
+                overloaded && self.causes_overload(tracked),
+                &tracked.kill_state,
+            ),
+            None => (overloaded, &self.kill_state),
+        };
+        let (kill_rate, last_update) = KillState::read(kill_state);
         if !overloaded && kill_rate == 0.0 {
-            return Proceed;
+            return (Proceed, NotOverloaded);
         }
 
-        let (query_effort, total_effort) = self.effort.current_effort(shape_hash);
-        // When `total_effort` is `ZERO_DURATION`, we haven't done any work. All are
-        // welcome
-        if total_effort == ZERO_DURATION {
-            return Proceed;
-        }
-
-        // If `query_effort` is `None`, we haven't seen the query. Since we
-        // are in an overload situation, we are very suspicious of new things
-        // and assume the worst. This ensures that even if we only ever see
-        // new queries, we drop `kill_rate` amount of traffic
-        let known_query = query_effort.is_some();
-        let query_effort = query_effort.unwrap_or_else(|| total_effort).as_millis() as f64;
-        let total_effort = total_effort.as_millis() as f64;
+        let (known_query, query_effort, total_effort) =
+            match self.query_effort(tracked.as_deref(), shape_hash) {
+                Some(efforts) => efforts,
+                None => return (Proceed, NoEffort),
+            };
 
-        if known_query && *JAIL_QUERIES && query_effort / total_effort > *JAIL_THRESHOLD {
-            // Any single query that causes at least JAIL_THRESHOLD of the
+        let jail = self
+            .config
+            .jail_threshold
+            .map_or(false, |threshold| query_effort / total_effort > threshold);
+        if known_query && jail {
+            // Any single query that causes at least the jail threshold of the
             // effort in an overload situation gets killed
             warn!(self.logger, "Jailing query";
                 "query" => query,
@@ -377,13 +1401,15 @@ impl LoadManager {
                 "query_effort_ms" => query_effort,
                 "total_effort_ms" => total_effort,
                 "ratio" => format!("{:.4}", query_effort/total_effort));
-            self.jailed_queries.write().unwrap().insert(shape_hash);
-            return if *SIMULATE { Proceed } else { TooExpensive };
+            self.jail(shape_hash, *SIMULATE);
+            let decision = TooExpensive(ExpensiveReason::Jailed);
+            return (self.enforce(decision, *SIMULATE), Jailed);
         }
 
         // Kill random queries in case we have no queries, or not enough queries
         // that cause at least 20% of the effort
-        let kill_rate = self.update_kill_rate(kill_rate, last_update, overloaded, wait_ms);
+        let kill_rate =
+            self.update_kill_rate(kill_state, kill_rate, last_update, overloaded, wait_ms);
         let decline =
             thread_rng().gen_bool((kill_rate * query_effort / total_effort).min(1.0).max(0.0));
         if decline {
@@ -394,36 +1420,154 @@ impl LoadManager {
                     "query_weight" => format!("{:.2}", query_effort / total_effort),
                     "kill_rate" => format!("{:.4}", kill_rate),
                 );
-                return Proceed;
-            } else {
-                return Throttle;
             }
+            let decision = Throttle(ThrottleReason::Overloaded);
+            return (self.enforce(decision, *SIMULATE), Overload);
         }
+        (Proceed, Overload)
+    }
+
+    /// Return whether we have seen the query `shape_hash` before, its
+    /// effort, and the total effort, both in ms. When `total_effort` is
+    /// `ZERO_DURATION`, we haven't done any work, and return `None` since
+    /// all queries are welcome
+    fn query_effort(
+        &self,
+        tracked: Option<&DeploymentEffort>,
+        shape_hash: u64,
+    ) -> Option<(bool, f64, f64)> {
+        let (query_effort, total_effort) = self.effort.current_effort(shape_hash);
+        if total_effort == ZERO_DURATION {
+            return None;
+        }
+
+        // If `query_effort` is `None`, we haven't seen the query. Since we
+        // are in an overload situation, we are very suspicious of new things
+        // and assume the worst. This ensures that even if we only ever see
+        // new queries, we drop `kill_rate` amount of traffic. With isolated
+        // deployments, the worst a new query can be is all the effort for
+        // its deployment
+        let (query_effort, worst_effort) = match tracked {
+            Some(tracked) => tracked.effort.current_effort(shape_hash),
+            None => (query_effort, total_effort),
+        };
+        let known_query = query_effort.is_some();
+        let query_effort = query_effort.unwrap_or(worst_effort).as_millis() as f64;
+        Some((known_query, query_effort, total_effort.as_millis() as f64))
+    }
+
+    /// Turn `decision` into what we actually do with the query. In
+    /// simulation mode, we always proceed, but keep track of the queries
+    /// we would have declined
+    fn enforce(&self, decision: Decision, simulate: bool) -> Decision {
+        use Decision::*;
+
+        if !simulate {
+            return decision;
+        }
+        let counter = match decision {
+            Proceed => return Proceed,
+            TooExpensive(_) => &self.simulation.too_expensive,
+            Throttle(_) => &self.simulation.throttled,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
         Proceed
     }
 
-    fn overloaded(&self, wait_stats: &PoolWaitStats) -> (bool, Duration) {
+    fn jail(&self, shape_hash: u64, simulate: bool) {
+        self.jailed_queries.write().unwrap().insert(shape_hash);
+        if let Some(exporter) = &self.exporter {
+            exporter.jailed(shape_hash);
+        }
+        if simulate {
+            self.simulation.jailed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Check the list of `blocked_queries` that would be passed to `new`
+    /// for entries that block the same query, so that operators can clean
+    /// up their lists. `new` logs what this finds
+    pub fn analyze_blocklist(blocked_queries: &[Arc<q::Document>]) -> BlocklistReport {
+        let mut entries: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, doc) in blocked_queries.iter().enumerate() {
+            entries.entry(shape_hash(doc)).or_default().push(i);
+        }
+        let distinct = entries.len();
+        let mut duplicates: Vec<_> = entries
+            .into_iter()
+            .filter(|(_, entries)| entries.len() > 1)
+            .map(|(shape_hash, entries)| BlocklistDuplicate {
+                shape_hash,
+                entries,
+            })
+            .collect();
+        duplicates.sort_by_key(|duplicate| duplicate.entries[0]);
+        BlocklistReport {
+            entries: blocked_queries.len(),
+            distinct,
+            duplicates,
+        }
+    }
+
+    /// The number of queries that would have been declined so far if we
+    /// were not running in simulation mode. All numbers are zero when
+    /// load management is enforced.
+    pub fn simulation_report(&self) -> SimulationReport {
+        SimulationReport {
+            throttled: self.simulation.throttled.load(Ordering::SeqCst),
+            too_expensive: self.simulation.too_expensive.load(Ordering::SeqCst),
+            jailed: self.simulation.jailed.load(Ordering::SeqCst),
+        }
+    }
+
+    // Load management can be disabled by setting the threshold to 0. This
+    // makes sure in particular that we never take any of the locks
+    // associated with it
+    fn disabled(&self) -> bool {
+        self.config.load_threshold == ZERO_DURATION
+    }
+
+    fn overloaded(&self, wait_stats: &PoolWaitStats, network: Option<&str>) -> (bool, Duration) {
+        let threshold = network
+            .and_then(|network| self.network_thresholds.get(network))
+            .unwrap_or(&self.config.load_threshold);
         let store_avg = wait_stats.read().unwrap().average();
         let semaphore_avg = self.semaphore_wait_stats.read().unwrap().average();
         let max_avg = store_avg.max(semaphore_avg);
-        let overloaded = max_avg
-            .map(|average| average > *LOAD_THRESHOLD)
-            .unwrap_or(false);
+        let overloaded = max_avg.map(|average| average > *threshold).unwrap_or(false);
         (overloaded, max_avg.unwrap_or(ZERO_DURATION))
     }
 
+    #[cfg(test)]
     fn kill_state(&self) -> (f64, Instant) {
-        let state = self.kill_state.read().unwrap();
-        (state.kill_rate, state.last_update)
+        KillState::read(&self.kill_state)
     }
 
     fn update_kill_rate(
         &self,
-        mut kill_rate: f64,
+        kill_state: &RwLock<KillState>,
+        kill_rate: f64,
         last_update: Instant,
         overloaded: bool,
         wait_ms: Duration,
     ) -> f64 {
+        let now = Instant::now();
+        let (kill_rate, _) =
+            self.update_kill_rate_at(kill_state, now, kill_rate, last_update, overloaded, wait_ms);
+        kill_rate
+    }
+
+    /// Update the `kill_rate` in `kill_state` as of `now` and log what
+    /// happened. Returns the new `kill_rate` and the event that was logged
+    fn update_kill_rate_at(
+        &self,
+        kill_state: &RwLock<KillState>,
+        now: Instant,
+        mut kill_rate: f64,
+        last_update: Instant,
+        overloaded: bool,
+        wait_ms: Duration,
+    ) -> (f64, KillStateLogEvent) {
         // The rates by which we increase and decrease the `kill_rate`; when
         // we increase the `kill_rate`, we do that in a way so that we do drop
         // fewer queries as the `kill_rate` approaches 1.0. After `n`
@@ -443,81 +1587,1148 @@ impl LoadManager {
 
         assert!(overloaded || kill_rate > 0.0);
 
-        let now = Instant::now();
-        if now.saturating_duration_since(last_update) > KILL_RATE_UPDATE_INTERVAL {
-            // Update the kill_rate
-            if overloaded {
-                kill_rate = (kill_rate + KILL_RATE_STEP_UP * (1.0 - kill_rate)).min(1.0);
-            } else {
-                kill_rate = (kill_rate - KILL_RATE_STEP_DOWN).max(0.0);
+        if now < last_update {
+            // The clock went backwards. Wait for the update interval
+            // starting from `now`
+            kill_state.write().unwrap().rebase(now);
+            return (kill_rate, KillStateLogEvent::Skip);
+        }
+        if now.saturating_duration_since(last_update) <= KILL_RATE_UPDATE_INTERVAL {
+            return (kill_rate, KillStateLogEvent::Skip);
+        }
+        // Update the kill_rate
+        if overloaded {
+            kill_rate = (kill_rate + KILL_RATE_STEP_UP * (1.0 - kill_rate)).min(1.0);
+        } else {
+            kill_rate = (kill_rate - KILL_RATE_STEP_DOWN).max(0.0);
+        }
+        let event = {
+            let mut state = kill_state.write().unwrap();
+            state.kill_rate = kill_rate;
+            state.last_update = now;
+            state.log_event(now, kill_rate, overloaded)
+        };
+        // Log information about what's happening after we've released the
+        // lock on kill_state
+        use KillStateLogEvent::*;
+        match event {
+            Settling => {
+                info!(self.logger, "Query overload improving";
+                    "wait_ms" => wait_ms.as_millis(),
+                    "kill_rate" => format!("{:.4}", kill_rate),
+                    "event" => "settling");
             }
-            let event = {
-                let mut state = self.kill_state.write().unwrap();
-                state.kill_rate = kill_rate;
-                state.last_update = now;
-                state.log_event(now, kill_rate, overloaded)
-            };
-            // Log information about what's happening after we've released the
-            // lock on self.kill_state
-            use KillStateLogEvent::*;
-            match event {
-                Settling => {
-                    info!(self.logger, "Query overload improving";
-                        "wait_ms" => wait_ms.as_millis(),
-                        "kill_rate" => format!("{:.4}", kill_rate),
-                        "event" => "settling");
-                }
-                Resolved(duration) => {
-                    info!(self.logger, "Query overload resolved";
-                        "duration_ms" => duration.as_millis(),
-                        "wait_ms" => wait_ms.as_millis(),
-                        "event" => "resolved");
-                }
-                Ongoing(duration) => {
-                    info!(self.logger, "Query overload still happening";
-                        "duration_ms" => duration.as_millis(),
-                        "wait_ms" => wait_ms.as_millis(),
-                        "kill_rate" => format!("{:.4}", kill_rate),
-                        "event" => "ongoing");
-                }
-                Start => {
-                    warn!(self.logger, "Query overload";
+            Resolved(duration) => {
+                info!(self.logger, "Query overload resolved";
+                    "duration_ms" => duration.as_millis(),
                     "wait_ms" => wait_ms.as_millis(),
-                    "event" => "start");
-                }
-                Skip => { /* do nothing */ }
+                    "event" => "resolved");
             }
+            Ongoing(duration) => {
+                info!(self.logger, "Query overload still happening";
+                    "duration_ms" => duration.as_millis(),
+                    "wait_ms" => wait_ms.as_millis(),
+                    "kill_rate" => format!("{:.4}", kill_rate),
+                    "event" => "ongoing");
+            }
+            Start => {
+                warn!(self.logger, "Query overload";
+                "wait_ms" => wait_ms.as_millis(),
+                "event" => "start");
+            }
+            Skip => { /* do nothing */ }
         }
-        kill_rate
+        (kill_rate, event)
+    }
+
+    /// Make it look like queries have been waiting `wait` on average for
+    /// the query semaphore, replacing all actual measurements
+    #[cfg(test)]
+    fn simulate_overload(&self, wait: Duration) {
+        let mut stats = MovingStats::default().with_percentiles();
+        stats.add(wait);
+        *self.semaphore_wait_stats.write().unwrap() = stats;
     }
 
     fn add_wait_time(&self, duration: Duration) {
-        let wait_avg = {
+        let (wait_avg, wait_p99) = {
             let mut wait_stats = self.semaphore_wait_stats.write().unwrap();
             wait_stats.add(duration);
-            wait_stats.average()
+            (wait_stats.average(), wait_stats.percentile(99.0))
         };
+        // The average hides bursts of long waits, which the 99th
+        // percentile shows
+        if let Some(wait_p99) = wait_p99 {
+            self.semaphore_wait_p99_gauge
+                .set(wait_p99.as_secs_f64() * 1000.0);
+        }
         if let Some(wait_avg) = wait_avg.map(|wait_avg| wait_avg.as_millis()) {
             self.semaphore_wait_gauge.set(wait_avg as f64);
+            if let Some(exporter) = &self.exporter {
+                exporter.semaphore_wait_ms(wait_avg as f64);
+            }
+        }
+    }
+}
+
+impl EventProducer<DecisionEvent> for LoadManager {
+    fn take_event_stream(
+        &mut self,
+    ) -> Option<Box<dyn Stream<Item = DecisionEvent, Error = ()> + Send>> {
+        let sampler = self.decision_sampler.as_mut()?;
+        if sampler.sender.is_some() {
+            return None;
         }
+        let (sender, receiver) = mpsc::channel(DECISION_EVENT_BUFFER);
+        sampler.sender = Some(Mutex::new(sender));
+        Some(Box::new(receiver))
     }
 }
 
 #[async_trait]
 impl QueryLoadManager for LoadManager {
-    async fn query_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+    async fn query_permit(&self) -> QueryPermit {
         let start = Instant::now();
-        let permit = self.query_semaphore.cheap_clone().acquire_owned().await;
+        // Queries only wait for the shared semaphore once they got past
+        // our own, so that the managers share it fairly
+        let permit = self.query_semaphore.acquire().await;
+        let shared = match &self.shared_semaphore {
+            Some(shared) => Some(shared.cheap_clone().acquire_owned().await),
+            None => None,
+        };
         self.add_wait_time(start.elapsed());
-        permit
+        match permit {
+            LocalPermit::Fair(permit) => QueryPermit::new(permit, shared),
+            LocalPermit::Unfair(permit) => QueryPermit::unfair(permit, shared),
+        }
     }
 
     fn record_work(&self, shape_hash: u64, duration: Duration, cache_status: CacheStatus) {
-        self.query_counters
-            .get(&cache_status)
-            .map(|counter| counter.inc());
-        if !*LOAD_MANAGEMENT_DISABLED {
-            self.effort.add(shape_hash, duration, &self.effort_gauge);
+        LoadManager::record_work(self, shape_hash, duration, cache_status)
+    }
+
+    fn record_deployment_work(
+        &self,
+        deployment: &SubgraphDeploymentId,
+        shape_hash: u64,
+        duration: Duration,
+        cache_status: CacheStatus,
+    ) {
+        LoadManager::record_deployment_work(self, deployment, shape_hash, duration, cache_status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::metrics::MockMetricsRegistry;
+    use slog::Discard;
+
+    fn load_manager() -> LoadManager {
+        let logger = Logger::root(Discard, o!());
+        LoadManager::new(&logger, vec![], Arc::new(MockMetricsRegistry::new()), 10)
+    }
+
+    #[test]
+    fn cache_status_counts() {
+        let manager = load_manager();
+        let counts = manager.cache_status_counts();
+        assert_eq!(CacheStatus::iter().count(), counts.len());
+        assert!(counts.values().all(|count| *count == 0));
+
+        let work = Duration::from_millis(10);
+        manager.record_work(1, work, CacheStatus::Hit);
+        manager.record_work(1, work, CacheStatus::Hit);
+        manager.record_work(2, work, CacheStatus::Miss);
+        manager.record_work(1, work, CacheStatus::Hit);
+        manager.record_work(3, work, CacheStatus::Shared);
+
+        let counts = manager.cache_status_counts();
+        assert_eq!(CacheStatus::iter().count(), counts.len());
+        assert_eq!(3, counts[&CacheStatus::Hit]);
+        assert_eq!(1, counts[&CacheStatus::Miss]);
+        assert_eq!(1, counts[&CacheStatus::Shared]);
+        assert_eq!(0, counts[&CacheStatus::Insert]);
+    }
+
+    #[test]
+    fn simulation_report() {
+        use Decision::*;
+
+        let throttle = Throttle(ThrottleReason::Overloaded);
+        let jailed = TooExpensive(ExpensiveReason::Jailed);
+
+        let manager = load_manager();
+        assert_eq!(SimulationReport::default(), manager.simulation_report());
+
+        // When enforcing, decisions are passed through and not counted
+        assert_eq!(throttle, manager.enforce(throttle, false));
+        assert_eq!(jailed, manager.enforce(jailed, false));
+        manager.jail(1, false);
+        assert_eq!(SimulationReport::default(), manager.simulation_report());
+
+        assert_eq!(Proceed, manager.enforce(Proceed, true));
+        assert_eq!(Proceed, manager.enforce(throttle, true));
+        assert_eq!(Proceed, manager.enforce(throttle, true));
+        manager.jail(2, true);
+        assert_eq!(Proceed, manager.enforce(jailed, true));
+        assert_eq!(
+            SimulationReport {
+                throttled: 2,
+                too_expensive: 1,
+                jailed: 1,
+            },
+            manager.simulation_report()
+        );
+    }
+
+    #[test]
+    fn effort_decays() {
+        let half_life = Duration::from_secs(60);
+        let mut inner = QueryEffortInner::new(
+            Duration::from_secs(3600),
+            Duration::from_secs(1),
+            Some(half_life),
+        );
+        let start = Instant::now();
+        inner.add_at(start, 1, Duration::from_secs(10));
+
+        let secs = |effort: Option<Duration>| effort.unwrap().as_secs_f64();
+        let (query, total) = inner.effort_at(start, 1);
+        assert!((secs(query) - 10.0).abs() < 0.001);
+        assert!((total.as_secs_f64() - 10.0).abs() < 0.001);
+
+        let (query, total) = inner.effort_at(start + half_life, 1);
+        assert!((secs(query) - 5.0).abs() < 0.001);
+        assert!((total.as_secs_f64() - 5.0).abs() < 0.001);
+
+        // A recent sample for another query dominates the old one
+        let later = start + 2 * half_life;
+        inner.add_at(later, 2, Duration::from_secs(10));
+        let (old, total) = inner.effort_at(later, 1);
+        let (recent, _) = inner.effort_at(later, 2);
+        assert!((secs(old) - 2.5).abs() < 0.001);
+        assert!((secs(recent) - 10.0).abs() < 0.001);
+        assert!((total.as_secs_f64() - 12.5).abs() < 0.001);
+
+        // Without a half life, old samples count fully until they leave
+        // the window
+        let mut inner =
+            QueryEffortInner::new(Duration::from_secs(3600), Duration::from_secs(1), None);
+        inner.add_at(start, 1, Duration::from_secs(10));
+        let (query, _) = inner.effort_at(start + half_life, 1);
+        assert_eq!(Some(Duration::from_secs(10)), query);
+    }
+
+    #[test]
+    fn slowest_queries() {
+        let window = Duration::from_secs(300);
+        let mut inner = QueryEffortInner::new(window, Duration::from_secs(1), None);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let ms = Duration::from_millis;
+
+        inner.add_at(at(0), 1, ms(900));
+        for i in 0..2 * MAX_SLOWEST_QUERIES as u64 {
+            inner.add_at(at(10), 100 + i, ms(i));
+        }
+        inner.add_at(at(20), 2, ms(500));
+        inner.add_at(at(30), 3, ms(700));
+
+        fn slowest(inner: &QueryEffortInner, now: Instant, n: usize) -> Vec<(u64, Duration)> {
+            inner
+                .slowest_at(now, n)
+                .into_iter()
+                .map(|query| (query.shape_hash, query.duration))
+                .collect()
+        }
+        assert_eq!(
+            vec![(1, ms(900)), (3, ms(700)), (2, ms(500)), (299, ms(199))],
+            slowest(&inner, at(30), 4)
+        );
+        assert_eq!(MAX_SLOWEST_QUERIES, inner.slowest_at(at(30), 1000).len());
+
+        // Executions that are older than the window are not reported, and
+        // are evicted once more work is recorded
+        assert_eq!(
+            vec![(3, ms(700)), (2, ms(500))],
+            slowest(&inner, at(301), 2)
+        );
+        inner.add_at(at(315), 4, ms(1));
+        assert_eq!(
+            vec![(3, ms(700)), (2, ms(500)), (4, ms(1))],
+            slowest(&inner, at(315), 10)
+        );
+        assert_eq!(3, inner.slowest.len());
+    }
+
+    #[test]
+    fn merge_efforts() {
+        let window = Duration::from_secs(300);
+        let bin = Duration::from_secs(1);
+        let ms = Duration::from_millis;
+        let effort = QueryEffort::new(window, bin);
+        let other = QueryEffort::new(window, bin);
+        effort.add(1, ms(100));
+        effort.add(1, ms(50));
+        other.add(2, ms(300));
+        other.add(1, ms(10));
+
+        effort.merge(&other);
+        assert_eq!((Some(ms(160)), ms(460)), effort.current_effort(1));
+        assert_eq!((Some(ms(300)), ms(460)), effort.current_effort(2));
+        let slowest: Vec<_> = effort.slowest(10).iter().map(|q| q.shape_hash).collect();
+        assert_eq!(vec![2, 1, 1, 1], slowest);
+
+        // The other effort is unchanged, and merging with itself does nothing
+        assert_eq!((None, ms(310)), other.current_effort(3));
+        other.merge(&other);
+        assert_eq!((Some(ms(300)), ms(310)), other.current_effort(2));
+    }
+
+    #[test]
+    fn failing_registry_does_not_panic() {
+        use crate::components::metrics::{Collector, PrometheusError};
+
+        /// A registry that refuses to create any metric, like one that
+        /// already has metrics with the same names
+        struct DuplicateRegistry;
+
+        impl MetricsRegistry for DuplicateRegistry {
+            fn register(&self, _name: &str, _c: Box<dyn Collector>) {}
+
+            fn unregister(&self, _metric: Box<dyn Collector>) {}
+
+            fn global_counter(
+                &self,
+                _name: &str,
+                _help: &str,
+                _const_labels: HashMap<String, String>,
+            ) -> Result<Counter, PrometheusError> {
+                Err(PrometheusError::AlreadyReg)
+            }
+
+            fn global_gauge(
+                &self,
+                _name: &str,
+                _help: &str,
+                _const_labels: HashMap<String, String>,
+            ) -> Result<Gauge, PrometheusError> {
+                Err(PrometheusError::AlreadyReg)
+            }
+
+            fn new_gauge(
+                &self,
+                _name: &str,
+                _help: &str,
+                _const_labels: HashMap<String, String>,
+            ) -> Result<Box<Gauge>, PrometheusError> {
+                Err(PrometheusError::AlreadyReg)
+            }
+        }
+
+        let logger = Logger::root(Discard, o!());
+        let manager = LoadManager::new(&logger, vec![], Arc::new(DuplicateRegistry), 10);
+        manager.record_work(1, Duration::from_millis(10), CacheStatus::Hit);
+        assert_eq!(1, manager.cache_status_counts()[&CacheStatus::Hit]);
+    }
+
+    #[test]
+    fn export_load_metrics() {
+        use std::sync::Mutex;
+
+        #[derive(Debug, PartialEq)]
+        enum Signal {
+            Effort(f64),
+            SemaphoreWait(f64),
+            CacheStatus(String),
+            Jailed(u64),
+        }
+
+        #[derive(Default)]
+        struct MockExporter {
+            signals: Mutex<Vec<Signal>>,
+        }
+
+        impl LoadMetricsExporter for MockExporter {
+            fn effort_ms(&self, effort_ms: f64) {
+                self.signals.lock().unwrap().push(Signal::Effort(effort_ms));
+            }
+
+            fn semaphore_wait_ms(&self, wait_ms: f64) {
+                self.signals
+                    .lock()
+                    .unwrap()
+                    .push(Signal::SemaphoreWait(wait_ms));
+            }
+
+            fn cache_status(&self, cache_status: CacheStatus) {
+                self.signals
+                    .lock()
+                    .unwrap()
+                    .push(Signal::CacheStatus(cache_status.to_string()));
+            }
+
+            fn jailed(&self, shape_hash: u64) {
+                self.signals
+                    .lock()
+                    .unwrap()
+                    .push(Signal::Jailed(shape_hash));
+            }
         }
+
+        let exporter = Arc::new(MockExporter::default());
+        let manager = load_manager().with_exporter(exporter.clone());
+
+        manager.add_work(1, Duration::from_millis(100), CacheStatus::Miss, true);
+        manager.jail(1, false);
+        manager.add_wait_time(Duration::from_millis(20));
+
+        let signals = exporter.signals.lock().unwrap();
+        assert_eq!(
+            vec![
+                Signal::CacheStatus(CacheStatus::Miss.to_string()),
+                Signal::Effort(manager.effort_gauge.get()),
+                Signal::Jailed(1),
+                Signal::SemaphoreWait(manager.semaphore_wait_gauge.get()),
+            ],
+            *signals
+        );
+        assert!(manager.effort_gauge.get() > 0.0);
+    }
+
+    #[test]
+    fn config_jail_threshold() {
+        let logger = Logger::root(Discard, o!());
+        let config = LoadManagerConfig {
+            load_threshold: Duration::from_millis(1),
+            jail_threshold: Some(0.01),
+            extra_query_permits: 0,
+            semaphore_mode: SemaphoreMode::Fair,
+            max_depth: None,
+        };
+        let manager = LoadManager::with_config(
+            &logger,
+            vec![],
+            Arc::new(MockMetricsRegistry::new()),
+            10,
+            config,
+        );
+        let wait_stats: PoolWaitStats = Default::default();
+
+        manager.record_work(1, Duration::from_millis(100), CacheStatus::Miss);
+        manager.record_work(2, Duration::from_millis(100), CacheStatus::Miss);
+        assert_eq!(
+            (Decision::Proceed, DecisionReason::NotOverloaded),
+            manager.decide_with_reason(&wait_stats, None, None, 1, "query")
+        );
+
+        // Once we are overloaded, both queries cause far more than the tiny
+        // jail threshold of the effort
+        manager.simulate_overload(Duration::from_millis(50));
+        assert_eq!(
+            (
+                Decision::TooExpensive(ExpensiveReason::Jailed),
+                DecisionReason::Jailed
+            ),
+            manager.decide_with_reason(&wait_stats, None, None, 1, "query")
+        );
+        assert!(manager.jailed_queries.read().unwrap().contains(&1));
+        assert!(!manager.jailed_queries.read().unwrap().contains(&2));
+
+        // Without a jail threshold, nothing gets jailed
+        let config = LoadManagerConfig {
+            jail_threshold: None,
+            ..manager.config.clone()
+        };
+        let manager = LoadManager::with_config(
+            &logger,
+            vec![],
+            Arc::new(MockMetricsRegistry::new()),
+            10,
+            config,
+        );
+        manager.record_work(1, Duration::from_millis(100), CacheStatus::Miss);
+        manager.simulate_overload(Duration::from_millis(50));
+        let (_, reason) = manager.decide_with_reason(&wait_stats, None, None, 1, "query");
+        assert_eq!(DecisionReason::Overload, reason);
+    }
+
+    #[test]
+    fn semaphore_wait_percentile() {
+        let manager = load_manager();
+        assert_eq!(0.0, manager.semaphore_wait_p99_gauge.get());
+
+        for _ in 0..20 {
+            manager.add_wait_time(Duration::from_millis(1));
+        }
+        let p99 = manager.semaphore_wait_p99_gauge.get();
+        assert!(p99 > 0.0 && p99 < 2.0);
+
+        // All samples land in the same bin; one slow wait among many fast
+        // ones still raises the percentile far above the average
+        manager.add_wait_time(Duration::from_millis(1000));
+        let p99 = manager.semaphore_wait_p99_gauge.get();
+        assert!(p99 > 500.0);
+        assert!(p99 > 10.0 * manager.semaphore_wait_gauge.get());
+    }
+
+    #[test]
+    fn throttled_query_span() {
+        use std::fmt;
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        type Fields = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+        struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+        impl Visit for FieldVisitor<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().to_owned(), value.to_owned());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_owned(), format!("{:?}", value));
+            }
+        }
+
+        /// Remembers the fields of every span that is created
+        struct SpanRecorder(Fields);
+
+        impl Subscriber for SpanRecorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.0.lock().unwrap();
+                let mut fields = HashMap::new();
+                span.record(&mut FieldVisitor(&mut fields));
+                spans.push(fields);
+                Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.0.lock().unwrap();
+                let fields = &mut spans[span.into_u64() as usize - 1];
+                values.record(&mut FieldVisitor(fields));
+            }
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, _event: &Event<'_>) {}
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let spans = Fields::default();
+        let manager = load_manager();
+        tracing::subscriber::with_default(SpanRecorder(spans.clone()), || {
+            manager.jail(7, false);
+            let span = manager.query_span(7, Decision::Throttle(ThrottleReason::Overloaded));
+            let _guard = span.enter();
+            manager.record_traced_work(7, Duration::from_millis(25), CacheStatus::Miss);
+        });
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(1, spans.len());
+        let span = &spans[0];
+        assert_eq!("throttle", span["decision"]);
+        assert_eq!("7", span["shape_hash"]);
+        assert_eq!("true", span["jailed"]);
+        assert_eq!("25", span["effort_ms"]);
+        assert_eq!("miss", span["cache_status"]);
+    }
+
+    #[test]
+    fn clock_goes_backwards() {
+        use KillStateLogEvent::*;
+
+        let manager = load_manager();
+        let wait_stats: PoolWaitStats = Default::default();
+        let step = |now: Instant, wait: Duration| {
+            manager.simulate_overload(wait);
+            let (overloaded, wait_ms) = manager.overloaded(&wait_stats, None);
+            let (kill_rate, last_update) = manager.kill_state();
+            manager.update_kill_rate_at(
+                &manager.kill_state,
+                now,
+                kill_rate,
+                last_update,
+                overloaded,
+                wait_ms,
+            )
+        };
+
+        let overload = Duration::from_secs(10);
+        let calm = ZERO_DURATION;
+        let start = Instant::now() + Duration::from_secs(1);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(Start, step(at(1), overload).1);
+        let (kill_rate, _) = step(at(3), overload);
+        assert!((kill_rate - 0.19).abs() < 0.0001);
+
+        // The clock jumps back to before the last update
+        assert_eq!((kill_rate, Skip), step(start, calm));
+        assert_eq!(start, manager.kill_state().1);
+
+        // Updates resume once the update interval has passed, counted
+        // from where the clock jumped to
+        let (kill_rate, event) = step(at(2), calm);
+        assert_eq!(0.0, kill_rate);
+        assert_eq!(Resolved(Duration::from_secs(2)), event);
+
+        // Effort statistics accept measurements from the past
+        let mut inner = QueryEffortInner::new(
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            Some(Duration::from_secs(60)),
+        );
+        inner.add_at(at(5), 1, Duration::from_millis(10));
+        inner.add_at(at(1), 1, Duration::from_millis(20));
+        let (effort, total) = inner.effort_at(at(1), 1);
+        assert_eq!(Some(total), effort);
+        assert_eq!(2, inner.slowest_at(at(1), 10).len());
+    }
+
+    #[test]
+    fn overload_lifecycle() {
+        use KillStateLogEvent::*;
+
+        let manager = load_manager();
+        let wait_stats: PoolWaitStats = Default::default();
+        let step = |now: Instant, wait: Duration| {
+            manager.simulate_overload(wait);
+            let (overloaded, wait_ms) = manager.overloaded(&wait_stats, None);
+            let (kill_rate, last_update) = manager.kill_state();
+            manager.update_kill_rate_at(
+                &manager.kill_state,
+                now,
+                kill_rate,
+                last_update,
+                overloaded,
+                wait_ms,
+            )
+        };
+
+        let overload = Duration::from_secs(10);
+        let calm = ZERO_DURATION;
+        let start = Instant::now() + Duration::from_secs(1);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let (kill_rate, event) = step(at(1), overload);
+        assert_eq!(Start, event);
+        assert!((kill_rate - 0.1).abs() < 0.0001);
+
+        // Updates happen at most once per second
+        assert_eq!((kill_rate, Skip), step(at(1), overload));
+
+        // Overload keeps going, but we only log about it every 30s
+        let (_, event) = step(at(3), overload);
+        assert_eq!(Skip, event);
+        let (kill_rate, event) = step(at(41), overload);
+        assert_eq!(Ongoing(Duration::from_secs(40)), event);
+        assert!((kill_rate - 0.271).abs() < 0.0001);
+
+        let (kill_rate, event) = step(at(43), calm);
+        assert_eq!(Settling, event);
+        assert!((kill_rate - 0.071).abs() < 0.0001);
+
+        let (kill_rate, event) = step(at(45), calm);
+        assert_eq!(Resolved(Duration::from_secs(44)), event);
+        assert_eq!(0.0, kill_rate);
+        assert_eq!(0.0, manager.kill_state().0);
+    }
+
+    #[test]
+    fn network_thresholds() {
+        let thresholds = HashMap::from_iter(vec![
+            ("mainnet".to_owned(), Duration::from_millis(200)),
+            ("xdai".to_owned(), Duration::from_millis(20)),
+        ]);
+        let manager = load_manager().with_network_thresholds(thresholds);
+        let wait_stats: PoolWaitStats = Default::default();
+        let wait = Duration::from_millis(50);
+        manager.simulate_overload(wait);
+
+        assert_eq!(
+            (false, wait),
+            manager.overloaded(&wait_stats, Some("mainnet"))
+        );
+        assert_eq!((true, wait), manager.overloaded(&wait_stats, Some("xdai")));
+
+        // Other networks use the global threshold
+        let global = wait > manager.config.load_threshold;
+        assert_eq!(
+            (global, wait),
+            manager.overloaded(&wait_stats, Some("ropsten"))
+        );
+        assert_eq!((global, wait), manager.overloaded(&wait_stats, None));
+    }
+
+    #[test]
+    fn audit_records() {
+        use serde_json::json;
+
+        let decisions = [
+            Decision::Proceed,
+            Decision::TooExpensive(ExpensiveReason::Blocked),
+            Decision::Throttle(ThrottleReason::Overloaded),
+        ];
+        let reasons = [
+            (DecisionReason::Blocked, "blocked"),
+            (DecisionReason::Disabled, "disabled"),
+            (DecisionReason::Jailed, "jailed"),
+            (DecisionReason::NotOverloaded, "not_overloaded"),
+            (DecisionReason::NoEffort, "no_effort"),
+            (DecisionReason::Overload, "overload"),
+        ];
+        for decision in decisions.iter() {
+            for (reason, reason_str) in reasons.iter() {
+                let record = decision.to_audit_record(*reason, 7, Some(Duration::from_millis(25)));
+                assert_eq!(
+                    json!({
+                        "decision": decision.as_str(),
+                        "reason": reason_str,
+                        "shape_hash": 7,
+                        "effort_ms": 25,
+                    }),
+                    serde_json::to_value(&record).unwrap()
+                );
+            }
+        }
+
+        let record = Decision::Throttle(ThrottleReason::Overloaded).to_audit_record(
+            DecisionReason::Overload,
+            7,
+            None,
+        );
+        assert_eq!(
+            r#"{"decision":"throttle","reason":"overload","shape_hash":7,"effort_ms":null}"#,
+            serde_json::to_string(&record).unwrap()
+        );
+    }
+
+    #[test]
+    fn rising_load_trend() {
+        let manager = load_manager();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(LoadTrend::Stable, manager.load_trend());
+
+        // The wait time for the semaphore stays the same
+        manager.simulate_overload(Duration::from_millis(10));
+        for i in 0..TREND_BINS as u64 {
+            let effort = Duration::from_millis(100 + 50 * i);
+            manager
+                .effort
+                .inner
+                .write()
+                .unwrap()
+                .add_at(at(i), 1, effort);
+        }
+        assert_eq!(LoadTrend::Rising, manager.load_trend());
+        assert_eq!(1.0, manager.load_trend_gauge.get());
+
+        for i in 0..TREND_BINS as u64 {
+            let effort = Duration::from_millis(1000 - 90 * i);
+            let now = at(TREND_BINS as u64 + i);
+            manager.effort.inner.write().unwrap().add_at(now, 1, effort);
+        }
+        assert_eq!(LoadTrend::Falling, manager.load_trend());
+
+        assert_eq!(
+            LoadTrend::Stable,
+            LoadTrend::from_changes(&[Some(0.05), Some(-0.5)])
+        );
+        assert_eq!(
+            LoadTrend::Rising,
+            LoadTrend::from_changes(&[None, Some(0.5)])
+        );
+    }
+
+    #[test]
+    fn deployment_isolation() {
+        let hot = SubgraphDeploymentId::new("hot").unwrap();
+        let idle = SubgraphDeploymentId::new("idle").unwrap();
+        let new = SubgraphDeploymentId::new("new").unwrap();
+        let ms = Duration::from_millis;
+        let wait_stats: PoolWaitStats = Default::default();
+
+        let logger = Logger::root(Discard, o!());
+        let config = LoadManagerConfig {
+            load_threshold: ms(1),
+            jail_threshold: None,
+            extra_query_permits: 0,
+            semaphore_mode: SemaphoreMode::Fair,
+            max_depth: None,
+        };
+        let manager = LoadManager::with_config(
+            &logger,
+            vec![],
+            Arc::new(MockMetricsRegistry::new()),
+            10,
+            config,
+        )
+        .with_deployment_isolation();
+        // The work comes in through the `QueryLoadManager`
+        let load_manager: &dyn QueryLoadManager = &manager;
+        for _ in 0..100 {
+            load_manager.record_deployment_work(&hot, 1, ms(500), CacheStatus::Miss);
+        }
+        load_manager.record_deployment_work(&idle, 2, ms(5), CacheStatus::Miss);
+
+        // The share of the effort a query is weighed with when throttling
+        let share = |deployment, shape_hash| {
+            let tracked = manager.tracked_deployment(deployment);
+            let (_, query_effort, total_effort) = manager
+                .query_effort(tracked.as_deref(), shape_hash)
+                .unwrap();
+            query_effort / total_effort
+        };
+        assert!(share(Some(&idle), 3) < 0.001);
+        assert!(share(Some(&hot), 3) > 0.99);
+        assert!(share(Some(&idle), 2) < 0.001);
+        // We know nothing about `new`, and assume the worst
+        assert_eq!(1.0, share(Some(&new), 3));
+
+        // Overload caused by `hot` throttles queries for `hot`, but not
+        // for `idle`, which neither counts as overloaded nor gets a kill
+        // rate
+        manager.simulate_overload(ms(50));
+        let decide = |deployment, shape_hash| {
+            manager
+                .decide_with_reason(&wait_stats, None, Some(deployment), shape_hash, "query")
+                .1
+        };
+        assert_eq!(DecisionReason::Overload, decide(&hot, 3));
+        assert_eq!(DecisionReason::NotOverloaded, decide(&idle, 3));
+        assert_eq!(DecisionReason::NotOverloaded, decide(&idle, 2));
+        let kill_rate = |deployment: &SubgraphDeploymentId| {
+            let tracked = manager.tracked_deployment(Some(deployment)).unwrap();
+            KillState::read(&tracked.kill_state).0
+        };
+        assert!(kill_rate(&hot) > 0.0);
+        assert_eq!(0.0, kill_rate(&idle));
+        assert_eq!(0.0, manager.kill_state().0);
+
+        // Queries against deployments we do not track use the kill rate
+        // for the whole node
+        assert_eq!(DecisionReason::Overload, decide(&new, 3));
+        assert!(manager.kill_state().0 > 0.0);
+
+        // Deployments that were idle for a whole window are forgotten
+        // the next time we start tracking a deployment
+        let later = Instant::now() + *WINDOW_SIZE + ms(1);
+        let efforts = manager.deployment_effort.as_ref().unwrap();
+        *efforts.read().unwrap()[&hot].last_work.lock().unwrap() = later;
+        assert!(manager.tracked_deployment_at(later, Some(&idle)).is_none());
+        assert!(manager.tracked_deployment_at(later, Some(&hot)).is_some());
+        assert_eq!(2, efforts.read().unwrap().len());
+        manager.add_deployment_effort_at(later, &new, 3, ms(5));
+        let mut tracked: Vec<_> = efforts.read().unwrap().keys().cloned().collect();
+        tracked.sort();
+        assert_eq!(vec![hot, new], tracked);
+    }
+
+    #[test]
+    fn blocked_vs_jailed() {
+        use graphql_parser::parse_query;
+
+        let blocked = Arc::new(parse_query("{ things { id } }").unwrap().into_static());
+        let blocked_hash = shape_hash(&blocked);
+        let logger = Logger::root(Discard, o!());
+        let manager = LoadManager::new(
+            &logger,
+            vec![blocked],
+            Arc::new(MockMetricsRegistry::new()),
+            10,
+        );
+        let wait_stats: PoolWaitStats = Default::default();
+        let decide = |shape_hash| {
+            manager
+                .decide_unless_disabled(&wait_stats, None, None, shape_hash, "query", false)
+                .0
+        };
+
+        assert_eq!(
+            Decision::TooExpensive(ExpensiveReason::Blocked),
+            manager.decide(&wait_stats, blocked_hash, "{ things { id } }")
+        );
+        assert_eq!(
+            Decision::TooExpensive(ExpensiveReason::Blocked),
+            decide(blocked_hash)
+        );
+
+        manager.jail(7, false);
+        assert_eq!(Decision::TooExpensive(ExpensiveReason::Jailed), decide(7));
+        assert_eq!(Decision::Proceed, decide(8));
+
+        // Both are reported as the same error
+        for reason in &[ExpensiveReason::Blocked, ExpensiveReason::Jailed] {
+            match Decision::TooExpensive(*reason).to_result() {
+                Err(QueryExecutionError::TooExpensive) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn set_max_concurrent() {
+        use tokio::time::timeout;
+
+        let manager = load_manager();
+        let max = *manager.max_concurrent_queries.lock().unwrap();
+        let wait = Duration::from_millis(50);
+
+        let mut permits = Vec::new();
+        for _ in 0..max {
+            permits.push(manager.query_permit().await);
+        }
+        assert!(timeout(wait, manager.query_permit()).await.is_err());
+
+        assert_eq!(max + 1, manager.set_max_concurrent(max + 1));
+        permits.push(
+            timeout(wait, manager.query_permit())
+                .await
+                .expect("the additional permit is available"),
+        );
+
+        // The limit can not go below the number of running queries
+        assert_eq!(max + 1, manager.set_max_concurrent(1));
+        permits.truncate(2);
+        assert_eq!(2, manager.set_max_concurrent(2));
+        drop(permits);
+        assert_eq!(1, manager.set_max_concurrent(1));
+        let _permit = manager.query_permit().await;
+        assert!(timeout(wait, manager.query_permit()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shared_semaphore() {
+        use std::sync::atomic::AtomicUsize;
+
+        const CAP: usize = 3;
+
+        let shared = Arc::new(tokio::sync::Semaphore::new(CAP));
+        let managers: Vec<_> = (0..2)
+            .map(|_| Arc::new(load_manager().with_shared_semaphore(shared.clone())))
+            .collect();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        let queries = (0..20).map(|i| {
+            let manager = managers[i % managers.len()].clone();
+            let running = running.clone();
+            let most_running = most_running.clone();
+            tokio::spawn(async move {
+                let _permit = manager.query_permit().await;
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::delay_for(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        for query in futures03::future::join_all(queries).await {
+            query.unwrap();
+        }
+
+        // Each manager on its own would allow more than `CAP` queries
+        assert!(*managers[0].max_concurrent_queries.lock().unwrap() > CAP);
+        assert_eq!(CAP, most_running.load(Ordering::SeqCst));
+        assert_eq!(CAP, shared.available_permits());
+    }
+
+    #[test]
+    fn blocklist_duplicates() {
+        let parse =
+            |query: &str| Arc::new(graphql_parser::parse_query(query).unwrap().into_static());
+        let blocklist = vec![
+            parse("{ things { id } }"),
+            parse("{ tokens(first: 10) { id } }"),
+            parse("{ stuff: things { id } }"),
+            parse("{ tokens(first: 1000) { id } }"),
+            parse("{ owners { id } }"),
+        ];
+
+        let report = LoadManager::analyze_blocklist(&blocklist);
+        assert_eq!(5, report.entries);
+        assert_eq!(3, report.distinct);
+        let duplicates: Vec<_> = report
+            .duplicates
+            .iter()
+            .map(|duplicate| duplicate.entries.clone())
+            .collect();
+        assert_eq!(vec![vec![0, 2], vec![1, 3]], duplicates);
+        assert_eq!(shape_hash(&blocklist[0]), report.duplicates[0].shape_hash);
+
+        let report = LoadManager::analyze_blocklist(&blocklist[3..]);
+        assert_eq!((2, 2), (report.entries, report.distinct));
+        assert!(report.duplicates.is_empty());
+
+        // Creating a load manager reports the duplicates
+        let (logger, logged) = crate::log::Capture::logger();
+        LoadManager::new(&logger, blocklist, Arc::new(MockMetricsRegistry::new()), 10);
+        let logged = |msg: &str| logged.lock().unwrap().iter().any(|logged| logged == msg);
+        assert!(logged("Blocked queries [0, 2] all block the same query"));
+        assert!(logged("Blocked queries [1, 3] all block the same query"));
+        assert!(logged(
+            "The 5 blocked queries only block 3 different queries"
+        ));
+    }
+
+    #[test]
+    fn sampled_decision_events() {
+        use futures::Future as _;
+
+        fn events(sample_rate: f64) -> Vec<DecisionEvent> {
+            let mut manager = load_manager().with_decision_events(sample_rate);
+            let stream = manager.take_event_stream().unwrap();
+            assert!(manager.take_event_stream().is_none());
+
+            let wait_stats: PoolWaitStats = Default::default();
+            for shape_hash in 0..10 {
+                manager.decide_with_reason(&wait_stats, None, None, shape_hash, "query");
+            }
+            // Dropping the manager ends the stream
+            drop(manager);
+            stream.collect().wait().unwrap()
+        }
+
+        let all = events(1.0);
+        assert_eq!(10, all.len());
+        for (shape_hash, event) in all.iter().enumerate() {
+            assert_eq!(Decision::Proceed, event.decision);
+            assert_eq!(shape_hash as u64, event.shape_hash);
+            assert_eq!(None, event.effort_ms);
+        }
+        assert!(events(0.0).is_empty());
+        assert!(load_manager().take_event_stream().is_none());
+    }
+
+    #[test]
+    fn query_depth_ceiling() {
+        use graphql_parser::parse_query;
+
+        let logger = Logger::root(Discard, o!());
+        let config = LoadManagerConfig {
+            max_depth: Some(2),
+            ..LoadManagerConfig::default()
+        };
+        let manager = LoadManager::with_config(
+            &logger,
+            vec![],
+            Arc::new(MockMetricsRegistry::new()),
+            10,
+            config,
+        );
+        let wait_stats: PoolWaitStats = Default::default();
+        let decide = |query: &str| {
+            let query = Query::new(parse_query(query).unwrap().into_static(), None);
+            manager.decide_for_query(&wait_stats, None, None, &query, None)
+        };
+
+        assert!(decide("{ a { b } }").is_ok());
+        match decide("{ a { ...f } } fragment f on A { b { c } }") {
+            Err(QueryExecutionError::TooDeep(2)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decision_events_are_bounded() {
+        use futures::Future as _;
+
+        let wait_stats: PoolWaitStats = Default::default();
+        let mut manager = load_manager().with_decision_events(1.0);
+        // Nothing is buffered before the stream is taken
+        manager.decide_with_reason(&wait_stats, None, None, 1, "query");
+        let stream = manager.take_event_stream().unwrap();
+
+        // A consumer that falls behind loses events instead of making us
+        // buffer all of them
+        for shape_hash in 0..2 * DECISION_EVENT_BUFFER as u64 {
+            manager.decide_with_reason(&wait_stats, None, None, shape_hash, "query");
+        }
+        drop(manager);
+        let events = stream.collect().wait().unwrap();
+        assert!(events.len() >= DECISION_EVENT_BUFFER);
+        assert!(events.len() <= DECISION_EVENT_BUFFER + 1);
+        assert_eq!(0, events[0].shape_hash);
+    }
+
+    #[test]
+    fn unfair_semaphore_under_saturation() {
+        use futures03::task::{noop_waker_ref, Context, Poll};
+
+        // The order in which queries that queue up behind one running query
+        // get to run. Three queries are waiting at first; while we are
+        // saturated, a new query arrives every time one finishes, and
+        // after that, the queue drains
+        fn served(semaphore_mode: SemaphoreMode) -> Vec<usize> {
+            const WAITING: usize = 3;
+            const ARRIVALS: usize = 7;
+
+            let logger = Logger::root(Discard, o!());
+            let config = LoadManagerConfig {
+                semaphore_mode,
+                ..LoadManagerConfig::default()
+            };
+            let manager = LoadManager::with_config(
+                &logger,
+                vec![],
+                Arc::new(MockMetricsRegistry::new()),
+                0,
+                config,
+            );
+            assert_eq!(1, manager.set_max_concurrent(1));
+
+            let mut cx = Context::from_waker(noop_waker_ref());
+            let mut running = match manager.query_permit().as_mut().poll(&mut cx) {
+                Poll::Ready(permit) => permit,
+                Poll::Pending => panic!("the first query has to run right away"),
+            };
+            let mut waiting = Vec::new();
+            let mut served = Vec::new();
+            for round in 0..ARRIVALS + WAITING {
+                if round < ARRIVALS {
+                    let mut query = manager.query_permit();
+                    assert!(query.as_mut().poll(&mut cx).is_pending());
+                    waiting.push((round, query));
+                }
+                if round < WAITING {
+                    continue;
+                }
+
+                // The running query finishes, and exactly one of the
+                // waiting queries gets its permit
+                drop(running);
+                let mut ready = Vec::new();
+                for (pos, (query_id, query)) in waiting.iter_mut().enumerate() {
+                    if let Poll::Ready(permit) = query.as_mut().poll(&mut cx) {
+                        ready.push((pos, *query_id, permit));
+                    }
+                }
+                assert_eq!(1, ready.len());
+                let (pos, query_id, permit) = ready.pop().unwrap();
+                waiting.remove(pos);
+                served.push(query_id);
+                running = permit;
+            }
+            served
+        }
+
+        // With a fair semaphore, every query waits for all the queries
+        // that arrived before it; with an unfair one, the newest query
+        // runs next, and the oldest ones wait until the load subsides
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6], served(SemaphoreMode::Fair));
+        assert_eq!(vec![3, 4, 5, 6, 2, 1, 0], served(SemaphoreMode::Unfair));
     }
 }
@@ -1,4 +1,4 @@
-use super::ObjectOrInterface;
+use super::{ObjectOrInterface, TryFromValue};
 use crate::data::schema::{META_FIELD_TYPE, SCHEMA_TYPE_NAME};
 use crate::prelude::s::{
     Definition, Directive, Document, EnumType, Field, InterfaceType, ObjectType, Type,
@@ -184,6 +184,12 @@ impl DocumentExt for Document {
 
 pub trait TypeExt {
     fn get_base_type(&self) -> &String;
+
+    /// Whether this is a list type, possibly wrapped in a non-null type
+    fn is_list(&self) -> bool;
+
+    /// Whether the outermost wrapper is a non-null type
+    fn is_non_null(&self) -> bool;
 }
 
 impl TypeExt for Type {
@@ -194,10 +200,33 @@ impl TypeExt for Type {
             Type::ListType(inner) => Self::get_base_type(&inner),
         }
     }
+
+    fn is_list(&self) -> bool {
+        match self {
+            Type::NamedType(_) => false,
+            Type::NonNullType(inner) => inner.is_list(),
+            Type::ListType(_) => true,
+        }
+    }
+
+    fn is_non_null(&self) -> bool {
+        match self {
+            Type::NonNullType(_) => true,
+            Type::NamedType(_) | Type::ListType(_) => false,
+        }
+    }
 }
 
 pub trait DirectiveExt {
     fn argument(&self, name: &str) -> Option<&Value>;
+
+    /// The argument `name` if it is present and a string
+    fn string_argument(&self, name: &str) -> Option<&str> {
+        match self.argument(name) {
+            Some(Value::String(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
 }
 
 impl DirectiveExt for Directive {
@@ -214,6 +243,19 @@ pub trait ValueExt {
     fn as_list(&self) -> Option<&Vec<Value>>;
     fn as_string(&self) -> Option<&String>;
     fn as_enum(&self) -> Option<&String>;
+
+    /// Follow `path` through nested values. Each element of `path` is
+    /// either the key of an object, or, when the value at that point is a
+    /// list, the index of a list element. For example, the path
+    /// `["chains", "0", "latestBlock", "number"]` leads to the number of the
+    /// latest block of the first chain in an indexing status. Returns `None`
+    /// if any element of the path does not exist
+    fn get_path(&self, path: &[&str]) -> Option<&Value>;
+
+    /// Follow `path` like `get_path` and convert the value found there. A
+    /// missing or `null` value results in `Ok(None)`, a value that can not
+    /// be converted in an error
+    fn get_path_as<T: TryFromValue>(&self, path: &[&str]) -> Result<Option<T>, anyhow::Error>;
 }
 
 impl ValueExt for Value {
@@ -244,6 +286,23 @@ impl ValueExt for Value {
             _ => None,
         }
     }
+
+    fn get_path(&self, path: &[&str]) -> Option<&Value> {
+        path.iter().try_fold(self, |value, key| match value {
+            Value::Object(object) => object.get(*key),
+            Value::List(list) => key.parse::<usize>().ok().and_then(|i| list.get(i)),
+            _ => None,
+        })
+    }
+
+    fn get_path_as<T: TryFromValue>(&self, path: &[&str]) -> Result<Option<T>, anyhow::Error> {
+        match self.get_path(path) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => T::try_from_value(value)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("invalid value at `{}`: {}", path.join("."), e)),
+        }
+    }
 }
 
 pub trait DirectiveFinder {
@@ -271,3 +330,103 @@ impl DirectiveFinder for Vec<Directive> {
         self.iter().find(|directive| directive.name.eq(&name))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object;
+
+    fn status() -> Value {
+        object! {
+            subgraph: "QmSubgraph",
+            chains: vec![object! {
+                network: "mainnet",
+                latestBlock: object! { number: "42", hash: "0x2a" },
+                earliestBlock: Value::Null,
+            }],
+        }
+    }
+
+    #[test]
+    fn get_path() {
+        let status = status();
+
+        assert_eq!(
+            Some(&Value::String("42".to_owned())),
+            status.get_path(&["chains", "0", "latestBlock", "number"])
+        );
+        assert_eq!(Some(&status), status.get_path(&[]));
+
+        assert_eq!(None, status.get_path(&["chains", "1", "latestBlock"]));
+        assert_eq!(None, status.get_path(&["chains", "first"]));
+        assert_eq!(None, status.get_path(&["subgraph", "id"]));
+        assert_eq!(None, status.get_path(&["nodes"]));
+    }
+
+    #[test]
+    fn wrapped_types() {
+        let named = || Type::NamedType("Foo".to_owned());
+        let non_null = |ty| Type::NonNullType(Box::new(ty));
+        let list = |ty| Type::ListType(Box::new(ty));
+
+        let cases = vec![
+            (named(), false, false),
+            (non_null(named()), false, true),
+            (list(named()), true, false),
+            (non_null(list(non_null(named()))), true, true),
+        ];
+        for (ty, is_list, is_non_null) in cases {
+            assert_eq!("Foo", ty.get_base_type(), "{}", ty);
+            assert_eq!(is_list, ty.is_list(), "{}", ty);
+            assert_eq!(is_non_null, ty.is_non_null(), "{}", ty);
+        }
+    }
+
+    #[test]
+    fn get_path_as() {
+        let status = status();
+
+        let number: Option<u64> = status
+            .get_path_as(&["chains", "0", "latestBlock", "number"])
+            .unwrap();
+        assert_eq!(Some(42), number);
+
+        let missing: Option<u64> = status
+            .get_path_as(&["chains", "0", "chainHeadBlock", "number"])
+            .unwrap();
+        assert_eq!(None, missing);
+        let null: Option<String> = status
+            .get_path_as(&["chains", "0", "earliestBlock"])
+            .unwrap();
+        assert_eq!(None, null);
+
+        let err = status
+            .get_path_as::<bool>(&["chains", "0", "network"])
+            .unwrap_err();
+        assert!(err.to_string().contains("chains.0.network"));
+    }
+
+    #[test]
+    fn directive_arguments() {
+        const SCHEMA: &str = "
+type Thing @entity @search(name: \"things\", language: en, limit: 10) { id: ID! }";
+        let schema = graphql_parser::parse_schema(SCHEMA).expect("Failed to parse schema");
+        let thing = schema.get_object_type_definitions()[0];
+        let search = thing.find_directive("search".to_owned()).unwrap();
+
+        assert_eq!(Some("things"), search.string_argument("name"));
+        assert_eq!(None, search.string_argument("language"));
+        assert_eq!(None, search.string_argument("limit"));
+        assert_eq!(None, search.string_argument("missing"));
+
+        assert_eq!(
+            Some(&Value::Enum("en".to_owned())),
+            search.argument("language")
+        );
+        assert_eq!(
+            Some(&Value::String("things".to_owned())),
+            search.argument("name")
+        );
+        assert_eq!(None, search.argument("missing"));
+    }
+}
@@ -5,14 +5,26 @@ pub use ext::{DirectiveExt, DocumentExt, ObjectTypeExt, TypeExt, ValueExt};
 
 mod values;
 
-pub use self::serialization::SerializableValue;
+pub use self::serialization::{SerializableValue, SerializableValueStream};
 
-pub use self::values::{TryFromValue, ValueList, ValueMap};
+pub use self::values::{
+    from_json_value_lossy, to_json_value_lossy, TryFromValue, ValueList, ValueMap,
+};
 
 pub mod shape_hash;
+pub use self::shape_hash::{shape_hash_str, QueryParseError};
+
+pub mod depth;
+pub use self::depth::{check_max_depth, max_depth};
 
 pub mod effort;
 
+pub mod plan_cache;
+pub use plan_cache::QueryPlanCache;
+
+pub mod referenced_types;
+pub use self::referenced_types::referenced_types;
+
 pub mod object_or_interface;
 pub use object_or_interface::ObjectOrInterface;
 
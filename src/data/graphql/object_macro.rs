@@ -8,6 +8,18 @@ pub fn object_value(data: Vec<(&str, q::Value)>) -> q::Value {
     ))
 }
 
+/// The fields of `value`, which must be an object. Used by the `..base`
+/// syntax of `object!`
+pub fn object_fields(value: q::Value) -> BTreeMap<String, q::Value> {
+    match value {
+        q::Value::Object(map) => map,
+        _ => panic!(
+            "object! can only copy fields from an object, not {:?}",
+            value
+        ),
+    }
+}
+
 pub trait IntoValue {
     fn into_value(self) -> q::Value;
 }
@@ -77,8 +89,21 @@ impl_into_values![
     (Number, Int)
 ];
 
+/// Build a `q::Value::Object` from a list of fields. Like with Rust's
+/// struct update syntax, `object! { name: value, ..base }` copies all
+/// fields from the object `base` that are not listed explicitly.
 #[macro_export]
 macro_rules! object {
+    ($($name:ident: $value:expr,)* ..$base:expr) => {
+        {
+            let mut result = $crate::data::graphql::object_macro::object_fields($base);
+            $(
+                let value = $crate::data::graphql::object_macro::IntoValue::into_value($value);
+                result.insert(stringify!($name).to_string(), value);
+            )*
+            ::graphql_parser::query::Value::Object(result)
+        }
+    };
     ($($name:ident: $value:expr,)*) => {
         {
             let mut result = ::std::collections::BTreeMap::new();
@@ -93,3 +118,38 @@ macro_rules! object {
         object! {$($name: $value,)*}
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_with_base() {
+        let base = object! {
+            __typename: "EthereumBlock",
+            hash: "0xabc",
+            number: "1",
+        };
+
+        let block = object! {
+            number: "2",
+            latest: true,
+            ..base.clone()
+        };
+        let expected = object! {
+            __typename: "EthereumBlock",
+            hash: "0xabc",
+            number: "2",
+            latest: true,
+        };
+        assert_eq!(expected, block);
+
+        assert_eq!(base, object! { ..base.clone() });
+    }
+
+    #[test]
+    #[should_panic]
+    fn object_with_non_object_base() {
+        object! { number: "2", ..q::Value::Null };
+    }
+}
@@ -2,7 +2,7 @@ use crate::prelude::Schema;
 use crate::{components::store::EntityType, prelude::s};
 use std::collections::BTreeMap;
 
-use super::ObjectTypeExt;
+use super::{DocumentExt, ObjectTypeExt};
 
 #[derive(Copy, Clone, Debug)]
 pub enum ObjectOrInterface<'a> {
@@ -67,8 +67,9 @@ impl<'a> ObjectOrInterface<'a> {
         }
     }
 
-    pub fn field(&self, name: &String) -> Option<&s::Field> {
-        self.fields().iter().find(|field| &field.name == name)
+    /// The field called `name`, whether `self` is an object or an interface
+    pub fn field(&self, name: &str) -> Option<&'a s::Field> {
+        self.fields().iter().find(|field| field.name == name)
     }
 
     pub fn object_types(self, schema: &'a Schema) -> Option<Vec<&'a s::ObjectType>> {
@@ -81,6 +82,15 @@ impl<'a> ObjectOrInterface<'a> {
         }
     }
 
+    /// The object types in `schema` that are instances of `self`: the object
+    /// type itself for an object, and all object types that implement `self`
+    /// for an interface. Since an object type must list every interface it
+    /// implements, including the ones implemented by its interfaces, this
+    /// also covers indirect implementations.
+    pub fn implementors(self, schema: &'a Schema) -> Vec<&'a s::ObjectType> {
+        self.object_types(schema).unwrap_or_default()
+    }
+
     /// `typename` is the name of an object type. Matches if `self` is an object and has the same
     /// name, or if self is an interface implemented by `typename`.
     pub fn matches(
@@ -103,3 +113,56 @@ impl<'a> ObjectOrInterface<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::SubgraphDeploymentId;
+
+    #[test]
+    fn implementors() {
+        const SCHEMA: &str = "
+interface Account { id: ID! }
+interface Named { name: String! }
+type Wallet implements Account @entity { id: ID! }
+type Vault implements Named & Account @entity { id: ID!, name: String! }
+type Token implements Named @entity { id: ID!, name: String! }
+type Block @entity { id: ID! }";
+
+        let schema = Schema::parse(SCHEMA, SubgraphDeploymentId::new("implementors").unwrap())
+            .expect("Failed to parse schema");
+        let names = |ooi: ObjectOrInterface| {
+            ooi.implementors(&schema)
+                .into_iter()
+                .map(|o| o.name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        let account = schema.document.object_or_interface("Account").unwrap();
+        assert_eq!(vec!["Wallet", "Vault"], names(account));
+        let named = schema.document.object_or_interface("Named").unwrap();
+        assert_eq!(vec!["Vault", "Token"], names(named));
+        let block = schema.document.object_or_interface("Block").unwrap();
+        assert_eq!(vec!["Block"], names(block));
+    }
+
+    #[test]
+    fn field() {
+        const SCHEMA: &str = "
+interface Account { id: ID!, balance: BigInt! }
+type Wallet implements Account @entity { id: ID!, balance: BigInt!, owner: String }";
+
+        let schema = graphql_parser::parse_schema(SCHEMA).expect("Failed to parse schema");
+
+        let wallet = schema.object_or_interface("Wallet").unwrap();
+        assert!(wallet.is_object());
+        assert_eq!("owner", wallet.field("owner").unwrap().name);
+        assert_eq!("balance", wallet.field("balance").unwrap().name);
+
+        let account = schema.object_or_interface("Account").unwrap();
+        assert!(account.is_interface());
+        assert_eq!("balance", account.field("balance").unwrap().name);
+        assert!(account.field("owner").is_none());
+        assert!(wallet.field("missing").is_none());
+    }
+}
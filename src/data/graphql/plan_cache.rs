@@ -0,0 +1,244 @@
+//! A cache for the validated and planned form of queries, so that queries
+//! that we have seen before do not need to be validated and planned again
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::shape_hash::shape_hash;
+use crate::components::metrics::{metric_or_noop, Counter, MetricsRegistry, NoopCounter};
+use crate::prelude::{q, Logger, SubgraphDeploymentId};
+
+/// The deployment and the `shape_hash` of the query
+type PlanKey = (SubgraphDeploymentId, u64);
+
+struct Plans<P> {
+    /// The plans for queries with the same key, together with the
+    /// normalized text of their query
+    plans: HashMap<PlanKey, Vec<(String, Arc<P>)>>,
+    // The keys and query texts of all plans, oldest first
+    order: VecDeque<(PlanKey, String)>,
+}
+
+impl<P> Plans<P> {
+    fn get(&self, key: &PlanKey, text: &str) -> Option<&Arc<P>> {
+        self.plans
+            .get(key)?
+            .iter()
+            .find(|(query, _)| query == text)
+            .map(|(_, plan)| plan)
+    }
+
+    fn remove(&mut self, key: &PlanKey, text: &str) {
+        if let Some(plans) = self.plans.get_mut(key) {
+            plans.retain(|(query, _)| query != text);
+            if plans.is_empty() {
+                self.plans.remove(key);
+            }
+        }
+    }
+}
+
+/// Holds up to `capacity` plans of type `P`, keyed by the deployment the
+/// query is for and the `shape_hash` of the query. Queries only share a plan
+/// if they also have the same normalized text, i.e., are the same apart from
+/// formatting and comments once they are printed after parsing; queries
+/// with the same `shape_hash` can still differ in their aliases and literal
+/// arguments, which changes the response and whether they are valid. The
+/// query is only printed to compare it to the queries that have the same
+/// `shape_hash`, if there are any. Plans must not depend on the values of
+/// variables. Once the cache is full, the oldest plan is evicted. When the
+/// schema of a deployment changes, its plans must be removed with
+/// `invalidate`.
+pub struct QueryPlanCache<P> {
+    capacity: usize,
+    plans: Mutex<Plans<P>>,
+    hits: Counter,
+    misses: Counter,
+}
+
+impl<P> QueryPlanCache<P> {
+    pub fn new(logger: &Logger, registry: Arc<dyn MetricsRegistry>, capacity: usize) -> Self {
+        let counter = |name: &str, help: &str| {
+            metric_or_noop(
+                logger,
+                name,
+                registry.global_counter(name, help, HashMap::new()),
+                || NoopCounter.into(),
+            )
+        };
+        Self {
+            capacity,
+            plans: Mutex::new(Plans {
+                plans: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            hits: counter(
+                "query_plan_cache_hits",
+                "Number of queries whose plan was found in the cache",
+            ),
+            misses: counter(
+                "query_plan_cache_misses",
+                "Number of queries that had to be validated and planned",
+            ),
+        }
+    }
+
+    /// Return the cached plan for `query` against `deployment`, or make
+    /// one with `plan` and cache it. Errors from `plan` are not cached.
+    pub fn get_or_plan<E>(
+        &self,
+        deployment: &SubgraphDeploymentId,
+        query: &q::Document,
+        plan: impl FnOnce() -> Result<P, E>,
+    ) -> Result<Arc<P>, E> {
+        let key = (deployment.clone(), shape_hash(query));
+        let mut text = None;
+        {
+            let plans = self.plans.lock().unwrap();
+            if plans.plans.contains_key(&key) {
+                let text = text.get_or_insert_with(|| query.to_string());
+                if let Some(plan) = plans.get(&key, text) {
+                    self.hits.inc();
+                    return Ok(plan.clone());
+                }
+            }
+        }
+
+        // Planning can take a while, and we do not want to block other
+        // queries while we plan
+        self.misses.inc();
+        let plan = Arc::new(plan()?);
+        let text = text.unwrap_or_else(|| query.to_string());
+
+        let mut plans = self.plans.lock().unwrap();
+        if plans.get(&key, &text).is_none() {
+            plans
+                .plans
+                .entry(key.clone())
+                .or_default()
+                .push((text.clone(), plan.clone()));
+            plans.order.push_back((key, text));
+        }
+        while plans.order.len() > self.capacity {
+            if let Some((key, text)) = plans.order.pop_front() {
+                plans.remove(&key, &text);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Remove all plans for `deployment`
+    pub fn invalidate(&self, deployment: &SubgraphDeploymentId) {
+        let mut plans = self.plans.lock().unwrap();
+        plans.plans.retain(|(id, _), _| id != deployment);
+        plans.order.retain(|((id, _), _)| id != deployment);
+    }
+
+    pub fn len(&self) -> usize {
+        self.plans.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.get() as u64
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.get() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::metrics::MockMetricsRegistry;
+    use graphql_parser::parse_query;
+    use slog::{o, Discard};
+    use std::cell::Cell;
+
+    fn query(text: &str) -> q::Document {
+        parse_query(text).unwrap().into_static()
+    }
+
+    #[test]
+    fn plans_are_reused() {
+        let logger = Logger::root(Discard, o!());
+        let cache = QueryPlanCache::new(&logger, Arc::new(MockMetricsRegistry::new()), 2);
+        let deployment = SubgraphDeploymentId::new("plans").unwrap();
+        let other = SubgraphDeploymentId::new("other").unwrap();
+        let one = query("{ things { id } }");
+        let two = query("{ things(first: 10) { id } }");
+        let three = query("{ others { id } }");
+
+        let validated = Cell::new(0);
+        let validate = |name: &str| {
+            validated.set(validated.get() + 1);
+            Ok::<_, ()>(format!("plan for {}", name))
+        };
+
+        let first = cache
+            .get_or_plan(&deployment, &one, || validate("one"))
+            .unwrap();
+        let second = cache
+            .get_or_plan(&deployment, &one, || validate("one"))
+            .unwrap();
+        assert_eq!("plan for one", *first);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(1, validated.get());
+        assert_eq!((1, 1), (cache.hits(), cache.misses()));
+
+        // Plans are kept per deployment, and errors are not cached
+        cache.get_or_plan(&other, &one, || validate("one")).unwrap();
+        assert_eq!(2, validated.get());
+        assert!(cache.get_or_plan(&other, &two, || Err(())).is_err());
+        assert_eq!(2, cache.len());
+
+        // A schema change for `deployment` only removes its plans
+        cache.invalidate(&deployment);
+        assert_eq!(1, cache.len());
+        cache
+            .get_or_plan(&deployment, &one, || validate("one"))
+            .unwrap();
+        assert_eq!(3, validated.get());
+
+        // The oldest plan is evicted once the cache is full
+        cache
+            .get_or_plan(&deployment, &three, || validate("three"))
+            .unwrap();
+        assert_eq!(2, cache.len());
+        cache.get_or_plan(&other, &one, || validate("one")).unwrap();
+        assert_eq!(5, validated.get());
+        assert_eq!((1, 6), (cache.hits(), cache.misses()));
+    }
+
+    #[test]
+    fn plans_are_keyed_by_query_text() {
+        let logger = Logger::root(Discard, o!());
+        let cache = QueryPlanCache::new(&logger, Arc::new(MockMetricsRegistry::new()), 10);
+        let deployment = SubgraphDeploymentId::new("plans").unwrap();
+        let plan_for = |text: &str, name: &'static str| {
+            *cache
+                .get_or_plan(&deployment, &query(text), || Ok::<_, ()>(name))
+                .unwrap()
+        };
+
+        assert_eq!("first", plan_for("{ things(first: 10) { id } }", "first"));
+
+        // Formatting and comments do not matter
+        let reformatted = "# all things\n{\n  things(first: 10) {\n    id\n  }\n}";
+        assert_eq!("first", plan_for(reformatted, "reformatted"));
+
+        // Queries with the same shape, but different literals or aliases,
+        // get their own plans
+        let literal = "{ things(first: -1) { id } }";
+        let alias = "{ stuff: things(first: 10) { id } }";
+        let field_alias = "{ things(first: 10) { key: id } }";
+        assert_eq!("literal", plan_for(literal, "literal"));
+        assert_eq!("alias", plan_for(alias, "alias"));
+        assert_eq!("field alias", plan_for(field_alias, "field alias"));
+        assert_eq!(4, cache.len());
+    }
+}
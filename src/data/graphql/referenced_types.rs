@@ -0,0 +1,210 @@
+//! The entity types that a query reads, e.g., so that cached results only
+//! need to be invalidated when the data of one of these types changes
+
+use std::collections::HashSet;
+
+use super::shape_hash::{select_operation, selection_set};
+use super::{DocumentExt, ObjectOrInterface, TypeExt};
+use crate::prelude::{q, Schema};
+
+/// The names of all entity types that the operation in `query` that will be
+/// executed reads according to `schema`, an API schema. Fields that return
+/// an interface read all object types that implement it; fragments add the
+/// entity types the fields in them read. Fields that `schema` does not know
+/// and the `_meta` field are ignored. The operation is selected as for
+/// `shape_hash_for_operation`, and if no operation can be selected, these
+/// are the types that any operation in `query` reads.
+pub fn referenced_types(
+    query: &q::Document,
+    operation_name: Option<&str>,
+    schema: &Schema,
+) -> HashSet<String> {
+    let operations: Vec<_> = match select_operation(query, operation_name) {
+        Some(operation) => vec![operation],
+        None => query
+            .definitions
+            .iter()
+            .filter_map(|defn| match defn {
+                q::Definition::Operation(op) => Some(op),
+                q::Definition::Fragment(_) => None,
+            })
+            .collect(),
+    };
+
+    let mut collector = Collector {
+        query,
+        schema,
+        types: HashSet::new(),
+        expanded: HashSet::new(),
+    };
+    for operation in operations {
+        use graphql_parser::query::OperationDefinition::*;
+        let root = match operation {
+            SelectionSet(_) | Query(_) => schema.document.get_root_query_type(),
+            Subscription(_) => schema.document.get_root_subscription_type(),
+            Mutation(_) => None,
+        };
+        if let Some(root) = root {
+            collector.collect(root.into(), selection_set(operation));
+        }
+    }
+    collector.types
+}
+
+struct Collector<'a> {
+    query: &'a q::Document,
+    schema: &'a Schema,
+    types: HashSet<String>,
+    // The fragments, and the types they were evaluated against, that we
+    // expanded already. Expanding them again can not add any types, and
+    // remembering them keeps fragments that spread themselves, which
+    // validation rejects, from making us recurse forever, and fragments
+    // that spread the next fragment several times from taking time that is
+    // exponential in the length of such a chain
+    expanded: HashSet<(&'a str, &'a str)>,
+}
+
+impl<'a> Collector<'a> {
+    /// Collect the types that the fields in `set` read, where `set` is
+    /// evaluated against `parent`
+    fn collect(&mut self, parent: ObjectOrInterface<'a>, set: &'a q::SelectionSet) {
+        for item in &set.items {
+            match item {
+                q::Selection::Field(field) => {
+                    let field_type = parent.field(&field.name).and_then(|field| {
+                        let name = field.field_type.get_base_type();
+                        self.schema.document.object_or_interface(name)
+                    });
+                    let field_type = match field_type {
+                        Some(field_type) if !field_type.is_meta() => field_type,
+                        _ => continue,
+                    };
+                    for object in field_type.implementors(self.schema) {
+                        self.types.insert(object.name.clone());
+                    }
+                    self.collect(field_type, &field.selection_set);
+                }
+                q::Selection::InlineFragment(frag) => {
+                    let parent = self.narrow(parent, frag.type_condition.as_ref());
+                    self.collect(parent, &frag.selection_set);
+                }
+                q::Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name.as_str();
+                    let defn = self.query.definitions.iter().find_map(|defn| match defn {
+                        q::Definition::Fragment(frag) if frag.name == name => Some(frag),
+                        _ => None,
+                    });
+                    if let Some(defn) = defn {
+                        if self.expanded.insert((name, parent.name())) {
+                            let parent = self.narrow(parent, Some(&defn.type_condition));
+                            self.collect(parent, &defn.selection_set);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The type that the fields in a fragment with `type_condition` are
+    /// evaluated against inside `parent`
+    fn narrow(
+        &self,
+        parent: ObjectOrInterface<'a>,
+        type_condition: Option<&q::TypeCondition>,
+    ) -> ObjectOrInterface<'a> {
+        match type_condition {
+            Some(q::TypeCondition::On(name)) => self
+                .schema
+                .document
+                .object_or_interface(name)
+                .unwrap_or(parent),
+            None => parent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::SubgraphDeploymentId;
+    use graphql_parser::parse_query;
+
+    const SCHEMA: &str = "
+type Query {
+  owners: [Owner!]!
+  pets: [Pet!]!
+  token(id: ID!): Token
+  _meta: _Meta_
+}
+interface Pet { id: ID!, owner: Owner! }
+type Cat implements Pet @entity { id: ID!, owner: Owner! }
+type Dog implements Pet @entity { id: ID!, owner: Owner!, toy: Toy }
+type Owner @entity { id: ID!, pets: [Pet!]!, wallet: Wallet }
+type Toy @entity { id: ID! }
+type Wallet @entity { id: ID! }
+type Token @entity { id: ID! }
+type _Meta_ { block: _Block_! }
+type _Block_ { number: Int! }";
+
+    fn types(query: &str) -> Vec<String> {
+        let schema = Schema::parse(SCHEMA, SubgraphDeploymentId::new("types").unwrap()).unwrap();
+        let query = parse_query(query).unwrap().into_static();
+        let mut types: Vec<_> = referenced_types(&query, None, &schema)
+            .into_iter()
+            .collect();
+        types.sort();
+        types
+    }
+
+    #[test]
+    fn multiple_types() {
+        assert_eq!(
+            vec!["Owner", "Token"],
+            types("{ owners { id } token(id: \"1\") { id } }")
+        );
+        assert_eq!(
+            vec!["Owner", "Wallet"],
+            types("{ owners { wallet { id } } }")
+        );
+        assert_eq!(
+            vec!["Token"],
+            types("{ token(id: \"1\") { id } _meta { block { number } } }")
+        );
+    }
+
+    #[test]
+    fn interface_fields() {
+        assert_eq!(vec!["Cat", "Dog"], types("{ pets { id } }"));
+        assert_eq!(
+            vec!["Cat", "Dog", "Owner"],
+            types("{ owners { pets { id } } }")
+        );
+
+        // Fields of one implementation are only found through fragments
+        let query = "
+            { pets { id ... on Dog { toy { id } } ...owner } }
+            fragment owner on Pet { owner { wallet { id } } }";
+        assert_eq!(vec!["Cat", "Dog", "Owner", "Toy", "Wallet"], types(query));
+    }
+
+    #[test]
+    fn fragments_spread_repeatedly() {
+        // Each fragment spreads the next one twice; expanding them anew
+        // every time would take 2^40 steps
+        let mut query = "{ owners { ...f0 } }".to_owned();
+        for i in 0..40 {
+            query.push_str(&format!(
+                " fragment f{} on Owner {{ id ...f{} ...f{} }}",
+                i,
+                i + 1,
+                i + 1
+            ));
+        }
+        query.push_str(" fragment f40 on Owner { wallet { id } }");
+        assert_eq!(vec!["Owner", "Wallet"], types(&query));
+
+        // Fragments that spread themselves do not make us recurse forever
+        let query = "{ owners { ...f } } fragment f on Owner { wallet { id } ...f }";
+        assert_eq!(vec!["Owner", "Wallet"], types(query));
+    }
+}
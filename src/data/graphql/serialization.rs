@@ -3,6 +3,16 @@ use serde::ser::*;
 
 pub struct SerializableValue<'a>(pub &'a Value);
 
+impl SerializableValue<'_> {
+    /// Render the value as indented JSON. The keys of objects are always
+    /// in sorted order since objects are kept in a `BTreeMap`, so that
+    /// the same value always renders the same way, which makes the output
+    /// suitable for comparisons in tests and for logs.
+    pub fn to_pretty_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("serializing a value to a string can not fail")
+    }
+}
+
 impl<'a> Serialize for SerializableValue<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -33,3 +43,88 @@ impl<'a> Serialize for SerializableValue<'a> {
         }
     }
 }
+
+/// Writes values to `writer` as a JSON array one at a time, so that a large
+/// result never needs to be held in memory in its entirety. The array is
+/// only complete once `finish` has been called.
+pub struct SerializableValueStream<W: std::io::Write> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: std::io::Write> SerializableValueStream<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, count: 0 }
+    }
+
+    /// Append `value` to the array
+    pub fn push(&mut self, value: &Value) -> Result<(), serde_json::Error> {
+        let sep: &[u8] = if self.count == 0 { b"[" } else { b"," };
+        self.writer.write_all(sep).map_err(serde_json::Error::io)?;
+        serde_json::to_writer(&mut self.writer, &SerializableValue(value))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The number of values written so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Close the array and return the underlying writer
+    pub fn finish(mut self) -> Result<W, serde_json::Error> {
+        let end: &[u8] = if self.count == 0 { b"[]" } else { b"]" };
+        self.writer.write_all(end).map_err(serde_json::Error::io)?;
+        self.writer.flush().map_err(serde_json::Error::io)?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object;
+
+    #[test]
+    fn stream_values() {
+        let empty = SerializableValueStream::new(Vec::new()).finish().unwrap();
+        assert_eq!(b"[]".to_vec(), empty);
+
+        let mut stream = SerializableValueStream::new(Vec::new());
+        for i in 0..10_000 {
+            let value = object! { id: format!("0x{:x}", i), count: i, even: i % 2 == 0 };
+            stream.push(&value).unwrap();
+        }
+        assert_eq!(10_000, stream.count());
+        let bytes = stream.finish().unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let list = json.as_array().unwrap();
+        assert_eq!(10_000, list.len());
+        for (i, item) in list.iter().enumerate() {
+            assert_eq!(Some(format!("0x{:x}", i).as_str()), item["id"].as_str());
+            assert_eq!(Some(i as u64), item["count"].as_u64());
+            assert_eq!(Some(i % 2 == 0), item["even"].as_bool());
+        }
+    }
+
+    #[test]
+    fn pretty_string() {
+        let value = object! {
+            name: "thing",
+            id: 1,
+            tags: vec!["a", "b"],
+            parent: Value::Null,
+        };
+        let expected = r#"{
+  "id": 1,
+  "name": "thing",
+  "parent": null,
+  "tags": [
+    "a",
+    "b"
+  ]
+}"#;
+        assert_eq!(expected, SerializableValue(&value).to_pretty_string());
+    }
+}
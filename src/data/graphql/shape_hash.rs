@@ -1,5 +1,7 @@
 use crate::prelude::{q, s};
+pub use graphql_parser::query::ParseError as QueryParseError;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 
 type ShapeHasher = DefaultHasher;
@@ -14,6 +16,108 @@ pub fn shape_hash(query: &q::Document) -> u64 {
     hasher.finish()
 }
 
+/// The `shape_hash` of just the operation in `query` that will be executed,
+/// together with the fragments it uses. As for execution, the operation is
+/// selected by `operation_name`, and if that is `None`, `query` must
+/// contain exactly one operation. If no operation can be selected, this is
+/// the `shape_hash` of the entire document.
+pub fn shape_hash_for_operation(query: &q::Document, operation_name: Option<&str>) -> u64 {
+    let operation = match select_operation(query, operation_name) {
+        Some(operation) => operation,
+        None => return shape_hash(query),
+    };
+
+    let mut fragments = HashSet::new();
+    used_fragments(query, selection_set(operation), &mut fragments);
+
+    let mut hasher = DefaultHasher::new();
+    operation.shape_hash(&mut hasher);
+    for defn in &query.definitions {
+        if let q::Definition::Fragment(frag) = defn {
+            if fragments.contains(frag.name.as_str()) {
+                frag.shape_hash(&mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// The operation in `query` that will be executed. As for execution, the
+/// operation is selected by `operation_name`, and if that is `None`,
+/// `query` must contain exactly one operation.
+pub(crate) fn select_operation<'a>(
+    query: &'a q::Document,
+    operation_name: Option<&str>,
+) -> Option<&'a q::OperationDefinition> {
+    fn name(op: &q::OperationDefinition) -> Option<&str> {
+        use graphql_parser::query::OperationDefinition::*;
+        match op {
+            SelectionSet(_) => None,
+            Query(query) => query.name.as_deref(),
+            Mutation(mutation) => mutation.name.as_deref(),
+            Subscription(subscription) => subscription.name.as_deref(),
+        }
+    }
+
+    let mut operations = query.definitions.iter().filter_map(|defn| match defn {
+        q::Definition::Operation(op) => Some(op),
+        q::Definition::Fragment(_) => None,
+    });
+    match operation_name {
+        Some(operation_name) => operations.find(|op| name(op) == Some(operation_name)),
+        None => match (operations.next(), operations.next()) {
+            (Some(op), None) => Some(op),
+            _ => None,
+        },
+    }
+}
+
+pub(crate) fn selection_set(op: &q::OperationDefinition) -> &q::SelectionSet {
+    use graphql_parser::query::OperationDefinition::*;
+    match op {
+        SelectionSet(set) => set,
+        Query(query) => &query.selection_set,
+        Mutation(mutation) => &mutation.selection_set,
+        Subscription(subscription) => &subscription.selection_set,
+    }
+}
+
+/// Add the names of all fragments that `set` uses, directly or through
+/// other fragments, to `fragments`
+fn used_fragments<'a>(
+    query: &'a q::Document,
+    set: &'a q::SelectionSet,
+    fragments: &mut HashSet<&'a str>,
+) {
+    for item in &set.items {
+        match item {
+            q::Selection::Field(field) => used_fragments(query, &field.selection_set, fragments),
+            q::Selection::InlineFragment(frag) => {
+                used_fragments(query, &frag.selection_set, fragments)
+            }
+            q::Selection::FragmentSpread(spread) => {
+                if fragments.insert(spread.fragment_name.as_str()) {
+                    let defn = query.definitions.iter().find_map(|defn| match defn {
+                        q::Definition::Fragment(frag) if frag.name == spread.fragment_name => {
+                            Some(frag)
+                        }
+                        _ => None,
+                    });
+                    if let Some(defn) = defn {
+                        used_fragments(query, &defn.selection_set, fragments);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse `query` and return its `shape_hash`
+pub fn shape_hash_str(query: &str) -> Result<u64, QueryParseError> {
+    let query = graphql_parser::parse_query(query)?.into_static();
+    Ok(shape_hash(&query))
+}
+
 // In all ShapeHash implementations, we never include anything to do with
 // the position of the element in the query, i.e., fields that involve
 // `Pos`
@@ -170,4 +274,57 @@ mod tests {
         assert_ne!(shape_hash(&q1), shape_hash(&q3));
         assert_ne!(shape_hash(&q2), shape_hash(&q4));
     }
+
+    #[test]
+    fn aliases_are_ignored() {
+        let hash = |query: &str| shape_hash(&parse_query(query).unwrap().into_static());
+
+        assert_eq!(hash("{ field }"), hash("{ a: field }"));
+        assert_eq!(
+            hash("{ things(first: 10) { id name } }"),
+            hash("{ stuff: things(first: 10) { key: id label: name } }")
+        );
+        assert_ne!(hash("{ a: field }"), hash("{ a: other }"));
+        assert_ne!(hash("{ field }"), hash("{ field { id } }"));
+    }
+
+    #[test]
+    fn hash_query_text() {
+        const Q: &str = "{ things(where: { stuff_gt: 42 }) { id name } }";
+        let doc = parse_query(Q).unwrap().into_static();
+
+        assert_eq!(shape_hash(&doc), shape_hash_str(Q).unwrap());
+        assert!(shape_hash_str("{ things { id }").is_err());
+    }
+
+    #[test]
+    fn hash_selected_operation() {
+        const DOC: &str = "
+            query things { things { ...thingFields } }
+            query others { others { id } }
+            fragment thingFields on Thing { id ...nameFields }
+            fragment nameFields on Thing { name }
+            fragment unused on Thing { unused }";
+        const THINGS: &str = "
+            query things { things { ...thingFields } }
+            fragment thingFields on Thing { id ...nameFields }
+            fragment nameFields on Thing { name }";
+        const OTHERS: &str = "query others { others { id } }";
+        let parse = |query: &str| parse_query(query).unwrap().into_static();
+        let doc = parse(DOC);
+
+        let things = shape_hash_for_operation(&doc, Some("things"));
+        let others = shape_hash_for_operation(&doc, Some("others"));
+        assert_ne!(things, others);
+        assert_eq!(things, shape_hash_for_operation(&parse(THINGS), None));
+        assert_eq!(others, shape_hash_for_operation(&parse(OTHERS), None));
+        assert_eq!(others, shape_hash(&parse(OTHERS)));
+
+        // Without a usable operation name, we hash the whole document
+        assert_eq!(shape_hash(&doc), shape_hash_for_operation(&doc, None));
+        assert_eq!(
+            shape_hash(&doc),
+            shape_hash_for_operation(&doc, Some("missing"))
+        );
+    }
 }
@@ -1,10 +1,84 @@
 use anyhow::{anyhow, Error};
 use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::str::FromStr;
 
-use crate::prelude::{q, BigInt, Entity};
+use crate::prelude::{q, BigDecimal, BigInt, Entity};
 use web3::types::{H160, H256};
 
+/// Convert a GraphQL value into a JSON value, the way it is shown to
+/// clients. Integers and floats become JSON numbers and enums become JSON
+/// strings; variables, which can not appear in output, become strings of
+/// the form `$name`. Floats that are not finite become `null` since JSON
+/// can not represent them.
+///
+/// The conversion is lossy: enums and variables can not be told apart from
+/// strings anymore, and `from_json_value_lossy` turns them into
+/// `q::Value::String`.
+pub fn to_json_value_lossy(value: &q::Value) -> serde_json::Value {
+    use serde_json::Value as J;
+
+    match value {
+        q::Value::Null => J::Null,
+        q::Value::Boolean(b) => J::Bool(*b),
+        q::Value::Int(n) => n.as_i64().map(J::from).unwrap_or(J::Null),
+        q::Value::Float(f) => serde_json::Number::from_f64(*f)
+            .map(J::Number)
+            .unwrap_or(J::Null),
+        q::Value::String(s) | q::Value::Enum(s) => J::String(s.clone()),
+        q::Value::Variable(name) => J::String(format!("${}", name)),
+        q::Value::List(values) => J::Array(values.iter().map(to_json_value_lossy).collect()),
+        q::Value::Object(map) => J::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), to_json_value_lossy(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert a JSON value into a GraphQL value. Integers that fit into an
+/// `i32` become `q::Value::Int`; all other integers become strings with
+/// their decimal representation, the same way we represent `BigInt`
+/// values, so that no precision is lost. Other numbers become
+/// `q::Value::Float`.
+///
+/// The conversion is lossy since integers outside the `i32` range can not
+/// be told apart from strings anymore. For values that do not contain
+/// enums, variables, integers outside the `i32` range, or floats that are
+/// not finite, `from_json_value_lossy(to_json_value_lossy(v)) == v`
+pub fn from_json_value_lossy(value: serde_json::Value) -> q::Value {
+    use serde_json::Value as J;
+
+    match value {
+        J::Null => q::Value::Null,
+        J::Bool(b) => q::Value::Boolean(b),
+        J::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                match i32::try_from(i) {
+                    Ok(i) => q::Value::Int(i.into()),
+                    Err(_) => q::Value::String(i.to_string()),
+                }
+            } else if n.is_u64() {
+                q::Value::String(n.to_string())
+            } else if n.is_f64() {
+                n.as_f64()
+                    .map(q::Value::Float)
+                    .unwrap_or_else(|| q::Value::String(n.to_string()))
+            } else {
+                // An integer too large for `u64`
+                q::Value::String(n.to_string())
+            }
+        }
+        J::String(s) => q::Value::String(s),
+        J::Array(values) => q::Value::List(values.into_iter().map(from_json_value_lossy).collect()),
+        J::Object(map) => q::Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, from_json_value_lossy(value)))
+                .collect(),
+        ),
+    }
+}
+
 pub trait TryFromValue: Sized {
     fn try_from_value(value: &q::Value) -> Result<Self, Error>;
 }
@@ -87,6 +161,11 @@ impl TryFromValue for H256 {
 impl TryFromValue for BigInt {
     fn try_from_value(value: &q::Value) -> Result<Self, Error> {
         match value {
+            q::Value::Int(n) => n
+                .as_i64()
+                .map(BigInt::from)
+                .ok_or_else(|| anyhow!("Cannot parse value into a BigInt: {:?}", n)),
+            // Values that do not fit into an `i64` are represented as `String`s.
             q::Value::String(s) => BigInt::from_str(s)
                 .map_err(|e| anyhow!("Cannot parse BigInt value from string `{}`: {}", s, e)),
             _ => Err(anyhow!("Cannot parse value into an BigInt: {:?}", value)),
@@ -94,6 +173,21 @@ impl TryFromValue for BigInt {
     }
 }
 
+impl TryFromValue for BigDecimal {
+    fn try_from_value(value: &q::Value) -> Result<Self, Error> {
+        match value {
+            q::Value::Int(n) => n
+                .as_i64()
+                .map(BigDecimal::from)
+                .ok_or_else(|| anyhow!("Cannot parse value into a BigDecimal: {:?}", n)),
+            q::Value::Float(f) if f.is_finite() => Ok(BigDecimal::from(*f)),
+            q::Value::String(s) => BigDecimal::from_str(s)
+                .map_err(|e| anyhow!("Cannot parse BigDecimal value from string `{}`: {}", s, e)),
+            _ => Err(anyhow!("Cannot parse value into a BigDecimal: {:?}", value)),
+        }
+    }
+}
+
 impl<T> TryFromValue for Vec<T>
 where
     T: TryFromValue,
@@ -127,6 +221,10 @@ pub trait ValueMap {
     fn get_optional<T: TryFromValue>(&self, key: &str) -> Result<Option<T>, Error>;
 }
 
+fn invalid_field(key: &str, e: Error) -> Error {
+    anyhow!("Invalid value for field `{}`: {}", key, e)
+}
+
 impl ValueMap for q::Value {
     fn get_required<T: TryFromValue>(&self, key: &str) -> Result<T, Error> {
         match self {
@@ -153,7 +251,7 @@ impl ValueMap for &BTreeMap<String, q::Value> {
     {
         self.get(key)
             .ok_or_else(|| anyhow!("Required field `{}` not set", key))
-            .and_then(|value| T::try_from_value(value).map_err(|e| e.into()))
+            .and_then(|value| T::try_from_value(value).map_err(|e| invalid_field(key, e)))
     }
 
     fn get_optional<T>(&self, key: &str) -> Result<Option<T>, Error>
@@ -164,7 +262,7 @@ impl ValueMap for &BTreeMap<String, q::Value> {
             q::Value::Null => Ok(None),
             _ => T::try_from_value(value)
                 .map(|value| Some(value))
-                .map_err(|e| e.into()),
+                .map_err(|e| invalid_field(key, e)),
         })
     }
 }
@@ -176,7 +274,7 @@ impl ValueMap for &HashMap<&String, q::Value> {
     {
         self.get(&String::from(key))
             .ok_or_else(|| anyhow!("Required field `{}` not set", key))
-            .and_then(|value| T::try_from_value(value).map_err(|e| e.into()))
+            .and_then(|value| T::try_from_value(value).map_err(|e| invalid_field(key, e)))
     }
 
     fn get_optional<T>(&self, key: &str) -> Result<Option<T>, Error>
@@ -188,7 +286,7 @@ impl ValueMap for &HashMap<&String, q::Value> {
                 q::Value::Null => Ok(None),
                 _ => T::try_from_value(value)
                     .map(|value| Some(value))
-                    .map_err(|e| e.into()),
+                    .map_err(|e| invalid_field(key, e)),
             })
     }
 }
@@ -197,6 +295,14 @@ pub trait ValueList {
     fn get_values<T>(&self) -> Result<Vec<T>, Error>
     where
         T: TryFromValue;
+
+    /// Convert every element of the list with `TryFromValue`, stopping at
+    /// the first element that can not be converted. The error for that
+    /// element mentions its index.
+    fn try_into_vec<T>(self) -> Result<Vec<T>, Error>
+    where
+        Self: Sized,
+        T: TryFromValue;
 }
 
 impl ValueList for q::Value {
@@ -209,6 +315,16 @@ impl ValueList for q::Value {
             _ => Err(anyhow!("value is not a list: {:?}", self)),
         }
     }
+
+    fn try_into_vec<T>(self) -> Result<Vec<T>, Error>
+    where
+        T: TryFromValue,
+    {
+        match self {
+            q::Value::List(values) => values.try_into_vec(),
+            _ => Err(anyhow!("value is not a list: {:?}", self)),
+        }
+    }
 }
 
 impl ValueList for Vec<q::Value> {
@@ -221,4 +337,196 @@ impl ValueList for Vec<q::Value> {
             Ok(acc)
         })
     }
+
+    fn try_into_vec<T>(self) -> Result<Vec<T>, Error>
+    where
+        T: TryFromValue,
+    {
+        self.iter()
+            .enumerate()
+            .map(|(index, value)| {
+                T::try_from_value(value)
+                    .map_err(|e| anyhow!("Invalid list element at index {}: {}", index, e))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object;
+    use serde_json::json;
+
+    #[test]
+    fn json_round_trip() {
+        let value = object! {
+            int: 42,
+            negative: -17,
+            big: "123456789012345678901234567890",
+            float: 1.5,
+            flag: true,
+            nothing: q::Value::Null,
+            list: vec![object! { id: "a", n: 1 }, object! { id: "b", n: 2 }],
+            nested: object! {
+                deeper: object! { max: i32::MAX, min: i32::MIN },
+            },
+        };
+
+        let json = to_json_value_lossy(&value);
+        assert_eq!(
+            json!({
+                "int": 42,
+                "negative": -17,
+                "big": "123456789012345678901234567890",
+                "float": 1.5,
+                "flag": true,
+                "nothing": null,
+                "list": [{ "id": "a", "n": 1 }, { "id": "b", "n": 2 }],
+                "nested": { "deeper": { "max": i32::MAX, "min": i32::MIN } }
+            }),
+            json
+        );
+        assert_eq!(value, from_json_value_lossy(json));
+    }
+
+    #[test]
+    fn large_json_ints_become_strings() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{ "i64": 9007199254740993, "u64": 18446744073709551615,
+                 "huge": 123456789012345678901234567890, "neg": -2147483649 }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            object! {
+                i64: "9007199254740993",
+                u64: "18446744073709551615",
+                huge: "123456789012345678901234567890",
+                neg: "-2147483649",
+            },
+            from_json_value_lossy(json)
+        );
+    }
+
+    #[test]
+    fn enums_and_variables_become_strings() {
+        let value = q::Value::List(vec![
+            q::Value::Enum("asc".to_owned()),
+            q::Value::Variable("first".to_owned()),
+        ]);
+        let json = to_json_value_lossy(&value);
+        assert_eq!(json!(["asc", "$first"]), json);
+
+        // They do not come back
+        assert_eq!(
+            q::Value::List(vec![
+                q::Value::String("asc".to_owned()),
+                q::Value::String("$first".to_owned()),
+            ]),
+            from_json_value_lossy(json)
+        );
+    }
+
+    #[test]
+    fn get_fields_from_map() {
+        let value = object! {
+            name: "thing",
+            count: 3,
+            flag: true,
+            nothing: q::Value::Null,
+        };
+        let map = match &value {
+            q::Value::Object(map) => map,
+            _ => unreachable!(),
+        };
+
+        let name: String = map.get_required("name").unwrap();
+        assert_eq!("thing", name);
+        assert_eq!(3u64, value.get_required::<u64>("count").unwrap());
+        assert_eq!(Some(true), map.get_optional::<bool>("flag").unwrap());
+        assert_eq!(None, map.get_optional::<bool>("nothing").unwrap());
+        assert_eq!(None, map.get_optional::<bool>("missing").unwrap());
+
+        let err = map.get_required::<String>("missing").unwrap_err();
+        assert!(err.to_string().contains("`missing`"));
+
+        let err = map.get_required::<bool>("name").unwrap_err();
+        assert!(err.to_string().contains("`name`"));
+        let err = map.get_optional::<bool>("count").unwrap_err();
+        assert!(err.to_string().contains("`count`"));
+    }
+
+    #[test]
+    fn list_into_vec() {
+        let list = q::Value::List(vec![
+            q::Value::String("a".to_owned()),
+            q::Value::String("b".to_owned()),
+            q::Value::Enum("c".to_owned()),
+        ]);
+        let strings: Vec<String> = list.try_into_vec().unwrap();
+        assert_eq!(vec!["a", "b", "c"], strings);
+
+        let list = vec![
+            q::Value::Boolean(true),
+            q::Value::Boolean(false),
+            q::Value::String("yes".to_owned()),
+            q::Value::Boolean(true),
+        ];
+        let err = list.try_into_vec::<bool>().unwrap_err();
+        assert!(err.to_string().contains("index 2"));
+
+        assert!(q::Value::Null.try_into_vec::<bool>().is_err());
+    }
+
+    #[test]
+    fn big_numbers() {
+        let balance = "123456789012345678901234567890";
+        let value = q::Value::String(balance.to_owned());
+        assert_eq!(
+            BigInt::from_str(balance).unwrap(),
+            BigInt::try_from_value(&value).unwrap()
+        );
+        assert_eq!(
+            BigDecimal::from_str(balance).unwrap(),
+            BigDecimal::try_from_value(&value).unwrap()
+        );
+
+        // One more than `i64::MAX`
+        let value = q::Value::String("9223372036854775808".to_owned());
+        assert_eq!(
+            BigInt::from(i64::MAX) + BigInt::from(1),
+            BigInt::try_from_value(&value).unwrap()
+        );
+        assert!(u64::try_from_value(&value).is_ok());
+
+        let value = q::Value::Int(q::Number::from(-17));
+        assert_eq!(BigInt::from(-17), BigInt::try_from_value(&value).unwrap());
+        assert_eq!(
+            BigDecimal::from(-17),
+            BigDecimal::try_from_value(&value).unwrap()
+        );
+
+        let value = q::Value::String("12.5".to_owned());
+        assert_eq!(
+            BigDecimal::from_str("12.5").unwrap(),
+            BigDecimal::try_from_value(&value).unwrap()
+        );
+        assert!(BigInt::try_from_value(&value).is_err());
+
+        let value = q::Value::Float(-2.25);
+        assert_eq!(
+            BigDecimal::from_str("-2.25").unwrap(),
+            BigDecimal::try_from_value(&value).unwrap()
+        );
+        assert!(BigInt::try_from_value(&value).is_err());
+        assert!(BigDecimal::try_from_value(&q::Value::Float(f64::NAN)).is_err());
+        assert!(BigDecimal::try_from_value(&q::Value::Float(f64::INFINITY)).is_err());
+
+        let value = q::Value::String("lots".to_owned());
+        let err = BigInt::try_from_value(&value).unwrap_err();
+        assert!(err.to_string().contains("`lots`"));
+        let err = BigDecimal::try_from_value(&value).unwrap_err();
+        assert!(err.to_string().contains("`lots`"));
+    }
 }
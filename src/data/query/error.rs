@@ -78,6 +78,7 @@ pub enum QueryExecutionError {
     EventStreamError,
     FulltextQueryRequiresFilter,
     DeploymentReverted,
+    SubscriptionClosed(String),
 }
 
 impl Error for QueryExecutionError {
@@ -217,6 +218,7 @@ impl fmt::Display for QueryExecutionError {
             TooExpensive => write!(f, "query is too expensive"),
             Throttled=> write!(f, "service is overloaded and can not run the query right now. Please try again in a few minutes"),
             DeploymentReverted => write!(f, "the chain was reorganized while executing the query"),
+            SubscriptionClosed(reason) => write!(f, "{}", reason),
         }
     }
 }
@@ -1,5 +1,6 @@
 use crate::components::store::{EntityType, SubgraphStore};
 use crate::data::graphql::ext::{DirectiveExt, DirectiveFinder, DocumentExt, TypeExt, ValueExt};
+use crate::data::graphql::ObjectOrInterface;
 use crate::data::store::ValueType;
 use crate::data::sub::{SubgraphDeploymentId, SubgraphName};
 use crate::prelude::{
@@ -210,12 +211,7 @@ impl From<&s::Directive> for FulltextDefinition {
     // Assumes the input is a Fulltext Directive that has already been validated because it makes
     // liberal use of unwrap() where specific types are expected
     fn from(directive: &Directive) -> Self {
-        let name = directive
-            .argument("name")
-            .unwrap()
-            .as_string()
-            .unwrap()
-            .clone();
+        let name = directive.string_argument("name").unwrap().to_owned();
 
         let algorithm = FulltextAlgorithm::try_from(
             directive.argument("algorithm").unwrap().as_enum().unwrap(),
@@ -1227,32 +1223,26 @@ impl Schema {
         Ok(())
     }
 
-    /// Validate that `object` implements `interface`.
+    /// Validate that `object` implements `interface` according to
+    /// `validate_interface_conformance`, reporting all fields of `interface`
+    /// that are missing or have a different type together.
     fn validate_interface_implementation(
         object: &ObjectType,
         interface: &InterfaceType,
     ) -> Result<(), SchemaValidationError> {
-        // Check that all fields in the interface exist in the object with same name and type.
-        let mut missing_fields = vec![];
-        for i in &interface.fields {
-            if object
+        validate_interface_conformance(object, interface).map_err(|errors| {
+            let missing_fields = interface
                 .fields
                 .iter()
-                .find(|o| o.name.eq(&i.name) && o.field_type.eq(&i.field_type))
-                .is_none()
-            {
-                missing_fields.push(i.to_string().trim().to_owned());
-            }
-        }
-        if !missing_fields.is_empty() {
-            Err(SchemaValidationError::InterfaceFieldsMissing(
+                .filter(|field| errors.iter().any(|error| error.field() == field.name))
+                .map(|field| field.to_string().trim().to_owned())
+                .collect();
+            SchemaValidationError::InterfaceFieldsMissing(
                 object.name.clone(),
                 interface.name.clone(),
                 Strings(missing_fields),
-            ))
-        } else {
-            Ok(())
-        }
+            )
+        })
     }
 
     fn subgraph_schema_object_type(&self) -> Option<&ObjectType> {
@@ -1289,6 +1279,77 @@ impl Schema {
     }
 }
 
+/// A way in which an object type fails to implement an interface
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum ConformanceError {
+    #[error("Type `{object}` does not define field `{field}` of interface `{interface}`")]
+    MissingField {
+        object: String,
+        interface: String,
+        field: String,
+    },
+    #[error(
+        "Field `{field}` of type `{object}` has type `{actual}`, but interface \
+         `{interface}` requires type `{expected}`"
+    )]
+    IncompatibleFieldType {
+        object: String,
+        interface: String,
+        field: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl ConformanceError {
+    /// The name of the interface field that the error is about
+    pub fn field(&self) -> &str {
+        match self {
+            ConformanceError::MissingField { field, .. }
+            | ConformanceError::IncompatibleFieldType { field, .. } => field,
+        }
+    }
+}
+
+/// Check that `object` defines every field of `interface` with a
+/// compatible type. Since queries against the interface read its fields
+/// from all implementations alike, a type is only compatible if it is
+/// exactly the type the interface declares, including nullability. All
+/// violations are reported, not just the first one.
+pub fn validate_interface_conformance(
+    object: &ObjectType,
+    interface: &InterfaceType,
+) -> Result<(), Vec<ConformanceError>> {
+    let object_fields = ObjectOrInterface::from(object);
+    let errors: Vec<_> = ObjectOrInterface::from(interface)
+        .fields()
+        .iter()
+        .filter_map(|expected| match object_fields.field(&expected.name) {
+            None => Some(ConformanceError::MissingField {
+                object: object.name.clone(),
+                interface: interface.name.clone(),
+                field: expected.name.clone(),
+            }),
+            Some(actual) if actual.field_type != expected.field_type => {
+                Some(ConformanceError::IncompatibleFieldType {
+                    object: object.name.clone(),
+                    interface: interface.name.clone(),
+                    field: expected.name.clone(),
+                    expected: expected.field_type.to_string(),
+                    actual: actual.field_type.to_string(),
+                })
+            }
+            Some(_) => None,
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
 #[test]
 fn non_existing_interface() {
     let schema = "type Foo implements Bar @entity { foo: Int }";
@@ -1560,3 +1621,64 @@ type Gravatar @entity {
 
     assert_eq!(schema.validate_fulltext_directives(), vec![]);
 }
+
+#[test]
+fn test_interface_conformance() {
+    const SCHEMA: &str = "
+interface Account { id: ID!, owner: Account, tags: [String] }
+type Wallet implements Account @entity { id: ID!, owner: Account, tags: [String], extra: Int }
+type Vault implements Account @entity { id: ID!, tags: [String] }
+type Broken implements Account @entity { id: String!, owner: Wallet!, tags: [String] }";
+
+    let document = graphql_parser::parse_schema(SCHEMA).expect("Failed to parse schema");
+    let account = document.find_interface("Account").unwrap();
+    let conformance = |name| {
+        let object = document.get_object_type_definition(name).unwrap();
+        Schema::validate_interface_implementation(object, account)
+    };
+    let missing = |object: &str, fields: &[&str]| {
+        Err(SchemaValidationError::InterfaceFieldsMissing(
+            object.to_owned(),
+            "Account".to_owned(),
+            Strings(fields.iter().map(|field| field.to_string()).collect()),
+        ))
+    };
+
+    assert_eq!(Ok(()), conformance("Wallet"));
+    assert_eq!(missing("Vault", &["owner: Account"]), conformance("Vault"));
+    // Fields must have exactly the type from the interface, even if their
+    // type would be more specific; all such fields are reported
+    assert_eq!(
+        missing("Broken", &["id: ID!", "owner: Account"]),
+        conformance("Broken")
+    );
+
+    let violations = |name| {
+        let object = document.get_object_type_definition(name).unwrap();
+        validate_interface_conformance(object, account)
+    };
+    assert_eq!(Ok(()), violations("Wallet"));
+    assert_eq!(
+        Err(vec![ConformanceError::MissingField {
+            object: "Vault".to_owned(),
+            interface: "Account".to_owned(),
+            field: "owner".to_owned(),
+        }]),
+        violations("Vault")
+    );
+    let incompatible =
+        |field: &str, expected: &str, actual: &str| ConformanceError::IncompatibleFieldType {
+            object: "Broken".to_owned(),
+            interface: "Account".to_owned(),
+            field: field.to_owned(),
+            expected: expected.to_owned(),
+            actual: actual.to_owned(),
+        };
+    assert_eq!(
+        Err(vec![
+            incompatible("id", "ID!", "String!"),
+            incompatible("owner", "Account", "Wallet!"),
+        ]),
+        violations("Broken")
+    );
+}
@@ -80,25 +80,69 @@ impl_slog_value!(SubgraphDeploymentId);
 /// `SubgraphDeploymentId` is fixed-length so cheap to clone.
 impl CheapClone for SubgraphDeploymentId {}
 
+/// The reasons why a string is not a valid `SubgraphDeploymentId`
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DeploymentIdError {
+    #[error("deployment id must not be empty")]
+    Empty,
+    #[error("deployment id `{id}` is {len} characters long, but at most 46 are allowed")]
+    TooLong { id: String, len: usize },
+    #[error("deployment id `{id}` contains the invalid character `{c}` at position {pos}; only alphanumeric characters and `_` are allowed")]
+    InvalidCharacter { id: String, c: char, pos: usize },
+    #[error("deployment id `{0}` is reserved")]
+    Reserved(String),
+}
+
+impl DeploymentIdError {
+    /// The string that failed validation
+    pub fn into_id(self) -> String {
+        use DeploymentIdError::*;
+        match self {
+            Empty => String::new(),
+            TooLong { id, .. } | InvalidCharacter { id, .. } | Reserved(id) => id,
+        }
+    }
+}
+
 impl SubgraphDeploymentId {
     /// Check that `s` is a valid `SubgraphDeploymentId` and create a new one.
     /// If `s` is longer than 46 characters, or contains characters other than
-    /// alphanumeric characters or `_`, return s (as a `String`) as the error
+    /// alphanumeric characters or `_`, return s (as a `String`) as the error.
+    /// Unlike `validate`, this accepts the empty string. Use `validate` to
+    /// find out why `s` is not valid
     pub fn new(s: impl Into<String>) -> Result<Self, String> {
+        match Self::validate(s) {
+            Err(DeploymentIdError::Empty) => Ok(SubgraphDeploymentId(String::new())),
+            result => result.map_err(DeploymentIdError::into_id),
+        }
+    }
+
+    /// Check that `s` is a valid `SubgraphDeploymentId` and create a new one.
+    /// The error describes exactly why `s` is not a valid id
+    pub fn validate(s: impl Into<String>) -> Result<Self, DeploymentIdError> {
         let s = s.into();
 
+        if s.is_empty() {
+            return Err(DeploymentIdError::Empty);
+        }
+
         // Enforce length limit
-        if s.len() > 46 {
-            return Err(s);
+        let len = s.chars().count();
+        if len > 46 {
+            return Err(DeploymentIdError::TooLong { id: s, len });
         }
 
         // Check that the ID contains only allowed characters.
-        if !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
-            return Err(s);
+        if let Some((pos, c)) = s
+            .chars()
+            .enumerate()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '_'))
+        {
+            return Err(DeploymentIdError::InvalidCharacter { id: s, c, pos });
         }
 
         if s == "subgraphs" {
-            return Err(s);
+            return Err(DeploymentIdError::Reserved(s));
         }
 
         Ok(SubgraphDeploymentId(s))
@@ -152,6 +196,63 @@ impl TryFromValue for SubgraphDeploymentId {
     }
 }
 
+#[test]
+fn test_subgraph_deployment_id_validation() {
+    use DeploymentIdError::*;
+
+    let id = SubgraphDeploymentId::validate("QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz")
+        .expect("valid id is accepted");
+    assert_eq!(
+        "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz",
+        id.as_str()
+    );
+    assert!(SubgraphDeploymentId::validate("test_subgraph").is_ok());
+
+    assert_eq!(Err(Empty), SubgraphDeploymentId::validate(""));
+
+    let long = "a".repeat(47);
+    assert_eq!(
+        Err(TooLong {
+            id: long.clone(),
+            len: 47
+        }),
+        SubgraphDeploymentId::validate(long)
+    );
+
+    assert_eq!(
+        Err(InvalidCharacter {
+            id: "Qm-abc".to_owned(),
+            c: '-',
+            pos: 2
+        }),
+        SubgraphDeploymentId::validate("Qm-abc")
+    );
+
+    assert_eq!(
+        Err(Reserved("subgraphs".to_owned())),
+        SubgraphDeploymentId::validate("subgraphs")
+    );
+
+    // The length is counted in characters, like the position of an
+    // invalid character
+    let long = "é".repeat(47);
+    assert_eq!(
+        Err(TooLong {
+            id: long.clone(),
+            len: 47
+        }),
+        SubgraphDeploymentId::validate(long)
+    );
+
+    // `new` reports the offending string, and still accepts the empty
+    // string
+    assert_eq!(
+        Err("Qm-abc".to_owned()),
+        SubgraphDeploymentId::new("Qm-abc")
+    );
+    assert_eq!("", SubgraphDeploymentId::new("").unwrap().as_str());
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct SubgraphName(String);
 
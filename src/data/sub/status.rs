@@ -2,7 +2,33 @@
 
 use super::schema::{SubgraphError, SubgraphHealth};
 use crate::data::graphql::{object, IntoValue};
-use crate::prelude::{q, web3::types::H256, EthereumBlockPointer, Value};
+use crate::prelude::{q, warn, web3::types::H256, EthereumBlockPointer, Logger, Value};
+use crate::util::futures::TimeoutError;
+use lazy_static::lazy_static;
+use std::env;
+use std::fmt::Debug;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+lazy_static! {
+    /// How long to wait for the chain head when building the status of a
+    /// chain before reporting the chain head as unavailable
+    pub static ref CHAIN_HEAD_TIMEOUT: Duration = {
+        let timeout = env::var("GRAPH_STATUS_CHAIN_HEAD_TIMEOUT")
+            .ok()
+            .map(|s| {
+                u64::from_str(&s).unwrap_or_else(|_| {
+                    panic!(
+                        "GRAPH_STATUS_CHAIN_HEAD_TIMEOUT must be a number, but is `{}`",
+                        s
+                    )
+                })
+            })
+            .unwrap_or(5);
+        Duration::from_secs(timeout)
+    };
+}
 
 pub enum Filter {
     SubgraphName(String),
@@ -43,22 +69,118 @@ impl From<EthereumBlockPointer> for EthereumBlock {
     }
 }
 
-/// Indexing status information related to the chain. Right now, we only
-/// support Ethereum, but once we support more chains, we'll have to turn this into
-/// an enum
+/// Fetch the chain head of `network` with `fetch` for the status of a
+/// chain, giving up after `timeout` so that an unresponsive node can not
+/// hold up the whole status response. Errors and timeouts are logged and
+/// returned; the chain head should then be reported as unavailable.
+pub async fn fetch_chain_head<F, B, E>(
+    logger: &Logger,
+    network: &str,
+    fetch: F,
+    timeout: Duration,
+) -> Result<Option<B>, TimeoutError<E>>
+where
+    F: Future<Output = Result<Option<B>, E>>,
+    E: Debug + Send + Sync + 'static,
+{
+    let result = match tokio::time::timeout(timeout, fetch).await {
+        Ok(result) => result.map_err(TimeoutError::Inner),
+        Err(_) => Err(TimeoutError::Elapsed),
+    };
+    if let Err(e) = &result {
+        warn!(logger, "Failed to fetch the chain head for the status";
+              "network" => network,
+              "error" => e.to_string());
+    }
+    result
+}
+
+/// The chain head from `fetch_chain_head`, and whether it was unavailable
+async fn chain_head<F, B, E>(
+    logger: &Logger,
+    network: &str,
+    fetch: F,
+    timeout: Duration,
+) -> (Option<B>, bool)
+where
+    F: Future<Output = Result<Option<B>, E>>,
+    E: Debug + Send + Sync + 'static,
+{
+    match fetch_chain_head(logger, network, fetch, timeout).await {
+        Ok(head) => (head, false),
+        Err(_) => (None, true),
+    }
+}
+
+/// Indexing status information related to an Ethereum chain
 #[derive(Debug)]
-pub struct ChainInfo {
+pub struct EthereumChainInfo {
     pub network: String,
     pub chain_head_block: Option<EthereumBlock>,
+    /// Set if the chain head could not be fetched, in which case
+    /// `chain_head_block` is `None`
+    pub chain_head_unavailable: bool,
     pub earliest_block: Option<EthereumBlock>,
     pub latest_block: Option<EthereumBlock>,
 }
 
-impl IntoValue for ChainInfo {
+impl EthereumChainInfo {
+    /// The status of `network`, with the chain head from `chain_head`. If
+    /// that fails, or takes longer than `CHAIN_HEAD_TIMEOUT`, the chain
+    /// head is reported as unavailable
+    pub async fn build<F, E>(
+        logger: &Logger,
+        network: String,
+        chain_head: F,
+        earliest_block: Option<EthereumBlock>,
+        latest_block: Option<EthereumBlock>,
+    ) -> Self
+    where
+        F: Future<Output = Result<Option<EthereumBlock>, E>>,
+        E: Debug + Send + Sync + 'static,
+    {
+        Self::build_with_timeout(
+            logger,
+            network,
+            chain_head,
+            *CHAIN_HEAD_TIMEOUT,
+            earliest_block,
+            latest_block,
+        )
+        .await
+    }
+
+    /// Like `build`, but wait at most `timeout` for the chain head
+    pub async fn build_with_timeout<F, E>(
+        logger: &Logger,
+        network: String,
+        chain_head: F,
+        timeout: Duration,
+        earliest_block: Option<EthereumBlock>,
+        latest_block: Option<EthereumBlock>,
+    ) -> Self
+    where
+        F: Future<Output = Result<Option<EthereumBlock>, E>>,
+        E: Debug + Send + Sync + 'static,
+    {
+        let (chain_head_block, chain_head_unavailable) =
+            self::chain_head(logger, &network, chain_head, timeout).await;
+        EthereumChainInfo {
+            network,
+            chain_head_block,
+            chain_head_unavailable,
+            earliest_block,
+            latest_block,
+        }
+    }
+}
+
+impl IntoValue for EthereumChainInfo {
     fn into_value(self) -> q::Value {
-        let ChainInfo {
+        let EthereumChainInfo {
             network,
             chain_head_block,
+            chain_head_unavailable,
             earliest_block,
             latest_block,
         } = self;
@@ -66,12 +188,118 @@ impl IntoValue for ChainInfo {
             __typename: "EthereumIndexingStatus",
             network: network,
             chainHeadBlock: chain_head_block,
+            chainHeadUnavailable: chain_head_unavailable,
             earliestBlock: earliest_block,
             latestBlock: latest_block,
         }
     }
 }
 
+/// A block on an Arweave chain, identified by its `indep_hash` and height
+#[derive(Debug)]
+pub struct ArweaveBlock {
+    pub hash: String,
+    pub number: u64,
+}
+
+impl IntoValue for ArweaveBlock {
+    fn into_value(self) -> q::Value {
+        object! {
+            __typename: "ArweaveBlock",
+            hash: self.hash,
+            number: format!("{}", self.number),
+        }
+    }
+}
+
+/// Indexing status information related to an Arweave chain
+#[derive(Debug)]
+pub struct ArweaveChainInfo {
+    pub network: String,
+    pub chain_head_block: Option<ArweaveBlock>,
+    /// Set if the chain head could not be fetched, in which case
+    /// `chain_head_block` is `None`
+    pub chain_head_unavailable: bool,
+    pub earliest_block: Option<ArweaveBlock>,
+    pub latest_block: Option<ArweaveBlock>,
+}
+
+impl ArweaveChainInfo {
+    /// The status of `network`, with the chain head from `chain_head`. If
+    /// that fails, or takes longer than `CHAIN_HEAD_TIMEOUT`, the chain
+    /// head is reported as unavailable
+    pub async fn build<F, E>(
+        logger: &Logger,
+        network: String,
+        chain_head: F,
+        earliest_block: Option<ArweaveBlock>,
+        latest_block: Option<ArweaveBlock>,
+    ) -> Self
+    where
+        F: Future<Output = Result<Option<ArweaveBlock>, E>>,
+        E: Debug + Send + Sync + 'static,
+    {
+        let (chain_head_block, chain_head_unavailable) =
+            self::chain_head(logger, &network, chain_head, *CHAIN_HEAD_TIMEOUT).await;
+        ArweaveChainInfo {
+            network,
+            chain_head_block,
+            chain_head_unavailable,
+            earliest_block,
+            latest_block,
+        }
+    }
+}
+
+impl IntoValue for ArweaveChainInfo {
+    fn into_value(self) -> q::Value {
+        let ArweaveChainInfo {
+            network,
+            chain_head_block,
+            chain_head_unavailable,
+            earliest_block,
+            latest_block,
+        } = self;
+        object! {
+            __typename: "ArweaveIndexingStatus",
+            network: network,
+            chainHeadBlock: chain_head_block,
+            chainHeadUnavailable: chain_head_unavailable,
+            earliestBlock: earliest_block,
+            latestBlock: latest_block,
+        }
+    }
+}
+
+/// Indexing status information related to one of the chains a subgraph
+/// indexes
+#[derive(Debug)]
+pub enum ChainStatus {
+    Ethereum(EthereumChainInfo),
+    Arweave(ArweaveChainInfo),
+}
+
+impl IntoValue for ChainStatus {
+    fn into_value(self) -> q::Value {
+        match self {
+            ChainStatus::Ethereum(info) => info.into_value(),
+            ChainStatus::Arweave(info) => info.into_value(),
+        }
+    }
+}
+
+impl From<EthereumChainInfo> for ChainStatus {
+    fn from(info: EthereumChainInfo) -> Self {
+        ChainStatus::Ethereum(info)
+    }
+}
+
+impl From<ArweaveChainInfo> for ChainStatus {
+    fn from(info: ArweaveChainInfo) -> Self {
+        ChainStatus::Arweave(info)
+    }
+}
+
 #[derive(Debug)]
 pub struct Info {
     pub subgraph: String,
@@ -80,8 +308,10 @@ pub struct Info {
     pub health: SubgraphHealth,
     pub fatal_error: Option<SubgraphError>,
     pub non_fatal_errors: Vec<SubgraphError>,
+    /// The last block at which the subgraph was healthy, if it ever was
+    pub last_healthy_block: Option<EthereumBlock>,
 
-    pub chains: Vec<ChainInfo>,
+    pub chains: Vec<ChainStatus>,
 
     pub entity_count: u64,
 
@@ -98,6 +328,7 @@ impl IntoValue for Info {
             health,
             node,
             non_fatal_errors,
+            last_healthy_block,
             synced,
         } = self;
 
@@ -137,9 +368,179 @@ impl IntoValue for Info {
             health: q::Value::from(health),
             fatalError: fatal_error_val,
             nonFatalErrors: non_fatal_errors,
+            lastHealthyBlock: last_healthy_block,
             chains: chains.into_iter().map(|chain| chain.into_value()).collect::<Vec<_>>(),
             entityCount: format!("{}", entity_count),
             node: node,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ethereum_block(number: u64) -> EthereumBlock {
+        EthereumBlock::new(H256::from_low_u64_be(number), number)
+    }
+
+    #[test]
+    fn ethereum_chain_status() {
+        let status = ChainStatus::from(EthereumChainInfo {
+            network: "mainnet".to_owned(),
+            chain_head_block: Some(ethereum_block(100)),
+            chain_head_unavailable: false,
+            earliest_block: Some(ethereum_block(1)),
+            latest_block: None,
+        });
+
+        let expected = object! {
+            __typename: "EthereumIndexingStatus",
+            network: "mainnet",
+            chainHeadBlock: object! {
+                __typename: "EthereumBlock",
+                hash: format!("{:064x}", 100),
+                number: "100",
+            },
+            chainHeadUnavailable: false,
+            earliestBlock: object! {
+                __typename: "EthereumBlock",
+                hash: format!("{:064x}", 1),
+                number: "1",
+            },
+            latestBlock: q::Value::Null,
+        };
+        assert_eq!(expected, status.into_value());
+    }
+
+    fn info(last_healthy_block: Option<EthereumBlock>) -> Info {
+        Info {
+            subgraph: "QmSubgraph".to_owned(),
+            synced: false,
+            health: SubgraphHealth::Unhealthy,
+            fatal_error: None,
+            non_fatal_errors: vec![],
+            last_healthy_block,
+            chains: vec![],
+            entity_count: 7,
+            node: None,
+        }
+    }
+
+    #[test]
+    fn last_healthy_block() {
+        let value = info(Some(ethereum_block(42))).into_value();
+        let block = match &value {
+            q::Value::Object(map) => map.get("lastHealthyBlock").cloned(),
+            _ => None,
+        };
+        let expected = object! {
+            __typename: "EthereumBlock",
+            hash: format!("{:064x}", 42),
+            number: "42",
+        };
+        assert_eq!(Some(expected), block);
+
+        let value = info(None).into_value();
+        let block = match &value {
+            q::Value::Object(map) => map.get("lastHealthyBlock").cloned(),
+            _ => None,
+        };
+        assert_eq!(Some(q::Value::Null), block);
+    }
+
+    #[test]
+    fn arweave_chain_status() {
+        let status = ChainStatus::from(ArweaveChainInfo {
+            network: "arweave-mainnet".to_owned(),
+            chain_head_block: Some(ArweaveBlock {
+                hash: "hash200".to_owned(),
+                number: 200,
+            }),
+            chain_head_unavailable: false,
+            earliest_block: None,
+            latest_block: Some(ArweaveBlock {
+                hash: "hash150".to_owned(),
+                number: 150,
+            }),
+        });
+
+        let expected = object! {
+            __typename: "ArweaveIndexingStatus",
+            network: "arweave-mainnet",
+            chainHeadBlock: object! {
+                __typename: "ArweaveBlock",
+                hash: "hash200",
+                number: "200",
+            },
+            chainHeadUnavailable: false,
+            earliestBlock: q::Value::Null,
+            latestBlock: object! {
+                __typename: "ArweaveBlock",
+                hash: "hash150",
+                number: "150",
+            },
+        };
+        assert_eq!(expected, status.into_value());
+    }
+
+    #[tokio::test]
+    async fn unresponsive_chain_head() {
+        use slog::{o, Discard};
+        use std::time::Instant;
+
+        let logger = Logger::root(Discard, o!());
+        let timeout = Duration::from_millis(50);
+        let never = futures03::future::pending::<Result<Option<EthereumBlock>, ()>>();
+
+        let start = Instant::now();
+        let status = EthereumChainInfo::build_with_timeout(
+            &logger,
+            "mainnet".to_owned(),
+            never,
+            timeout,
+            None,
+            Some(ethereum_block(7)),
+        )
+        .await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(status.chain_head_unavailable);
+
+        let value = ChainStatus::from(status).into_value();
+        let field = |name: &str| match &value {
+            q::Value::Object(map) => map.get(name).cloned(),
+            _ => None,
+        };
+        assert_eq!(Some(q::Value::Null), field("chainHeadBlock"));
+        assert_eq!(Some(q::Value::Boolean(true)), field("chainHeadUnavailable"));
+
+        // A node that responds in time
+        let fetch = async { Ok::<_, ()>(Some(ethereum_block(100))) };
+        let head = fetch_chain_head(&logger, "mainnet", fetch, timeout).await;
+        assert_eq!(Some(100), head.unwrap().map(|block| block.number()));
+
+        // The status of a chain whose node fails right away
+        let fetch = async { Err::<Option<ArweaveBlock>, _>("connection refused") };
+        let status =
+            ArweaveChainInfo::build(&logger, "arweave-mainnet".to_owned(), fetch, None, None).await;
+        assert!(status.chain_head_block.is_none());
+        assert!(status.chain_head_unavailable);
+    }
+
+    #[test]
+    fn optional_block_into_value() {
+        assert_eq!(q::Value::Null, None::<EthereumBlock>.into_value());
+        assert_eq!(
+            ethereum_block(3).into_value(),
+            Some(ethereum_block(3)).into_value()
+        );
+        assert_eq!(
+            object! {
+                __typename: "EthereumBlock",
+                hash: format!("{:064x}", 3),
+                number: "3",
+            },
+            Some(ethereum_block(3)).into_value()
+        );
+    }
+}
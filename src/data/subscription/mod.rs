@@ -3,5 +3,5 @@ mod result;
 mod subscription;
 
 pub use self::error::SubscriptionError;
-pub use self::result::{QueryResultStream, SubscriptionResult};
+pub use self::result::{isolate_panics, QueryResultStream, SubscriptionResult};
 pub use self::subscription::Subscription;
@@ -1,5 +1,7 @@
-use crate::prelude::QueryResult;
+use crate::prelude::{QueryExecutionError, QueryResult};
+use futures03::stream::StreamExt;
 use std::marker::Unpin;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 
 /// A stream of query results for a subscription.
@@ -8,3 +10,65 @@ pub type QueryResultStream =
 
 /// The result of running a subscription, if successful.
 pub type SubscriptionResult = QueryResultStream;
+
+/// Make sure that a panic while producing the results of one subscription
+/// only ends that subscription. If polling `stream` panics, the returned
+/// stream yields one last result containing a `QueryExecutionError::Panic`
+/// and then ends. That way, a server can send an error for just the
+/// failed subscription while all other subscriptions on the same
+/// connection keep running.
+pub fn isolate_panics(stream: QueryResultStream) -> QueryResultStream {
+    Box::new(
+        AssertUnwindSafe(stream)
+            .catch_unwind()
+            .map(|result| match result {
+                Ok(result) => result,
+                Err(panic) => {
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_owned());
+                    Arc::new(QueryExecutionError::Panic(msg).into())
+                }
+            }),
+    )
+}
+
+#[test]
+fn panic_only_ends_one_subscription() {
+    use crate::prelude::q;
+    use futures03::stream;
+    use std::collections::BTreeMap;
+
+    fn result(name: &str, i: i32) -> Arc<QueryResult> {
+        let mut map = BTreeMap::new();
+        map.insert(name.to_owned(), q::Value::Int(i.into()));
+        Arc::new(map.into())
+    }
+
+    let good: QueryResultStream = Box::new(stream::iter(0..3).map(|i| result("good", i)));
+    let bad: QueryResultStream = Box::new(stream::iter(0..3).map(|i| {
+        if i == 1 {
+            panic!("resolver failed");
+        }
+        result("bad", i)
+    }));
+
+    let results = futures03::executor::block_on(
+        stream::select(isolate_panics(good), isolate_panics(bad)).collect::<Vec<_>>(),
+    );
+
+    let count = |name: &str| {
+        results
+            .iter()
+            .filter(|r| serde_json::to_string(&***r).unwrap().contains(name))
+            .count()
+    };
+    let errors = results.iter().filter(|r| r.has_errors()).count();
+
+    // All results for `good` arrive, `bad` ends after its first result
+    assert_eq!(3, count("good"));
+    assert_eq!(1, count("bad"));
+    assert_eq!(1, errors);
+}
@@ -19,13 +19,16 @@ pub mod cheap_clone;
 /// Module with mocks for different parts of the system.
 pub mod mock {
     pub use crate::components::ethereum::MockEthereumAdapter;
+    pub use crate::components::metrics::MockMetricsRegistry;
     pub use crate::components::store::MockStore;
 }
 
 /// Wrapper for spawning tasks that abort on panic, which is our default.
 mod task_spawn;
 pub use task_spawn::{
-    block_on, spawn, spawn_allow_panic, spawn_blocking, spawn_blocking_allow_panic,
+    block_on, set_panic_logger, spawn, spawn_allow_panic, spawn_blocking,
+    spawn_blocking_allow_panic, spawn_blocking_named, spawn_cancelable, spawn_named,
+    CancellationToken,
 };
 
 pub use bytes;
@@ -79,9 +82,14 @@ pub mod prelude {
         LightEthereumBlockExt, ProviderEthRpcMetrics, SubgraphEthRpcMetrics,
     };
     pub use crate::components::graphql::{
-        GraphQlRunner, QueryLoadManager, SubscriptionResultFuture,
+        GraphQlRunner, QueryLoadManager, QueryPermit, SubscriptionResultFuture,
+    };
+    pub use crate::components::health::{
+        ComponentHealth, HealthAggregator, HealthCheck, Readiness,
+    };
+    pub use crate::components::link_resolver::{
+        JsonStreamValue, JsonValueStream, LinkResolver, LinkResolverMetrics,
     };
-    pub use crate::components::link_resolver::{JsonStreamValue, JsonValueStream, LinkResolver};
     pub use crate::components::metrics::{
         aggregate::Aggregate, stopwatch::StopwatchMetrics, Collector, Counter, CounterVec, Gauge,
         GaugeVec, Histogram, HistogramOpts, HistogramVec, MetricsRegistry, Opts, PrometheusError,
@@ -101,15 +109,15 @@ pub mod prelude {
         BLOCK_NUMBER_MAX, SUBSCRIPTION_THROTTLE_INTERVAL,
     };
     pub use crate::components::sub::{
-        BlockState, DataSourceTemplateInfo, HostMetrics, RuntimeHost, RuntimeHostBuilder,
-        SubgraphAssignmentProvider, SubgraphInstance, SubgraphInstanceManager, SubgraphRegistrar,
-        SubgraphVersionSwitchingMode,
+        BlockState, DataSourceTemplateInfo, HostMetrics, InstanceState, InstanceStatus,
+        RuntimeHost, RuntimeHostBuilder, SubgraphAssignmentProvider, SubgraphInstance,
+        SubgraphInstanceManager, SubgraphRegistrar, SubgraphVersionSwitchingMode,
     };
     pub use crate::components::{EventConsumer, EventProducer};
 
     pub use crate::cheap_clone::CheapClone;
     pub use crate::data::graphql::{
-        shape_hash::shape_hash, SerializableValue, TryFromValue, ValueMap,
+        shape_hash::shape_hash, SerializableValue, SerializableValueStream, TryFromValue, ValueMap,
     };
     pub use crate::data::query::{
         Query, QueryError, QueryExecutionError, QueryResult, QueryVariables,
@@ -124,11 +132,11 @@ pub mod prelude {
     pub use crate::data::sub::schema::SubgraphDeploymentEntity;
     pub use crate::data::sub::{
         BlockHandlerFilter, CreateSubgraphResult, DataSource, DataSourceContext,
-        DataSourceTemplate, DeploymentState, Link, MappingABI, MappingBlockHandler,
-        MappingCallHandler, MappingEventHandler, SubgraphAssignmentProviderError,
-        SubgraphDeploymentId, SubgraphManifest, SubgraphManifestResolveError,
-        SubgraphManifestValidationError, SubgraphName, SubgraphRegistrarError,
-        UnvalidatedSubgraphManifest,
+        DataSourceTemplate, DeploymentIdError, DeploymentState, Link, MappingABI,
+        MappingBlockHandler, MappingCallHandler, MappingEventHandler,
+        SubgraphAssignmentProviderError, SubgraphDeploymentId, SubgraphManifest,
+        SubgraphManifestResolveError, SubgraphManifestValidationError, SubgraphName,
+        SubgraphRegistrarError, UnvalidatedSubgraphManifest,
     };
     pub use crate::data::subscription::{
         QueryResultStream, Subscription, SubscriptionError, SubscriptionResult,
@@ -145,8 +153,8 @@ pub mod prelude {
     };
     pub use crate::log::split::split_logger;
     pub use crate::util::cache_weight::CacheWeight;
-    pub use crate::util::futures::{retry, TimeoutError};
-    pub use crate::util::stats::MovingStats;
+    pub use crate::util::futures::{retry, RetryError, TimeoutError};
+    pub use crate::util::stats::{DecayingStats, MovingStats};
 
     macro_rules! static_graphql {
         ($m:ident, $m2:ident, {$($n:ident,)*}) => {
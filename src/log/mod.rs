@@ -23,6 +23,8 @@ use slog::*;
 use slog_async;
 use slog_envlogger;
 use slog_term::*;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
 use std::{env, fmt, io, result};
 
 pub mod codes;
@@ -30,6 +32,31 @@ pub mod elastic;
 pub mod factory;
 pub mod split;
 
+/// A drain that keeps the message of every record logged to it, so that
+/// tests can check what was logged
+#[cfg(test)]
+pub(crate) struct Capture(pub Arc<Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl Capture {
+    /// A logger that logs into a new `Capture`, and the messages logged
+    pub(crate) fn logger() -> (Logger, Arc<Mutex<Vec<String>>>) {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        (Logger::root(Capture(logged.clone()), o!()), logged)
+    }
+}
+
+#[cfg(test)]
+impl Drain for Capture {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> result::Result<(), Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
 pub fn logger(show_debug: bool) -> Logger {
     let use_color = isatty::stdout_isatty();
     let decorator = slog_term::TermDecorator::new().build();
@@ -1,23 +1,138 @@
 use futures03::future::{FutureExt, TryFutureExt};
+use lazy_static::lazy_static;
+use slog::{crit, Logger};
 use std::future::Future as Future03;
 use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
+lazy_static! {
+    static ref PANIC_LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+}
+
+/// Log the message about a panic in a task through `logger` instead of
+/// printing it to stdout. Since the process aborts right after logging, the
+/// message only makes it out if `logger` does not buffer it.
+pub fn set_panic_logger(logger: Logger) {
+    *PANIC_LOGGER.write().unwrap() = Some(logger);
+}
+
+fn report_panic(msg: &str) {
+    let logger = PANIC_LOGGER.read().unwrap_or_else(|e| e.into_inner());
+    match logger.as_ref() {
+        Some(logger) => crit!(logger, "{}", msg),
+        None => println!("{}", msg),
+    }
+}
+
 fn abort_on_panic<T: Send + 'static>(
     f: impl Future03<Output = T> + Send + 'static,
 ) -> impl Future03<Output = T> {
-    // We're crashing, unwind safety doesn't matter.
-    AssertUnwindSafe(f).catch_unwind().unwrap_or_else(|_| {
-        println!("Panic in tokio task, aborting!");
+    abort_on_panic_named(None, f)
+}
+
+fn abort_on_panic_named<T: Send + 'static>(
+    name: Option<String>,
+    f: impl Future03<Output = T> + Send + 'static,
+) -> impl Future03<Output = T> {
+    on_panic(name, f, |msg| {
+        report_panic(&msg);
         std::process::abort()
     })
 }
 
+/// Calls `handle` with a message naming the task if `f` panics.
+fn on_panic<T: Send + 'static>(
+    name: Option<String>,
+    f: impl Future03<Output = T> + Send + 'static,
+    handle: impl FnOnce(String) -> T + Send + 'static,
+) -> impl Future03<Output = T> {
+    // We're crashing, unwind safety doesn't matter.
+    AssertUnwindSafe(f).catch_unwind().unwrap_or_else(move |_| {
+        let msg = match name {
+            Some(name) => format!("Panic in tokio task `{}`, aborting!", name),
+            None => "Panic in tokio task, aborting!".to_owned(),
+        };
+        handle(msg)
+    })
+}
+
 /// Aborts on panic.
 pub fn spawn<T: Send + 'static>(f: impl Future03<Output = T> + Send + 'static) -> JoinHandle<T> {
     tokio::spawn(abort_on_panic(f))
 }
 
+/// Aborts on panic, and includes `name` in the message printed before
+/// aborting.
+pub fn spawn_named<T: Send + 'static>(
+    name: &str,
+    f: impl Future03<Output = T> + Send + 'static,
+) -> JoinHandle<T> {
+    tokio::spawn(abort_on_panic_named(Some(name.to_owned()), f))
+}
+
+/// A token that can be used to cancel tasks started with
+/// `spawn_cancelable`. All clones of a token are cancelled together.
+#[derive(Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn cancel(&self) {
+        // We hold a receiver ourselves, so this can not fail
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once the token has been cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        loop {
+            if *receiver.borrow() {
+                return;
+            }
+            if receiver.recv().await.is_none() {
+                // Can't happen since `self` holds the sender
+                return futures03::future::pending().await;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Aborts on panic. Resolves to `None` if `token` is cancelled before `f`
+/// completes, in which case `f` is dropped.
+pub fn spawn_cancelable<T: Send + 'static>(
+    f: impl Future03<Output = T> + Send + 'static,
+    token: CancellationToken,
+) -> JoinHandle<Option<T>> {
+    spawn(async move {
+        tokio::select! {
+            _ = token.cancelled() => None,
+            v = f => Some(v),
+        }
+    })
+}
+
 pub fn spawn_allow_panic<T: Send + 'static>(
     f: impl Future03<Output = T> + Send + 'static,
 ) -> JoinHandle<T> {
@@ -31,6 +146,16 @@ pub fn spawn_blocking<T: Send + 'static>(
     tokio::task::spawn_blocking(move || block_on(abort_on_panic(f)))
 }
 
+/// Aborts on panic, and includes `name` in the message printed before
+/// aborting.
+pub fn spawn_blocking_named<T: Send + 'static>(
+    name: &str,
+    f: impl Future03<Output = T> + Send + 'static,
+) -> JoinHandle<T> {
+    let f = abort_on_panic_named(Some(name.to_owned()), f);
+    tokio::task::spawn_blocking(move || block_on(f))
+}
+
 /// Does not abort on panic, panics result in an `Err` in `JoinHandle`.
 pub fn spawn_blocking_allow_panic<R: 'static + Send>(
     f: impl 'static + FnOnce() -> R + Send,
@@ -42,3 +167,75 @@ pub fn spawn_blocking_allow_panic<R: 'static + Send>(
 pub fn block_on<T>(f: impl Future03<Output = T>) -> T {
     tokio::runtime::Handle::current().block_on(f)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn panicky() -> String {
+        if true {
+            panic!("boom");
+        }
+        String::new()
+    }
+
+    #[tokio::test]
+    async fn panic_message_names_task() {
+        let msg = tokio::spawn(on_panic(Some("poller".to_owned()), panicky(), |msg| msg))
+            .await
+            .unwrap();
+        assert_eq!("Panic in tokio task `poller`, aborting!", msg);
+
+        let msg = tokio::spawn(on_panic(None, panicky(), |msg| msg))
+            .await
+            .unwrap();
+        assert_eq!("Panic in tokio task, aborting!", msg);
+    }
+
+    #[test]
+    fn panic_logger() {
+        use crate::log::Capture;
+
+        // Put the logger other tests might have set back when we are done,
+        // even if an assertion fails
+        struct RestorePanicLogger(Option<Logger>);
+
+        impl Drop for RestorePanicLogger {
+            fn drop(&mut self) {
+                *PANIC_LOGGER.write().unwrap_or_else(|e| e.into_inner()) = self.0.take();
+            }
+        }
+
+        let _restore = RestorePanicLogger(PANIC_LOGGER.read().unwrap().clone());
+        let (logger, logged) = Capture::logger();
+        set_panic_logger(logger);
+        report_panic("Panic in tokio task `poller`, aborting!");
+        assert_eq!(
+            vec!["Panic in tokio task `poller`, aborting!".to_owned()],
+            *logged.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_task() {
+        use std::time::Duration;
+
+        let token = CancellationToken::new();
+        let task = spawn_cancelable(
+            async {
+                tokio::time::delay_for(Duration::from_secs(3600)).await;
+                1
+            },
+            token.clone(),
+        );
+        assert!(!token.is_cancelled());
+        token.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(None, task.await.unwrap());
+
+        let token = CancellationToken::new();
+        let task = spawn_cancelable(async { 1 }, token.clone());
+        assert_eq!(Some(1), task.await.unwrap());
+        token.cancel();
+    }
+}
@@ -1,6 +1,7 @@
 use ethabi::{Contract, Event, Function, ParamType};
+use std::collections::HashMap;
 use tiny_keccak::Keccak;
-use web3::types::H256;
+use web3::types::{Log, H256};
 
 /// Hashes a string to a H256 hash.
 pub fn string_to_h256(s: &str) -> H256 {
@@ -46,6 +47,25 @@ fn event_param_type_signature(kind: &ParamType) -> String {
     }
 }
 
+/// Returns the 4-byte selector of the custom Solidity error `name` with
+/// parameters `params`, i.e., the first 4 bytes of the hash of its
+/// `Name(type,type)` signature. Reverts with that error return data that
+/// starts with the selector.
+pub fn error_selector(name: &str, params: &[ParamType]) -> [u8; 4] {
+    let signature = format!(
+        "{}({})",
+        name,
+        params
+            .iter()
+            .map(event_param_type_signature)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let mut selector = [0u8; 4];
+    selector.copy_from_slice(&string_to_h256(&signature).as_bytes()[..4]);
+    selector
+}
+
 /// Returns an `Event(uint256,address)` signature for an event, without `indexed` hints.
 fn ambiguous_event_signature(event: &Event) -> String {
     format!(
@@ -60,6 +80,12 @@ fn ambiguous_event_signature(event: &Event) -> String {
     )
 }
 
+/// Returns the hash of the event signature, i.e., the first topic of all
+/// logs emitted for a non-anonymous `event`.
+pub fn event_topic0(event: &Event) -> H256 {
+    string_to_h256(&ambiguous_event_signature(event))
+}
+
 /// Returns an `Event(indexed uint256,address)` type signature for an event.
 fn event_signature(event: &Event) -> String {
     format!(
@@ -105,16 +131,54 @@ pub fn contract_event_with_signature<'a>(
         })
 }
 
+/// Returns the non-anonymous contract event whose logs have `topic0` as
+/// their first topic, if it exists.
+pub fn contract_event_with_topic<'a>(contract: &'a Contract, topic0: &H256) -> Option<&'a Event> {
+    contract
+        .events()
+        .find(|event| !event.anonymous && event_topic0(event) == *topic0)
+}
+
+/// Groups `logs` by the contract event they were emitted for, matching the
+/// first topic of each log against the events of `contract`. The groups are
+/// keyed by that topic; `contract_event_with_topic` looks up the event for
+/// it. Logs without topics and logs that match no event, e.g., logs of
+/// anonymous events, are skipped. Within each group, logs keep the order
+/// they have in `logs`.
+pub fn group_logs_by_event<'a>(
+    contract: &Contract,
+    logs: &'a [Log],
+) -> HashMap<H256, Vec<&'a Log>> {
+    let topics: Vec<_> = contract
+        .events()
+        .filter(|event| !event.anonymous)
+        .map(event_topic0)
+        .collect();
+    let mut groups: HashMap<H256, Vec<&Log>> = HashMap::new();
+    for log in logs {
+        if let Some(topic0) = log.topics.first().filter(|topic0| topics.contains(topic0)) {
+            groups.entry(*topic0).or_default().push(log);
+        }
+    }
+    groups
+}
+
+/// Returns `true` if `function` can change state, i.e., is payable or
+/// nonpayable.
+fn is_state_changing(function: &Function) -> bool {
+    match function.state_mutability {
+        ethabi::StateMutability::Payable | ethabi::StateMutability::NonPayable => true,
+        ethabi::StateMutability::Pure | ethabi::StateMutability::View => false,
+    }
+}
+
 pub fn contract_function_with_signature<'a>(
     contract: &'a Contract,
     target_signature: &str,
 ) -> Option<&'a Function> {
     contract
         .functions()
-        .filter(|function| match function.state_mutability {
-            ethabi::StateMutability::Payable | ethabi::StateMutability::NonPayable => true,
-            ethabi::StateMutability::Pure | ethabi::StateMutability::View => false,
-        })
+        .filter(|function| is_state_changing(function))
         .find(|function| {
             // Construct the argument function signature:
             // `address,uint256,bool`
@@ -131,3 +195,252 @@ pub fn contract_function_with_signature<'a>(
             target_signature == actual_signature
         })
 }
+
+/// Returns all payable and nonpayable overloads of the function `name`.
+/// Useful to present the available choices when the signature of a function
+/// is not known exactly.
+pub fn contract_functions_by_name<'a>(contract: &'a Contract, name: &str) -> Vec<&'a Function> {
+    contract
+        .functions()
+        .filter(|function| function.name == name && is_state_changing(function))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethabi::EventParam;
+
+    fn event(name: &str, inputs: Vec<(ParamType, bool)>) -> Event {
+        Event {
+            name: name.to_owned(),
+            inputs: inputs
+                .into_iter()
+                .enumerate()
+                .map(|(i, (kind, indexed))| EventParam {
+                    name: format!("param{}", i),
+                    kind,
+                    indexed,
+                })
+                .collect(),
+            anonymous: false,
+        }
+    }
+
+    fn tuple(components: Vec<ParamType>) -> ParamType {
+        ParamType::Tuple(components.into_iter().map(Box::new).collect())
+    }
+
+    #[test]
+    fn tuple_array_signature() {
+        use ParamType::*;
+
+        let kind = Array(Box::new(tuple(vec![Uint(256), Address])));
+        assert_eq!("(uint256,address)[]", event_param_type_signature(&kind));
+
+        let kind = FixedArray(Box::new(tuple(vec![Uint(256), Address])), 3);
+        assert_eq!("(uint256,address)[3]", event_param_type_signature(&kind));
+    }
+
+    #[test]
+    fn nested_tuple_array_signature() {
+        use ParamType::*;
+
+        // `((uint256,address)[],bool)[]`
+        let inner = Array(Box::new(tuple(vec![Uint(256), Address])));
+        let kind = Array(Box::new(tuple(vec![inner, Bool])));
+        assert_eq!(
+            "((uint256,address)[],bool)[]",
+            event_param_type_signature(&kind)
+        );
+
+        // `(uint256,address)[2][]`
+        let kind = Array(Box::new(FixedArray(
+            Box::new(tuple(vec![Uint(256), Address])),
+            2,
+        )));
+        assert_eq!("(uint256,address)[2][]", event_param_type_signature(&kind));
+    }
+
+    #[test]
+    fn tuple_array_event_signature() {
+        use ParamType::*;
+
+        let event = event(
+            "Batch",
+            vec![
+                (Address, true),
+                (Array(Box::new(tuple(vec![Uint(256), Address]))), false),
+            ],
+        );
+        assert_eq!(
+            "Batch(address,(uint256,address)[])",
+            ambiguous_event_signature(&event)
+        );
+        assert_eq!(
+            "Batch(indexed address,(uint256,address)[])",
+            event_signature(&event)
+        );
+    }
+
+    #[test]
+    fn functions_by_name() {
+        const ABI: &str = r#"[
+            {
+                "type": "function",
+                "name": "transfer",
+                "inputs": [
+                    { "name": "to", "type": "address" },
+                    { "name": "value", "type": "uint256" }
+                ],
+                "outputs": [{ "name": "", "type": "bool" }],
+                "constant": false,
+                "payable": false,
+                "stateMutability": "nonpayable"
+            },
+            {
+                "type": "function",
+                "name": "transfer",
+                "inputs": [
+                    { "name": "to", "type": "address" },
+                    { "name": "value", "type": "uint256" },
+                    { "name": "data", "type": "bytes" }
+                ],
+                "outputs": [{ "name": "", "type": "bool" }],
+                "constant": false,
+                "payable": false,
+                "stateMutability": "nonpayable"
+            },
+            {
+                "type": "function",
+                "name": "balanceOf",
+                "inputs": [{ "name": "owner", "type": "address" }],
+                "outputs": [{ "name": "", "type": "uint256" }],
+                "constant": true,
+                "payable": false,
+                "stateMutability": "view"
+            }
+        ]"#;
+        let contract = Contract::load(ABI.as_bytes()).expect("ABI is valid");
+
+        let mut transfers = contract_functions_by_name(&contract, "transfer")
+            .into_iter()
+            .map(|function| function.inputs.len())
+            .collect::<Vec<_>>();
+        transfers.sort();
+        assert_eq!(vec![2, 3], transfers);
+
+        // `balanceOf` is a view function
+        assert!(contract_functions_by_name(&contract, "balanceOf").is_empty());
+        assert!(contract_functions_by_name(&contract, "approve").is_empty());
+
+        // Exact matching is unaffected
+        assert!(
+            contract_function_with_signature(&contract, "transfer(address,uint256,bytes)")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn custom_error_selectors() {
+        use ParamType::*;
+
+        // The errors Solidity uses for `require` and for failed assertions
+        assert_eq!([0x08, 0xc3, 0x79, 0xa0], error_selector("Error", &[String]));
+        assert_eq!(
+            [0x4e, 0x48, 0x7b, 0x71],
+            error_selector("Panic", &[Uint(256)])
+        );
+
+        assert_ne!(
+            error_selector("InsufficientBalance", &[Uint(256)]),
+            error_selector("InsufficientBalance", &[Uint(256), Uint(256)])
+        );
+    }
+
+    #[test]
+    fn logs_grouped_by_event() {
+        use web3::types::{Bytes, H160};
+
+        const ABI: &str = r#"[
+            {
+                "type": "event",
+                "name": "Transfer",
+                "inputs": [
+                    { "name": "from", "type": "address", "indexed": true },
+                    { "name": "to", "type": "address", "indexed": true },
+                    { "name": "value", "type": "uint256", "indexed": false }
+                ],
+                "anonymous": false
+            },
+            {
+                "type": "event",
+                "name": "Approval",
+                "inputs": [
+                    { "name": "owner", "type": "address", "indexed": true },
+                    { "name": "spender", "type": "address", "indexed": true },
+                    { "name": "value", "type": "uint256", "indexed": false }
+                ],
+                "anonymous": false
+            }
+        ]"#;
+        let contract = Contract::load(ABI.as_bytes()).expect("ABI is valid");
+        let transfer = contract.event("Transfer").unwrap();
+        let approval = contract.event("Approval").unwrap();
+
+        fn log(topics: Vec<H256>, index: u64) -> Log {
+            Log {
+                address: H160::zero(),
+                topics,
+                data: Bytes(vec![]),
+                block_hash: None,
+                block_number: None,
+                transaction_hash: None,
+                transaction_index: None,
+                log_index: Some(index.into()),
+                transaction_log_index: None,
+                log_type: None,
+                removed: None,
+            }
+        }
+        let unknown = string_to_h256("Unknown(uint256)");
+        let logs = vec![
+            log(vec![event_topic0(transfer), H256::zero(), H256::zero()], 0),
+            log(vec![event_topic0(approval), H256::zero(), H256::zero()], 1),
+            log(vec![unknown], 2),
+            log(vec![event_topic0(transfer), H256::zero(), H256::zero()], 3),
+            log(vec![], 4),
+        ];
+
+        let groups = group_logs_by_event(&contract, &logs);
+        let indexes = |event: &Event| -> Vec<u64> {
+            groups[&event_topic0(event)]
+                .iter()
+                .map(|log| log.log_index.unwrap().as_u64())
+                .collect()
+        };
+        assert_eq!(2, groups.len());
+        assert_eq!(vec![0, 3], indexes(transfer));
+        assert_eq!(vec![1], indexes(approval));
+
+        assert_eq!(
+            Some(approval),
+            contract_event_with_topic(&contract, &event_topic0(approval))
+        );
+        assert_eq!(None, contract_event_with_topic(&contract, &unknown));
+    }
+
+    #[test]
+    fn transfer_topic0() {
+        use ParamType::*;
+
+        let transfer = event(
+            "Transfer",
+            vec![(Address, true), (Address, true), (Uint(256), false)],
+        );
+        let expected = "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+            .parse::<H256>()
+            .unwrap();
+        assert_eq!(expected, event_topic0(&transfer));
+    }
+}
@@ -3,6 +3,7 @@ use futures::prelude::*;
 use slog::{debug, trace, warn, Logger};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
@@ -17,6 +18,7 @@ pub fn retry<I, E>(operation_name: impl ToString, logger: &Logger) -> RetryConfi
         log_after: 1,
         warn_after: 10,
         limit: RetryConfigProperty::Unknown,
+        jitter: true,
         phantom_item: PhantomData,
         phantom_error: PhantomData,
     }
@@ -29,6 +31,7 @@ pub struct RetryConfig<I, E> {
     log_after: u64,
     warn_after: u64,
     limit: RetryConfigProperty<usize>,
+    jitter: bool,
     phantom_item: PhantomData<I>,
     phantom_error: PhantomData<E>,
 }
@@ -81,6 +84,14 @@ where
         self
     }
 
+    /// Randomize the delays between attempts, so that many operations that
+    /// fail at the same time do not all retry at the same time. Jitter is
+    /// enabled by default; disabling it makes the delays reproducible.
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
     /// Set how long (in seconds) to wait for an attempt to complete before giving up on that
     /// attempt.
     pub fn timeout_secs(self, timeout_secs: u64) -> RetryConfigWithTimeout<I, E> {
@@ -118,7 +129,20 @@ where
     E: Debug + Send + Send + Sync + 'static,
 {
     /// Rerun the provided function as many times as needed.
-    pub fn run<F, R>(self, mut try_it: F) -> impl Future<Item = I, Error = TimeoutError<E>>
+    pub fn run<F, R>(self, try_it: F) -> impl Future<Item = I, Error = TimeoutError<E>>
+    where
+        F: FnMut() -> R + Send,
+        R: Future<Item = I, Error = E> + Send,
+    {
+        self.run_with_attempts(try_it).map_err(|e| e.error)
+    }
+
+    /// Like `run`, but when giving up, also report how many attempts were
+    /// made.
+    pub fn run_with_attempts<F, R>(
+        self,
+        mut try_it: F,
+    ) -> impl Future<Item = I, Error = RetryError<E>>
     where
         F: FnMut() -> R + Send,
         R: Future<Item = I, Error = E> + Send,
@@ -131,6 +155,7 @@ where
         let log_after = self.inner.log_after;
         let warn_after = self.inner.warn_after;
         let limit_opt = self.inner.limit.unwrap(&operation_name, "limit");
+        let strategy = retry_strategy(limit_opt, self.inner.jitter);
         let timeout = self.timeout;
 
         trace!(logger, "Run with retry: {}", operation_name);
@@ -141,7 +166,7 @@ where
             condition,
             log_after,
             warn_after,
-            limit_opt,
+            strategy,
             move || {
                 try_it()
                     .timeout(timeout)
@@ -172,6 +197,7 @@ impl<I, E> RetryConfigNoTimeout<I, E> {
         let log_after = self.inner.log_after;
         let warn_after = self.inner.warn_after;
         let limit_opt = self.inner.limit.unwrap(&operation_name, "limit");
+        let strategy = retry_strategy(limit_opt, self.inner.jitter);
 
         trace!(logger, "Run with retry: {}", operation_name);
 
@@ -181,13 +207,13 @@ impl<I, E> RetryConfigNoTimeout<I, E> {
             condition,
             log_after,
             warn_after,
-            limit_opt,
+            strategy,
             // No timeout, so all errors are inner errors
             move || try_it().map_err(TimeoutError::Inner),
         )
         .map_err(|e| {
             // No timeout, so all errors are inner errors
-            e.into_inner().unwrap()
+            e.error.into_inner().unwrap()
         })
     }
 }
@@ -216,15 +242,24 @@ impl<T: Debug + Send + Sync + 'static> TimeoutError<T> {
     }
 }
 
+/// The error from the last attempt of an operation that was retried, and
+/// how many attempts were made in total.
+#[derive(Error, Debug)]
+#[error("{error} (after {attempts} attempts)")]
+pub struct RetryError<E: Debug + Send + Sync + 'static> {
+    pub error: TimeoutError<E>,
+    pub attempts: u64,
+}
+
 fn run_retry<I, E, F, R>(
     operation_name: String,
     logger: Logger,
     condition: RetryIf<I, E>,
     log_after: u64,
     warn_after: u64,
-    limit_opt: Option<usize>,
+    strategy: Box<dyn Iterator<Item = Duration> + Send>,
     mut try_it_with_timeout: F,
-) -> impl Future<Item = I, Error = TimeoutError<E>> + Send
+) -> impl Future<Item = I, Error = RetryError<E>> + Send
 where
     I: Debug + Send,
     E: Debug + Send + Sync + 'static,
@@ -233,13 +268,14 @@ where
 {
     let condition = Arc::new(condition);
 
-    let mut attempt_count = 0;
-    Retry::spawn(retry_strategy(limit_opt), move || {
+    let attempts = Arc::new(AtomicU64::new(0));
+    let attempts_made = attempts.clone();
+    Retry::spawn(strategy, move || {
         let operation_name = operation_name.clone();
         let logger = logger.clone();
         let condition = condition.clone();
 
-        attempt_count += 1;
+        let attempt_count = attempts.fetch_add(1, Ordering::SeqCst) + 1;
 
         try_it_with_timeout().then(move |result_with_timeout| {
             let is_elapsed = result_with_timeout
@@ -299,22 +335,41 @@ where
             }
         })
     })
-    .then(|retry_result| {
+    .then(move |retry_result| {
         // Unwrap the inner result.
         // The outer Ok/Err is only used for retry control flow.
-        match retry_result {
+        let result = match retry_result {
             Ok(r) => r,
             Err(e) => e,
-        }
+        };
+        result.map_err(|error| RetryError {
+            error,
+            attempts: attempts_made.load(Ordering::SeqCst),
+        })
     })
 }
 
-fn retry_strategy(limit_opt: Option<usize>) -> Box<dyn Iterator<Item = Duration> + Send> {
+fn retry_strategy(
+    limit_opt: Option<usize>,
+    with_jitter: bool,
+) -> Box<dyn Iterator<Item = Duration> + Send> {
     // Exponential backoff, but with a maximum
     let max_delay_ms = 30_000;
-    let backoff = ExponentialBackoff::from_millis(2)
-        .max_delay(Duration::from_millis(max_delay_ms))
-        .map(jitter);
+    let backoff = ExponentialBackoff::from_millis(2).max_delay(Duration::from_millis(max_delay_ms));
+    limit_backoff(backoff, limit_opt, with_jitter)
+}
+
+/// Apply jitter, if requested, and the limit to the delays from `backoff`
+fn limit_backoff(
+    backoff: impl Iterator<Item = Duration> + Send + 'static,
+    limit_opt: Option<usize>,
+    with_jitter: bool,
+) -> Box<dyn Iterator<Item = Duration> + Send> {
+    let backoff: Box<dyn Iterator<Item = Duration> + Send> = if with_jitter {
+        Box::new(backoff.map(jitter))
+    } else {
+        Box::new(backoff)
+    };
 
     // Apply limit (maximum retry count)
     match limit_opt {
@@ -503,4 +558,49 @@ mod tests {
 
         assert_eq!(result, 10);
     }
+
+    #[tokio::test]
+    async fn attempts_in_error() {
+        let logger = Logger::root(::slog::Discard, o!());
+        let c = Mutex::new(0);
+
+        let result = retry("test", &logger)
+            .no_logging()
+            .limit(5)
+            .timeout_secs(10)
+            .run_with_attempts(move || {
+                let mut c_guard = c.lock().unwrap();
+                *c_guard += 1;
+                future::err::<(), _>(*c_guard)
+            })
+            .compat()
+            .await;
+
+        match result {
+            Err(RetryError {
+                error: TimeoutError::Inner(5),
+                attempts: 5,
+            }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delays_without_jitter() {
+        use tokio_retry::strategy::FixedInterval;
+
+        let ms = Duration::from_millis;
+        let delays: Vec<_> =
+            limit_backoff(FixedInterval::from_millis(10), Some(4), false).collect();
+        assert_eq!(vec![ms(10), ms(10), ms(10)], delays);
+
+        let delays: Vec<_> = retry_strategy(Some(5), false).collect();
+        assert_eq!(vec![ms(2), ms(4), ms(8), ms(16)], delays);
+
+        // With jitter, delays are at most as long as without it
+        let delays: Vec<_> =
+            limit_backoff(FixedInterval::from_millis(10), Some(100), true).collect();
+        assert_eq!(99, delays.len());
+        assert!(delays.iter().all(|delay| *delay <= ms(10)));
+    }
 }
@@ -17,4 +17,6 @@ pub mod cache_weight;
 
 pub mod timed_rw_lock;
 
+pub mod unfair_semaphore;
+
 pub mod jobs;
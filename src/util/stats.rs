@@ -30,20 +30,80 @@ lazy_static! {
     };
 }
 
+// The number of buckets in a `Histogram`, and how many buckets cover
+// durations that differ by a factor of two
+const HISTOGRAM_BUCKETS: usize = 128;
+const BUCKETS_PER_DOUBLING: usize = 4;
+
+/// How many measurements of each magnitude a bin has. Bucket `0` counts
+/// measurements below 1µs, and bucket `i > 0` those between
+/// `2^((i - 1) / 4)` and `2^(i / 4)` microseconds, so that each bucket is
+/// about 19% wider than the one before it. The last bucket also counts all
+/// measurements that are longer than that, i.e., about an hour.
+#[derive(Clone)]
+struct Histogram([u32; HISTOGRAM_BUCKETS]);
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram([0; HISTOGRAM_BUCKETS])
+    }
+
+    fn bucket(duration: Duration) -> usize {
+        let micros = duration.as_secs_f64() * 1_000_000.0;
+        if micros < 1.0 {
+            return 0;
+        }
+        let bucket = (BUCKETS_PER_DOUBLING as f64 * micros.log2()).floor() as usize + 1;
+        bucket.min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    /// The smallest and the largest duration in µs that go into `bucket`
+    fn bounds(bucket: usize) -> (f64, f64) {
+        let bound = |bucket: usize| 2f64.powf(bucket as f64 / BUCKETS_PER_DOUBLING as f64);
+        match bucket {
+            0 => (0.0, 1.0),
+            _ => (bound(bucket - 1), bound(bucket)),
+        }
+    }
+
+    fn add(&mut self, duration: Duration) {
+        self.0[Self::bucket(duration)] += 1;
+    }
+
+    fn remove(&mut self, other: &Histogram) {
+        for (count, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *count -= other;
+        }
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *count += other;
+        }
+    }
+}
+
 /// One bin of durations. The bin starts at time `start`, and we've added `count`
-/// entries to it whose durations add up to `duration`
+/// entries to it whose durations add up to `duration`. If the bin has a
+/// `histogram`, it also counts the entries by magnitude
 struct Bin {
     start: Instant,
     duration: Duration,
     count: u32,
+    histogram: Option<Box<Histogram>>,
 }
 
 impl Bin {
-    fn new(start: Instant) -> Self {
+    fn new(start: Instant, histogram: bool) -> Self {
         Self {
             start,
             duration: Duration::from_millis(0),
             count: 0,
+            histogram: if histogram {
+                Some(Box::new(Histogram::new()))
+            } else {
+                None
+            },
         }
     }
 
@@ -51,6 +111,9 @@ impl Bin {
     fn add(&mut self, duration: Duration) {
         self.count += 1;
         self.duration += duration;
+        if let Some(histogram) = &mut self.histogram {
+            histogram.add(duration);
+        }
     }
 
     /// Remove the measurements for `other` from this bin. Only used to
@@ -58,6 +121,18 @@ impl Bin {
     fn remove(&mut self, other: &Bin) {
         self.count -= other.count;
         self.duration -= other.duration;
+        if let (Some(histogram), Some(other)) = (&mut self.histogram, &other.histogram) {
+            histogram.remove(other);
+        }
+    }
+
+    /// Add all measurements in `other` to this bin
+    fn merge(&mut self, other: &Bin) {
+        self.count += other.count;
+        self.duration += other.duration;
+        if let (Some(histogram), Some(other)) = (&mut self.histogram, &other.histogram) {
+            histogram.merge(other);
+        }
     }
 
     /// Return `true` if the average of measurements in this bin is above
@@ -86,7 +161,7 @@ pub struct MovingStats {
     /// and the front has the oldest entries
     bins: VecDeque<Bin>,
     /// Sum over the values in `elements` The `start` of this bin
-    /// is meaningless
+    /// is meaningless. It has a histogram if we keep track of percentiles
     total: Bin,
 }
 
@@ -117,10 +192,18 @@ impl MovingStats {
             window_size,
             bin_size,
             bins: VecDeque::with_capacity(capacity),
-            total: Bin::new(Instant::now()),
+            total: Bin::new(Instant::now(), false),
         }
     }
 
+    /// Also count the measurements by magnitude so that `percentile` can
+    /// be computed. That takes about 500 bytes for each bin with
+    /// measurements. Must be called before any measurements are added
+    pub fn with_percentiles(mut self) -> Self {
+        self.total.histogram = Some(Box::new(Histogram::new()));
+        self
+    }
+
     /// Return `true` if the average of measurements in within `window_size`
     /// is above `duration`
     pub fn average_gt(&self, duration: Duration) -> bool {
@@ -153,7 +236,8 @@ impl MovingStats {
             .map(|bin| now.saturating_duration_since(bin.start) >= self.bin_size)
             .unwrap_or(true);
         if need_new_bin {
-            self.bins.push_back(Bin::new(now));
+            let histogram = self.total.histogram.is_some();
+            self.bins.push_back(Bin::new(now, histogram));
         }
         self.expire_bins(now);
         // unwrap is fine because we just added a bin if there wasn't one
@@ -179,6 +263,169 @@ impl MovingStats {
     pub fn duration(&self) -> Duration {
         self.total.duration
     }
+
+    /// Add the measurements from `other` to this one. Measurements from
+    /// `other` go into the bin that covers their time if there is one, and
+    /// into a new bin otherwise; measurements that are outside the window
+    /// that ends with our most recent bin are dropped. The window and bin
+    /// sizes of `other` are ignored.
+    pub fn merge(&mut self, other: &MovingStats) {
+        for bin in &other.bins {
+            // The index of the first bin that starts after `bin`
+            let pos = self
+                .bins
+                .iter()
+                .position(|existing| existing.start > bin.start)
+                .unwrap_or(self.bins.len());
+            let covering = pos
+                .checked_sub(1)
+                .filter(|prev| bin.start - self.bins[*prev].start < self.bin_size);
+            match covering {
+                Some(prev) => self.bins[prev].merge(bin),
+                None => {
+                    let mut new_bin = Bin::new(bin.start, self.total.histogram.is_some());
+                    new_bin.merge(bin);
+                    self.bins.insert(pos, new_bin);
+                }
+            }
+            self.total.merge(bin);
+        }
+        if let Some(latest) = self.bins.back().map(|bin| bin.start) {
+            self.expire_bins(latest);
+        }
+    }
+
+    /// Return how much the total duration per bin changed between the
+    /// older and the newer half of the `bins` most recent bins, relative
+    /// to the older half; `0.5` means that the newer half has 50% more
+    /// total duration. Bins without measurements are not stored, and are
+    /// therefore skipped. Returns `None` if there are fewer than two bins,
+    /// or if the older half has no measurements.
+    pub fn trend(&self, bins: usize) -> Option<f64> {
+        let bins = bins.min(self.bins.len());
+        if bins < 2 {
+            return None;
+        }
+        let recent: Vec<f64> = self
+            .bins
+            .iter()
+            .skip(self.bins.len() - bins)
+            .map(|bin| bin.duration.as_secs_f64())
+            .collect();
+        let (older, newer) = recent.split_at(bins / 2);
+        let older = older.iter().sum::<f64>() / older.len() as f64;
+        let newer = newer.iter().sum::<f64>() / newer.len() as f64;
+        if older == 0.0 {
+            return None;
+        }
+        Some((newer - older) / older)
+    }
+
+    /// Return the `p`-th percentile of the measurements in the current
+    /// window, where `p` is clamped to `[0, 100]`. Since we only count
+    /// measurements by magnitude, the measurements of a magnitude are
+    /// treated as if they were spread evenly across it, which makes the
+    /// result about as precise as that magnitude; between the
+    /// measurements, we interpolate linearly. Measurements that were
+    /// merged from `MovingStats` without percentiles are ignored. Returns
+    /// `None` if there are no measurements, or if the stats do not keep
+    /// track of percentiles, see `with_percentiles`.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let histogram = self.total.histogram.as_ref()?;
+        let total: u64 = histogram.0.iter().map(|count| *count as u64).sum();
+        if total == 0 {
+            return None;
+        }
+
+        // The value in µs of the measurement with index `i` if all
+        // measurements were sorted by duration
+        let value = |i: u64| {
+            let mut seen = 0;
+            for (bucket, count) in histogram.0.iter().enumerate() {
+                let count = *count as u64;
+                if i < seen + count {
+                    let (lo, hi) = Histogram::bounds(bucket);
+                    return lo + (hi - lo) * ((i - seen) as f64 + 0.5) / count as f64;
+                }
+                seen += count;
+            }
+            Histogram::bounds(HISTOGRAM_BUCKETS - 1).1
+        };
+
+        let rank = p.max(0.0).min(100.0) / 100.0 * (total - 1) as f64;
+        let (lo, hi) = (rank.floor(), rank.ceil());
+        let (lo_value, hi_value) = (value(lo as u64), value(hi as u64));
+        let micros = lo_value + (hi_value - lo_value) * (rank - lo);
+        Some(Duration::from_secs_f64(micros / 1_000_000.0))
+    }
+}
+
+/// A total of measurements that decays exponentially over time, so that a
+/// measurement taken `half_life` ago counts half as much as one taken right
+/// now. Unlike with `MovingStats`, measurements never drop out of the total
+/// all at once, which avoids sudden changes once a burst of measurements
+/// becomes older than the window.
+pub struct DecayingStats {
+    half_life: Duration,
+    /// The decayed total in seconds as of `updated`
+    value: f64,
+    updated: Instant,
+}
+
+impl DecayingStats {
+    /// # Panics
+    ///
+    /// Panics if `half_life` is `0`
+    pub fn new(half_life: Duration) -> Self {
+        assert!(half_life > Duration::from_secs(0));
+
+        DecayingStats {
+            half_life,
+            value: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn half_life(&self) -> Duration {
+        self.half_life
+    }
+
+    pub fn add(&mut self, duration: Duration) {
+        self.add_at(Instant::now(), duration);
+    }
+
+    /// Add a measurement taken at `now`. Measurements are expected to be
+    /// added with monotonically increasing `now` values; a measurement
+    /// from before the last one is treated as if it had been taken at the
+    /// same time as the last one.
+    pub fn add_at(&mut self, now: Instant, duration: Duration) {
+        let now = now.max(self.updated);
+        self.value = self.decayed(now) + duration.as_secs_f64();
+        self.updated = now;
+    }
+
+    /// The decayed total of all measurements as of now
+    pub fn duration(&self) -> Duration {
+        self.duration_at(Instant::now())
+    }
+
+    /// The decayed total of all measurements as of `now`
+    pub fn duration_at(&self, now: Instant) -> Duration {
+        Duration::from_secs_f64(self.decayed(now))
+    }
+
+    /// Add the decayed total of `other` to this one. The half life of
+    /// `other` is ignored.
+    pub fn merge(&mut self, other: &DecayingStats) {
+        let now = self.updated.max(other.updated);
+        self.value = self.decayed(now) + other.decayed(now);
+        self.updated = now;
+    }
+
+    fn decayed(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * 0.5f64.powf(elapsed / self.half_life.as_secs_f64())
+    }
 }
 
 #[cfg(test)]
@@ -234,4 +481,150 @@ mod tests {
         assert_eq!(20, stats.total.count);
         assert_eq!(Duration::from_secs(5 * 86 + 16 * 10), stats.total.duration);
     }
+
+    #[test]
+    fn percentiles() {
+        let mut stats = MovingStats::new(Duration::from_secs(100), Duration::from_secs(1));
+        stats.add(Duration::from_millis(1));
+        // Percentiles are only tracked when asked for
+        assert_eq!(None, stats.percentile(50.0));
+
+        let mut stats =
+            MovingStats::new(Duration::from_secs(100), Duration::from_secs(1)).with_percentiles();
+        assert_eq!(None, stats.percentile(50.0));
+
+        // One measurement per bin, from 1ms to 100ms, in random order
+        let start = Instant::now();
+        for i in 0..100 {
+            let ms = (i * 37) % 100 + 1;
+            stats.add_at(start + Duration::from_secs(i), Duration::from_millis(ms));
+        }
+        // Percentiles are as precise as the buckets of the histogram
+        let close = |p, expected: f64| {
+            let ms = stats.percentile(p).unwrap().as_secs_f64() * 1000.0;
+            assert!(
+                (ms - expected).abs() / expected < 0.1,
+                "p{} is {}ms but should be close to {}ms",
+                p,
+                ms,
+                expected
+            );
+        };
+        close(50.0, 50.5);
+        close(90.0, 90.1);
+        close(99.0, 99.01);
+        close(0.0, 1.0);
+        close(-10.0, 1.0);
+        close(150.0, 100.0);
+    }
+
+    #[test]
+    fn percentiles_within_bin() {
+        let mut stats =
+            MovingStats::new(Duration::from_secs(5), Duration::from_secs(1)).with_percentiles();
+        let start = Instant::now();
+        // A burst of one slow measurement among many fast ones in the
+        // same bin is not averaged away
+        for _ in 0..20 {
+            stats.add_at(start, Duration::from_millis(1));
+        }
+        stats.add_at(start, Duration::from_millis(1000));
+
+        let ms = |p| stats.percentile(p).unwrap().as_secs_f64() * 1000.0;
+        assert!(ms(50.0) < 1.5);
+        assert!(ms(99.0) > 500.0);
+        assert!(ms(99.0) > 10.0 * stats.average().unwrap().as_secs_f64() * 1000.0);
+        assert!(ms(100.0) > 800.0);
+
+        // Once the measurements expire, so do the percentiles
+        stats.add_at(start + Duration::from_secs(10), Duration::from_millis(1));
+        assert!(ms(100.0) < 1.5);
+    }
+
+    #[test]
+    fn percentiles_across_bins() {
+        let mut stats =
+            MovingStats::new(Duration::from_secs(5), Duration::from_secs(1)).with_percentiles();
+        let start = Instant::now();
+        // 9 fast measurements in one bin, and a slow one in the next
+        for _ in 0..9 {
+            stats.add_at(start, Duration::from_millis(10));
+        }
+        stats.add_at(start + Duration::from_secs(1), Duration::from_millis(1000));
+
+        let ms = |p| stats.percentile(p).unwrap().as_secs_f64() * 1000.0;
+        assert!((ms(50.0) - 10.0).abs() < 1.0);
+        assert!((ms(100.0) - 1000.0).abs() < 100.0);
+        // Between the last fast and the slow measurement
+        assert!(ms(95.0) > 400.0 && ms(95.0) < 600.0);
+    }
+
+    #[test]
+    fn merge_moving_stats() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut stats = MovingStats::new(secs(5), secs(1));
+        let mut other = MovingStats::new(secs(5), secs(1));
+        stats.add_at(start + secs(3), secs(1));
+        stats.add_at(start + secs(5), secs(1));
+        // Outside of the merged window
+        other.add_at(start, secs(10));
+        // Goes into an existing bin, and a new one in between
+        other.add_at(start + secs(3), secs(2));
+        other.add_at(start + secs(4), secs(3));
+
+        stats.merge(&other);
+        let bins: Vec<_> = stats
+            .bins
+            .iter()
+            .map(|bin| (bin.start - start, bin.count, bin.duration))
+            .collect();
+        assert_eq!(
+            vec![
+                (secs(3), 2, secs(3)),
+                (secs(4), 1, secs(3)),
+                (secs(5), 1, secs(1))
+            ],
+            bins
+        );
+        assert_eq!(4, stats.total.count);
+        assert_eq!(secs(7), stats.duration());
+    }
+
+    #[test]
+    fn decaying_vs_moving() {
+        let start = Instant::now();
+        let mut moving = MovingStats::new(Duration::from_secs(10), Duration::from_secs(1));
+        let mut decaying = DecayingStats::new(Duration::from_secs(5));
+        for _ in 0..10 {
+            moving.add_at(start, Duration::from_secs(1));
+            decaying.add_at(start, Duration::from_secs(1));
+        }
+
+        let mut last = decaying.duration_at(start).as_secs_f64();
+        assert!((last - 10.0).abs() < 0.001);
+        for secs in 1..=15 {
+            let now = start + Duration::from_secs(secs);
+
+            // The moving total stays the same until the burst leaves the
+            // window, and then drops to zero at once
+            moving.expire_bins(now);
+            let expected = if secs < 10 { 10 } else { 0 };
+            assert_eq!(Duration::from_secs(expected), moving.duration());
+
+            // The decaying total drops a little every second
+            let current = decaying.duration_at(now).as_secs_f64();
+            assert!(current > 0.0);
+            assert!((current / last - 0.5f64.powf(0.2)).abs() < 0.001);
+            last = current;
+        }
+        let at_window = decaying.duration_at(start + Duration::from_secs(10));
+        assert!((at_window.as_secs_f64() - 2.5).abs() < 0.001);
+
+        // A half life below a millisecond is fine, too
+        let mut decaying = DecayingStats::new(Duration::from_micros(100));
+        decaying.add_at(start, Duration::from_secs(1));
+        let halved = decaying.duration_at(start + Duration::from_micros(100));
+        assert!((halved.as_secs_f64() - 0.5).abs() < 0.001);
+    }
 }
@@ -0,0 +1,208 @@
+//! A semaphore that gives a released permit to the task that started
+//! waiting for it most recently
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+struct State {
+    available: usize,
+    next_id: u64,
+    // The tasks that are waiting for a permit, in the order in which they
+    // started waiting
+    waiters: Vec<(u64, oneshot::Sender<()>)>,
+}
+
+/// A counting semaphore like `tokio::sync::Semaphore`, except that it is
+/// not fair: when a permit is released and tasks are waiting for one, the
+/// permit goes to the task that started waiting last rather than the one
+/// that has waited the longest.
+///
+/// When there are always more tasks waiting than there are permits, a fair
+/// semaphore makes every task wait about as long as the longest wait; if
+/// tasks have a deadline, e.g., because clients time out, most of them
+/// miss it, and the permits are spent on work nobody waits for anymore.
+/// This semaphore instead serves the most recent tasks quickly, which are
+/// the most likely to make their deadline, at the cost of letting tasks
+/// that got pushed down far enough wait until the load subsides, or until
+/// they give up.
+pub struct UnfairSemaphore {
+    state: Mutex<State>,
+}
+
+/// A permit from an `UnfairSemaphore`, which is given back when it is
+/// dropped
+pub struct UnfairPermit {
+    semaphore: Arc<UnfairSemaphore>,
+}
+
+impl Drop for UnfairPermit {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+/// Removes a waiting task from the semaphore if it stops waiting before it
+/// was given a permit, and gives the permit back if it was given one but
+/// did not take it
+struct Waiter<'a> {
+    semaphore: &'a UnfairSemaphore,
+    id: u64,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut receiver = match self.receiver.take() {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        {
+            let mut state = self.semaphore.state.lock().unwrap();
+            if let Some(pos) = state.waiters.iter().position(|(id, _)| *id == self.id) {
+                state.waiters.remove(pos);
+                return;
+            }
+        }
+        // We are not waiting anymore, so the permit was sent to us
+        if receiver.try_recv().is_ok() {
+            self.semaphore.release();
+        }
+    }
+}
+
+impl UnfairSemaphore {
+    pub fn new(permits: usize) -> Self {
+        UnfairSemaphore {
+            state: Mutex::new(State {
+                available: permits,
+                next_id: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().available
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        for _ in 0..n {
+            self.release();
+        }
+    }
+
+    /// Remove a permit from the semaphore for good if one is available
+    /// right now. Returns `false` if all permits are taken
+    pub fn try_forget(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.available == 0 {
+            return false;
+        }
+        state.available -= 1;
+        true
+    }
+
+    pub async fn acquire_owned(self: Arc<Self>) -> UnfairPermit {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                None
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push((id, sender));
+                Some((id, receiver))
+            }
+        };
+
+        if let Some((id, receiver)) = waiting {
+            let mut waiter = Waiter {
+                semaphore: &self,
+                id,
+                receiver: Some(receiver),
+            };
+            waiter
+                .receiver
+                .as_mut()
+                .unwrap()
+                .await
+                .expect("the semaphore does not drop waiting tasks");
+            // We took the permit; make sure it is not given back twice
+            waiter.receiver = None;
+        }
+        UnfairPermit { semaphore: self }
+    }
+
+    /// Give the permit to the task that started waiting last, or make it
+    /// available if nobody is waiting
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some((_, sender)) = state.waiters.pop() {
+            if sender.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn last_waiter_goes_first() {
+        let semaphore = Arc::new(UnfairSemaphore::new(1));
+        let permit = semaphore.clone().acquire_owned().await;
+
+        let (sender, mut order) = tokio::sync::mpsc::unbounded_channel();
+        let waiters: Vec<_> = (0..3)
+            .map(|i| {
+                let semaphore = semaphore.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire_owned().await;
+                    sender.send(i).unwrap();
+                })
+            })
+            .collect();
+        // Let all of them start waiting
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        drop(permit);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Some(i) = order.recv().await {
+            received.push(i);
+        }
+        assert_eq!(vec![2, 1, 0], received);
+        assert_eq!(1, semaphore.available_permits());
+    }
+
+    #[tokio::test]
+    async fn waiters_that_give_up() {
+        let semaphore = Arc::new(UnfairSemaphore::new(1));
+        let permit = semaphore.clone().acquire_owned().await;
+
+        let wait = Duration::from_millis(10);
+        assert!(timeout(wait, semaphore.clone().acquire_owned())
+            .await
+            .is_err());
+        drop(permit);
+        assert_eq!(1, semaphore.available_permits());
+
+        assert!(semaphore.try_forget());
+        assert!(!semaphore.try_forget());
+        semaphore.add_permits(2);
+        let _first = semaphore.clone().acquire_owned().await;
+        let _second = semaphore.clone().acquire_owned().await;
+        assert_eq!(0, semaphore.available_permits());
+    }
+}
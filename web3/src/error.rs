@@ -1,5 +1,5 @@
 //! Web3 Error
-use crate::rpc::error::Error as RPCError;
+use crate::rpc::error::{Error as RPCError, ErrorCode};
 use derive_more::{Display, From};
 use secp256k1::Error as Secp256k1Error;
 use serde_json::Error as SerdeError;
@@ -36,6 +36,25 @@ pub enum Error {
     Internal,
 }
 
+impl Error {
+    /// Whether the request that failed with this error might succeed when
+    /// it is sent again. Errors from the transport and RPC errors that the
+    /// node reports as its own fault are retryable, whereas responses that
+    /// we could not decode, and RPC errors about the request itself, will
+    /// not change no matter how often we retry.
+    pub fn is_retryable(&self) -> bool {
+        use self::Error::*;
+        match self {
+            Unreachable | Transport(_) | Io(_) => true,
+            Rpc(e) => match e.code {
+                ErrorCode::InternalError | ErrorCode::ServerError(_) => true,
+                _ => false,
+            },
+            Decoder(_) | InvalidResponse(_) | Signing(_) | Internal => false,
+        }
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         use self::Error::*;
@@ -85,3 +104,35 @@ impl PartialEq for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorCode, RPCError};
+    use std::io::{Error as IoError, ErrorKind};
+
+    #[test]
+    fn is_retryable() {
+        let rpc = |code| {
+            Error::Rpc(RPCError {
+                code,
+                message: "rpc failed".into(),
+                data: None,
+            })
+        };
+
+        assert!(Error::Unreachable.is_retryable());
+        assert!(Error::Transport("connection reset".into()).is_retryable());
+        assert!(Error::Io(IoError::from(ErrorKind::TimedOut)).is_retryable());
+        assert!(rpc(ErrorCode::InternalError).is_retryable());
+        assert!(rpc(ErrorCode::ServerError(-32000)).is_retryable());
+
+        assert!(!rpc(ErrorCode::MethodNotFound).is_retryable());
+        assert!(!rpc(ErrorCode::InvalidParams).is_retryable());
+        assert!(!rpc(ErrorCode::InvalidRequest).is_retryable());
+        assert!(!rpc(ErrorCode::ParseError).is_retryable());
+        assert!(!Error::InvalidResponse("not json".into()).is_retryable());
+        assert!(!Error::Decoder("invalid hex".into()).is_retryable());
+        assert!(!Error::Signing(secp256k1::Error::InvalidSecretKey).is_retryable());
+        assert!(!Error::Internal.is_retryable());
+    }
+}
@@ -64,10 +64,20 @@ type Pending = oneshot::Sender<Result<hyper::Chunk>>;
 /// A future representing pending HTTP request, resolves to a response.
 pub type FetchTask<F> = Response<F, hyper::Chunk>;
 
+/// Extracts the result of a single request from the response.
+pub type SingleExtract = Box<dyn Fn(hyper::Chunk) -> Result<rpc::Value> + Send + Sync>;
+
+/// A future representing a pending batch, resolves to the results of all
+/// requests in the batch.
+pub type BatchTask = crate::Result<Vec<Result<rpc::Value>>>;
+
 /// HTTP Transport (synchronous)
 #[derive(Debug, Clone)]
 pub struct Http {
     id: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
+    strict_ids: bool,
+    max_batch_size: Option<usize>,
     url: hyper::Uri,
     headers: Option<HeaderMap>,
     write_sender: mpsc::UnboundedSender<(hyper::Request<hyper::Body>, Pending)>,
@@ -144,6 +154,8 @@ impl Http {
         } = params;
 
         let (write_sender, write_receiver) = mpsc::unbounded();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let counter = in_flight.clone();
 
         #[cfg(feature = "tls")]
         let client =
@@ -155,13 +167,27 @@ impl Http {
         handle.spawn(
             write_receiver
                 .map(move |(request, tx): (_, Pending)| {
-                    client
-                        .request(request)
-                        .then(move |response| Ok((response, tx)))
+                    use futures::future::Either::{A, B};
+                    // The caller dropped the response future before we got
+                    // around to sending the request; don't send it at all.
+                    if tx.is_canceled() {
+                        return A(future::ok((None, tx)));
+                    }
+                    counter.fetch_add(1, atomic::Ordering::AcqRel);
+                    B(Cancelable {
+                        request: client.request(request),
+                        tx: Some(tx),
+                        in_flight: counter.clone(),
+                    })
                 })
                 .buffer_unordered(max_parallel)
                 .for_each(|(response, tx)| {
                     use futures::future::Either::{A, B};
+                    let response = match response {
+                        Some(response) => response,
+                        // Nobody is waiting for the response anymore
+                        None => return A(future::ok(())),
+                    };
                     let future = match response {
                         Ok(ref res) if !res.status().is_success() => {
                             A(future::err(Error::Transport(format!(
@@ -172,12 +198,12 @@ impl Http {
                         Ok(res) => B(res.into_body().concat2().map_err(Into::into)),
                         Err(err) => A(future::err(err.into())),
                     };
-                    future.then(move |result| {
+                    B(future.then(move |result| {
                         if let Err(err) = tx.send(result) {
                             log::warn!("Error resuming asynchronous request: {:?}", err);
                         }
                         Ok(())
-                    })
+                    }))
                 }),
         );
 
@@ -204,6 +230,9 @@ impl Http {
 
         Ok(Http {
             id: Default::default(),
+            in_flight,
+            strict_ids: false,
+            max_batch_size: None,
             url: url.parse()?,
             headers,
             write_sender,
@@ -246,10 +275,95 @@ impl Http {
 
         Response::new(id, result, rx, extract)
     }
+
+    /// Also reject responses to single requests that do not contain the
+    /// id of the request. By default, only responses with the wrong id are
+    /// rejected, since some endpoints do not echo the id at all.
+    pub fn with_strict_ids(mut self) -> Self {
+        self.strict_ids = true;
+        self
+    }
+
+    /// Split batches into several HTTP requests with at most
+    /// `max_batch_size` calls each. The chunks of a batch are sent one
+    /// after the other, and no further chunks are sent once the future for
+    /// the batch is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch_size` is `0`
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        assert!(max_batch_size > 0);
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    fn send_chunk(
+        &self,
+        requests: Vec<(RequestId, rpc::Call)>,
+    ) -> FetchTask<fn(hyper::Chunk) -> Result<Vec<Result<rpc::Value>>>> {
+        let id = requests.first().map(|(id, _)| *id).unwrap_or(0);
+        let requests = requests.into_iter().map(|(_, call)| call).collect();
+        self.send_request(id, rpc::Request::Batch(requests), batch_response)
+    }
+
+    /// The number of requests that have been sent and are waiting for a
+    /// response. Once this reaches `max_parallel`, further requests are
+    /// queued until one of them gets its response.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(atomic::Ordering::Acquire)
+    }
+
+    /// Check that the endpoint is alive by asking it for `net_version`.
+    /// Resolves to `true` if the endpoint answered, and to `false` if it
+    /// could not be reached. Responses that are not a successful JSON-RPC
+    /// response are returned as errors.
+    pub fn health_check(&self) -> impl Future<Item = bool, Error = Error> {
+        self.execute("net_version", vec![])
+            .then(|result| match result {
+                Ok(_) => Ok(true),
+                Err(Error::Unreachable) | Err(Error::Transport(_)) | Err(Error::Io(_)) => Ok(false),
+                Err(e) => Err(e),
+            })
+    }
+}
+
+/// A request that is in flight. Resolves to the response, or to `None` as
+/// soon as the receiving end of `tx` is dropped, in which case the request
+/// is dropped, too.
+struct Cancelable<F> {
+    request: F,
+    tx: Option<Pending>,
+    /// Decremented once the request is done
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<F: Future> Future for Cancelable<F> {
+    type Item = (Option<::std::result::Result<F::Item, F::Error>>, Pending);
+    type Error = ();
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let canceled = match self.tx {
+            Some(ref mut tx) => tx.poll_cancel() == Ok(futures::Async::Ready(())),
+            None => panic!("Cancelable polled after completion"),
+        };
+        let response = if canceled {
+            None
+        } else {
+            match self.request.poll() {
+                Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+                Ok(futures::Async::Ready(response)) => Some(Ok(response)),
+                Err(err) => Some(Err(err)),
+            }
+        };
+        let tx = self.tx.take().expect("tx is only taken once");
+        self.in_flight.fetch_sub(1, atomic::Ordering::AcqRel);
+        Ok(futures::Async::Ready((response, tx)))
+    }
 }
 
 impl Transport for Http {
-    type Out = FetchTask<fn(hyper::Chunk) -> Result<rpc::Value>>;
+    type Out = FetchTask<SingleExtract>;
 
     fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
         let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
@@ -259,35 +373,55 @@ impl Transport for Http {
     }
 
     fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
-        self.send_request(id, rpc::Request::Single(request), single_response)
+        let strict_ids = self.strict_ids;
+        let extract: SingleExtract =
+            Box::new(move |response| single_response(id, strict_ids, response));
+        self.send_request(id, rpc::Request::Single(request), extract)
     }
 }
 
 impl BatchTransport for Http {
-    type Batch = FetchTask<fn(hyper::Chunk) -> Result<Vec<Result<rpc::Value>>>>;
+    type Batch = BatchTask;
 
     fn send_batch<T>(&self, requests: T) -> Self::Batch
     where
         T: IntoIterator<Item = (RequestId, rpc::Call)>,
     {
-        let mut it = requests.into_iter();
-        let (id, first) = it
-            .next()
-            .map(|x| (x.0, Some(x.1)))
-            .unwrap_or_else(|| (0, None));
-        let requests = first.into_iter().chain(it.map(|x| x.1)).collect();
-
-        self.send_request(id, rpc::Request::Batch(requests), batch_response)
+        let mut requests: Vec<_> = requests.into_iter().collect();
+        let max_batch_size = match self.max_batch_size {
+            Some(max_batch_size) if requests.len() > max_batch_size => max_batch_size,
+            _ => return Box::new(self.send_chunk(requests)),
+        };
+
+        let mut chunks = Vec::new();
+        while !requests.is_empty() {
+            let rest = requests.split_off(max_batch_size.min(requests.len()));
+            chunks.push(requests);
+            requests = rest;
+        }
+        // Only send a chunk once the previous one is done, so that dropping
+        // the batch keeps the remaining chunks from being sent
+        let http = self.clone();
+        Box::new(
+            futures::stream::iter_ok(chunks)
+                .and_then(move |chunk| http.send_chunk(chunk))
+                .concat2(),
+        )
     }
 }
 
-/// Parse bytes RPC response into `Result`.
-fn single_response<T: Deref<Target = [u8]>>(response: T) -> Result<rpc::Value> {
+/// Parse bytes RPC response into `Result`, checking that it is the response
+/// to the request with `id`. Responses without an id are only accepted if
+/// `strict_ids` is `false`.
+fn single_response<T: Deref<Target = [u8]>>(
+    id: RequestId,
+    strict_ids: bool,
+    response: T,
+) -> Result<rpc::Value> {
     let mut json: serde_json::Value = serde_json::from_slice(&*response)
         .map_err(|e| Error::InvalidResponse(format!("{:?}", e)))?;
-    if let Some(id) = json.get_mut("id") {
-        id.take();
-    }
+    let echoed = json.get_mut("id").map(|id| id.take());
+    check_response_id(id, echoed, strict_ids)?;
     let response =
         serde_json::from_value(json).map_err(|e| Error::InvalidResponse(format!("{:?}", e)))?;
     match response {
@@ -296,6 +430,36 @@ fn single_response<T: Deref<Target = [u8]>>(response: T) -> Result<rpc::Value> {
     }
 }
 
+fn check_response_id(
+    id: RequestId,
+    echoed: Option<serde_json::Value>,
+    strict_ids: bool,
+) -> Result<()> {
+    use serde_json::Value;
+
+    let matches = match echoed {
+        None | Some(Value::Null) if !strict_ids => true,
+        None | Some(Value::Null) => {
+            return Err(Error::InvalidResponse(format!(
+                "Expected response for request {}, got response without id.",
+                id
+            )))
+        }
+        Some(Value::Number(ref n)) => n.as_u64() == Some(id as u64),
+        Some(Value::String(ref s)) => *s == id.to_string(),
+        Some(_) => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::InvalidResponse(format!(
+            "Expected response for request {}, got response with id {}.",
+            id,
+            echoed.unwrap()
+        )))
+    }
+}
+
 /// Parse bytes RPC batch response into `Result`.
 fn batch_response<T: Deref<Target = [u8]>>(response: T) -> Result<Vec<Result<rpc::Value>>> {
     // See comment in `single_response`.
@@ -320,6 +484,215 @@ fn batch_response<T: Deref<Target = [u8]>>(response: T) -> Result<Vec<Result<rpc
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Read one HTTP request from `stream`, returning its body
+    fn read_request<R: Read>(stream: &mut R) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let len = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let line = line.to_lowercase();
+                        line.strip_prefix("content-length:")
+                            .map(|len| len.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if buf.len() >= end + 4 + len || n == 0 {
+                    return text[end + 4..].to_string();
+                }
+            }
+            if n == 0 {
+                return String::new();
+            }
+        }
+    }
+
+    /// The response to the JSON-RPC request or batch in `body`, with the
+    /// result `0x1` for every call and the ids of the calls echoed
+    fn respond(body: &str) -> String {
+        let output = |call: &serde_json::Value| {
+            let id = call["id"].clone();
+            serde_json::json!({"jsonrpc": "2.0", "id": id, "result": "0x1"})
+        };
+        let response = match serde_json::from_str::<serde_json::Value>(body).unwrap() {
+            serde_json::Value::Array(calls) => calls.iter().map(output).collect(),
+            call => output(&call),
+        };
+        response.to_string()
+    }
+
+    /// Start a server that answers every JSON-RPC request after `delay`
+    /// with the result `0x1`. Returns the server's URL and a counter of the
+    /// requests it received
+    fn slow_server(delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    let body = respond(&read_request(&mut stream));
+                    counter.fetch_add(1, atomic::Ordering::SeqCst);
+                    thread::sleep(delay);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                         Content-Type: application/json\r\n\
+                         Content-Length: {}\r\n\
+                         Connection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes());
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn dropped_requests_are_never_sent() {
+        // Only one request can be in flight, and the other two are queued
+        // until it is done
+        let (url, requests) = slow_server(Duration::from_millis(200));
+        let (_eloop, http) = Http::with_max_parallel(&url, 1).unwrap();
+
+        let first = http.execute("eth_blockNumber", vec![]);
+        let second = http.execute("eth_blockNumber", vec![]);
+        let third = http.execute("eth_blockNumber", vec![]);
+        drop(second);
+        drop(third);
+
+        assert_eq!(Ok(rpc::Value::String("0x1".to_owned())), first.wait());
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(1, requests.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn batches_are_split() {
+        let (url, requests) = slow_server(Duration::from_millis(0));
+        let (_eloop, http) = Http::new(&url).unwrap();
+        let http = http.with_max_batch_size(2);
+
+        let calls: Vec<_> = (0..5)
+            .map(|_| http.prepare("eth_blockNumber", vec![]))
+            .collect();
+        let results = http.send_batch(calls).wait().unwrap();
+        assert_eq!(5, results.len());
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(3, requests.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn dropped_batches_send_no_further_chunks() {
+        use futures::executor::{self, Notify, NotifyHandle};
+        use std::sync::atomic::AtomicBool;
+
+        /// Remembers that the task was woken up, i.e., that the chunk in
+        /// flight got its response
+        struct Woken(AtomicBool);
+
+        impl Notify for Woken {
+            fn notify(&self, _id: usize) {
+                self.0.store(true, atomic::Ordering::SeqCst);
+            }
+        }
+
+        let (url, requests) = slow_server(Duration::from_millis(0));
+        let (_eloop, http) = Http::new(&url).unwrap();
+        let http = http.with_max_batch_size(2);
+
+        // A batch of three chunks; polling it sends the first chunk
+        let calls: Vec<_> = (0..6)
+            .map(|_| http.prepare("eth_blockNumber", vec![]))
+            .collect();
+        let mut batch = executor::spawn(http.send_batch(calls));
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        let notify = NotifyHandle::from(woken.clone());
+        assert!(batch.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+        // Drop the batch once the first chunk is complete
+        while !woken.0.load(atomic::Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(batch);
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(1, requests.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn response_ids() {
+        let response =
+            |id: &str| format!(r#"{{"jsonrpc":"2.0",{}"result":"0x1"}}"#, id).into_bytes();
+        let ok = Ok(rpc::Value::String("0x1".to_owned()));
+
+        // Matching ids
+        assert_eq!(ok, single_response(7, false, response(r#""id":7,"#)));
+        assert_eq!(ok, single_response(7, true, response(r#""id":7,"#)));
+        assert_eq!(ok, single_response(7, true, response(r#""id":"7","#)));
+
+        // Mismatching ids
+        for strict_ids in &[false, true] {
+            match single_response(7, *strict_ids, response(r#""id":8,"#)) {
+                Err(Error::InvalidResponse(msg)) => assert!(msg.contains("with id 8"), "{}", msg),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        // Missing ids
+        assert_eq!(ok, single_response(7, false, response("")));
+        assert_eq!(ok, single_response(7, false, response(r#""id":null,"#)));
+        match single_response(7, true, response("")) {
+            Err(Error::InvalidResponse(msg)) => assert!(msg.contains("without id"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn in_flight_requests() {
+        let (url, _) = slow_server(Duration::from_millis(300));
+        let (_eloop, http) = Http::with_max_parallel(&url, 2).unwrap();
+        assert_eq!(0, http.in_flight());
+
+        let requests: Vec<_> = (0..3)
+            .map(|_| http.execute("eth_blockNumber", vec![]))
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(2, http.in_flight());
+
+        for request in requests {
+            assert_eq!(Ok(rpc::Value::String("0x1".to_owned())), request.wait());
+        }
+        assert_eq!(0, http.in_flight());
+    }
+
+    #[test]
+    fn health_check() {
+        let (url, requests) = slow_server(Duration::from_millis(0));
+        let (_eloop, http) = Http::new(&url).unwrap();
+        assert_eq!(Ok(true), http.health_check().wait());
+        assert_eq!(1, requests.load(atomic::Ordering::SeqCst));
+
+        // Nothing listens on a port that we just released
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (_eloop, http) = Http::new(&format!("http://{}", addr)).unwrap();
+        assert_eq!(Ok(false), http.health_check().wait());
+    }
 
     #[test]
     fn http_supports_basic_auth_with_user_and_password() {
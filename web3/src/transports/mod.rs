@@ -8,6 +8,12 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 pub mod batch;
 pub use self::batch::Batch;
 
+pub mod reconnecting;
+pub use self::reconnecting::ReconnectingTransport;
+
+pub mod recording;
+pub use self::recording::{RecordingTransport, ReplayTransport};
+
 #[cfg(feature = "http")]
 pub mod http;
 #[cfg(feature = "http")]
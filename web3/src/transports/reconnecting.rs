@@ -0,0 +1,239 @@
+//! Reconnecting Transport
+
+use crate::helpers;
+use crate::rpc;
+use crate::transports::Result;
+use crate::{Error, RequestId, Transport};
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use parking_lot::RwLock;
+use std::fmt;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_timer::Timer;
+
+type Connect<T> = dyn Fn(&str) -> Result<T> + Send + Sync;
+
+/// Transport that reconnects to `url` when a request fails with a
+/// retryable error, waiting with exponential backoff before each new
+/// attempt, and then sends the failed request again over the new
+/// connection. This is meant for long-lived connections like WebSockets
+/// that are useless once the connection was dropped.
+pub struct ReconnectingTransport<T> {
+    url: Arc<String>,
+    connect: Arc<Connect<T>>,
+    transport: Arc<RwLock<T>>,
+    id: Arc<AtomicUsize>,
+    timer: Timer,
+    initial_delay: Duration,
+    max_delay: Duration,
+    limit: usize,
+}
+
+impl<T> ReconnectingTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    /// Connects to `url` with `connect`, and uses `connect` again to
+    /// reconnect whenever a request fails with a retryable error.
+    pub fn new<F>(url: &str, connect: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Result<T> + Send + Sync + 'static,
+    {
+        let transport = connect(url)?;
+        Ok(ReconnectingTransport {
+            url: Arc::new(url.to_owned()),
+            connect: Arc::new(connect),
+            transport: Arc::new(RwLock::new(transport)),
+            id: Arc::new(AtomicUsize::new(1)),
+            timer: Timer::default(),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            limit: 10,
+        })
+    }
+
+    /// Sets the delay before the first reconnect and the maximum delay
+    /// between reconnects. The delay doubles with every failed attempt.
+    pub fn backoff(mut self, initial_delay: Duration, max_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sets the number of times a request is sent again before its error
+    /// is returned.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32.checked_shl(attempt as u32).unwrap_or(u32::max_value());
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Replaces the current connection with a new one. If connecting
+    /// fails, the old connection is kept, and the next attempt to send a
+    /// request over it will lead to another reconnect.
+    fn reconnect(&self) {
+        match (self.connect)(&self.url) {
+            Ok(transport) => *self.transport.write() = transport,
+            Err(e) => log::warn!("Reconnecting to {} failed: {}", self.url, e),
+        }
+    }
+}
+
+#[cfg(feature = "ws")]
+impl ReconnectingTransport<crate::transports::WebSocket> {
+    /// Creates a WebSocket transport for `url` that reconnects using the
+    /// event loop `handle`.
+    pub fn websocket(
+        url: &str,
+        handle: &crate::transports::tokio_core::reactor::Handle,
+    ) -> Result<Self> {
+        let handle = handle.remote().clone();
+        Self::new(url, move |url| {
+            let handle = handle.handle().ok_or(Error::Internal)?;
+            crate::transports::WebSocket::with_event_loop(url, &handle)
+        })
+    }
+}
+
+impl<T> Clone for ReconnectingTransport<T> {
+    fn clone(&self) -> Self {
+        ReconnectingTransport {
+            url: self.url.clone(),
+            connect: self.connect.clone(),
+            transport: self.transport.clone(),
+            id: self.id.clone(),
+            timer: self.timer.clone(),
+            initial_delay: self.initial_delay,
+            max_delay: self.max_delay,
+            limit: self.limit,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ReconnectingTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectingTransport")
+            .field("url", &self.url)
+            .field("transport", &*self.transport.read())
+            .finish()
+    }
+}
+
+impl<T> Transport for ReconnectingTransport<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send + 'static,
+{
+    type Out = crate::Result<rpc::Value>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        // Ids are assigned here rather than by the underlying transport so
+        // that they stay unique across connections
+        let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
+        let request = helpers::build_request(id, method, params);
+
+        (id, request)
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let this = self.clone();
+        Box::new(future::loop_fn(0, move |attempt| {
+            let this = this.clone();
+            let transport = this.transport.read().clone();
+            transport
+                .send(id, request.clone())
+                .then(move |result| match result {
+                    Err(ref e) if e.is_retryable() && attempt < this.limit => {
+                        log::debug!("Request {} to {} failed, reconnecting: {}", id, this.url, e);
+                        let sleep = this
+                            .timer
+                            .sleep(this.delay(attempt))
+                            .map_err(|e| Error::Transport(format!("{:?}", e)));
+                        Either::A(sleep.map(move |_| {
+                            this.reconnect();
+                            Loop::Continue(attempt + 1)
+                        }))
+                    }
+                    result => Either::B(future::result(result.map(Loop::Break))),
+                })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReconnectingTransport;
+    use crate::rpc;
+    use crate::{Error, RequestId, Transport};
+    use futures::{future, Future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A connection that fails every request when it is `broken`
+    #[derive(Debug, Clone)]
+    struct MockTransport {
+        broken: bool,
+    }
+
+    impl Transport for MockTransport {
+        type Out = crate::Result<rpc::Value>;
+
+        fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+            (1, crate::helpers::build_request(1, method, params))
+        }
+
+        fn send(&self, _id: RequestId, _request: rpc::Call) -> Self::Out {
+            if self.broken {
+                Box::new(future::err(Error::Transport("connection closed".into())))
+            } else {
+                Box::new(future::ok(rpc::Value::String("0x2a".into())))
+            }
+        }
+    }
+
+    fn transport(
+        broken_connections: usize,
+    ) -> (ReconnectingTransport<MockTransport>, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let transport = ReconnectingTransport::new("ws://localhost:8546", move |_| {
+            let broken = counter.fetch_add(1, Ordering::SeqCst) < broken_connections;
+            Ok(MockTransport { broken })
+        })
+        .unwrap()
+        .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        (transport, connects)
+    }
+
+    #[test]
+    fn should_reconnect_after_failure() {
+        let (transport, connects) = transport(1);
+
+        let result = transport.execute("eth_blockNumber", vec![]).wait();
+
+        assert_eq!(result, Ok(rpc::Value::String("0x2a".into())));
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn should_give_up_after_limit() {
+        let (transport, connects) = transport(usize::max_value());
+        let transport = transport.limit(3);
+
+        let result = transport.execute("eth_blockNumber", vec![]).wait();
+
+        assert_eq!(result, Err(Error::Transport("connection closed".into())));
+        assert_eq!(connects.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,271 @@
+//! Recording and Replaying Transports
+
+use crate::rpc;
+use crate::{Error, RequestId, Transport};
+use futures::{future, Future};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+/// One request and the response to it, as it is stored in a recording
+#[derive(Debug, Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    params: rpc::Params,
+    response: Response,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Result(rpc::Value),
+    Error(rpc::Error),
+    /// The request failed without a response from the node, e.g., because
+    /// of a transport error
+    Failure(String),
+}
+
+impl Response {
+    fn from_result(result: &Result<rpc::Value, Error>) -> Self {
+        match result {
+            Ok(value) => Response::Result(value.clone()),
+            Err(Error::Rpc(e)) => Response::Error(e.clone()),
+            Err(e) => Response::Failure(e.to_string()),
+        }
+    }
+
+    fn into_result(self) -> Result<rpc::Value, Error> {
+        match self {
+            Response::Result(value) => Ok(value),
+            Response::Error(e) => Err(Error::Rpc(e)),
+            Response::Failure(e) => Err(Error::Transport(e)),
+        }
+    }
+}
+
+fn method_and_params(request: &rpc::Call) -> Option<(&str, &rpc::Params)> {
+    match request {
+        rpc::Call::MethodCall(call) => Some((&call.method, &call.params)),
+        rpc::Call::Notification(call) => Some((&call.method, &call.params)),
+        rpc::Call::Invalid { .. } => None,
+    }
+}
+
+/// Transport that sends requests over another transport and appends every
+/// request together with its response to a file, one JSON object per line.
+/// A `ReplayTransport` can later serve the recorded responses, e.g., to
+/// run tests against a session with a real node without the node.
+#[derive(Debug, Clone)]
+pub struct RecordingTransport<T> {
+    transport: T,
+    file: Arc<Mutex<File>>,
+}
+
+impl<T> RecordingTransport<T> {
+    /// Records all requests sent over `transport` to the file at `path`.
+    /// If the file already exists, the requests are appended to it.
+    pub fn new<P: AsRef<Path>>(transport: T, path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordingTransport {
+            transport,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+
+impl<T> Transport for RecordingTransport<T>
+where
+    T: Transport,
+    T::Out: Send + 'static,
+{
+    type Out = crate::Result<rpc::Value>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        self.transport.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let recorded =
+            method_and_params(&request).map(|(method, params)| (method.to_owned(), params.clone()));
+        let file = self.file.clone();
+        Box::new(self.transport.send(id, request).then(move |result| {
+            if let Some((method, params)) = recorded {
+                let interaction = Interaction {
+                    method,
+                    params,
+                    response: Response::from_result(&result),
+                };
+                let line =
+                    serde_json::to_string(&interaction).expect("interactions can be serialized");
+                if let Err(e) = writeln!(file.lock(), "{}", line) {
+                    log::warn!("Failed to record request {}: {}", id, e);
+                }
+            }
+            result
+        }))
+    }
+}
+
+type ReplayKey = (String, String);
+
+/// Transport that answers requests with the responses from a recording
+/// made by a `RecordingTransport`, without talking to a node. Requests are
+/// matched by method and parameters; when the same request was recorded
+/// several times, its responses are served in the order in which they were
+/// recorded. Requests without a recorded response fail with a transport
+/// error. Errors other than errors from the node are replayed as transport
+/// errors with the same message.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    responses: Arc<Mutex<HashMap<ReplayKey, VecDeque<Response>>>>,
+    id: Arc<AtomicUsize>,
+}
+
+impl ReplayTransport {
+    /// Replays the recording in the file at `path`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Replays the recording read from `reader`
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut responses: HashMap<ReplayKey, VecDeque<Response>> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let interaction: Interaction = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            responses
+                .entry(Self::key(&interaction.method, &interaction.params))
+                .or_default()
+                .push_back(interaction.response);
+        }
+        Ok(ReplayTransport {
+            responses: Arc::new(Mutex::new(responses)),
+            id: Arc::new(AtomicUsize::new(1)),
+        })
+    }
+
+    fn key(method: &str, params: &rpc::Params) -> ReplayKey {
+        let params = serde_json::to_string(params).expect("params can be serialized");
+        (method.to_owned(), params)
+    }
+}
+
+impl Transport for ReplayTransport {
+    type Out = crate::Result<rpc::Value>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        let id = self.id.fetch_add(1, atomic::Ordering::AcqRel);
+        (id, crate::helpers::build_request(id, method, params))
+    }
+
+    fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+        let (method, params) = match method_and_params(&request) {
+            Some(call) => call,
+            None => return Box::new(future::err(Error::Transport("invalid request".into()))),
+        };
+        let key = Self::key(method, params);
+        let response = self
+            .responses
+            .lock()
+            .get_mut(&key)
+            .and_then(|responses| responses.pop_front());
+        let result = match response {
+            Some(response) => response.into_result(),
+            None => Err(Error::Transport(format!(
+                "no recorded response for {} with params {}",
+                key.0, key.1
+            ))),
+        };
+        Box::new(future::result(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordingTransport, ReplayTransport};
+    use crate::rpc;
+    use crate::{Error, RequestId, Transport};
+    use futures::{future, Future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A node whose block number goes up with every request for it
+    #[derive(Debug, Clone, Default)]
+    struct MockTransport {
+        block: Arc<AtomicUsize>,
+    }
+
+    impl Transport for MockTransport {
+        type Out = crate::Result<rpc::Value>;
+
+        fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+            (1, crate::helpers::build_request(1, method, params))
+        }
+
+        fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+            let method = match request {
+                rpc::Call::MethodCall(call) => call.method,
+                _ => unreachable!(),
+            };
+            match method.as_str() {
+                "eth_blockNumber" => {
+                    let block = self.block.fetch_add(1, Ordering::SeqCst) + 1;
+                    Box::new(future::ok(rpc::Value::String(format!("{:#x}", block))))
+                }
+                "eth_getBalance" => Box::new(future::ok(rpc::Value::String("0x2a".into()))),
+                _ => Box::new(future::err(Error::Rpc(rpc::Error::method_not_found()))),
+            }
+        }
+    }
+
+    fn calls() -> Vec<(&'static str, Vec<rpc::Value>)> {
+        let address = rpc::Value::String("0x0000000000000000000000000000000000000001".into());
+        vec![
+            ("eth_blockNumber", vec![]),
+            (
+                "eth_getBalance",
+                vec![address.clone(), rpc::Value::String("latest".into())],
+            ),
+            ("eth_blockNumber", vec![]),
+            ("eth_unknown", vec![address]),
+        ]
+    }
+
+    fn execute<T: Transport>(transport: &T) -> Vec<Result<rpc::Value, Error>> {
+        calls()
+            .into_iter()
+            .map(|(method, params)| transport.execute(method, params).wait())
+            .collect()
+    }
+
+    #[test]
+    fn should_replay_recorded_responses() {
+        let path =
+            std::env::temp_dir().join(format!("web3-recording-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recording = RecordingTransport::new(MockTransport::default(), &path).unwrap();
+        let recorded = execute(&recording);
+        assert_eq!(Ok(rpc::Value::String("0x1".into())), recorded[0]);
+        assert_eq!(Ok(rpc::Value::String("0x2".into())), recorded[2]);
+
+        let replay = ReplayTransport::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded, execute(&replay));
+
+        // Every recorded response is only served once
+        match replay.execute("eth_blockNumber", vec![]).wait() {
+            Err(Error::Transport(msg)) => assert!(msg.contains("eth_blockNumber"), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}